@@ -0,0 +1,71 @@
+//! Compares building a sorted, deduplicated Vec with repeated
+//! `util::insert_sorted` against collecting and sorting once.
+//!
+//! Run with `cargo bench --bench insert_sorted`.
+
+#[allow(dead_code)]
+#[path = "../src/util.rs"]
+mod util;
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Deterministic pseudo-random (package, payload index) style keys, with some
+/// duplicates like the payloads shared between packages.
+fn items(n: usize) -> Vec<(u32, usize)> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..n)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (
+                (state % 8) as u32,
+                (state >> 8) as usize % (n * 4 / 5).max(1),
+            )
+        })
+        .collect()
+}
+
+fn time(iterations: u32, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    start.elapsed() / iterations
+}
+
+fn main() {
+    let cmp = |a: &(u32, usize), b: &(u32, usize)| a.cmp(b);
+    println!(
+        "{:>6} {:>14} {:>14} {:>14}",
+        "items", "insert_sorted", "sort_unstable", "build_sorted"
+    );
+    for n in [10, 25, 50, 100, 250, 1000, 10000] {
+        let data = items(n);
+        let iterations = (1_000_000 / n as u32).max(10);
+        let insert = time(iterations, || {
+            let mut list = Vec::new();
+            for item in &data {
+                util::insert_sorted(&mut list, *item, cmp);
+            }
+            black_box(list);
+        });
+        let sort_unstable = time(iterations, || {
+            let mut list = Vec::with_capacity(data.len());
+            for item in &data {
+                list.push(*item);
+            }
+            list.sort_unstable_by(cmp);
+            list.dedup();
+            black_box(list);
+        });
+        let build = time(iterations, || {
+            black_box(util::build_sorted(data.iter().copied(), cmp));
+        });
+        println!(
+            "{:>6} {:>14?} {:>14?} {:>14?}",
+            n, insert, sort_unstable, build
+        );
+    }
+}
@@ -6,10 +6,19 @@ pub enum Arch {
     X86,
     Arm,
     Arm64,
+    /// ARM64EC ("emulation compatible"): arm64 code that interoperates with x64.
+    /// Built with the arm64-targeting tools plus the `lib\arm64ec` libraries.
+    Arm64Ec,
 }
 
 impl Arch {
+    /// The arch of the OS: on Windows the one it reports for the machine, so a
+    /// 32-bit build of msvcup on 64-bit Windows still picks x64 host tools;
+    /// elsewhere the arch msvcup was built for.
     pub fn native() -> Option<Arch> {
+        if let Some(arch) = windows_os_arch() {
+            return Some(arch);
+        }
         if cfg!(target_arch = "x86_64") {
             Some(Arch::X64)
         } else if cfg!(target_arch = "x86") {
@@ -23,12 +32,29 @@ impl Arch {
         }
     }
 
+    /// Parse a `PROCESSOR_ARCHITECTURE` value (`AMD64`, `x86`, `ARM` or `ARM64`).
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn from_windows_processor_architecture(s: &str) -> Option<Arch> {
+        if s.eq_ignore_ascii_case("AMD64") {
+            Some(Arch::X64)
+        } else if s.eq_ignore_ascii_case("x86") {
+            Some(Arch::X86)
+        } else if s.eq_ignore_ascii_case("ARM") {
+            Some(Arch::Arm)
+        } else if s.eq_ignore_ascii_case("ARM64") {
+            Some(Arch::Arm64)
+        } else {
+            None
+        }
+    }
+
     pub fn from_str_exact(s: &str) -> Option<Arch> {
         match s {
             "x64" => Some(Arch::X64),
             "x86" => Some(Arch::X86),
             "arm" => Some(Arch::Arm),
             "arm64" => Some(Arch::Arm64),
+            "arm64ec" => Some(Arch::Arm64Ec),
             _ => None,
         }
     }
@@ -42,6 +68,8 @@ impl Arch {
             Some(Arch::Arm)
         } else if s.eq_ignore_ascii_case("arm64") {
             Some(Arch::Arm64)
+        } else if s.eq_ignore_ascii_case("arm64ec") {
+            Some(Arch::Arm64Ec)
         } else {
             None
         }
@@ -53,10 +81,50 @@ impl Arch {
             Arch::X86 => "x86",
             Arch::Arm => "arm",
             Arch::Arm64 => "arm64",
+            Arch::Arm64Ec => "arm64ec",
         }
     }
 
-    pub const ALL: [Arch; 4] = [Arch::X64, Arch::X86, Arch::Arm, Arch::Arm64];
+    /// The arch whose tools and payloads are used when targeting this arch.
+    /// ARM64EC is built with the arm64 toolchain, everything else is itself.
+    pub fn payload_arch(&self) -> Arch {
+        match self {
+            Arch::Arm64Ec => Arch::Arm64,
+            other => *other,
+        }
+    }
+
+    /// Whether a package or payload for `pkg_arch` is needed when targeting this arch.
+    pub fn accepts_payload_arch(&self, pkg_arch: Arch) -> bool {
+        pkg_arch == *self || pkg_arch == self.payload_arch()
+    }
+
+    /// Why targeting this arch is deprecated, if it is.
+    pub fn deprecation(&self) -> Option<&'static str> {
+        match self {
+            Arch::Arm => Some(
+                "32-bit ARM is deprecated: Windows 11 doesn't run it and Windows SDK \
+                 10.0.26100 and later no longer ship its libraries",
+            ),
+            _ => None,
+        }
+    }
+
+    pub const ALL: [Arch; 5] = [Arch::X64, Arch::X86, Arch::Arm, Arch::Arm64, Arch::Arm64Ec];
+}
+
+/// `PROCESSOR_ARCHITECTURE` is the arch of the process, a 32-bit process on a
+/// 64-bit Windows sees the machine's in `PROCESSOR_ARCHITEW6432` instead.
+#[cfg(windows)]
+fn windows_os_arch() -> Option<Arch> {
+    ["PROCESSOR_ARCHITEW6432", "PROCESSOR_ARCHITECTURE"]
+        .into_iter()
+        .find_map(|var| Arch::from_windows_processor_architecture(&std::env::var(var).ok()?))
+}
+
+#[cfg(not(windows))]
+fn windows_os_arch() -> Option<Arch> {
+    None
 }
 
 impl fmt::Display for Arch {
@@ -75,12 +143,35 @@ mod tests {
         assert!(Arch::native().is_some());
     }
 
+    #[test]
+    fn from_windows_processor_architecture() {
+        assert_eq!(
+            Arch::from_windows_processor_architecture("AMD64"),
+            Some(Arch::X64)
+        );
+        assert_eq!(
+            Arch::from_windows_processor_architecture("x86"),
+            Some(Arch::X86)
+        );
+        assert_eq!(
+            Arch::from_windows_processor_architecture("ARM"),
+            Some(Arch::Arm)
+        );
+        assert_eq!(
+            Arch::from_windows_processor_architecture("ARM64"),
+            Some(Arch::Arm64)
+        );
+        assert_eq!(Arch::from_windows_processor_architecture("IA64"), None);
+        assert_eq!(Arch::from_windows_processor_architecture(""), None);
+    }
+
     #[test]
     fn from_str_exact_valid() {
         assert_eq!(Arch::from_str_exact("x64"), Some(Arch::X64));
         assert_eq!(Arch::from_str_exact("x86"), Some(Arch::X86));
         assert_eq!(Arch::from_str_exact("arm"), Some(Arch::Arm));
         assert_eq!(Arch::from_str_exact("arm64"), Some(Arch::Arm64));
+        assert_eq!(Arch::from_str_exact("arm64ec"), Some(Arch::Arm64Ec));
     }
 
     #[test]
@@ -103,6 +194,7 @@ mod tests {
         assert_eq!(Arch::from_str_ignore_case("ARM"), Some(Arch::Arm));
         assert_eq!(Arch::from_str_ignore_case("Arm64"), Some(Arch::Arm64));
         assert_eq!(Arch::from_str_ignore_case("ARM64"), Some(Arch::Arm64));
+        assert_eq!(Arch::from_str_ignore_case("ARM64EC"), Some(Arch::Arm64Ec));
     }
 
     #[test]
@@ -111,6 +203,13 @@ mod tests {
         assert_eq!(Arch::from_str_ignore_case("mips"), None);
     }
 
+    #[test]
+    fn only_arm_is_deprecated() {
+        for arch in Arch::ALL {
+            assert_eq!(arch.deprecation().is_some(), arch == Arch::Arm, "{}", arch);
+        }
+    }
+
     #[test]
     fn as_str_roundtrip() {
         for arch in Arch::ALL {
@@ -126,7 +225,17 @@ mod tests {
     }
 
     #[test]
-    fn all_contains_four_variants() {
-        assert_eq!(Arch::ALL.len(), 4);
+    fn all_contains_five_variants() {
+        assert_eq!(Arch::ALL.len(), 5);
+    }
+
+    #[test]
+    fn arm64ec_uses_arm64_payloads() {
+        assert_eq!(Arch::Arm64Ec.payload_arch(), Arch::Arm64);
+        assert_eq!(Arch::X64.payload_arch(), Arch::X64);
+        assert!(Arch::Arm64Ec.accepts_payload_arch(Arch::Arm64));
+        assert!(Arch::Arm64Ec.accepts_payload_arch(Arch::Arm64Ec));
+        assert!(!Arch::Arm64Ec.accepts_payload_arch(Arch::X64));
+        assert!(!Arch::Arm64.accepts_payload_arch(Arch::Arm64Ec));
     }
 }
@@ -1,4 +1,9 @@
 use crate::arch::Arch;
+use crate::install::{FinishKind, env_entries};
+use crate::manifest::MsvcupDir;
+use crate::packages::MsvcupPackage;
+use anyhow::Result;
+use std::path::Path;
 
 pub struct Tool {
     pub name: &'static str,
@@ -35,19 +40,44 @@ pub const SDK_TOOLS: &[Tool] = &[
     },
 ];
 
-pub fn generate_toolchain_cmake(target_cpu: Arch, has_msvc: bool, has_sdk: bool) -> String {
+pub const NASM_TOOLS: &[Tool] = &[Tool {
+    name: "nasm",
+    cmake_names: &["ASM_NASM_COMPILER"],
+}];
+
+pub const PYTHON_TOOLS: &[Tool] = &[Tool {
+    name: "python",
+    cmake_names: &[],
+}];
+
+pub fn generate_toolchain_cmake(
+    target_cpu: Arch,
+    has_msvc: bool,
+    has_sdk: bool,
+    has_nasm: bool,
+) -> String {
     let mut content = String::new();
     content.push_str("set(CMAKE_SYSTEM_NAME Windows)\n");
 
     let processor = match target_cpu {
         Arch::X64 => Some("AMD64"),
         Arch::X86 => Some("X86"),
-        Arch::Arm => None,
-        Arch::Arm64 => Some("ARM64"),
+        Arch::Arm => Some("ARM"),
+        Arch::Arm64 | Arch::Arm64Ec => Some("ARM64"),
     };
     if let Some(proc) = processor {
         content.push_str(&format!("set(CMAKE_SYSTEM_PROCESSOR {})\n", proc));
     }
+    if target_cpu == Arch::Arm64Ec {
+        content.push_str("set(CMAKE_C_FLAGS_INIT \"/arm64EC\")\n");
+        content.push_str("set(CMAKE_CXX_FLAGS_INIT \"/arm64EC\")\n");
+        for kind in ["EXE", "SHARED", "MODULE"] {
+            content.push_str(&format!(
+                "set(CMAKE_{}_LINKER_FLAGS_INIT \"/MACHINE:ARM64EC\")\n",
+                kind
+            ));
+        }
+    }
 
     if has_msvc {
         for tool in MSVC_TOOLS {
@@ -69,6 +99,207 @@ pub fn generate_toolchain_cmake(target_cpu: Arch, has_msvc: bool, has_sdk: bool)
             }
         }
     }
+    if has_nasm {
+        for tool in NASM_TOOLS {
+            for cmake_name in tool.cmake_names {
+                content.push_str(&format!(
+                    "set(CMAKE_{} \"${{CMAKE_CURRENT_LIST_DIR}}/{}.exe\")\n",
+                    cmake_name, tool.name
+                ));
+            }
+        }
+    }
+
+    // The response files are only written once the packages are installed
+    if has_msvc || has_sdk {
+        content.push_str("if(EXISTS \"${CMAKE_CURRENT_LIST_DIR}/cl.rsp\")\n");
+        for lang in ["C", "CXX"] {
+            content.push_str(&format!(
+                "  string(APPEND CMAKE_{}_FLAGS_INIT \" @\\\"${{CMAKE_CURRENT_LIST_DIR}}/cl.rsp\\\"\")\n",
+                lang
+            ));
+        }
+        content.push_str("endif()\n");
+        content.push_str("if(EXISTS \"${CMAKE_CURRENT_LIST_DIR}/link.rsp\")\n");
+        for kind in ["EXE", "SHARED", "MODULE"] {
+            content.push_str(&format!(
+                "  string(APPEND CMAKE_{}_LINKER_FLAGS_INIT \" @\\\"${{CMAKE_CURRENT_LIST_DIR}}/link.rsp\\\"\")\n",
+                kind
+            ));
+        }
+        content.push_str("endif()\n");
+    }
 
     content
 }
+
+/// Write `cl.rsp` and `link.rsp` with the include and library directories of the
+/// msvc and sdk packages. These need the installed version directories, so they
+/// are skipped until every such package is installed, `msvcup install --rsp-dir`
+/// writes them afterwards.
+pub fn write_rsp_files(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_arch: Arch,
+    out_dir: &Path,
+) -> Result<()> {
+    let mut cl_rsp = String::new();
+    let mut link_rsp = String::new();
+    for msvcup_pkg in msvcup_pkgs {
+        let Some(finish_kind) = FinishKind::from_package_kind(msvcup_pkg.kind) else {
+            continue;
+        };
+        let install_path = msvcup_dir.package_path(msvcup_pkg);
+        let version = match crate::install::query_package_version(msvcup_pkg, &install_path) {
+            Ok(Some((version, _))) => version,
+            _ => {
+                log::info!(
+                    "{} is not installed yet, run 'msvcup-autoenv install' to generate cl.rsp and link.rsp",
+                    msvcup_pkg
+                );
+                return Ok(());
+            }
+        };
+        cl_rsp.push_str(&generate_cl_rsp(
+            finish_kind,
+            &install_path,
+            &version,
+            target_arch,
+        ));
+        link_rsp.push_str(&generate_link_rsp(
+            finish_kind,
+            &install_path,
+            &version,
+            target_arch,
+        ));
+    }
+    if cl_rsp.is_empty() {
+        return Ok(());
+    }
+    crate::util::update_file(&out_dir.join("cl.rsp"), cl_rsp.as_bytes())?;
+    crate::util::update_file(&out_dir.join("link.rsp"), link_rsp.as_bytes())?;
+    Ok(())
+}
+
+/// `cl.rsp` lines adding a package's include directories, one `/I"<dir>"` per line.
+pub fn generate_cl_rsp(
+    finish_kind: FinishKind,
+    install_path: &Path,
+    install_version: &str,
+    target: Arch,
+) -> String {
+    rsp_lines(
+        finish_kind,
+        install_path,
+        install_version,
+        target,
+        "INCLUDE",
+        "/I",
+    )
+}
+
+/// `link.rsp` lines adding a package's library directories, one `/LIBPATH:"<dir>"` per line.
+pub fn generate_link_rsp(
+    finish_kind: FinishKind,
+    install_path: &Path,
+    install_version: &str,
+    target: Arch,
+) -> String {
+    rsp_lines(
+        finish_kind,
+        install_path,
+        install_version,
+        target,
+        "LIB",
+        "/LIBPATH:",
+    )
+}
+
+fn rsp_lines(
+    finish_kind: FinishKind,
+    install_path: &Path,
+    install_version: &str,
+    target: Arch,
+    var: &str,
+    flag: &str,
+) -> String {
+    // The host arch only affects PATH
+    let host = Arch::native().unwrap_or(Arch::X64);
+    let env = env_entries(finish_kind, install_version, host, target, install_path);
+    env.get(var)
+        .into_iter()
+        .flatten()
+        .map(|dir| format!("{}\"{}\"\n", flag, dir))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cl_and_link_rsp() {
+        let msvc = Path::new("C:\\msvcup\\msvc-14.43.17.13");
+        let sdk = Path::new("C:\\msvcup\\sdk-10.0.22621.7");
+        assert_eq!(
+            generate_cl_rsp(FinishKind::Msvc, msvc, "14.43.34808", Arch::X64),
+            "/I\"C:\\msvcup\\msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\include\"\n"
+        );
+        let cl_rsp = generate_cl_rsp(FinishKind::Sdk, sdk, "10.0.22621.0", Arch::X64);
+        assert!(cl_rsp.starts_with(
+            "/I\"C:\\msvcup\\sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\ucrt\"\n"
+        ));
+        assert_eq!(cl_rsp.lines().count(), 5);
+
+        assert_eq!(
+            generate_link_rsp(FinishKind::Msvc, msvc, "14.43.34808", Arch::Arm64Ec),
+            "/LIBPATH:\"C:\\msvcup\\msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\lib\\arm64ec\"\n\
+             /LIBPATH:\"C:\\msvcup\\msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\lib\\arm64\"\n"
+        );
+        let link_rsp = generate_link_rsp(FinishKind::Sdk, sdk, "10.0.22621.0", Arch::X86);
+        assert!(link_rsp.contains("Lib\\10.0.22621.0\\ucrt\\x86\"\n"));
+        assert!(link_rsp.contains("Lib\\10.0.22621.0\\um\\x86\"\n"));
+    }
+
+    #[test]
+    fn toolchain_cmake_uses_rsp_files() {
+        let cmake = generate_toolchain_cmake(Arch::Arm64Ec, true, true, false);
+        assert!(cmake.contains("set(CMAKE_C_FLAGS_INIT \"/arm64EC\")\n"));
+        assert!(cmake.contains(
+            "  string(APPEND CMAKE_CXX_FLAGS_INIT \" @\\\"${CMAKE_CURRENT_LIST_DIR}/cl.rsp\\\"\")\n"
+        ));
+        assert!(cmake.contains(
+            "  string(APPEND CMAKE_SHARED_LINKER_FLAGS_INIT \" @\\\"${CMAKE_CURRENT_LIST_DIR}/link.rsp\\\"\")\n"
+        ));
+        assert!(!generate_toolchain_cmake(Arch::X64, false, false, false).contains("rsp"));
+    }
+
+    #[test]
+    fn toolchain_cmake_nasm() {
+        let nasm = "set(CMAKE_ASM_NASM_COMPILER \"${CMAKE_CURRENT_LIST_DIR}/nasm.exe\")\n";
+        assert!(generate_toolchain_cmake(Arch::X64, true, true, true).contains(nasm));
+        assert!(!generate_toolchain_cmake(Arch::X64, true, true, false).contains("NASM"));
+        // nasm alone doesn't need the response files
+        let cmake = generate_toolchain_cmake(Arch::X64, false, false, true);
+        assert!(cmake.contains(nasm));
+        assert!(!cmake.contains("rsp"));
+    }
+
+    #[test]
+    fn toolchain_cmake_system_processor() {
+        for (arch, processor) in [
+            (Arch::X64, "AMD64"),
+            (Arch::X86, "X86"),
+            (Arch::Arm, "ARM"),
+            (Arch::Arm64, "ARM64"),
+            (Arch::Arm64Ec, "ARM64"),
+        ] {
+            let cmake = generate_toolchain_cmake(arch, true, true, false);
+            assert!(
+                cmake.contains(&format!("set(CMAKE_SYSTEM_PROCESSOR {})\n", processor)),
+                "{}",
+                arch
+            );
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use crate::arch::Arch;
+use crate::error::{ErrorKind, bail_kind};
+use crate::install::{FinishKind, env_entries, query_package_version, select_host_arch};
+use crate::manifest::MsvcupDir;
+use crate::packages::MsvcupPackage;
+use anyhow::{Result, bail};
+use fs_err as fs;
+use std::path::Path;
+
+/// The files `bazel-toolchain` writes to its output directory.
+pub const BUILD_FILE_NAME: &str = "BUILD.bazel";
+pub const BZL_FILE_NAME: &str = "msvcup_toolchain.bzl";
+/// Stands in for the tools MSVC doesn't have (nm, objcopy, ...), like the
+/// `msvc_nop.bat` of Bazel's auto-configured toolchain.
+pub const NOP_FILE_NAME: &str = "msvc_nop.bat";
+
+/// The names Bazel's auto-configured MSVC toolchain and the `@platforms` repo
+/// use for a target arch.
+struct BazelArch {
+    /// `cpu` of the toolchain config
+    cpu: &'static str,
+    /// `@platforms//cpu` constraint
+    constraint: &'static str,
+    /// `/MACHINE` of lib.exe and link.exe
+    machine: &'static str,
+    /// The MASM (or ARMASM) assembler
+    assembler: &'static str,
+}
+
+fn bazel_arch(arch: Arch) -> Option<BazelArch> {
+    let (cpu, constraint, machine, assembler) = match arch {
+        Arch::X64 => ("x64_windows", "x86_64", "X64", "ml64.exe"),
+        Arch::X86 => ("x64_x86_windows", "x86_32", "X86", "ml.exe"),
+        Arch::Arm => ("x64_arm_windows", "armv7", "ARM", "armasm.exe"),
+        Arch::Arm64 => ("arm64_windows", "arm64", "ARM64", "armasm64.exe"),
+        Arch::Arm64Ec => return None,
+    };
+    Some(BazelArch {
+        cpu,
+        constraint,
+        machine,
+        assembler,
+    })
+}
+
+/// The values of a Bazel `cc_toolchain_config` for the installed msvc and sdk
+/// packages, taken from their versioned directories like `env` does.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BazelToolchain {
+    pub target_arch: Arch,
+    pub host_arch: Arch,
+    pub cl: String,
+    pub link: String,
+    pub lib: String,
+    pub ml: String,
+    /// The msvc and sdk tool directories
+    pub path: Vec<String>,
+    /// `INCLUDE`, also the `cxx_builtin_include_directories`
+    pub include: Vec<String>,
+    /// `LIB`, also passed as `/LIBPATH:` default link flags
+    pub lib_dirs: Vec<String>,
+}
+
+impl BazelToolchain {
+    /// Resolve the toolchain from an installed msvc and sdk package, using the
+    /// host tools [`install`](crate::install) would put on `PATH` for
+    /// `native_arch`.
+    pub fn resolve(
+        msvcup_dir: &MsvcupDir,
+        msvcup_pkgs: &[MsvcupPackage],
+        target_arch: Arch,
+        native_arch: Arch,
+    ) -> Result<Self> {
+        if bazel_arch(target_arch).is_none() {
+            bail_kind!(
+                ErrorKind::Usage,
+                "bazel-toolchain doesn't support {}, Bazel has no MSVC toolchain for it",
+                target_arch
+            );
+        }
+        let mut msvc_host = None;
+        let (mut path, mut include, mut lib_dirs) = (Vec::new(), Vec::new(), Vec::new());
+        for kind in [FinishKind::Msvc, FinishKind::Sdk] {
+            let Some(msvcup_pkg) = msvcup_pkgs
+                .iter()
+                .find(|p| FinishKind::from_package_kind(p.kind) == Some(kind))
+            else {
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "bazel-toolchain needs an msvc and an sdk package"
+                );
+            };
+            let install_path = msvcup_dir.package_path(msvcup_pkg);
+            if !install_path.is_dir() {
+                bail!(
+                    "'{}' does not exist, install {} first",
+                    install_path.display(),
+                    msvcup_pkg
+                );
+            }
+            let (version, _) = query_package_version(msvcup_pkg, &install_path)?
+                .expect("msvc and sdk packages have a versioned layout");
+            let Some(host_arch) = select_host_arch(kind, &version, &install_path, native_arch)
+            else {
+                bail!("{}: no host tools are installed", msvcup_pkg);
+            };
+            if kind == FinishKind::Msvc {
+                msvc_host = Some(host_arch);
+            }
+            let mut env = env_entries(kind, &version, host_arch, target_arch, &install_path);
+            path.extend(env.remove("PATH").unwrap_or_default());
+            include.extend(env.remove("INCLUDE").unwrap_or_default());
+            lib_dirs.extend(env.remove("LIB").unwrap_or_default());
+        }
+        let bazel = bazel_arch(target_arch).expect("checked above");
+        // The first PATH entry is the msvc bin\Host{host}\{target} directory
+        let tool = |exe_name: &str| format!("{}\\{}", path[0], exe_name);
+        Ok(Self {
+            target_arch,
+            host_arch: msvc_host.expect("msvc is resolved first"),
+            cl: tool("cl.exe"),
+            link: tool("link.exe"),
+            lib: tool("lib.exe"),
+            ml: tool(bazel.assembler),
+            path,
+            include,
+            lib_dirs,
+        })
+    }
+
+    /// The `.bzl` file holding the resolved paths in a dict named after the Bazel cpu.
+    pub fn render_bzl(&self) -> String {
+        let bazel = bazel_arch(self.target_arch).expect("checked by resolve");
+        let mut link_flags = vec![format!("/MACHINE:{}", bazel.machine)];
+        link_flags.extend(self.lib_dirs.iter().map(|dir| format!("/LIBPATH:{}", dir)));
+
+        let mut out = String::new();
+        out.push_str("# Generated by msvcup bazel-toolchain, do not edit.\n\n");
+        out.push_str(&format!("MSVCUP_{} = {{\n", bazel.cpu.to_uppercase()));
+        out.push_str(&format!("    \"cpu\": {},\n", starlark_str(bazel.cpu)));
+        out.push_str(&format!(
+            "    \"host_cpu\": {},\n",
+            starlark_str(bazel_arch(self.host_arch).map_or("x86_64", |a| a.constraint))
+        ));
+        out.push_str(&format!(
+            "    \"target_cpu\": {},\n",
+            starlark_str(bazel.constraint)
+        ));
+        for (key, value) in [
+            ("msvc_cl_path", &self.cl),
+            ("msvc_link_path", &self.link),
+            ("msvc_lib_path", &self.lib),
+            ("msvc_ml_path", &self.ml),
+            ("msvc_env_path", &self.path.join(";")),
+            ("msvc_env_include", &self.include.join(";")),
+            ("msvc_env_lib", &self.lib_dirs.join(";")),
+        ] {
+            out.push_str(&format!(
+                "    {}: {},\n",
+                starlark_str(key),
+                starlark_str(value)
+            ));
+        }
+        for (key, values) in [
+            ("cxx_builtin_include_directories", &self.include),
+            (
+                "archiver_flags",
+                &vec![format!("/MACHINE:{}", bazel.machine)],
+            ),
+            ("default_link_flags", &link_flags),
+        ] {
+            out.push_str(&format!("    {}: [\n", starlark_str(key)));
+            for value in values {
+                out.push_str(&format!("        {},\n", starlark_str(value)));
+            }
+            out.push_str("    ],\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// The `BUILD.bazel` declaring the toolchain with the `cc_toolchain_config`
+    /// rule of Bazel's auto-configured MSVC toolchain, filled in from the `.bzl`.
+    pub fn render_build(&self) -> String {
+        let bazel = bazel_arch(self.target_arch).expect("checked by resolve");
+        let name = format!("msvcup_{}", self.target_arch);
+        let dict = format!("MSVCUP_{}", bazel.cpu.to_uppercase());
+        format!(
+            r#"# Generated by msvcup bazel-toolchain, do not edit.
+
+load("@bazel_tools//tools/cpp:windows_cc_toolchain_config.bzl", "cc_toolchain_config")
+load("@rules_cc//cc:defs.bzl", "cc_toolchain")
+load(":{bzl}", "{dict}")
+
+package(default_visibility = ["//visibility:public"])
+
+filegroup(
+    name = "empty",
+    srcs = [],
+)
+
+filegroup(
+    name = "nop",
+    srcs = ["{nop}"],
+)
+
+cc_toolchain_config(
+    name = "{name}_config",
+    abi_libc_version = "local",
+    abi_version = "local",
+    archiver_flags = {dict}["archiver_flags"],
+    compiler = "msvc-cl",
+    cpu = {dict}["cpu"],
+    cxx_builtin_include_directories = {dict}["cxx_builtin_include_directories"],
+    dbg_mode_debug_flag = "/DEBUG:FULL",
+    default_link_flags = {dict}["default_link_flags"],
+    fastbuild_mode_debug_flag = "/DEBUG:FASTLINK",
+    host_system_name = "local",
+    msvc_cl_path = {dict}["msvc_cl_path"],
+    msvc_env_include = {dict}["msvc_env_include"],
+    msvc_env_lib = {dict}["msvc_env_lib"],
+    msvc_env_path = {dict}["msvc_env_path"],
+    msvc_env_tmp = "C:\\Windows\\Temp",
+    msvc_lib_path = {dict}["msvc_lib_path"],
+    msvc_link_path = {dict}["msvc_link_path"],
+    msvc_ml_path = {dict}["msvc_ml_path"],
+    supports_parse_showincludes = True,
+    target_libc = "msvcrt",
+    target_system_name = "local",
+    tool_paths = {{
+        "ar": {dict}["msvc_lib_path"],
+        "cpp": {dict}["msvc_cl_path"],
+        "gcc": {dict}["msvc_cl_path"],
+        "gcov": "{nop}",
+        "ld": {dict}["msvc_link_path"],
+        "ml": {dict}["msvc_ml_path"],
+        "nm": "{nop}",
+        "objcopy": "{nop}",
+        "objdump": "{nop}",
+        "strip": "{nop}",
+    }},
+    toolchain_identifier = "{name}",
+)
+
+cc_toolchain(
+    name = "{name}_cc_toolchain",
+    all_files = ":nop",
+    ar_files = ":empty",
+    as_files = ":empty",
+    compiler_files = ":empty",
+    dwp_files = ":empty",
+    linker_files = ":empty",
+    objcopy_files = ":empty",
+    strip_files = ":empty",
+    supports_param_files = 1,
+    toolchain_config = ":{name}_config",
+    toolchain_identifier = "{name}",
+)
+
+toolchain(
+    name = "{name}_toolchain",
+    exec_compatible_with = [
+        "@platforms//cpu:" + {dict}["host_cpu"],
+        "@platforms//os:windows",
+    ],
+    target_compatible_with = [
+        "@platforms//cpu:" + {dict}["target_cpu"],
+        "@platforms//os:windows",
+    ],
+    toolchain = ":{name}_cc_toolchain",
+    toolchain_type = "@bazel_tools//tools/cpp:toolchain_type",
+)
+"#,
+            bzl = BZL_FILE_NAME,
+            nop = NOP_FILE_NAME,
+        )
+    }
+}
+
+/// A Starlark string literal.
+fn starlark_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Write a Bazel package to `out_dir` that registers the msvc and sdk packages as
+/// a C++ toolchain for `target_arch`. Files that didn't change aren't rewritten.
+pub fn bazel_toolchain_command(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_arch: Arch,
+    out_dir: &Path,
+) -> Result<()> {
+    let native_arch = Arch::native().unwrap_or(Arch::X64);
+    let toolchain = BazelToolchain::resolve(msvcup_dir, msvcup_pkgs, target_arch, native_arch)?;
+    fs::create_dir_all(out_dir)?;
+    for (name, content) in [
+        (BUILD_FILE_NAME, toolchain.render_build()),
+        (BZL_FILE_NAME, toolchain.render_bzl()),
+        (NOP_FILE_NAME, "@echo off\n".to_string()),
+    ] {
+        crate::util::update_file(&out_dir.join(name), content.as_bytes())?;
+    }
+    log::info!(
+        "wrote the {} toolchain to '{}', register it with \
+         register_toolchains(\"//{}:msvcup_{}_toolchain\")",
+        target_arch,
+        out_dir.display(),
+        out_dir.display(),
+        target_arch
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::MsvcupPackageKind;
+    use crate::test_support::TempDir;
+
+    /// A fabricated install tree with the directories `resolve` looks at.
+    fn fixture_install(root: &Path) -> Vec<MsvcupPackage> {
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13");
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        let msvc_dir = root
+            .join(msvc.pool_string())
+            .join("VC")
+            .join("Tools")
+            .join("MSVC");
+        std::fs::create_dir_all(msvc_dir.join("14.43.34808").join("bin").join("Hostx64")).unwrap();
+        let sdk_dir = root.join(sdk.pool_string()).join("Windows Kits").join("10");
+        std::fs::create_dir_all(sdk_dir.join("Include").join("10.0.22621.0")).unwrap();
+        std::fs::create_dir_all(sdk_dir.join("bin").join("10.0.22621.0").join("x64")).unwrap();
+        vec![msvc, sdk]
+    }
+
+    #[test]
+    fn golden_output() {
+        let root = TempDir::new("bazel_toolchain");
+        let pkgs = fixture_install(&root);
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        // An arm64 host without arm64 tools falls back to the x64-hosted ones
+        let toolchain =
+            BazelToolchain::resolve(&msvcup_dir, &pkgs, Arch::X64, Arch::Arm64).unwrap();
+        assert_eq!(toolchain.host_arch, Arch::X64);
+
+        // Paths in the golden files are relative to `<root>/`
+        let root_prefix = starlark_str(&format!("{}{}", root.display(), std::path::MAIN_SEPARATOR));
+        let root_prefix = &root_prefix[1..root_prefix.len() - 1];
+        assert_eq!(
+            toolchain.render_bzl().replace(root_prefix, "<root>/"),
+            include_str!("../tests/golden/bazel_toolchain/msvcup_toolchain.bzl")
+        );
+        assert_eq!(
+            toolchain.render_build(),
+            include_str!("../tests/golden/bazel_toolchain/BUILD.bazel")
+        );
+
+        let out = root.join("bazel");
+        bazel_toolchain_command(&msvcup_dir, &pkgs, Arch::X64, &out).unwrap();
+        for name in [BUILD_FILE_NAME, BZL_FILE_NAME, NOP_FILE_NAME] {
+            assert!(out.join(name).is_file(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn resolve_errors() {
+        let root = TempDir::new("bazel_toolchain_errors");
+        let pkgs = fixture_install(&root);
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+
+        let err =
+            BazelToolchain::resolve(&msvcup_dir, &pkgs[..1], Arch::X64, Arch::X64).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bazel-toolchain needs an msvc and an sdk package"
+        );
+        assert_eq!(crate::error::exit_code(&err), 2);
+        assert!(BazelToolchain::resolve(&msvcup_dir, &pkgs, Arch::Arm64Ec, Arch::X64).is_err());
+
+        let missing = [
+            pkgs[0].clone(),
+            MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.26100.1"),
+        ];
+        let err = BazelToolchain::resolve(&msvcup_dir, &missing, Arch::X64, Arch::X64).unwrap_err();
+        assert!(
+            err.to_string().contains("install sdk-10.0.26100.1 first"),
+            "{}",
+            err
+        );
+    }
+}
@@ -4,10 +4,12 @@
 //!
 //! **Shim mode** (default, when invoked as a tool name):
 //! 1. Reads `msvcup.toml` next to the binary for package info and install dir
-//! 2. Loads `env-{arch}.json` from each installed package directory
+//! 2. Loads `env-{arch}.json` (`env-{arch}-nopath.json` with `vcvars_path = false`)
+//!    from each installed package directory
 //! 3. If env JSON is missing, errors with "run msvcup-autoenv install first"
 //! 4. Prepends env vars (PATH, INCLUDE, LIB) from the JSON
-//! 5. Finds the real tool in PATH and forwards execution
+//! 5. Finds the real tool in the MSVCUP_BIN directories (set instead of PATH
+//!    when `vcvars_path = false`) or in PATH and forwards execution
 //!
 //! **Install mode** (`msvcup-autoenv install`):
 //! 1. Reads `msvcup.toml` to find packages and lock file
@@ -106,7 +108,8 @@ fn resolve_install_dir(config: &MsvcupConfig) -> String {
     }
 }
 
-/// Resolve cache_dir with priority: config > MSVCUP_CACHE_DIR env var > {install_dir}\cache.
+/// Resolve cache_dir with priority: config > MSVCUP_CACHE_DIR env var >
+/// MSVCUP_GLOBAL_CACHE env var > {install_dir}\cache.
 #[cfg(windows)]
 fn resolve_cache_dir(config: &MsvcupConfig, install_dir: &str) -> String {
     if let Some(ref dir) = config.msvcup.cache_dir {
@@ -115,9 +118,27 @@ fn resolve_cache_dir(config: &MsvcupConfig, install_dir: &str) -> String {
     if let Ok(dir) = std::env::var("MSVCUP_CACHE_DIR") {
         return dir;
     }
+    if let Ok(dir) = std::env::var("MSVCUP_GLOBAL_CACHE")
+        && !dir.is_empty()
+    {
+        return dir;
+    }
     format!("{}\\cache", install_dir)
 }
 
+/// The directory of `pkg_str` (e.g. `sdk-10.0.22621.7`), under the
+/// `install_roots` entry of its kind if the config has one.
+#[cfg(windows)]
+fn pool_dir(config: &MsvcupConfig, install_dir: &str, pkg_str: &str) -> String {
+    let kind = pkg_str.split_once('-').map_or(pkg_str, |(kind, _)| kind);
+    let root = config
+        .msvcup
+        .install_roots
+        .get(kind)
+        .map_or(install_dir, String::as_str);
+    format!("{}\\{}", root, pkg_str)
+}
+
 // --- Install command ---
 
 #[cfg(windows)]
@@ -156,29 +177,43 @@ fn install_command(self_dir: &std::path::Path) -> Result<(), String> {
         .arg(&cache_dir)
         .arg("--install-dir")
         .arg(&install_dir);
+    for (kind, root) in &config.msvcup.install_roots {
+        cmd.arg("--install-root").arg(format!("{}={}", kind, root));
+    }
+    if config.msvcup.vcvars_path == Some(false) {
+        cmd.arg("--no-vcvars-path");
+    }
+    // cl.rsp and link.rsp need the installed versions, so msvcup writes them here
+    cmd.arg("--target-cpu")
+        .arg(&config.msvcup.target_arch)
+        .arg("--rsp-dir")
+        .arg(self_dir);
     for pkg in &pkg_strings {
         cmd.arg(pkg);
     }
 
-    let status = cmd
-        .status()
+    let mut child = cmd
+        .spawn()
         .map_err(|e| format!("failed to run '{}': {e}", msvcup_exe.display()))?;
+    let exit_code = wait_through_console_ctrl_events(&mut child)
+        .map_err(|e| format!("failed to wait for '{}': {e}", msvcup_exe.display()))?;
 
-    if !status.success() {
+    if exit_code != 0 {
         return Err(format!(
-            "msvcup install failed with exit code {:?}",
-            status.code()
+            "msvcup install failed with exit code {}",
+            exit_code
         ));
     }
 
     // Verify env JSON files exist
     let target_arch = &config.msvcup.target_arch;
+    check_target_arch(&config, &install_dir, &pkg_strings, target_arch)?;
 
     for pkg_str in &pkg_strings {
-        if pkg_str.starts_with("ninja-") || pkg_str.starts_with("cmake-") {
+        if !has_env_files(pkg_str) {
             continue;
         }
-        let json_path = format!("{}\\{}\\env-{}.json", install_dir, pkg_str, target_arch);
+        let json_path = env_json_path(&config, &install_dir, pkg_str);
         if !std::path::Path::new(&json_path).exists() {
             return Err(format!(
                 "installation succeeded but '{}' was not generated",
@@ -199,7 +234,7 @@ fn shim_forward(
     self_basename: &str,
     args: &[String],
 ) -> Result<i32, String> {
-    use std::process::Command;
+    use std::process::{Command, Stdio};
 
     let config = read_config(self_dir)?;
 
@@ -212,27 +247,78 @@ fn shim_forward(
         pkg_strings.push(format!("{}-{}", name, version));
     }
 
+    check_target_arch(&config, &install_dir, &pkg_strings, target_arch)?;
+
     // Load env JSON for each package and apply env vars
     for pkg_str in &pkg_strings {
-        if pkg_str.starts_with("ninja-") || pkg_str.starts_with("cmake-") {
+        if !has_env_files(pkg_str) {
             continue;
         }
-        let json_path = format!("{}\\{}\\env-{}.json", install_dir, pkg_str, target_arch);
-        load_env_json(&json_path)?;
+        load_env_json(&env_json_path(&config, &install_dir, pkg_str))?;
+    }
+    // nasm and python have no env files, their exe is at the top of the package
+    // directory
+    for pkg_str in &pkg_strings {
+        if pkg_str.starts_with("nasm-") || pkg_str.starts_with("python-") {
+            prepend_env("PATH", &pool_dir(&config, &install_dir, pkg_str));
+        }
+    }
+
+    // Find and execute the real tool. Env files generated without PATH entries
+    // list the tool directories in MSVCUP_BIN instead.
+    let real_exe = find_in_bin_dirs(self_basename)
+        .or_else(|| find_in_path(self_basename, self_dir))
+        .ok_or_else(|| {
+            format!(
+                "unable to find '{}' in PATH after setting up environment",
+                self_basename
+            )
+        })?;
+
+    // Interactive tools read from the console, so stdio is passed straight through.
+    let mut child = Command::new(&real_exe)
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| format!("failed to execute '{}': {e}", real_exe.display()))?;
+    wait_through_console_ctrl_events(&mut child)
+        .map_err(|e| format!("failed to wait for '{}': {e}", real_exe.display()))
+}
+
+/// Wait for a child tool to exit, surviving Ctrl+C/Ctrl+Break in the meantime.
+///
+/// Console control events are delivered to every process attached to the console,
+/// so the child already receives them and decides how to shut down. The wrapper
+/// only has to not die first: exiting early would leave the child's output
+/// truncated (e.g. half-written PDBs from cl.exe) and report the wrong exit code.
+#[cfg(windows)]
+fn wait_through_console_ctrl_events(child: &mut std::process::Child) -> std::io::Result<i32> {
+    type HandlerRoutine = unsafe extern "system" fn(ctrl_type: u32) -> i32;
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
     }
 
-    // Find and execute the real tool
-    let real_exe = find_in_path(self_basename, self_dir).ok_or_else(|| {
-        format!(
-            "unable to find '{}' in PATH after setting up environment",
-            self_basename
-        )
-    })?;
+    const CTRL_C_EVENT: u32 = 0;
+    const CTRL_BREAK_EVENT: u32 = 1;
+
+    unsafe extern "system" fn handler(ctrl_type: u32) -> i32 {
+        // Returning TRUE marks the event as handled so the default handler
+        // doesn't terminate us; close/logoff/shutdown events are left alone.
+        matches!(ctrl_type, CTRL_C_EVENT | CTRL_BREAK_EVENT) as i32
+    }
 
-    match Command::new(&real_exe).args(args).status() {
-        Ok(status) => Ok(status.code().unwrap_or(1)),
-        Err(e) => Err(format!("failed to execute '{}': {e}", real_exe.display())),
+    // SAFETY: `handler` is a valid handler routine for the lifetime of the process.
+    let installed = unsafe { SetConsoleCtrlHandler(Some(handler), 1) } != 0;
+    let result = child.wait();
+    if installed {
+        // SAFETY: removes the handler added above.
+        unsafe { SetConsoleCtrlHandler(Some(handler), 0) };
     }
+    Ok(result?.code().unwrap_or(1))
 }
 
 // --- Helpers ---
@@ -252,11 +338,78 @@ fn read_config(self_dir: &std::path::Path) -> Result<MsvcupConfig, String> {
         .map_err(|e| format!("cannot parse '{}': {e}", config_path.display()))
 }
 
+/// The env file of a package for the configured target arch. With
+/// `vcvars_path = false` that's the one `msvcup install --no-vcvars-path` adds
+/// next to the default one.
+#[cfg(windows)]
+fn env_json_path(config: &MsvcupConfig, install_dir: &str, pkg_str: &str) -> String {
+    format!(
+        "{}\\env-{}{}.json",
+        pool_dir(config, install_dir, pkg_str),
+        config.msvcup.target_arch,
+        if config.msvcup.vcvars_path == Some(false) {
+            "-nopath"
+        } else {
+            ""
+        }
+    )
+}
+
+/// List the target archs an installed package directory has env-{arch}.json files for.
+#[cfg(windows)]
+fn available_target_archs(pool_dir: &std::path::Path) -> Vec<String> {
+    let mut archs = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(pool_dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(arch) = name
+                .strip_prefix("env-")
+                .and_then(|s| s.strip_suffix(".json"))
+                .filter(|arch| !arch.contains('-'))
+            {
+                archs.push(arch.to_string());
+            }
+        }
+    }
+    archs.sort();
+    archs
+}
+
+/// Check up-front that every installed package supports `target_arch`, so a
+/// mismatch is reported with the archs that are available instead of as a
+/// missing file. Packages that aren't installed yet are left to `load_env_json`.
+#[cfg(windows)]
+fn check_target_arch(
+    config: &MsvcupConfig,
+    install_dir: &str,
+    pkg_strings: &[String],
+    target_arch: &str,
+) -> Result<(), String> {
+    for pkg_str in pkg_strings {
+        if !has_env_files(pkg_str) {
+            continue;
+        }
+        let archs = available_target_archs(std::path::Path::new(&pool_dir(
+            config,
+            install_dir,
+            pkg_str,
+        )));
+        if !archs.is_empty() && !archs.iter().any(|a| a == target_arch) {
+            return Err(format!(
+                "package '{}' does not support target_arch '{}' (available: {})",
+                pkg_str,
+                target_arch,
+                archs.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
 /// Load env-{arch}.json and prepend entries to environment variables.
 #[cfg(windows)]
 fn load_env_json(json_path: &str) -> Result<(), String> {
     use std::collections::HashMap;
-    use std::env;
 
     let content = match std::fs::read_to_string(json_path) {
         Ok(c) => c,
@@ -276,20 +429,35 @@ fn load_env_json(json_path: &str) -> Result<(), String> {
         if new_paths.is_empty() {
             continue;
         }
-        let current = env::var(name).unwrap_or_default();
-        let new_value = if current.is_empty() {
-            new_paths.join(";")
-        } else {
-            format!("{};{}", new_paths.join(";"), current)
-        };
-        // SAFETY: this binary is single-threaded
-        unsafe {
-            env::set_var(name, &new_value);
-        }
+        prepend_env(name, &new_paths.join(";"));
     }
     Ok(())
 }
 
+/// Prepend `entries` (`;`-separated) to the environment variable `name`.
+#[cfg(windows)]
+fn prepend_env(name: &str, entries: &str) {
+    let current = std::env::var(name).unwrap_or_default();
+    let new_value = if current.is_empty() {
+        entries.to_string()
+    } else {
+        format!("{};{}", entries, current)
+    };
+    // SAFETY: this binary is single-threaded
+    unsafe {
+        std::env::set_var(name, &new_value);
+    }
+}
+
+/// Whether a package writes `env-<arch>.json` files. ninja, cmake, nasm and
+/// python are plain tool directories.
+#[cfg(windows)]
+fn has_env_files(pkg_str: &str) -> bool {
+    !["ninja-", "cmake-", "nasm-", "python-"]
+        .iter()
+        .any(|prefix| pkg_str.starts_with(prefix))
+}
+
 /// Find the msvcup binary: first next to ourselves, then in PATH.
 #[cfg(windows)]
 fn find_msvcup_binary(self_dir: &std::path::Path) -> Option<std::path::PathBuf> {
@@ -312,6 +480,17 @@ fn find_msvcup_binary(self_dir: &std::path::Path) -> Option<std::path::PathBuf>
     None
 }
 
+/// Look for an executable in the absolute tool directories from MSVCUP_BIN.
+#[cfg(windows)]
+fn find_in_bin_dirs(exe_name: &str) -> Option<std::path::PathBuf> {
+    let bin_var = std::env::var("MSVCUP_BIN").ok()?;
+    bin_var
+        .split(';')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| std::path::Path::new(dir).join(exe_name))
+        .find(|candidate| candidate.exists())
+}
+
 /// Search PATH for an executable, skipping the directory `skip_dir` (our own dir).
 #[cfg(windows)]
 fn find_in_path(exe_name: &str, skip_dir: &std::path::Path) -> Option<std::path::PathBuf> {
@@ -360,6 +539,10 @@ struct MsvcupConfig {
 struct MsvcupSettings {
     cache_dir: Option<String>,
     install_dir: Option<String>,
+    #[serde(default)]
+    install_roots: std::collections::BTreeMap<String, String>,
     lock_file: String,
     target_arch: String,
+    #[serde(default)]
+    vcvars_path: Option<bool>,
 }
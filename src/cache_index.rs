@@ -0,0 +1,112 @@
+use crate::install::cache_entry_name;
+use crate::sha::Sha256;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The entries of the payload cache, read with a single directory scan so the
+/// "already fetched" decision for each payload is a set lookup instead of a
+/// `stat`, which is slow on network and overlay volumes.
+///
+/// Only complete `{sha256}-{name}` entries are indexed; `.lock` files and
+/// `.fetching` downloads are not. The index is a hint: a miss still checks the
+/// file under its lock, since another process may have fetched it since the scan.
+#[derive(Debug, Default)]
+pub struct CacheIndex {
+    entries: Mutex<HashSet<String>>,
+}
+
+impl CacheIndex {
+    /// Index the entries of `cache_dir`. A cache dir that doesn't exist yet is empty.
+    pub fn scan(cache_dir: &Path) -> Result<Self> {
+        let dir_entries = match std::fs::read_dir(cache_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("reading directory '{}'", cache_dir.display()));
+            }
+        };
+        let mut entries = HashSet::new();
+        for dir_entry in dir_entries {
+            let dir_entry = dir_entry
+                .with_context(|| format!("reading directory '{}'", cache_dir.display()))?;
+            if !dir_entry.file_type().is_ok_and(|t| t.is_file()) {
+                continue;
+            }
+            let Ok(file_name) = dir_entry.file_name().into_string() else {
+                continue;
+            };
+            if is_entry_name(&file_name) {
+                entries.insert(file_name);
+            }
+        }
+        log::debug!(
+            "cache index: {} entries in '{}'",
+            entries.len(),
+            cache_dir.display()
+        );
+        Ok(Self {
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn contains(&self, sha256: &Sha256, name: &str) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .contains(&cache_entry_name(sha256, name))
+    }
+
+    /// Record an entry that was added to the cache after the scan.
+    pub fn insert(&self, sha256: &Sha256, name: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(cache_entry_name(sha256, name));
+    }
+}
+
+fn is_entry_name(file_name: &str) -> bool {
+    if file_name.ends_with(".lock") || file_name.ends_with(".fetching") {
+        return false;
+    }
+    match file_name.split_once('-') {
+        Some((sha256, name)) => !name.is_empty() && Sha256::parse_hex(sha256).is_some(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{HELLO_SHA256, TempDir};
+
+    #[test]
+    fn scan_indexes_complete_entries() {
+        let root = TempDir::new("cache_index");
+        std::fs::create_dir_all(root.join(format!("{}-dir.vsix", HELLO_SHA256))).unwrap();
+        for name in [
+            format!("{}-a.vsix", HELLO_SHA256),
+            format!("{}-a.vsix.lock", HELLO_SHA256),
+            format!("{}-b.msi.fetching", HELLO_SHA256),
+            "notes.txt".to_string(),
+        ] {
+            std::fs::write(root.join(name), "").unwrap();
+        }
+
+        let sha = Sha256::parse_hex(HELLO_SHA256).unwrap();
+        let index = CacheIndex::scan(&root).unwrap();
+        assert!(index.contains(&sha, "a.vsix"));
+        assert!(!index.contains(&sha, "b.msi"));
+        assert!(!index.contains(&sha, "dir.vsix"));
+        assert_eq!(index.entries.lock().unwrap().len(), 1);
+
+        index.insert(&sha, "b.msi");
+        assert!(index.contains(&sha, "b.msi"));
+
+        let missing = CacheIndex::scan(&root.join("missing")).unwrap();
+        assert!(!missing.contains(&sha, "a.vsix"));
+    }
+}
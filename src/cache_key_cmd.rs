@@ -0,0 +1,208 @@
+use crate::arch::Arch;
+use crate::channel_kind::ChannelKind;
+use crate::lockfile_parse::parse_lock_file;
+use crate::manifest::MsvcupDir;
+use crate::output::{OutputFormat, print_records};
+use crate::packages::MsvcupPackage;
+use crate::sha::Sha256Streaming;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use serde::Serialize;
+use std::path::Path;
+
+/// Bumped when the key inputs change, so old keys never match new ones.
+const KEY_SCHEMA: &str = "msvcup-cache-key-1";
+
+/// What a CI cache restored with the key holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheScope {
+    /// The download cache
+    Download,
+    /// The installed package directories
+    Install,
+}
+
+impl CacheScope {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "download" => Some(Self::Download),
+            "install" => Some(Self::Install),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Download => "download",
+            Self::Install => "install",
+        }
+    }
+}
+
+/// A `cache key` record.
+#[derive(Debug, Serialize)]
+pub struct CacheKey {
+    pub key: String,
+    pub scope: CacheScope,
+    /// The directories to save and restore under the key
+    pub paths: Vec<String>,
+}
+
+/// The cache key for a lock file.
+///
+/// The key is `msvcup-<scope>-<sha256>` over the lock file's JSON with sorted
+/// keys and no whitespace, the msvcup version, the channel URL and, for the
+/// install scope, the host arch (which decides the ninja and cmake payloads
+/// that get installed). Reformatting the lock file or reordering its keys
+/// keeps the key; any change to its packages, payloads or cabs changes it, as
+/// does upgrading msvcup.
+pub fn cache_key(
+    lock_file_content: &str,
+    lock_file_path: &str,
+    scope: CacheScope,
+    channel_kind: &ChannelKind,
+    host_arch: Arch,
+) -> Result<String> {
+    // Rejects lock files this version can't read
+    parse_lock_file(lock_file_path, lock_file_content)?;
+    let value: serde_json::Value = serde_json::from_str(lock_file_content)
+        .with_context(|| format!("parsing lock file '{}'", lock_file_path))?;
+    // serde_json's map is sorted, so this is the canonical form
+    let canonical = serde_json::to_string(&value)?;
+
+    let mut hasher = Sha256Streaming::new();
+    for part in [
+        KEY_SCHEMA,
+        env!("CARGO_PKG_VERSION"),
+        scope.as_str(),
+        channel_kind.https_url(),
+        match scope {
+            CacheScope::Download => "",
+            CacheScope::Install => host_arch.as_str(),
+        },
+        &canonical,
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(format!(
+        "msvcup-{}-{}",
+        scope.as_str(),
+        hasher.finalize().to_hex()
+    ))
+}
+
+pub fn cache_key_command(
+    msvcup_dir: &MsvcupDir,
+    lock_file_path: &str,
+    scope: CacheScope,
+    cache_dir: &Path,
+    channel_kind: &ChannelKind,
+    format: OutputFormat,
+) -> Result<()> {
+    let content = fs::read_to_string(lock_file_path)?;
+    let host_arch = Arch::native().unwrap_or(Arch::X64);
+    let key = cache_key(&content, lock_file_path, scope, channel_kind, host_arch)?;
+    let paths = match scope {
+        CacheScope::Download => vec![cache_dir.to_path_buf()],
+        CacheScope::Install => parse_lock_file(lock_file_path, &content)?
+            .packages
+            .iter()
+            .map(|pkg| match MsvcupPackage::from_pool_string(&pkg.name) {
+                Ok(msvcup_pkg) => msvcup_dir.package_path(&msvcup_pkg),
+                Err(_) => msvcup_dir.path(&[&pkg.name]),
+            })
+            .collect(),
+    };
+    let record = CacheKey {
+        key,
+        scope,
+        paths: paths
+            .iter()
+            .map(|p| p.to_string_lossy().into_owned())
+            .collect(),
+    };
+    print_records(format, &[record], |r| r.key.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK_FILE: &str = r#"{"version": 1, "packages": [{"name": "ninja-1.12.1", "payloads": [
+        {"url": "https://github.com/ninja-build/ninja/releases/download/v1.12.1/ninja-win.zip",
+         "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"}]}]}"#;
+
+    fn key(content: &str, scope: CacheScope, host_arch: Arch) -> String {
+        cache_key(
+            content,
+            "msvcup.lock",
+            scope,
+            &ChannelKind::Release,
+            host_arch,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn whitespace_and_key_order_keep_the_key() {
+        let base = key(LOCK_FILE, CacheScope::Download, Arch::X64);
+        assert!(base.starts_with("msvcup-download-"), "{}", base);
+        assert_eq!(base.len(), "msvcup-download-".len() + 64);
+
+        let reformatted = serde_json::to_string_pretty(
+            &serde_json::from_str::<serde_json::Value>(LOCK_FILE).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(key(&reformatted, CacheScope::Download, Arch::X64), base);
+        let reordered = LOCK_FILE.replace(
+            r#"{"version": 1, "packages": [{"name": "ninja-1.12.1", "payloads": ["#,
+            r#"{"packages": [{"payloads": ["#,
+        );
+        let reordered = reordered.replace(
+            r#""sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"}]}]}"#,
+            r#""sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"}], "name": "ninja-1.12.1"}], "version": 1}"#,
+        );
+        assert!(reordered.starts_with(r#"{"packages""#), "{}", reordered);
+        assert_eq!(key(&reordered, CacheScope::Download, Arch::X64), base);
+    }
+
+    #[test]
+    fn key_inputs() {
+        let download = key(LOCK_FILE, CacheScope::Download, Arch::X64);
+        // The host arch only matters for the install scope
+        assert_eq!(key(LOCK_FILE, CacheScope::Download, Arch::Arm64), download);
+        let install = key(LOCK_FILE, CacheScope::Install, Arch::X64);
+        assert!(install.starts_with("msvcup-install-"), "{}", install);
+        assert_ne!(key(LOCK_FILE, CacheScope::Install, Arch::Arm64), install);
+
+        let other_payload =
+            LOCK_FILE.replace("v1.12.1/ninja-win.zip", "v1.12.1/ninja-winarm64.zip");
+        assert_ne!(
+            key(&other_payload, CacheScope::Download, Arch::X64),
+            download
+        );
+
+        let preview = cache_key(
+            LOCK_FILE,
+            "msvcup.lock",
+            CacheScope::Download,
+            &ChannelKind::Preview,
+            Arch::X64,
+        )
+        .unwrap();
+        assert_ne!(preview, download);
+
+        assert!(
+            cache_key(
+                r#"{"version": 99, "packages": []}"#,
+                "msvcup.lock",
+                CacheScope::Download,
+                &ChannelKind::Release,
+                Arch::X64,
+            )
+            .is_err()
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::arch::Arch;
+use crate::env_cmd::load_env;
+use crate::install::BIN_VAR;
+use crate::manifest::MsvcupDir;
+use crate::packages::MsvcupPackage;
+use anyhow::{Context, Result, bail};
+use fs_err as fs;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The Rust target triple that builds for `arch` with the MSVC toolchain.
+pub fn rust_target_triple(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X64 => "x86_64-pc-windows-msvc",
+        Arch::X86 => "i686-pc-windows-msvc",
+        Arch::Arm => "thumbv7a-pc-windows-msvc",
+        Arch::Arm64 => "aarch64-pc-windows-msvc",
+        Arch::Arm64Ec => "arm64ec-pc-windows-msvc",
+    }
+}
+
+/// Settings that make cargo build a target with the msvcup toolchain.
+#[derive(Debug)]
+pub struct CargoConfig {
+    pub triple: &'static str,
+    pub linker: PathBuf,
+    /// `[env]` entries: INCLUDE, LIB and the cc crate's per-target CC and AR
+    pub env: Vec<(String, String)>,
+}
+
+impl CargoConfig {
+    /// Build the settings from the merged environment of the installed packages.
+    /// The tools are taken by absolute path from the `PATH` (or `MSVCUP_BIN`)
+    /// entries.
+    pub fn from_env(target_arch: Arch, env: &BTreeMap<String, Vec<String>>) -> Result<Self> {
+        let triple = rust_target_triple(target_arch);
+        let bin_dirs: Vec<&String> = ["PATH", BIN_VAR]
+            .iter()
+            .filter_map(|name| env.get(*name))
+            .flatten()
+            .collect();
+        let find_tool = |exe_name: &str| -> Result<PathBuf> {
+            bin_dirs
+                .iter()
+                .map(|dir| Path::new(dir.as_str()).join(exe_name))
+                .find(|path| path.is_file())
+                .with_context(|| {
+                    format!(
+                        "'{}' not found in the package tool directories, is an msvc package installed for {}?",
+                        exe_name, target_arch
+                    )
+                })
+        };
+        let linker = find_tool("link.exe")?;
+        let triple_var = triple.replace('-', "_");
+        let mut config_env = Vec::new();
+        for name in ["INCLUDE", "LIB"] {
+            let Some(entries) = env.get(name) else {
+                bail!("the packages don't set {}, is the sdk installed?", name);
+            };
+            config_env.push((name.to_string(), entries.join(";")));
+        }
+        for (var, exe_name) in [("CC", "cl.exe"), ("AR", "lib.exe")] {
+            config_env.push((
+                format!("{}_{}", var, triple_var),
+                find_tool(exe_name)?.to_string_lossy().into_owned(),
+            ));
+        }
+        Ok(Self {
+            triple,
+            linker,
+            env: config_env,
+        })
+    }
+
+    /// Apply the settings to the content of an existing `.cargo/config.toml`
+    /// (or an empty one), keeping its other sections, keys and comments.
+    pub fn merge_into(&self, existing: &str) -> Result<String> {
+        let mut doc: toml_edit::DocumentMut = existing
+            .parse()
+            .context("parsing the existing cargo config")?;
+        let target = doc
+            .entry("target")
+            .or_insert_with(toml_edit::table)
+            .as_table_mut()
+            .context("'target' in the cargo config is not a table")?;
+        target.set_implicit(true);
+        let triple_table = target
+            .entry(self.triple)
+            .or_insert_with(toml_edit::table)
+            .as_table_mut()
+            .with_context(|| {
+                format!(
+                    "'target.{}' in the cargo config is not a table",
+                    self.triple
+                )
+            })?;
+        triple_table["linker"] = toml_edit::value(self.linker.to_string_lossy().into_owned());
+
+        let env = doc
+            .entry("env")
+            .or_insert_with(toml_edit::table)
+            .as_table_mut()
+            .context("'env' in the cargo config is not a table")?;
+        for (name, value) in &self.env {
+            env[name.as_str()] = toml_edit::value(value.as_str());
+        }
+        Ok(doc.to_string())
+    }
+}
+
+/// Print a `.cargo/config.toml` that builds Rust's MSVC target for `target_arch`
+/// with the given packages, or merge it into `out_path`.
+pub fn cargo_config_command(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_arch: Arch,
+    out_path: Option<&Path>,
+) -> Result<()> {
+    let env = load_env(msvcup_dir, msvcup_pkgs, target_arch)?;
+    let config = CargoConfig::from_env(target_arch, &env)?;
+    let Some(out_path) = out_path else {
+        print!("{}", config.merge_into("")?);
+        return Ok(());
+    };
+    let existing = match fs::read_to_string(out_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let content = config
+        .merge_into(&existing)
+        .with_context(|| format!("updating '{}'", out_path.display()))?;
+    if let Some(dir) = out_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(out_path, content)?;
+    log::info!(
+        "wrote the {} settings to '{}'",
+        config.triple,
+        out_path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn fixture_config() -> CargoConfig {
+        CargoConfig {
+            triple: "x86_64-pc-windows-msvc",
+            linker: PathBuf::from("C:\\msvcup\\msvc\\bin\\link.exe"),
+            env: vec![
+                (
+                    "INCLUDE".to_string(),
+                    "C:\\msvc\\include;C:\\sdk\\um".to_string(),
+                ),
+                ("LIB".to_string(), "C:\\msvc\\lib".to_string()),
+                (
+                    "CC_x86_64_pc_windows_msvc".to_string(),
+                    "C:\\msvcup\\msvc\\bin\\cl.exe".to_string(),
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn triples() {
+        assert_eq!(rust_target_triple(Arch::X64), "x86_64-pc-windows-msvc");
+        assert_eq!(rust_target_triple(Arch::X86), "i686-pc-windows-msvc");
+        assert_eq!(rust_target_triple(Arch::Arm64), "aarch64-pc-windows-msvc");
+    }
+
+    #[test]
+    fn fresh_config() {
+        assert_eq!(
+            fixture_config().merge_into("").unwrap(),
+            "[target.x86_64-pc-windows-msvc]\n\
+             linker = 'C:\\msvcup\\msvc\\bin\\link.exe'\n\
+             \n\
+             [env]\n\
+             INCLUDE = 'C:\\msvc\\include;C:\\sdk\\um'\n\
+             LIB = 'C:\\msvc\\lib'\n\
+             CC_x86_64_pc_windows_msvc = 'C:\\msvcup\\msvc\\bin\\cl.exe'\n"
+        );
+    }
+
+    #[test]
+    fn merge_keeps_unrelated_settings() {
+        let existing = "# project settings\n\
+                        [build]\n\
+                        rustflags = [\"-Dwarnings\"]\n\
+                        \n\
+                        [target.x86_64-pc-windows-msvc]\n\
+                        linker = \"old-link.exe\"\n\
+                        runner = \"wine\"\n\
+                        \n\
+                        [env]\n\
+                        RUST_LOG = \"debug\"\n\
+                        LIB = \"C:\\\\old\"\n";
+        let merged = fixture_config().merge_into(existing).unwrap();
+        let value: toml::Table = toml::from_str(&merged).unwrap();
+        assert_eq!(value["build"]["rustflags"][0].as_str(), Some("-Dwarnings"));
+        let target = &value["target"]["x86_64-pc-windows-msvc"];
+        assert_eq!(
+            target["linker"].as_str(),
+            Some("C:\\msvcup\\msvc\\bin\\link.exe")
+        );
+        assert_eq!(target["runner"].as_str(), Some("wine"));
+        assert_eq!(value["env"]["RUST_LOG"].as_str(), Some("debug"));
+        assert_eq!(value["env"]["LIB"].as_str(), Some("C:\\msvc\\lib"));
+        assert!(merged.starts_with("# project settings\n"));
+
+        assert!(fixture_config().merge_into("env = 1\n").is_err());
+    }
+
+    #[test]
+    fn from_env_finds_tools() {
+        let root = TempDir::new("cargo_config");
+        let bin = root.join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        for exe_name in ["link.exe", "cl.exe", "lib.exe"] {
+            std::fs::write(bin.join(exe_name), "").unwrap();
+        }
+        let bin_str = bin.to_str().unwrap().to_string();
+        let mut env = BTreeMap::new();
+        env.insert(
+            "INCLUDE".to_string(),
+            vec!["inc1".to_string(), "inc2".to_string()],
+        );
+        env.insert("LIB".to_string(), vec!["lib1".to_string()]);
+        env.insert(BIN_VAR.to_string(), vec![bin_str]);
+
+        let config = CargoConfig::from_env(Arch::Arm64, &env).unwrap();
+        assert_eq!(config.triple, "aarch64-pc-windows-msvc");
+        assert_eq!(config.linker, bin.join("link.exe"));
+        let names: Vec<&str> = config.env.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "INCLUDE",
+                "LIB",
+                "CC_aarch64_pc_windows_msvc",
+                "AR_aarch64_pc_windows_msvc"
+            ]
+        );
+        assert_eq!(config.env[0].1, "inc1;inc2");
+
+        env.remove(BIN_VAR);
+        let err = CargoConfig::from_env(Arch::Arm64, &env).unwrap_err();
+        assert!(err.to_string().contains("'link.exe' not found"), "{}", err);
+    }
+}
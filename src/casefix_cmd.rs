@@ -0,0 +1,492 @@
+use crate::file_exclusions::SKIP_PREFIX;
+use crate::splat_cmd::SPLAT_MANIFEST_NAME;
+use crate::util::create_symlink;
+use anyhow::{Context, Result, bail};
+use fs_err as fs;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// How `casefix` makes a tree usable with lowercase `#include`s and library names
+/// on a case-sensitive filesystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CasefixMode {
+    /// Rename every file and directory to lowercase
+    Lowercase,
+    /// Keep the names and add a lowercase symlink next to every entry that isn't
+    Symlinks,
+}
+
+impl CasefixMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "lowercase" => Some(Self::Lowercase),
+            "symlinks" => Some(Self::Symlinks),
+            _ => None,
+        }
+    }
+}
+
+/// What [`casefix`] did to a tree.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CasefixStats {
+    pub renamed: usize,
+    pub linked: usize,
+    /// Aliases removed because their target is gone, or by `lowercase` mode
+    pub removed: usize,
+    /// Aliases already in place
+    pub unchanged: usize,
+}
+
+#[derive(Debug, Default)]
+struct Plan {
+    collisions: Vec<Vec<String>>,
+    /// `(from, to)`, children before their parent directory
+    renames: Vec<(String, String)>,
+    /// `(alias, target name, is_dir)`
+    aliases: Vec<(String, String, bool)>,
+    removals: Vec<String>,
+    unchanged: usize,
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    /// The sibling a lowercase alias created by casefix points to
+    alias_of: Option<String>,
+}
+
+/// `/`-separated path of `name` in the directory `rel_dir` of the tree.
+fn join_rel(rel_dir: &str, name: &str) -> String {
+    if rel_dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", rel_dir, name)
+    }
+}
+
+/// Whether names in `dir` are case-sensitive, checked with a probe file.
+pub fn is_case_sensitive(dir: &Path) -> Result<bool> {
+    let probe = dir.join(".msvcup-casefix-probe");
+    fs::write(&probe, "")?;
+    let sensitive = std::fs::symlink_metadata(dir.join(".MSVCUP-CASEFIX-PROBE")).is_err();
+    fs::remove_file(&probe)?;
+    Ok(sensitive)
+}
+
+fn plan_dir(root: &Path, rel_dir: &str, mode: CasefixMode, plan: &mut Plan) -> Result<()> {
+    let dir_path = root.join(rel_dir);
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(&dir_path)? {
+        let dir_entry = dir_entry?;
+        let Ok(name) = dir_entry.file_name().into_string() else {
+            log::warn!(
+                "'{}': skipping a name that isn't UTF-8",
+                dir_entry.path().display()
+            );
+            continue;
+        };
+        // The install metadata of a pool directory, its names are cache entry names
+        if rel_dir.is_empty() && name == "install" {
+            continue;
+        }
+        // Symlinks are never followed, only recognized as aliases from an earlier run
+        let file_type = dir_entry.file_type()?;
+        let alias_of = if file_type.is_symlink() && name == name.to_lowercase() {
+            fs::read_link(dir_entry.path())?
+                .to_str()
+                .filter(|target| {
+                    !target.contains(['/', '\\'])
+                        && *target != name
+                        && target.to_lowercase() == name
+                })
+                .map(str::to_string)
+        } else {
+            None
+        };
+        entries.push(Entry {
+            name,
+            is_dir: file_type.is_dir(),
+            alias_of,
+        });
+    }
+
+    let real_names: HashSet<&str> = entries
+        .iter()
+        .filter(|e| e.alias_of.is_none())
+        .map(|e| e.name.as_str())
+        .collect();
+    let mut aliases: HashMap<&str, &str> = HashMap::new();
+    let mut groups: BTreeMap<String, Vec<&Entry>> = BTreeMap::new();
+    for entry in &entries {
+        match &entry.alias_of {
+            Some(target)
+                if mode == CasefixMode::Symlinks && real_names.contains(target.as_str()) =>
+            {
+                aliases.insert(&entry.name, target);
+            }
+            Some(_) => plan.removals.push(join_rel(rel_dir, &entry.name)),
+            None => groups
+                .entry(entry.name.to_lowercase())
+                .or_default()
+                .push(entry),
+        }
+    }
+
+    for (lower, mut group) in groups {
+        if group.len() > 1 {
+            group.sort_by(|a, b| a.name.cmp(&b.name));
+            plan.collisions
+                .push(group.iter().map(|e| join_rel(rel_dir, &e.name)).collect());
+            continue;
+        }
+        let entry = group[0];
+        let rel = join_rel(rel_dir, &entry.name);
+        if entry.is_dir {
+            plan_dir(root, &rel, mode, plan)?;
+        }
+        if entry.name == lower {
+            continue;
+        }
+        match mode {
+            CasefixMode::Lowercase => plan.renames.push((rel, join_rel(rel_dir, &lower))),
+            CasefixMode::Symlinks => {
+                if aliases.get(lower.as_str()) == Some(&entry.name.as_str()) {
+                    plan.unchanged += 1;
+                } else {
+                    plan.aliases.push((
+                        join_rel(rel_dir, &lower),
+                        entry.name.clone(),
+                        entry.is_dir,
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make the names in `root` resolvable in lowercase, for compiling against
+/// Windows headers and libraries on a case-sensitive filesystem. Works on a pool
+/// directory (whose `.files` install manifests are updated to match) or a splat
+/// output directory.
+///
+/// Entries whose names only differ in case can't both be made lowercase; if any
+/// exist they're all reported and nothing is changed. Symlinks are never followed,
+/// and running it again only fixes up what changed.
+pub fn casefix(root: &Path, mode: CasefixMode) -> Result<CasefixStats> {
+    let mut plan = Plan::default();
+    plan_dir(root, "", mode, &mut plan)?;
+    if !plan.collisions.is_empty() {
+        bail!(
+            "'{}' has names that only differ in case, nothing was changed:\n  {}",
+            root.display(),
+            plan.collisions
+                .iter()
+                .map(|group| group.join(" <-> "))
+                .collect::<Vec<_>>()
+                .join("\n  ")
+        );
+    }
+
+    for rel in &plan.removals {
+        fs::remove_file(root.join(rel))?;
+    }
+    for (from, to) in &plan.renames {
+        fs::rename(root.join(from), root.join(to))?;
+    }
+    for (alias, target, is_dir) in &plan.aliases {
+        create_symlink(Path::new(target), &root.join(alias), *is_dir)?;
+    }
+
+    update_install_manifests(root, &plan, mode)?;
+    if mode == CasefixMode::Lowercase {
+        update_splat_manifest(root)?;
+    }
+    Ok(CasefixStats {
+        renamed: plan.renames.len(),
+        linked: plan.aliases.len(),
+        removed: plan.removals.len(),
+        unchanged: plan.unchanged,
+    })
+}
+
+/// Keep the `.files` manifests of a pool directory in step with the tree:
+/// removed aliases are dropped, renamed paths are lowercased and new aliases are
+/// recorded in the manifest of the payload that installed their target, so
+/// they're removed along with it. Lines for files `--exclude` left out are kept
+/// as they are.
+fn update_install_manifests(root: &Path, plan: &Plan, mode: CasefixMode) -> Result<()> {
+    let install_meta_dir = root.join("install");
+    if !install_meta_dir.is_dir() {
+        return Ok(());
+    }
+    let mut manifest_paths: Vec<PathBuf> = fs::read_dir(&install_meta_dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<_>>()?;
+    manifest_paths.retain(|path| path.extension().is_some_and(|ext| ext == "files"));
+    manifest_paths.sort();
+
+    let removed: HashSet<&str> = plan.removals.iter().map(String::as_str).collect();
+    let mut manifests = Vec::new();
+    for path in manifest_paths {
+        let content = fs::read_to_string(&path)?;
+        let new_lines: Vec<String> = content
+            .lines()
+            .filter(|l| !l.is_empty() && !removed.contains(l))
+            .map(|line| match mode {
+                CasefixMode::Lowercase if !line.starts_with(SKIP_PREFIX) => {
+                    lowercase_manifest_entry(root, line)
+                }
+                _ => line.to_string(),
+            })
+            .collect();
+        manifests.push((path, new_lines));
+    }
+
+    for (alias, target, _) in &plan.aliases {
+        let target_rel = match alias.rsplit_once('/') {
+            Some((dir, _)) => join_rel(dir, target),
+            None => target.clone(),
+        };
+        let target_dir_prefix = format!("{}/", target_rel);
+        let owner = manifests.iter_mut().find(|(_, lines)| {
+            lines
+                .iter()
+                .any(|l| *l == target_rel || l.starts_with(&target_dir_prefix))
+        });
+        if let Some((_, lines)) = owner
+            && !lines.iter().any(|l| l == alias)
+        {
+            lines.push(alias.clone());
+        }
+    }
+
+    for (path, lines) in manifests {
+        let mut content = lines.join("\n");
+        content.push('\n');
+        crate::util::update_file(&path, content.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Manifests written by older versions contain absolute paths, only the part
+/// inside the pool directory was renamed.
+fn lowercase_manifest_entry(root: &Path, entry: &str) -> String {
+    let path = Path::new(entry);
+    if !path.is_absolute() {
+        return entry.to_lowercase();
+    }
+    match path.strip_prefix(root) {
+        Ok(rel) => root
+            .join(rel.to_string_lossy().to_lowercase())
+            .display()
+            .to_string(),
+        Err(_) => entry.to_string(),
+    }
+}
+
+/// Lowercase the output paths in a splat manifest, so the next `splat` knows
+/// which files it produced.
+fn update_splat_manifest(root: &Path) -> Result<()> {
+    let path = root.join(SPLAT_MANIFEST_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut new_content = String::new();
+    for line in content.lines() {
+        let mut fields: Vec<String> = line.split('\t').map(str::to_string).collect();
+        if let Some(dest) = fields.get_mut(1) {
+            *dest = dest.to_lowercase();
+        }
+        new_content.push_str(&fields.join("\t"));
+        new_content.push('\n');
+    }
+    crate::util::update_file(&path, new_content.as_bytes())
+}
+
+pub fn casefix_command(root: &Path, mode: CasefixMode) -> Result<()> {
+    if !root.is_dir() {
+        bail!("'{}' is not a directory", root.display());
+    }
+    if !is_case_sensitive(root)
+        .with_context(|| format!("checking whether '{}' is case-sensitive", root.display()))?
+    {
+        log::info!(
+            "'{}' is on a case-insensitive filesystem, nothing to do",
+            root.display()
+        );
+        return Ok(());
+    }
+    if mode == CasefixMode::Lowercase && root.join("install").is_dir() {
+        log::warn!(
+            "'{}' is an install directory, installing its package again expects \
+             the original names, use --mode symlinks to keep them",
+            root.display()
+        );
+    }
+    let stats = casefix(root, mode)?;
+    log::info!(
+        "'{}': {} renamed, {} linked, {} stale alias(es) removed, {} already linked",
+        root.display(),
+        stats.renamed,
+        stats.linked,
+        stats.removed,
+        stats.unchanged
+    );
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn make_tree(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, file).unwrap();
+        }
+    }
+
+    /// A pool directory with two payloads' files and their `.files` manifests.
+    fn pool_fixture(root: &Path) -> bool {
+        make_tree(
+            root,
+            &[
+                "Include/um/Windows.h",
+                "Include/um/winbase.h",
+                "Lib/um/x64/Kernel32.Lib",
+                "install/aa-headers.vsix.files",
+                "install/bb-libs.msi.files",
+            ],
+        );
+        std::fs::write(
+            root.join("install/aa-headers.vsix.files"),
+            "Include/um/Windows.h\nInclude/um/winbase.h\nskip Include/um/WinRT.winmd\n",
+        )
+        .unwrap();
+        std::fs::write(
+            root.join("install/bb-libs.msi.files"),
+            "Lib/um/x64/Kernel32.Lib\n",
+        )
+        .unwrap();
+        is_case_sensitive(root).unwrap()
+    }
+
+    fn read(root: &Path, rel: &str) -> String {
+        std::fs::read_to_string(root.join(rel)).unwrap()
+    }
+
+    #[test]
+    fn symlinks_mode() {
+        let root = TempDir::new("casefix_symlinks");
+        if !pool_fixture(&root) {
+            return;
+        }
+
+        let stats = casefix(&root, CasefixMode::Symlinks).unwrap();
+        assert_eq!(stats.linked, 4);
+        assert_eq!(read(&root, "include/um/windows.h"), "Include/um/Windows.h");
+        assert_eq!(
+            read(&root, "lib/um/x64/kernel32.lib"),
+            "Lib/um/x64/Kernel32.Lib"
+        );
+        assert_eq!(
+            std::fs::read_link(root.join("include")).unwrap(),
+            Path::new("Include")
+        );
+        assert_eq!(
+            read(&root, "install/aa-headers.vsix.files"),
+            "Include/um/Windows.h\nInclude/um/winbase.h\nskip Include/um/WinRT.winmd\n\
+             Include/um/windows.h\ninclude\n"
+        );
+        assert_eq!(
+            read(&root, "install/bb-libs.msi.files"),
+            "Lib/um/x64/Kernel32.Lib\nLib/um/x64/kernel32.lib\nlib\n"
+        );
+
+        // Running again changes nothing
+        let stats = casefix(&root, CasefixMode::Symlinks).unwrap();
+        assert_eq!(
+            stats,
+            CasefixStats {
+                unchanged: 4,
+                ..Default::default()
+            }
+        );
+
+        // An alias whose target is gone is removed, along with its manifest line
+        std::fs::remove_file(root.join("Include/um/Windows.h")).unwrap();
+        let stats = casefix(&root, CasefixMode::Symlinks).unwrap();
+        assert_eq!(stats.removed, 1);
+        assert!(std::fs::symlink_metadata(root.join("Include/um/windows.h")).is_err());
+        assert!(!read(&root, "install/aa-headers.vsix.files").contains("windows.h"));
+    }
+
+    #[test]
+    fn lowercase_mode() {
+        let root = TempDir::new("casefix_lowercase");
+        if !pool_fixture(&root) {
+            return;
+        }
+        // Aliases from an earlier symlinks run are replaced by the renames
+        casefix(&root, CasefixMode::Symlinks).unwrap();
+
+        let stats = casefix(&root, CasefixMode::Lowercase).unwrap();
+        assert_eq!(stats.renamed, 4);
+        assert_eq!(stats.removed, 4);
+        assert_eq!(read(&root, "include/um/windows.h"), "Include/um/Windows.h");
+        assert!(root.join("lib/um/x64/kernel32.lib").is_file());
+        assert!(!root.join("Include").exists());
+        assert_eq!(
+            read(&root, "install/aa-headers.vsix.files"),
+            "include/um/windows.h\ninclude/um/winbase.h\nskip Include/um/WinRT.winmd\n"
+        );
+
+        assert_eq!(
+            casefix(&root, CasefixMode::Lowercase).unwrap(),
+            CasefixStats::default()
+        );
+    }
+
+    #[test]
+    fn collisions_are_reported() {
+        let root = TempDir::new("casefix_collision");
+        make_tree(
+            &root,
+            &["Include/a.h", "include/b.h", "um/Windows.h", "um/windows.h"],
+        );
+        if !is_case_sensitive(&root).unwrap() {
+            return;
+        }
+        let err = casefix(&root, CasefixMode::Symlinks).unwrap_err();
+        assert!(
+            err.to_string().ends_with(
+                "nothing was changed:\n  Include <-> include\n  um/Windows.h <-> um/windows.h"
+            ),
+            "{}",
+            err
+        );
+        assert!(std::fs::symlink_metadata(root.join("include/a.h")).is_err());
+    }
+
+    #[test]
+    fn symlinks_are_not_followed() {
+        let root = TempDir::new("casefix_no_follow");
+        let outside = TempDir::new("casefix_outside");
+        make_tree(&outside, &["Sub/File.h"]);
+        make_tree(&root, &["Local.h"]);
+        if !is_case_sensitive(&root).unwrap() {
+            return;
+        }
+        std::os::unix::fs::symlink(&outside, root.join("Linked")).unwrap();
+
+        let stats = casefix(&root, CasefixMode::Lowercase).unwrap();
+        assert_eq!(stats.renamed, 2);
+        assert!(root.join("linked").is_symlink());
+        assert!(outside.join("Sub/File.h").is_file());
+    }
+}
@@ -1,44 +1,84 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[allow(dead_code)]
+use crate::error::{ErrorKind, bail_kind};
+use crate::sha::Sha256;
+use anyhow::Result;
+
+const RELEASE_MANIFEST_ID: &str = "Microsoft.VisualStudio.Manifests.VisualStudio";
+const PREVIEW_MANIFEST_ID: &str = "Microsoft.VisualStudio.Manifests.VisualStudioPreview";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ChannelKind {
     Release,
     Preview,
+    /// An ad-hoc channel manifest URL (private mirror, archived channel, ...).
+    /// `subdir` is derived from a hash of the URL so each URL gets its own cache.
+    Custom {
+        url: String,
+        subdir: String,
+    },
 }
 
 impl ChannelKind {
-    pub fn https_url(&self) -> &'static str {
+    pub fn custom(url: &str) -> Result<ChannelKind> {
+        let parsed = match reqwest::Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(e) => bail_kind!(ErrorKind::Usage, "invalid channel url '{}': {}", url, e),
+        };
+        if parsed.scheme() != "https" && parsed.scheme() != "http" {
+            bail_kind!(
+                ErrorKind::Usage,
+                "invalid channel url '{}': expected an http or https url",
+                url
+            );
+        }
+        let sha256 = Sha256::of_bytes(url.as_bytes());
+        Ok(ChannelKind::Custom {
+            url: url.to_string(),
+            subdir: format!("custom-{}", &sha256.to_hex()[..16]),
+        })
+    }
+
+    pub fn https_url(&self) -> &str {
         match self {
             ChannelKind::Release => "https://aka.ms/vs/17/release/channel",
             ChannelKind::Preview => "https://aka.ms/vs/17/pre/channel",
+            ChannelKind::Custom { url, .. } => url,
         }
     }
 
-    pub fn vs_manifest_channel_id(&self) -> &'static str {
-        match self {
-            ChannelKind::Release => "Microsoft.VisualStudio.Manifests.VisualStudio",
-            ChannelKind::Preview => "Microsoft.VisualStudio.Manifests.VisualStudioPreview",
-        }
+    /// Whether the channel URL is an aka.ms redirect whose target should be
+    /// resolved and cached separately, rather than fetched directly.
+    pub fn is_redirect(&self) -> bool {
+        !matches!(self, ChannelKind::Custom { .. })
     }
 
-    pub fn subdir(&self) -> &'static str {
+    /// The channelItem ids that identify the VS manifest in the channel manifest.
+    /// A custom channel could mirror either release or preview.
+    pub fn vs_manifest_channel_ids(&self) -> &'static [&'static str] {
         match self {
-            ChannelKind::Release => "vs-release",
-            ChannelKind::Preview => "vs-preview",
+            ChannelKind::Release => &[RELEASE_MANIFEST_ID],
+            ChannelKind::Preview => &[PREVIEW_MANIFEST_ID],
+            ChannelKind::Custom { .. } => &[RELEASE_MANIFEST_ID, PREVIEW_MANIFEST_ID],
         }
     }
 
-    pub fn channel_subdir(&self) -> &'static str {
+    fn name(&self) -> &str {
         match self {
-            ChannelKind::Release => "channel-release",
-            ChannelKind::Preview => "channel-preview",
+            ChannelKind::Release => "release",
+            ChannelKind::Preview => "preview",
+            ChannelKind::Custom { subdir, .. } => subdir,
         }
     }
 
-    pub fn channel_url_subdir(&self) -> &'static str {
-        match self {
-            ChannelKind::Release => "channel-release-url",
-            ChannelKind::Preview => "channel-preview-url",
-        }
+    pub fn subdir(&self) -> String {
+        format!("vs-{}", self.name())
+    }
+
+    pub fn channel_subdir(&self) -> String {
+        format!("channel-{}", self.name())
+    }
+
+    pub fn channel_url_subdir(&self) -> String {
+        format!("channel-{}-url", self.name())
     }
 }
 
@@ -63,8 +103,8 @@ mod tests {
     #[test]
     fn manifest_channel_ids_differ() {
         assert_ne!(
-            ChannelKind::Release.vs_manifest_channel_id(),
-            ChannelKind::Preview.vs_manifest_channel_id()
+            ChannelKind::Release.vs_manifest_channel_ids(),
+            ChannelKind::Preview.vs_manifest_channel_ids()
         );
     }
 
@@ -86,4 +126,40 @@ mod tests {
         assert!(ChannelKind::Release.subdir().contains("release"));
         assert!(ChannelKind::Preview.subdir().contains("preview"));
     }
+
+    #[test]
+    fn builtin_subdirs_unchanged() {
+        assert_eq!(ChannelKind::Release.subdir(), "vs-release");
+        assert_eq!(ChannelKind::Preview.channel_subdir(), "channel-preview");
+        assert_eq!(
+            ChannelKind::Release.channel_url_subdir(),
+            "channel-release-url"
+        );
+    }
+
+    #[test]
+    fn custom_channel() {
+        let a = ChannelKind::custom("https://mirror.example.com/vs/channel").unwrap();
+        let b = ChannelKind::custom("https://mirror.example.com/vs/other").unwrap();
+        assert_eq!(a.https_url(), "https://mirror.example.com/vs/channel");
+        assert!(!a.is_redirect());
+        assert!(a.subdir().starts_with("vs-custom-"));
+        assert_ne!(a.subdir(), b.subdir());
+        assert_eq!(
+            a,
+            ChannelKind::custom("https://mirror.example.com/vs/channel").unwrap()
+        );
+    }
+
+    #[test]
+    fn custom_channel_rejects_invalid_urls() {
+        for url in [
+            "not a url",
+            "ftp://example.com/channel",
+            "file:///tmp/channel",
+        ] {
+            let err = ChannelKind::custom(url).unwrap_err();
+            assert_eq!(crate::error::exit_code(&err), 2, "{}", url);
+        }
+    }
 }
@@ -1,9 +1,13 @@
 use crate::arch::Arch;
+use crate::error::{ErrorKind, bail_kind};
 use crate::packages::{MsvcupPackage, MsvcupPackageKind};
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// The name of the project config, looked up from the working directory upwards.
+pub const CONFIG_FILE_NAME: &str = "msvcup.toml";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MsvcupConfig {
@@ -17,10 +21,23 @@ pub struct MsvcupSettings {
     pub cache_dir: Option<String>,
     /// Installation directory for extracted packages
     pub install_dir: Option<String>,
+    /// Installation directories of single package kinds, e.g. `sdk = "D:/msvcup"`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub install_roots: BTreeMap<String, String>,
     /// Path to the lock file (relative to config file location)
     pub lock_file: String,
     /// Target architecture (x64, x86, arm64, arm)
     pub target_arch: String,
+    /// Prepend the tool directories to PATH in the env files (default true).
+    /// With `false` the shims run tools by absolute path instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcvars_path: Option<bool>,
+    /// Globs of files `install` doesn't extract, see `install --exclude`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Directory `msvcup resolve` places the shims in (relative to config file location)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub out_dir: Option<String>,
 }
 
 impl MsvcupConfig {
@@ -28,31 +45,62 @@ impl MsvcupConfig {
         let content = fs_err::read_to_string(path)?;
         let config: MsvcupConfig = toml::from_str(&content)
             .with_context(|| format!("parsing config file '{}'", path.display()))?;
-        config.validate()?;
+        config
+            .validate()
+            .with_context(|| format!("checking config file '{}'", path.display()))?;
         Ok(config)
     }
 
+    /// Find the `msvcup.toml` in `start_dir` or the closest of its parents and
+    /// read it. Returns None if there is none; a config that can't be parsed is
+    /// an error rather than skipped.
+    pub fn discover(start_dir: &Path) -> Result<Option<(PathBuf, Self)>> {
+        let Some(config_path) = find_config(start_dir) else {
+            return Ok(None);
+        };
+        log::debug!("using config '{}'", config_path.display());
+        let config = Self::from_file(&config_path)?;
+        Ok(Some((config_path, config)))
+    }
+
     fn validate(&self) -> Result<()> {
         if Arch::from_str_exact(&self.msvcup.target_arch).is_none() {
-            bail!(
-                "invalid target_arch '{}', expected one of: x64, x86, arm, arm64",
+            bail_kind!(
+                ErrorKind::Usage,
+                "invalid target_arch '{}', expected one of: x64, x86, arm, arm64, arm64ec",
                 self.msvcup.target_arch
             );
         }
         for (name, version) in &self.packages {
             if MsvcupPackageKind::from_prefix(&format!("{}-{}", name, version)).is_none() {
-                bail!(
-                    "unknown package '{}', expected one of: msvc, sdk, msbuild, diasdk, ninja, cmake",
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "unknown package '{}', expected one of: msvc, sdk, msbuild, diasdk, ninja, cmake, nasm, python",
                     name
                 );
             }
         }
         if self.packages.is_empty() {
-            bail!("no packages specified in config");
+            bail_kind!(ErrorKind::Usage, "no packages specified in config");
+        }
+        for name in self.msvcup.install_roots.keys() {
+            if let Err(e) = name.parse::<MsvcupPackageKind>() {
+                bail_kind!(ErrorKind::Usage, "invalid install_roots entry: {}", e);
+            }
         }
         Ok(())
     }
 
+    /// The `install_roots` of the `[msvcup]` section, validated when the config
+    /// was read.
+    pub fn install_roots(&self) -> Vec<(MsvcupPackageKind, String)> {
+        self.msvcup
+            .install_roots
+            .iter()
+            .filter_map(|(name, root)| Some((name.parse().ok()?, root.clone())))
+            .collect()
+    }
+
     pub fn target_arch(&self) -> Arch {
         Arch::from_str_exact(&self.msvcup.target_arch).unwrap()
     }
@@ -73,11 +121,85 @@ impl MsvcupConfig {
         let config_dir = config_path.parent().unwrap_or(Path::new("."));
         config_dir.join(&self.msvcup.lock_file)
     }
+
+    /// Resolve the shim directory relative to the config file's directory
+    pub fn out_dir_path(&self, config_path: &Path) -> Option<PathBuf> {
+        let config_dir = config_path.parent().unwrap_or(Path::new("."));
+        self.msvcup.out_dir.as_ref().map(|dir| config_dir.join(dir))
+    }
+}
+
+/// The path of the `msvcup.toml` in `start_dir` or the closest of its parents.
+pub fn find_config(start_dir: &Path) -> Option<PathBuf> {
+    start_dir
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// The `install` settings given on the command line. Anything left out is
+/// taken from a project config with [`InstallArgs::merge_config`].
+#[derive(Debug, Default)]
+pub struct InstallArgs {
+    pub packages: Vec<MsvcupPackage>,
+    pub lock_files: Vec<String>,
+    pub cache_dir: Option<String>,
+    pub install_dir: Option<String>,
+    pub install_roots: Vec<(MsvcupPackageKind, String)>,
+    pub target_arch: Option<Arch>,
+    pub vcvars_path: Option<bool>,
+    pub exclude: Vec<String>,
+}
+
+impl InstallArgs {
+    /// Fill in the settings that weren't given from `config`, the command line
+    /// wins. The config's packages are only used when none were given, and its
+    /// lock file only when no `--lock-file` was, and likewise its `exclude`.
+    pub fn merge_config(&mut self, config: &MsvcupConfig, config_path: &Path) -> Result<()> {
+        if self.packages.is_empty() {
+            self.packages = config.msvcup_packages()?;
+        }
+        if self.lock_files.is_empty() {
+            self.lock_files.push(
+                config
+                    .lock_file_path(config_path)
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        if self.cache_dir.is_none() {
+            self.cache_dir = config.msvcup.cache_dir.clone();
+        }
+        self.merge_install_dirs(config);
+        self.target_arch.get_or_insert(config.target_arch());
+        if self.vcvars_path.is_none() {
+            self.vcvars_path = config.msvcup.vcvars_path;
+        }
+        if self.exclude.is_empty() {
+            self.exclude = config.msvcup.exclude.clone();
+        }
+        Ok(())
+    }
+
+    /// Fill in the install directory and the install roots of the kinds that
+    /// weren't given from `config`. The commands reading installed packages only
+    /// take these from the config, so they find what `install` put there.
+    pub fn merge_install_dirs(&mut self, config: &MsvcupConfig) {
+        if self.install_dir.is_none() {
+            self.install_dir = config.msvcup.install_dir.clone();
+        }
+        for (kind, root) in config.install_roots() {
+            if !self.install_roots.iter().any(|(k, _)| *k == kind) {
+                self.install_roots.push((kind, root));
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::TempDir;
 
     /// Parse and validate a config from a TOML string (test helper).
     fn from_toml_str(content: &str) -> Result<MsvcupConfig> {
@@ -153,6 +275,25 @@ unknown_pkg = "1.0"
         assert!(err.to_string().contains("unknown package"));
     }
 
+    #[test]
+    fn reject_unknown_install_root_kind() {
+        let toml = r#"
+[msvcup]
+lock_file = "msvc.lock"
+target_arch = "x64"
+install_roots = { sdkk = "D:/msvcup" }
+
+[packages]
+sdk = "10.0.22621.7"
+"#;
+        let err = from_toml_str(toml).unwrap_err();
+        assert!(
+            err.to_string().contains("invalid install_roots entry"),
+            "{}",
+            err
+        );
+    }
+
     #[test]
     fn reject_empty_packages() {
         let toml = r#"
@@ -203,4 +344,141 @@ msvc = "14.43.34808"
         let result = MsvcupConfig::from_file(Path::new("/nonexistent/path/msvcup.toml"));
         assert!(result.is_err());
     }
+
+    #[test]
+    fn discover_walks_up() {
+        let root = TempDir::new("config_discover");
+        let nested = root.join("project").join("src").join("deep");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert!(MsvcupConfig::discover(&nested).unwrap().is_none());
+
+        let config_path = root.join("project").join(CONFIG_FILE_NAME);
+        std::fs::write(&config_path, valid_config_toml()).unwrap();
+        let (found_path, config) = MsvcupConfig::discover(&nested).unwrap().unwrap();
+        assert_eq!(found_path, config_path);
+        assert_eq!(
+            config.lock_file_path(&found_path),
+            root.join("project").join("msvc.lock")
+        );
+
+        // The closest config wins
+        std::fs::write(
+            nested.join(CONFIG_FILE_NAME),
+            valid_config_toml().replace("x64", "arm64"),
+        )
+        .unwrap();
+        let (_, config) = MsvcupConfig::discover(&nested).unwrap().unwrap();
+        assert_eq!(config.target_arch(), Arch::Arm64);
+    }
+
+    #[test]
+    fn discover_reports_malformed_config() {
+        let root = TempDir::new("config_malformed");
+        std::fs::write(
+            root.join(CONFIG_FILE_NAME),
+            "[msvcup]\nlock_file = \"msvc.lock\"\ntarget_arch = x64\n",
+        )
+        .unwrap();
+        let err = MsvcupConfig::discover(&root).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("parsing config file"), "{}", message);
+        assert!(message.contains("line 3"), "{}", message);
+
+        std::fs::write(
+            root.join(CONFIG_FILE_NAME),
+            "[msvcup]\ntarget_arch = \"x64\"\n\n[packages]\nmsvc = \"14.43.34808\"\n",
+        )
+        .unwrap();
+        let message = format!("{:#}", MsvcupConfig::discover(&root).unwrap_err());
+        assert!(message.contains("missing field `lock_file`"), "{}", message);
+    }
+
+    #[test]
+    fn install_args_prefer_command_line() {
+        let toml = r#"
+[msvcup]
+lock_file = "msvc.lock"
+target_arch = "arm64"
+cache_dir = "/tmp/cache"
+vcvars_path = false
+exclude = ["*.winmd"]
+out_dir = "shims"
+
+[msvcup.install_roots]
+sdk = "/mnt/slow"
+msvc = "/mnt/fast"
+
+[packages]
+msvc = "14.43.34808"
+sdk = "10.0.22621.7"
+"#;
+        let config = from_toml_str(toml).unwrap();
+        let config_path = Path::new("/repo/msvcup.toml");
+        assert_eq!(
+            config.out_dir_path(config_path),
+            Some(PathBuf::from("/repo/shims"))
+        );
+
+        let mut args = InstallArgs::default();
+        args.merge_config(&config, config_path).unwrap();
+        assert_eq!(args.packages.len(), 2);
+        assert_eq!(
+            args.lock_files,
+            [Path::new("/repo/msvc.lock").to_str().unwrap()]
+        );
+        assert_eq!(args.cache_dir.as_deref(), Some("/tmp/cache"));
+        assert_eq!(args.install_dir, None);
+        assert_eq!(
+            args.install_roots,
+            [
+                (MsvcupPackageKind::Msvc, "/mnt/fast".to_string()),
+                (MsvcupPackageKind::Sdk, "/mnt/slow".to_string())
+            ]
+        );
+        assert_eq!(args.target_arch, Some(Arch::Arm64));
+        assert_eq!(args.vcvars_path, Some(false));
+        assert_eq!(args.exclude, ["*.winmd"]);
+
+        let mut args = InstallArgs {
+            packages: vec![MsvcupPackage::from_string("msvc-14.30.17.6").unwrap()],
+            lock_files: vec!["other.lock".to_string()],
+            cache_dir: Some("cli-cache".to_string()),
+            install_roots: vec![(MsvcupPackageKind::Sdk, "/mnt/other".to_string())],
+            target_arch: Some(Arch::X64),
+            exclude: vec!["*/arm64/*".to_string()],
+            ..Default::default()
+        };
+        args.merge_config(&config, config_path).unwrap();
+        assert_eq!(
+            args.install_roots,
+            [
+                (MsvcupPackageKind::Sdk, "/mnt/other".to_string()),
+                (MsvcupPackageKind::Msvc, "/mnt/fast".to_string())
+            ]
+        );
+        assert_eq!(args.packages.len(), 1);
+        assert_eq!(args.packages[0].to_string(), "msvc-14.30.17.6");
+        assert_eq!(args.lock_files, ["other.lock"]);
+        assert_eq!(args.cache_dir.as_deref(), Some("cli-cache"));
+        assert_eq!(args.target_arch, Some(Arch::X64));
+        assert_eq!(args.exclude, ["*/arm64/*"]);
+
+        let mut args = InstallArgs {
+            install_dir: Some("/cli".to_string()),
+            install_roots: vec![(MsvcupPackageKind::Msvc, "/mnt/cli".to_string())],
+            ..Default::default()
+        };
+        args.merge_install_dirs(&config);
+        assert_eq!(args.install_dir.as_deref(), Some("/cli"));
+        assert_eq!(
+            args.install_roots,
+            [
+                (MsvcupPackageKind::Msvc, "/mnt/cli".to_string()),
+                (MsvcupPackageKind::Sdk, "/mnt/slow".to_string())
+            ]
+        );
+        assert!(args.packages.is_empty());
+        assert!(args.lock_files.is_empty());
+        assert_eq!(args.target_arch, None);
+    }
 }
@@ -0,0 +1,125 @@
+use crate::error::{ErrorKind, bail_kind};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Check that each directory's volume has room for the bytes about to be written
+/// to it, so an install fails up front instead of with ENOSPC halfway through an
+/// extraction. Directories on the same volume share its free space. A directory
+/// that doesn't exist yet is checked on its closest existing parent.
+pub fn check_available_space(needs: &[(&Path, u64)]) -> Result<()> {
+    // (volume, path to query, bytes needed)
+    let mut volumes: Vec<(Option<u64>, PathBuf, u64)> = Vec::new();
+    for (dir, bytes) in needs {
+        let existing = existing_ancestor(dir);
+        let volume = volume_id(&existing);
+        match volumes
+            .iter_mut()
+            .find(|(v, path, _)| (v.is_some() && *v == volume) || *path == existing)
+        {
+            Some((_, _, total)) => *total += bytes,
+            None => volumes.push((volume, existing, *bytes)),
+        }
+    }
+    for (_, path, needed) in volumes {
+        let available = fs2::available_space(&path)
+            .with_context(|| format!("querying the free space of '{}'", path.display()))?;
+        log::debug!(
+            "'{}': {} needed, {} available",
+            path.display(),
+            format_size(needed),
+            format_size(available)
+        );
+        if needed > available {
+            bail_kind!(
+                ErrorKind::Filesystem,
+                "not enough free space on the volume of '{}': the install needs about {} but \
+                 only {} is available, free up space or pass --skip-disk-check",
+                path.display(),
+                format_size(needed),
+                format_size(available)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn existing_ancestor(dir: &Path) -> PathBuf {
+    dir.ancestors()
+        .find(|path| !path.as_os_str().is_empty() && path.exists())
+        .unwrap_or(Path::new("."))
+        .to_path_buf()
+}
+
+#[cfg(unix)]
+fn volume_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| m.dev())
+}
+
+/// Paths on the same drive only share free space when they have the same prefix
+/// (`C:`), mount points inside a drive aren't detected.
+#[cfg(windows)]
+fn volume_id(path: &Path) -> Option<u64> {
+    use std::hash::{Hash, Hasher};
+    let prefix = std::path::absolute(path).ok()?.components().next()?;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    prefix
+        .as_os_str()
+        .to_string_lossy()
+        .to_lowercase()
+        .hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn volume_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// `bytes` in B, KiB, MiB, GiB or TiB with one decimal.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", value, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn check_space() {
+        let root = TempDir::new("disk_space");
+        let missing = root.join("not").join("yet");
+        assert_eq!(existing_ancestor(&missing), root.path());
+
+        check_available_space(&[(&root, 0), (&missing, 1)]).unwrap();
+        let err = check_available_space(&[(&missing, u64::MAX / 2)]).unwrap_err();
+        assert!(err.to_string().contains("--skip-disk-check"), "{}", err);
+        assert_eq!(crate::error::exit_code(&err), 6);
+
+        // Two dirs on one volume need the sum of their sizes
+        let available = fs2::available_space(&root).unwrap();
+        check_available_space(&[(&root, available / 2)]).unwrap();
+        assert!(check_available_space(&[(&root, available), (&missing, available)]).is_err());
+    }
+}
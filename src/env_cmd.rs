@@ -0,0 +1,456 @@
+use crate::arch::Arch;
+use crate::error::{ErrorKind, bail_kind};
+use crate::install::FinishKind;
+use crate::manifest::MsvcupDir;
+use crate::packages::MsvcupPackage;
+use crate::sha::Sha256;
+use anyhow::{Context, Result, bail};
+use fs_err as fs;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+/// Merge the `env-{arch}.json` entries of the installed packages, in package order.
+/// Packages without env files (ninja, cmake, ...) are skipped.
+pub fn load_env(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_arch: Arch,
+) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut env: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for msvcup_pkg in msvcup_pkgs {
+        if FinishKind::from_package_kind(msvcup_pkg.kind).is_none() {
+            continue;
+        }
+        let json_path = msvcup_dir
+            .package_path(msvcup_pkg)
+            .join(format!("env-{}.json", target_arch));
+        let content = match fs::read_to_string(&json_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let available = env_target_archs(json_path.parent().unwrap());
+                if !available.is_empty() {
+                    bail!(
+                        "{} has no {} libraries (available: {})",
+                        msvcup_pkg,
+                        target_arch,
+                        available
+                            .iter()
+                            .map(Arch::as_str)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+                bail!(
+                    "'{}' does not exist, install {} for {} first",
+                    json_path.display(),
+                    msvcup_pkg,
+                    target_arch
+                )
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let pkg_env: HashMap<String, Vec<String>> = serde_json::from_str(&content)
+            .with_context(|| format!("parsing '{}'", json_path.display()))?;
+        for (name, entries) in pkg_env {
+            let merged = env.entry(name).or_default();
+            for entry in entries {
+                if !merged.contains(&entry) {
+                    merged.push(entry);
+                }
+            }
+        }
+    }
+    Ok(env)
+}
+
+/// The target archs a package has env files for, i.e. that it has libraries for.
+fn env_target_archs(pool_dir: &Path) -> Vec<Arch> {
+    Arch::ALL
+        .into_iter()
+        .filter(|arch| pool_dir.join(format!("env-{}.json", arch)).is_file())
+        .collect()
+}
+
+/// Print the merged environment as `NAME=entry;entry` lines.
+pub fn print_env(env: &BTreeMap<String, Vec<String>>) {
+    for (name, entries) in env {
+        println!("{}={}", name, entries.join(";"));
+    }
+}
+
+/// A `GITHUB_ENV` entry in the multiline syntax, which also handles values
+/// containing `=` or newlines. The delimiter is derived from the value so
+/// rerunning produces the same block.
+pub fn github_env_block(name: &str, value: &str) -> String {
+    let sha256 = Sha256::of_bytes(value.as_bytes());
+    let delimiter = format!("ghadelimiter_{}", sha256.to_hex());
+    format!("{}<<{}\n{}\n{}\n", name, delimiter, value, delimiter)
+}
+
+/// Append the environment to the `GITHUB_ENV` and `GITHUB_PATH` files.
+///
+/// `PATH` entries go to `GITHUB_PATH`, one per line; every other variable is
+/// prepended to its current value (from `current_var`). Entries already present
+/// in the current value, and blocks or path lines already in the files, are
+/// skipped so running this twice in a job doesn't duplicate anything.
+pub fn write_github_env(
+    env: &BTreeMap<String, Vec<String>>,
+    github_env_path: &Path,
+    github_path_path: &Path,
+    current_var: impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+    let existing_path_file = read_existing(github_path_path)?;
+    let existing_env_file = read_existing(github_env_path)?;
+    let current_path = current_var("PATH").unwrap_or_default();
+
+    let mut path_lines = String::new();
+    let mut env_blocks = String::new();
+    for (name, entries) in env {
+        if name.eq_ignore_ascii_case("PATH") {
+            for entry in entries {
+                let in_path = current_path.split(';').any(|p| p == entry);
+                let in_file = existing_path_file.lines().any(|l| l == entry);
+                if !in_path && !in_file {
+                    path_lines.push_str(entry);
+                    path_lines.push('\n');
+                }
+            }
+            continue;
+        }
+        let current = current_var(name).unwrap_or_default();
+        let mut value: Vec<&str> = entries
+            .iter()
+            .map(String::as_str)
+            .filter(|entry| !current.split(';').any(|c| c == *entry))
+            .collect();
+        if value.is_empty() {
+            continue;
+        }
+        value.extend(current.split(';').filter(|c| !c.is_empty()));
+        let block = github_env_block(name, &value.join(";"));
+        if !existing_env_file.contains(&block) {
+            env_blocks.push_str(&block);
+        }
+    }
+
+    append(github_path_path, &path_lines)?;
+    append(github_env_path, &env_blocks)?;
+    Ok(())
+}
+
+/// Escape the data of an Azure Pipelines logging command.
+fn azure_escape_data(value: &str) -> String {
+    value
+        .replace('%', "%AZP25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape a property value of an Azure Pipelines logging command, which also
+/// can't contain the `;` and `]` that end it.
+fn azure_escape_property(value: &str) -> String {
+    azure_escape_data(value)
+        .replace(';', "%3B")
+        .replace(']', "%5D")
+}
+
+/// The Azure Pipelines logging commands that set up the environment for the
+/// following steps of the job.
+///
+/// `PATH` entries become `task.prependpath` commands; every other variable is
+/// prepended to its current value (from `current_var`) and set with a
+/// non-secret, job-scoped `task.setvariable`. Entries already in the current
+/// value are skipped, so running this again in a later step emits nothing new.
+pub fn azure_logging_commands(
+    env: &BTreeMap<String, Vec<String>>,
+    current_var: impl Fn(&str) -> Option<String>,
+) -> Vec<String> {
+    let current_path = current_var("PATH").unwrap_or_default();
+    let mut commands = Vec::new();
+    for (name, entries) in env {
+        if name.eq_ignore_ascii_case("PATH") {
+            // Each prependpath goes in front of the previous one, so add the last first
+            for entry in entries.iter().rev() {
+                if !current_path.split(';').any(|p| p == entry) {
+                    commands.push(format!(
+                        "##vso[task.prependpath]{}",
+                        azure_escape_data(entry)
+                    ));
+                }
+            }
+            continue;
+        }
+        let current = current_var(name).unwrap_or_default();
+        let mut value: Vec<&str> = entries
+            .iter()
+            .map(String::as_str)
+            .filter(|entry| !current.split(';').any(|c| c == *entry))
+            .collect();
+        if value.is_empty() {
+            continue;
+        }
+        value.extend(current.split(';').filter(|c| !c.is_empty()));
+        commands.push(format!(
+            "##vso[task.setvariable variable={};issecret=false;isoutput=false]{}",
+            azure_escape_property(name),
+            azure_escape_data(&value.join(";"))
+        ));
+    }
+    commands
+}
+
+fn read_existing(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn append(path: &Path, content: &str) -> Result<()> {
+    if content.is_empty() {
+        return Ok(());
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Where `env` puts the environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvOutput {
+    /// `NAME=value` lines on stdout
+    Print,
+    /// The GitHub Actions `GITHUB_ENV` and `GITHUB_PATH` files
+    Github,
+    /// Azure Pipelines logging commands on stdout
+    Azure,
+}
+
+impl EnvOutput {
+    /// The output for the `--github` and `--azure` flags. Without either, Azure
+    /// Pipelines agents (which set `TF_BUILD`) get logging commands.
+    pub fn from_flags(github: bool, azure: bool, tf_build: bool) -> Self {
+        if github {
+            Self::Github
+        } else if azure || tf_build {
+            Self::Azure
+        } else {
+            Self::Print
+        }
+    }
+}
+
+/// Print the toolchain environment of the given packages, append it to the GitHub
+/// Actions `GITHUB_ENV` and `GITHUB_PATH` files, or print it as Azure Pipelines
+/// logging commands.
+pub fn env_command(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_arch: Arch,
+    output: EnvOutput,
+) -> Result<()> {
+    match output {
+        EnvOutput::Print => {
+            print_env(&load_env(msvcup_dir, msvcup_pkgs, target_arch)?);
+            return Ok(());
+        }
+        EnvOutput::Azure => {
+            let env = load_env(msvcup_dir, msvcup_pkgs, target_arch)?;
+            for command in azure_logging_commands(&env, |name| std::env::var(name).ok()) {
+                println!("{}", command);
+            }
+            return Ok(());
+        }
+        EnvOutput::Github => {}
+    }
+    let (Some(github_env), Some(github_path)) = (
+        std::env::var_os("GITHUB_ENV"),
+        std::env::var_os("GITHUB_PATH"),
+    ) else {
+        bail_kind!(
+            ErrorKind::Usage,
+            "GITHUB_ENV and GITHUB_PATH are not set, --github only works inside a GitHub Actions job"
+        );
+    };
+    let env = load_env(msvcup_dir, msvcup_pkgs, target_arch)?;
+    write_github_env(
+        &env,
+        Path::new(&github_env),
+        Path::new(&github_path),
+        |name| std::env::var(name).ok(),
+    )?;
+    log::info!(
+        "added {} variable(s) to '{}'",
+        env.len(),
+        Path::new(&github_env).display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::MsvcupPackageKind;
+    use crate::test_support::TempDir;
+
+    fn write_env_json(root: &Path, pkg: &MsvcupPackage, json: &str) {
+        let dir = root.join(pkg.pool_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("env-x64.json"), json).unwrap();
+    }
+
+    #[test]
+    fn load_env_merges_packages() {
+        let root = TempDir::new("env_load");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13");
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        let ninja = MsvcupPackage::new(MsvcupPackageKind::Ninja, "1.12.1");
+        write_env_json(
+            &root,
+            &msvc,
+            r#"{"INCLUDE": ["C:\\msvc\\include"], "PATH": ["C:\\msvc\\bin"]}"#,
+        );
+
+        // The sdk isn't installed yet
+        let pkgs = [msvc.clone(), sdk.clone(), ninja];
+        let err = load_env(&msvcup_dir, &pkgs, Arch::X64).unwrap_err();
+        assert!(
+            err.to_string().contains("install sdk-10.0.22621.7"),
+            "{}",
+            err
+        );
+
+        write_env_json(
+            &root,
+            &sdk,
+            r#"{"INCLUDE": ["C:\\sdk\\ucrt", "C:\\sdk\\um"], "LIB": ["C:\\sdk\\lib"]}"#,
+        );
+        let env = load_env(&msvcup_dir, &pkgs, Arch::X64).unwrap();
+
+        // An installed package without arm libraries (e.g. a 10.0.26100 sdk)
+        let err = load_env(&msvcup_dir, &pkgs, Arch::Arm).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "msvc-14.43.17.13 has no arm libraries (available: x64)"
+        );
+
+        assert_eq!(
+            env["INCLUDE"],
+            ["C:\\msvc\\include", "C:\\sdk\\ucrt", "C:\\sdk\\um"]
+        );
+        assert_eq!(env["LIB"], ["C:\\sdk\\lib"]);
+        assert_eq!(env["PATH"], ["C:\\msvc\\bin"]);
+    }
+
+    #[test]
+    fn github_env_block_multiline() {
+        let block = github_env_block("WEIRD", "a=b\nc");
+        let mut lines = block.lines();
+        let header = lines.next().unwrap();
+        let delimiter = header.strip_prefix("WEIRD<<").unwrap();
+        assert!(delimiter.starts_with("ghadelimiter_"));
+        assert_eq!(lines.next(), Some("a=b"));
+        assert_eq!(lines.next(), Some("c"));
+        assert_eq!(lines.next(), Some(delimiter));
+        assert_eq!(lines.next(), None);
+        assert_eq!(block, github_env_block("WEIRD", "a=b\nc"));
+    }
+
+    #[test]
+    fn azure_commands_for_installed_layout() {
+        let root = TempDir::new("env_azure");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13");
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        write_env_json(
+            &root,
+            &msvc,
+            r#"{"INCLUDE": ["C:\\msvcup\\msvc\\include"], "LIB": ["C:\\msvcup\\msvc\\lib\\x64"],
+                "PATH": ["C:\\msvcup\\msvc\\bin\\Hostx64\\x64"]}"#,
+        );
+        write_env_json(
+            &root,
+            &sdk,
+            r#"{"INCLUDE": ["C:\\msvcup\\sdk\\ucrt", "C:\\msvcup\\sdk\\um"],
+                "LIB": ["C:\\100%\\sdk\\um\\x64"], "PATH": ["C:\\msvcup\\sdk\\bin\\x64"]}"#,
+        );
+        let env = load_env(&msvcup_dir, &[msvc, sdk], Arch::X64).unwrap();
+        let current = |name: &str| match name {
+            "LIB" => Some("C:\\other\\lib".to_string()),
+            "PATH" => Some("C:\\Windows;C:\\msvcup\\sdk\\bin\\x64".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            azure_logging_commands(&env, current),
+            [
+                "##vso[task.setvariable variable=INCLUDE;issecret=false;isoutput=false]\
+                 C:\\msvcup\\msvc\\include;C:\\msvcup\\sdk\\ucrt;C:\\msvcup\\sdk\\um",
+                "##vso[task.setvariable variable=LIB;issecret=false;isoutput=false]\
+                 C:\\msvcup\\msvc\\lib\\x64;C:\\100%AZP25\\sdk\\um\\x64;C:\\other\\lib",
+                "##vso[task.prependpath]C:\\msvcup\\msvc\\bin\\Hostx64\\x64",
+            ]
+        );
+
+        // A later step sees the variables set, nothing is emitted again
+        let applied = |name: &str| {
+            env.get(name)
+                .map(|entries| format!("{};C:\\other", entries.join(";")))
+        };
+        assert!(azure_logging_commands(&env, applied).is_empty());
+    }
+
+    #[test]
+    fn azure_escaping() {
+        assert_eq!(azure_escape_data("50%\r\nx;]"), "50%AZP25%0D%0Ax;]");
+        assert_eq!(azure_escape_property("a;b]c%"), "a%3Bb%5Dc%AZP25");
+        assert_eq!(EnvOutput::from_flags(false, false, true), EnvOutput::Azure);
+        assert_eq!(EnvOutput::from_flags(true, false, true), EnvOutput::Github);
+        assert_eq!(EnvOutput::from_flags(false, false, false), EnvOutput::Print);
+    }
+
+    #[test]
+    fn write_github_env_is_idempotent() {
+        let root = TempDir::new("env_github");
+        let github_env = root.join("github_env");
+        let github_path = root.join("github_path");
+
+        let mut env = BTreeMap::new();
+        env.insert(
+            "INCLUDE".to_string(),
+            vec!["C:\\msvc\\include".to_string(), "C:\\sdk\\um".to_string()],
+        );
+        env.insert("LIB".to_string(), vec!["C:\\sdk\\lib".to_string()]);
+        env.insert(
+            "PATH".to_string(),
+            vec!["C:\\msvc\\bin".to_string(), "C:\\Windows".to_string()],
+        );
+        let current = |name: &str| match name {
+            "INCLUDE" => Some("C:\\other\\include".to_string()),
+            "LIB" => Some("C:\\sdk\\lib".to_string()),
+            "PATH" => Some("C:\\Windows;C:\\tools".to_string()),
+            _ => None,
+        };
+
+        write_github_env(&env, &github_env, &github_path, current).unwrap();
+        write_github_env(&env, &github_env, &github_path, current).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&github_path).unwrap(),
+            "C:\\msvc\\bin\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&github_env).unwrap(),
+            github_env_block(
+                "INCLUDE",
+                "C:\\msvc\\include;C:\\sdk\\um;C:\\other\\include"
+            )
+        );
+    }
+}
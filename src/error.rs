@@ -0,0 +1,123 @@
+use std::fmt;
+
+/// Broad failure categories, each mapped to a documented process exit code so
+/// scripts wrapping msvcup can tell failures apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Bad arguments, package names or config values
+    Usage,
+    /// The lock file doesn't (and can't be made to) match the requested packages
+    LockFileMismatch,
+    /// A download or HTTP request failed
+    Network,
+    /// Downloaded content didn't match its expected sha256
+    HashMismatch,
+    /// Reading or writing the filesystem failed
+    Filesystem,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Usage => 2,
+            Self::LockFileMismatch => 3,
+            Self::Network => 4,
+            Self::HashMismatch => 5,
+            Self::Filesystem => 6,
+        }
+    }
+}
+
+/// An error tagged with an [`ErrorKind`]. Wrap it in `anyhow::Error` like any other
+/// error; [`exit_code`] finds it anywhere in the context chain.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Return early with an [`Error`] of the given kind, like `anyhow::bail!`.
+macro_rules! bail_kind {
+    ($kind:expr, $($arg:tt)*) => {
+        return Err($crate::error::Error::new($kind, format!($($arg)*)).into())
+    };
+}
+pub(crate) use bail_kind;
+
+/// Exit code for a failed command.
+///
+/// Explicitly tagged errors win, then errors are classified by their underlying
+/// cause (HTTP client or I/O). Anything else exits with 1.
+pub fn exit_code(err: &anyhow::Error) -> u8 {
+    if let Some(e) = err.chain().find_map(|e| e.downcast_ref::<Error>()) {
+        return e.kind.exit_code();
+    }
+    if err.chain().any(|e| e.is::<reqwest::Error>()) {
+        return ErrorKind::Network.exit_code();
+    }
+    if err.chain().any(|e| e.is::<std::io::Error>()) {
+        return ErrorKind::Filesystem.exit_code();
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn tagged_error_exit_code() {
+        let err: anyhow::Error = Error::new(ErrorKind::HashMismatch, "bad hash").into();
+        assert_eq!(exit_code(&err), 5);
+        assert_eq!(err.to_string(), "bad hash");
+    }
+
+    #[test]
+    fn tagged_error_found_through_context() {
+        let err = Err::<(), _>(Error::new(ErrorKind::Usage, "bad package"))
+            .context("installing")
+            .unwrap_err();
+        assert_eq!(exit_code(&err), 2);
+    }
+
+    #[test]
+    fn io_error_is_filesystem() {
+        let err = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            .context("writing file")
+            .unwrap_err();
+        assert_eq!(exit_code(&err), 6);
+    }
+
+    #[test]
+    fn untagged_error_is_generic() {
+        assert_eq!(exit_code(&anyhow::anyhow!("something")), 1);
+    }
+
+    #[test]
+    fn bail_kind_macro() {
+        fn f() -> anyhow::Result<()> {
+            bail_kind!(ErrorKind::LockFileMismatch, "missing {}", "pkg");
+        }
+        let err = f().unwrap_err();
+        assert_eq!(exit_code(&err), 3);
+        assert_eq!(err.to_string(), "missing pkg");
+    }
+}
@@ -1,72 +1,201 @@
 use crate::arch::Arch;
+use crate::packages::MsvcupPackageKind;
+use std::fmt;
 
-pub enum ParseUrlResult {
-    Ok { arch: Arch },
-    Unexpected { offset: usize, what: String },
+/// A package msvcup fetches from its project's releases instead of the VS
+/// manifest.
+#[derive(Debug)]
+pub struct ExtraPackageSpec {
+    pub kind: MsvcupPackageKind,
+    pub name: &'static str,
+    pub source: ExtraSource,
+    /// Release asset paths below the version and the host arch each one is for.
+    /// `{version}` stands for the release version.
+    pub arch_patterns: &'static [(&'static str, Arch)],
 }
 
-pub fn parse_url(url: &str) -> ParseUrlResult {
-    let ninja_prefix = "https://github.com/ninja-build/ninja/releases/download/v";
-    if let Some(rest) = url.strip_prefix(ninja_prefix) {
-        let version_end = scan_version(rest, 0);
-        if version_end == 0 {
-            return ParseUrlResult::Unexpected {
-                offset: ninja_prefix.len(),
-                what: "a version".to_string(),
-            };
+/// Where the releases of an [`ExtraPackageSpec`] are downloaded from.
+#[derive(Debug)]
+pub enum ExtraSource {
+    /// GitHub releases tagged `v<version>`
+    GithubRelease {
+        owner: &'static str,
+        repo: &'static str,
+    },
+    /// A download site with a directory per version, `<prefix><version>/`
+    Site {
+        host: &'static str,
+        prefix: &'static str,
+    },
+}
+
+impl fmt::Display for ExtraSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::GithubRelease { owner, repo } => {
+                write!(f, "the {}/{} GitHub releases", owner, repo)
+            }
+            Self::Site { host, .. } => f.write_str(host),
         }
-        let remaining = &rest[version_end..];
-        let arch = if remaining == "/ninja-win.zip" {
-            Arch::X64
-        } else if remaining == "/ninja-winarm64.zip" {
-            Arch::Arm64
-        } else {
-            return ParseUrlResult::Unexpected {
-                offset: ninja_prefix.len() + version_end,
-                what: "either '/ninja-win.zip' or '/ninja-winarm64.zip'".to_string(),
-            };
-        };
-        return ParseUrlResult::Ok { arch };
     }
+}
+
+impl ExtraPackageSpec {
+    /// The release download URL up to the version, e.g.
+    /// `https://github.com/ninja-build/ninja/releases/download/v`.
+    pub fn url_prefix(&self) -> String {
+        match &self.source {
+            ExtraSource::GithubRelease { owner, repo } => {
+                format!("https://github.com/{}/{}/releases/download/v", owner, repo)
+            }
+            ExtraSource::Site { prefix, .. } => prefix.to_string(),
+        }
+    }
+}
+
+const SUPPORTED_PACKAGES: &[ExtraPackageSpec] = &[
+    ExtraPackageSpec {
+        kind: MsvcupPackageKind::Ninja,
+        name: "ninja",
+        source: ExtraSource::GithubRelease {
+            owner: "ninja-build",
+            repo: "ninja",
+        },
+        arch_patterns: &[
+            ("ninja-win.zip", Arch::X64),
+            ("ninja-winarm64.zip", Arch::Arm64),
+        ],
+    },
+    ExtraPackageSpec {
+        kind: MsvcupPackageKind::Cmake,
+        name: "cmake",
+        source: ExtraSource::GithubRelease {
+            owner: "Kitware",
+            repo: "CMake",
+        },
+        arch_patterns: &[
+            ("cmake-{version}-windows-x86_64.zip", Arch::X64),
+            ("cmake-{version}-windows-i386.zip", Arch::X86),
+            ("cmake-{version}-windows-arm64.zip", Arch::Arm64),
+        ],
+    },
+    ExtraPackageSpec {
+        kind: MsvcupPackageKind::Nasm,
+        name: "nasm",
+        source: ExtraSource::Site {
+            host: "nasm.us",
+            prefix: "https://www.nasm.us/pub/nasm/releasebuilds/",
+        },
+        arch_patterns: &[
+            ("win64/nasm-{version}-win64.zip", Arch::X64),
+            ("win32/nasm-{version}-win32.zip", Arch::X86),
+        ],
+    },
+    ExtraPackageSpec {
+        kind: MsvcupPackageKind::Python,
+        name: "python",
+        source: ExtraSource::Site {
+            host: "python.org",
+            prefix: "https://www.python.org/ftp/python/",
+        },
+        arch_patterns: &[
+            ("python-{version}-embed-amd64.zip", Arch::X64),
+            ("python-{version}-embed-win32.zip", Arch::X86),
+            ("python-{version}-embed-arm64.zip", Arch::Arm64),
+        ],
+    },
+];
+
+/// The packages [`parse_url`] accepts release downloads of.
+pub fn supported_packages() -> &'static [ExtraPackageSpec] {
+    SUPPORTED_PACKAGES
+}
+
+/// A ninja, cmake, nasm or python release download.
+#[derive(Debug)]
+pub struct ExtraPayload {
+    pub kind: MsvcupPackageKind,
+    pub arch: Arch,
+}
+
+/// A URL that isn't a known ninja, cmake, nasm or python release download.
+#[derive(Debug)]
+pub struct ParseUrlError {
+    pub url: String,
+    pub offset: usize,
+    pub what: String,
+}
+
+impl fmt::Display for ParseUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unexpected content in URL at offset {}, expected {} but got '{}'",
+            self.offset,
+            self.what,
+            &self.url[self.offset..]
+        )
+    }
+}
+
+impl std::error::Error for ParseUrlError {}
 
-    let cmake_prefix = "https://github.com/Kitware/CMake/releases/download/v";
-    if let Some(rest) = url.strip_prefix(cmake_prefix) {
+pub fn parse_url(url: &str) -> Result<ExtraPayload, ParseUrlError> {
+    let unexpected = |offset: usize, what: String| ParseUrlError {
+        url: url.to_string(),
+        offset,
+        what,
+    };
+
+    for spec in supported_packages() {
+        let prefix = spec.url_prefix();
+        let Some(rest) = url.strip_prefix(prefix.as_str()) else {
+            continue;
+        };
         let version_end = scan_version(rest, 0);
         if version_end == 0 {
-            return ParseUrlResult::Unexpected {
-                offset: cmake_prefix.len(),
-                what: "a version".to_string(),
-            };
+            return Err(unexpected(prefix.len(), "a version".to_string()));
         }
         let version = &rest[..version_end];
-        let remaining = &rest[version_end..];
-
-        let expected_mid = format!("/cmake-{}-windows-", version);
-        if let Some(after_mid) = remaining.strip_prefix(expected_mid.as_str()) {
-            let arch = if after_mid == "x86_64.zip" {
-                Arch::X64
-            } else if after_mid == "i386.zip" {
-                Arch::X86
-            } else if after_mid == "arm64.zip" {
-                Arch::Arm64
-            } else {
-                return ParseUrlResult::Unexpected {
-                    offset: cmake_prefix.len() + version_end + expected_mid.len(),
-                    what: "'x86_64.zip', 'i386.zip', or 'arm64.zip'".to_string(),
-                };
-            };
-            return ParseUrlResult::Ok { arch };
-        } else {
-            return ParseUrlResult::Unexpected {
-                offset: cmake_prefix.len() + version_end,
-                what: "'/cmake-<version>-windows-<arch>.zip'".to_string(),
-            };
-        }
+        let assets: Vec<(String, Arch)> = spec
+            .arch_patterns
+            .iter()
+            .map(|(pattern, arch)| (format!("/{}", pattern.replace("{version}", version)), *arch))
+            .collect();
+        return match assets
+            .iter()
+            .find(|(asset, _)| *asset == rest[version_end..])
+        {
+            Some((_, arch)) => Ok(ExtraPayload {
+                kind: spec.kind,
+                arch: *arch,
+            }),
+            None => Err(unexpected(
+                prefix.len() + version_end,
+                one_of(assets.iter().map(|(asset, _)| asset.clone()).collect()),
+            )),
+        };
     }
 
-    ParseUrlResult::Unexpected {
-        offset: 0,
-        what: format!("either '{}' or '{}'", ninja_prefix, cmake_prefix),
+    Err(unexpected(
+        0,
+        one_of(
+            supported_packages()
+                .iter()
+                .map(|spec| spec.url_prefix())
+                .collect(),
+        ),
+    ))
+}
+
+/// `either 'a' or 'b'`, or `'a', 'b', or 'c'` for longer lists.
+fn one_of(items: Vec<String>) -> String {
+    let quoted: Vec<String> = items.iter().map(|item| format!("'{}'", item)).collect();
+    match quoted.as_slice() {
+        [only] => only.clone(),
+        [a, b] => format!("either {} or {}", a, b),
+        [init @ .., last] => format!("{}, or {}", init.join(", "), last),
+        [] => String::new(),
     }
 }
 
@@ -90,10 +219,8 @@ mod tests {
     fn ninja_x64() {
         let url = "https://github.com/ninja-build/ninja/releases/download/v1.12.1/ninja-win.zip";
         match parse_url(url) {
-            ParseUrlResult::Ok { arch } => assert_eq!(arch, Arch::X64),
-            ParseUrlResult::Unexpected { offset, what } => {
-                panic!("unexpected at {}: {}", offset, what)
-            }
+            Ok(payload) => assert_eq!(payload.arch, Arch::X64),
+            Err(e) => panic!("{}", e),
         }
     }
 
@@ -102,33 +229,29 @@ mod tests {
         let url =
             "https://github.com/ninja-build/ninja/releases/download/v1.12.1/ninja-winarm64.zip";
         match parse_url(url) {
-            ParseUrlResult::Ok { arch } => assert_eq!(arch, Arch::Arm64),
-            ParseUrlResult::Unexpected { offset, what } => {
-                panic!("unexpected at {}: {}", offset, what)
-            }
+            Ok(payload) => assert_eq!(payload.arch, Arch::Arm64),
+            Err(e) => panic!("{}", e),
         }
     }
 
     #[test]
     fn ninja_no_version() {
         let url = "https://github.com/ninja-build/ninja/releases/download/v/ninja-win.zip";
-        assert!(matches!(parse_url(url), ParseUrlResult::Unexpected { .. }));
+        assert!(parse_url(url).is_err());
     }
 
     #[test]
     fn ninja_bad_suffix() {
         let url = "https://github.com/ninja-build/ninja/releases/download/v1.12.1/ninja-linux.zip";
-        assert!(matches!(parse_url(url), ParseUrlResult::Unexpected { .. }));
+        assert!(parse_url(url).is_err());
     }
 
     #[test]
     fn cmake_x64() {
         let url = "https://github.com/Kitware/CMake/releases/download/v3.31.4/cmake-3.31.4-windows-x86_64.zip";
         match parse_url(url) {
-            ParseUrlResult::Ok { arch } => assert_eq!(arch, Arch::X64),
-            ParseUrlResult::Unexpected { offset, what } => {
-                panic!("unexpected at {}: {}", offset, what)
-            }
+            Ok(payload) => assert_eq!(payload.arch, Arch::X64),
+            Err(e) => panic!("{}", e),
         }
     }
 
@@ -136,10 +259,8 @@ mod tests {
     fn cmake_x86() {
         let url = "https://github.com/Kitware/CMake/releases/download/v3.31.4/cmake-3.31.4-windows-i386.zip";
         match parse_url(url) {
-            ParseUrlResult::Ok { arch } => assert_eq!(arch, Arch::X86),
-            ParseUrlResult::Unexpected { offset, what } => {
-                panic!("unexpected at {}: {}", offset, what)
-            }
+            Ok(payload) => assert_eq!(payload.arch, Arch::X86),
+            Err(e) => panic!("{}", e),
         }
     }
 
@@ -147,30 +268,72 @@ mod tests {
     fn cmake_arm64() {
         let url = "https://github.com/Kitware/CMake/releases/download/v3.31.4/cmake-3.31.4-windows-arm64.zip";
         match parse_url(url) {
-            ParseUrlResult::Ok { arch } => assert_eq!(arch, Arch::Arm64),
-            ParseUrlResult::Unexpected { offset, what } => {
-                panic!("unexpected at {}: {}", offset, what)
-            }
+            Ok(payload) => assert_eq!(payload.arch, Arch::Arm64),
+            Err(e) => panic!("{}", e),
         }
     }
 
     #[test]
     fn cmake_no_version() {
         let url = "https://github.com/Kitware/CMake/releases/download/v/cmake--windows-x86_64.zip";
-        assert!(matches!(parse_url(url), ParseUrlResult::Unexpected { .. }));
+        assert!(parse_url(url).is_err());
     }
 
     #[test]
     fn cmake_bad_arch() {
         let url = "https://github.com/Kitware/CMake/releases/download/v3.31.4/cmake-3.31.4-windows-mips.zip";
-        assert!(matches!(parse_url(url), ParseUrlResult::Unexpected { .. }));
+        assert!(parse_url(url).is_err());
+    }
+
+    #[test]
+    fn nasm_win64() {
+        let url = "https://www.nasm.us/pub/nasm/releasebuilds/2.16.03/win64/nasm-2.16.03-win64.zip";
+        let payload = parse_url(url).unwrap();
+        assert_eq!(payload.kind, MsvcupPackageKind::Nasm);
+        assert_eq!(payload.arch, Arch::X64);
+    }
+
+    #[test]
+    fn nasm_win32() {
+        let url = "https://www.nasm.us/pub/nasm/releasebuilds/2.16.03/win32/nasm-2.16.03-win32.zip";
+        assert_eq!(parse_url(url).unwrap().arch, Arch::X86);
+    }
+
+    #[test]
+    fn nasm_other_version_in_asset() {
+        let url = "https://www.nasm.us/pub/nasm/releasebuilds/2.16.03/win64/nasm-2.16.01-win64.zip";
+        let err = parse_url(url).unwrap_err();
+        assert_eq!(
+            err.what,
+            "either '/win64/nasm-2.16.03-win64.zip' or '/win32/nasm-2.16.03-win32.zip'"
+        );
+    }
+
+    #[test]
+    fn python_embed() {
+        for (asset, arch) in [
+            ("amd64", Arch::X64),
+            ("win32", Arch::X86),
+            ("arm64", Arch::Arm64),
+        ] {
+            let url = format!(
+                "https://www.python.org/ftp/python/3.13.1/python-3.13.1-embed-{}.zip",
+                asset
+            );
+            let payload = parse_url(&url).unwrap();
+            assert_eq!(payload.kind, MsvcupPackageKind::Python);
+            assert_eq!(payload.arch, arch);
+        }
+        // The full installer can't be extracted
+        let url = "https://www.python.org/ftp/python/3.13.1/python-3.13.1-amd64.exe";
+        assert!(parse_url(url).is_err());
     }
 
     #[test]
     fn unknown_url() {
         assert!(matches!(
             parse_url("https://example.com/something"),
-            ParseUrlResult::Unexpected { offset: 0, .. }
+            Err(ParseUrlError { offset: 0, .. })
         ));
     }
 
@@ -178,10 +341,49 @@ mod tests {
     fn empty_url() {
         assert!(matches!(
             parse_url(""),
-            ParseUrlResult::Unexpected { offset: 0, .. }
+            Err(ParseUrlError { offset: 0, .. })
         ));
     }
 
+    #[test]
+    fn error_message() {
+        let url = "https://github.com/ninja-build/ninja/releases/download/v1.12.1/ninja-linux.zip";
+        let err = parse_url(url).unwrap_err();
+        assert_eq!(err.offset, 62);
+        assert_eq!(
+            err.to_string(),
+            "unexpected content in URL at offset 62, expected either '/ninja-win.zip' or \
+             '/ninja-winarm64.zip' but got '/ninja-linux.zip'"
+        );
+    }
+
+    #[test]
+    fn cmake_error_lists_assets() {
+        let url = "https://github.com/Kitware/CMake/releases/download/v3.31.4/cmake-3.31.4-windows-mips.zip";
+        let err = parse_url(url).unwrap_err();
+        assert_eq!(
+            err.what,
+            "'/cmake-3.31.4-windows-x86_64.zip', '/cmake-3.31.4-windows-i386.zip', \
+             or '/cmake-3.31.4-windows-arm64.zip'"
+        );
+    }
+
+    #[test]
+    fn supported_packages_parse_their_urls() {
+        for spec in supported_packages() {
+            for (pattern, arch) in spec.arch_patterns {
+                let url = format!(
+                    "{}1.2.3/{}",
+                    spec.url_prefix(),
+                    pattern.replace("{version}", "1.2.3")
+                );
+                let payload = parse_url(&url).unwrap();
+                assert_eq!(payload.kind, spec.kind, "{}", url);
+                assert_eq!(payload.arch, *arch, "{}", url);
+            }
+        }
+    }
+
     #[test]
     fn scan_version_basic() {
         assert_eq!(scan_version("1.12.1/rest", 0), 6);
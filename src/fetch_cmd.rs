@@ -1,68 +1,605 @@
-use crate::lock_file::LockFile;
+use crate::error::{self, ErrorKind, bail_kind};
+use crate::install::cache_entry_path;
+use crate::lockfile_parse::parse_lock_file;
 use crate::manifest::{MsvcupDir, fetch};
 use crate::sha::Sha256;
 use crate::util::basename_from_url;
-use anyhow::{Result, bail};
-use fs_err as fs;
-use std::path::PathBuf;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
+/// What `fetch` downloads into the cache.
+#[derive(Debug, Clone)]
+pub enum FetchSource {
+    /// One http(s) URL, verified against `sha256` when it's given
+    Url {
+        url: String,
+        sha256: Option<Sha256>,
+        /// Where to put the file instead of the cache
+        output: Option<FetchOutput>,
+        /// Also put it in the cache when there's an `output`
+        also_cache: bool,
+    },
+    /// Every payload and cab listed in a lock file
+    LockFile(String),
+}
+
+/// Where `fetch --output` puts the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchOutput {
+    Path(PathBuf),
+    /// `-`: write the content to stdout and the hash to stderr
+    Stdout,
+}
+
+impl FetchOutput {
+    pub fn from_arg(s: &str) -> Self {
+        match s {
+            "-" => Self::Stdout,
+            _ => Self::Path(PathBuf::from(s)),
+        }
+    }
+}
+
+/// Fetch `source`. Only https URLs are fetched unless `allow_http` is given,
+/// which is meant for testing against a local mock server.
 pub async fn fetch_command(
     client: &reqwest::Client,
-    url: &str,
+    source: &FetchSource,
     cache_dir: Option<&str>,
+    allow_http: bool,
 ) -> Result<()> {
-    // Validate it's a known package URL
-    match crate::extra::parse_url(url) {
-        crate::extra::ParseUrlResult::Ok { .. } => {}
-        crate::extra::ParseUrlResult::Unexpected { offset, what } => {
-            bail!(
-                "invalid package url '{}' expected {} at offset {} but got '{}'",
-                url,
-                what,
-                offset,
-                &url[offset..]
-            );
+    let cache_dir = match cache_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => MsvcupDir::new()?.path(&["cache"]),
+    };
+    let cache_dir_str = cache_dir.to_str().unwrap();
+    if let FetchSource::Url { url, .. } = source {
+        let url = check_url(url)?;
+        if !allow_http {
+            require_https(&url)?;
         }
     }
 
-    let msvcup_dir = MsvcupDir::new()?;
-    let cache_dir = cache_dir
-        .map(PathBuf::from)
-        .unwrap_or_else(|| msvcup_dir.path(&["cache"]));
-    let cache_dir_str = cache_dir.to_str().unwrap();
+    match source {
+        FetchSource::Url {
+            url,
+            sha256,
+            output: None,
+            ..
+        } => {
+            let sha256 = fetch_url(client, cache_dir_str, url, sha256.as_ref()).await?;
+            println!("{}", sha256);
+        }
+        FetchSource::Url {
+            url,
+            sha256,
+            output: Some(output),
+            also_cache,
+        } => {
+            let cache_dir = also_cache.then_some(cache_dir_str);
+            let sha256 = fetch_to_output(client, url, sha256.as_ref(), output, cache_dir).await?;
+            match output {
+                FetchOutput::Path(_) => println!("{}", sha256),
+                FetchOutput::Stdout => eprintln!("{}", sha256),
+            }
+        }
+        FetchSource::LockFile(lock_file_path) => {
+            fetch_lock_file(client, cache_dir_str, lock_file_path, allow_http).await?;
+        }
+    }
+    Ok(())
+}
 
-    let cache_path = PathBuf::from(cache_dir_str).join("nohash");
-    let cache_lock_path = format!("{}.lock", cache_path.display());
+/// Fetch `url` into the cache as `<sha256>-<basename>`. With `expected`, a
+/// download with another hash is removed and fails, and an entry that's already
+/// cached isn't fetched again.
+async fn fetch_url(
+    client: &reqwest::Client,
+    cache_dir: &str,
+    url: &str,
+    expected: Option<&Sha256>,
+) -> Result<Sha256> {
+    check_url(url)?;
+    if let Ok(payload) = crate::extra::parse_url(url) {
+        log::debug!("{} payload for {} hosts", payload.kind, payload.arch);
+    }
 
-    let _cache_lock = LockFile::lock(&cache_lock_path)?;
+    if let Some(expected) = expected {
+        let final_path = cache_entry_path(cache_dir, expected, basename_from_url(url));
+        if tokio::fs::try_exists(&final_path).await? {
+            log::info!("{}: already exists", final_path.display());
+            return Ok(*expected);
+        }
+    }
 
-    let sha256 = fetch(client, url, &cache_path, None).await?;
+    let cache_path = temp_path(Path::new(cache_dir), basename_from_url(url));
+    let sha256 = fetch_verified(client, url, &cache_path, expected).await?;
 
     // Move to proper cache location
-    finish_cache_fetch(cache_dir_str, url, &sha256, &cache_path)?;
+    finish_cache_fetch(cache_dir, url, &sha256, &cache_path).await?;
+    Ok(sha256)
+}
+
+/// Fetch `url` to `output`, and with a `cache_dir` into the cache too. The
+/// download goes to a temporary file first, so `output` is only replaced (and
+/// stdout only written) once the download completed and its hash matched.
+async fn fetch_to_output(
+    client: &reqwest::Client,
+    url: &str,
+    expected: Option<&Sha256>,
+    output: &FetchOutput,
+    cache_dir: Option<&str>,
+) -> Result<Sha256> {
+    check_url(url)?;
+    let name = basename_from_url(url);
+    let temp_dir = match output {
+        // Next to the output, so it can be renamed into place
+        FetchOutput::Path(path) => match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+        FetchOutput::Stdout => std::env::temp_dir(),
+    };
+    let temp = temp_path(&temp_dir, name);
+    let sha256 = fetch_verified(client, url, &temp, expected).await?;
+
+    let written = async {
+        if let Some(cache_dir) = cache_dir {
+            let cache_temp = temp_path(Path::new(cache_dir), name);
+            tokio::fs::create_dir_all(cache_dir)
+                .await
+                .with_context(|| format!("creating directory '{}'", cache_dir))?;
+            tokio::fs::copy(&temp, &cache_temp)
+                .await
+                .with_context(|| format!("copying '{}' into the cache", temp.display()))?;
+            finish_cache_fetch(cache_dir, url, &sha256, &cache_temp).await?;
+        }
+        match output {
+            FetchOutput::Path(path) => {
+                tokio::fs::rename(&temp, path).await.with_context(|| {
+                    format!("renaming '{}' to '{}'", temp.display(), path.display())
+                })?;
+                log::info!("{}: fetched", path.display());
+            }
+            FetchOutput::Stdout => {
+                let src = temp.clone();
+                let copied = tokio::task::spawn_blocking(move || -> Result<()> {
+                    let mut file = fs_err::File::open(&src)?;
+                    let mut stdout = std::io::stdout().lock();
+                    std::io::copy(&mut file, &mut stdout).context("writing to stdout")?;
+                    std::io::Write::flush(&mut stdout).context("writing to stdout")?;
+                    Ok(())
+                })
+                .await
+                .context("writing to stdout");
+                // The temporary download goes whether or not the copy succeeded
+                let removed = tokio::fs::remove_file(&temp)
+                    .await
+                    .with_context(|| format!("removing '{}'", temp.display()));
+                copied??;
+                removed?;
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    if written.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    written.map(|()| sha256)
+}
+
+/// A file name in `dir` no other fetch, in this or another process, uses.
+fn temp_path(dir: &Path, name: &str) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    dir.join(format!(
+        ".{}.{}-{}.fetching",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Fetch `url` to `path` (creating its directory) and check it against
+/// `expected`. A download with another hash is removed.
+async fn fetch_verified(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    expected: Option<&Sha256>,
+) -> Result<Sha256> {
+    let sha256 = match fetch(client, url, path, None).await {
+        Ok(sha256) => sha256,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(path).await;
+            return Err(e);
+        }
+    };
+    match expected {
+        Some(expected) if sha256 != *expected => {
+            tokio::fs::remove_file(path)
+                .await
+                .with_context(|| format!("removing '{}'", path.display()))?;
+            bail_kind!(
+                ErrorKind::HashMismatch,
+                "SHA256 mismatch for '{}':\nexpected: {}\nactual  : {}",
+                url,
+                expected,
+                sha256
+            );
+        }
+        Some(_) => {}
+        None => log::warn!("{}: no --sha256 given, the content was not verified", url),
+    }
+    Ok(sha256)
+}
+
+/// Parse `url`, rejecting anything but an http or https URL.
+fn check_url(url: &str) -> Result<reqwest::Url> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| {
+        error::Error::new(ErrorKind::Usage, format!("invalid url '{}': {}", url, e))
+    })?;
+    check_scheme(&parsed)?;
+    Ok(parsed)
+}
 
-    println!("{}", sha256);
+fn check_scheme(url: &reqwest::Url) -> Result<()> {
+    if !matches!(url.scheme(), "http" | "https") {
+        bail_kind!(
+            ErrorKind::Usage,
+            "invalid url '{}': expected an http or https URL",
+            url
+        );
+    }
+    Ok(())
+}
 
+/// Reject anything but an https URL. Every VS manifest and GitHub URL msvcup
+/// fetches is https, so a plain http one is a mistake or a downgrade.
+pub fn require_https(url: &reqwest::Url) -> Result<()> {
+    check_scheme(url)?;
+    if url.scheme() != "https" {
+        bail_kind!(
+            ErrorKind::Usage,
+            "msvcup fetch requires HTTPS URLs for security; got HTTP: '{}'\n\
+             pass --allow-http to fetch from a local test server",
+            url
+        );
+    }
     Ok(())
 }
 
-fn finish_cache_fetch(
+/// The URL and hash of every payload and cab of a lock file, sorted by URL and
+/// without duplicates. Payloads for every host architecture are included.
+fn lock_file_entries(lock_file_path: &str, content: &str) -> Result<Vec<(String, Sha256)>> {
+    let lock_file = parse_lock_file(lock_file_path, content)?;
+    let parse = |url: &str, sha256: &str| {
+        Sha256::parse_hex(sha256)
+            .map(|sha256| (url.to_string(), sha256))
+            .ok_or_else(|| anyhow::anyhow!("invalid sha256 for '{}': '{}'", url, sha256))
+    };
+    let mut entries = Vec::new();
+    for lock_pkg in &lock_file.packages {
+        for entry in &lock_pkg.payloads {
+            entries.push(parse(&entry.url, &entry.sha256)?);
+        }
+    }
+    for cab in lock_file.cabs.values() {
+        entries.push(parse(&cab.url, &cab.sha256)?);
+    }
+    entries.sort_by(|(a_url, a_sha256), (b_url, b_sha256)| {
+        (a_url, a_sha256.bytes).cmp(&(b_url, b_sha256.bytes))
+    });
+    entries.dedup();
+    Ok(entries)
+}
+
+async fn fetch_lock_file(
+    client: &reqwest::Client,
+    cache_dir: &str,
+    lock_file_path: &str,
+    allow_http: bool,
+) -> Result<()> {
+    let content = tokio::fs::read_to_string(lock_file_path)
+        .await
+        .with_context(|| format!("reading lock file '{}'", lock_file_path))?;
+    let entries = lock_file_entries(lock_file_path, &content)?;
+    // Checked up front so nothing is fetched from a lock file that would fail
+    if !allow_http {
+        for (url, _) in &entries {
+            require_https(&check_url(url)?)
+                .with_context(|| format!("lock file '{}'", lock_file_path))?;
+        }
+    }
+    let mut fetched = 0usize;
+    for (url, sha256) in &entries {
+        let final_path = cache_entry_path(cache_dir, sha256, basename_from_url(url));
+        if tokio::fs::try_exists(&final_path).await? {
+            log::debug!("{}: already exists", final_path.display());
+            continue;
+        }
+        fetch_url(client, cache_dir, url, Some(sha256)).await?;
+        fetched += 1;
+    }
+    log::info!(
+        "fetched {} of {} lock file entries into '{}'",
+        fetched,
+        entries.len(),
+        cache_dir
+    );
+    Ok(())
+}
+
+async fn finish_cache_fetch(
     cache_dir: &str,
     url: &str,
     sha256: &Sha256,
-    cache_path: &PathBuf,
+    cache_path: &Path,
 ) -> Result<()> {
     let name = basename_from_url(url);
     let cache_basename = format!("{}-{}", sha256, name);
     let final_path = PathBuf::from(cache_dir).join(&cache_basename);
 
-    if final_path.exists() {
+    if tokio::fs::try_exists(&final_path).await? {
         log::info!("{}: already exists", final_path.display());
-        fs::remove_file(cache_path)?;
+        tokio::fs::remove_file(cache_path)
+            .await
+            .with_context(|| format!("removing '{}'", cache_path.display()))?;
     } else {
         log::info!("{}: newly fetched", final_path.display());
-        fs::create_dir_all(cache_dir)?;
-        fs::rename(cache_path, &final_path)?;
+        tokio::fs::create_dir_all(cache_dir)
+            .await
+            .with_context(|| format!("creating directory '{}'", cache_dir))?;
+        tokio::fs::rename(cache_path, &final_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "renaming '{}' to '{}'",
+                    cache_path.display(),
+                    final_path.display()
+                )
+            })?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{HELLO_SHA256, TempDir};
+
+    /// Serves "hello" for every request, returning the base URL and the number
+    /// of requests served.
+    fn serve_hello() -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+                );
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    #[test]
+    fn only_http_urls() {
+        check_url("https://example.com/a.vsix").unwrap();
+        check_url("http://127.0.0.1:8080/a.msi").unwrap();
+        for url in ["file:///etc/passwd", "ftp://example.com/a.zip", "not a url"] {
+            let err = check_url(url).unwrap_err();
+            assert_eq!(error::exit_code(&err), 2, "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn https_required_without_allow_http() {
+        let parse = |url: &str| reqwest::Url::parse(url).unwrap();
+        require_https(&parse("https://example.com/a.vsix")).unwrap();
+        for url in ["http://127.0.0.1:8080/a.msi", "ftp://example.com/a.zip"] {
+            let err = require_https(&parse(url)).unwrap_err();
+            assert_eq!(error::exit_code(&err), 2, "{}", url);
+        }
+        assert!(
+            require_https(&parse("http://example.com/a.msi"))
+                .unwrap_err()
+                .to_string()
+                .starts_with("msvcup fetch requires HTTPS URLs for security; got HTTP")
+        );
+
+        let (base, requests) = serve_hello();
+        let dir = TempDir::new("fetch_https");
+        let cache_dir = dir.join("cache");
+        let lock_file = dir.join("msvcup.lock");
+        std::fs::write(
+            &lock_file,
+            format!(
+                r#"{{"version": 1, "packages": [{{"name": "ninja-1.12.1", "payloads": [
+                    {{"url": "{}/ninja-win.zip", "sha256": "{}"}}]}}]}}"#,
+                base, HELLO_SHA256
+            ),
+        )
+        .unwrap();
+        let client = reqwest::Client::new();
+        let source = FetchSource::LockFile(lock_file.to_str().unwrap().to_string());
+        let cache_dir_str = cache_dir.to_str().unwrap();
+        let err = fetch_command(&client, &source, Some(cache_dir_str), false)
+            .await
+            .unwrap_err();
+        assert_eq!(error::exit_code(&err), 2);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 0);
+        fetch_command(&client, &source, Some(cache_dir_str), true)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn entries_of_a_lock_file() {
+        let sha = |c: &str| c.repeat(64);
+        let content = format!(
+            r#"{{"version": 1,
+                "cabs": {{"b.cab": {{"url": "https://example.com/b.cab", "sha256": "{b}"}},
+                          "a.cab": {{"url": "https://example.com/a.cab", "sha256": "{a}"}}}},
+                "packages": [{{"name": "msvc-14.43.17.13", "payloads": [
+                    {{"url": "https://example.com/x.msi", "sha256": "{c}"}}]}},
+                    {{"name": "sdk-10.0.22621.7", "payloads": [
+                    {{"url": "https://example.com/y.msi", "sha256": "{b}"}},
+                    {{"url": "https://example.com/x.msi", "sha256": "{c}"}}]}}]}}"#,
+            a = sha("a"),
+            b = sha("b"),
+            c = sha("c")
+        );
+        let entries = lock_file_entries("msvcup.lock", &content).unwrap();
+        let urls: Vec<&str> = entries.iter().map(|(url, _)| url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://example.com/a.cab",
+                "https://example.com/b.cab",
+                "https://example.com/x.msi",
+                "https://example.com/y.msi"
+            ]
+        );
+        assert_eq!(entries[0].1.to_string(), sha("a"));
+
+        let invalid = content.replace(&sha("a"), "zz");
+        assert!(lock_file_entries("msvcup.lock", &invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_with_expected_sha256() {
+        let (base, requests) = serve_hello();
+        let cache_dir = TempDir::new("fetch_sha256");
+        let cache_dir_str = cache_dir.to_str().unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("{}/files/hello%20world.txt", base);
+
+        let wrong = Sha256::parse_hex(&"0".repeat(64)).unwrap();
+        let err = fetch_url(&client, cache_dir_str, &url, Some(&wrong))
+            .await
+            .unwrap_err();
+        assert_eq!(error::exit_code(&err), 5);
+        // The failed download is removed
+        assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 0);
+
+        let hello = Sha256::parse_hex(HELLO_SHA256).unwrap();
+        assert_eq!(
+            fetch_url(&client, cache_dir_str, &url, Some(&hello))
+                .await
+                .unwrap(),
+            hello
+        );
+        let cached = cache_entry_path(cache_dir_str, &hello, basename_from_url(&url));
+        assert_eq!(std::fs::read_to_string(&cached).unwrap(), "hello");
+
+        // Already cached under the expected hash, so nothing is downloaded
+        let served = requests.load(std::sync::atomic::Ordering::SeqCst);
+        fetch_url(&client, cache_dir_str, &url, Some(&hello))
+            .await
+            .unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), served);
+
+        // Without a hash it's hashed after the download
+        assert_eq!(
+            fetch_url(&client, cache_dir_str, &url, None).await.unwrap(),
+            hello
+        );
+    }
+
+    #[test]
+    fn temp_paths_are_unique() {
+        let dir = Path::new("cache");
+        let a = temp_path(dir, "ninja-win.zip");
+        assert_ne!(a, temp_path(dir, "ninja-win.zip"));
+        assert!(a.starts_with(dir));
+        assert_eq!(FetchOutput::from_arg("-"), FetchOutput::Stdout);
+        assert_eq!(
+            FetchOutput::from_arg("tools/ninja.zip"),
+            FetchOutput::Path(PathBuf::from("tools/ninja.zip"))
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_to_an_output_path() {
+        let (base, _) = serve_hello();
+        let dir = TempDir::new("fetch_output");
+        let cache_dir = dir.join("cache");
+        let cache_dir_str = cache_dir.to_str().unwrap();
+        let output = dir.join("tools").join("hello.zip");
+        let client = reqwest::Client::new();
+        let url = format!("{}/hello.zip", base);
+        let hello = Sha256::parse_hex(HELLO_SHA256).unwrap();
+
+        let wrong = Sha256::parse_hex(&"0".repeat(64)).unwrap();
+        let out = FetchOutput::Path(output.clone());
+        let err = fetch_to_output(&client, &url, Some(&wrong), &out, Some(cache_dir_str))
+            .await
+            .unwrap_err();
+        assert_eq!(error::exit_code(&err), 5);
+        assert!(!output.exists());
+        assert_eq!(std::fs::read_dir(dir.join("tools")).unwrap().count(), 0);
+        assert!(!cache_dir.exists());
+
+        // Only the output without --also-cache
+        let sha256 = fetch_to_output(&client, &url, Some(&hello), &out, None)
+            .await
+            .unwrap();
+        assert_eq!(sha256, hello);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello");
+        assert!(!cache_dir.exists());
+
+        // Replaces the output and adds it to the cache
+        std::fs::write(&output, "old").unwrap();
+        fetch_to_output(&client, &url, None, &out, Some(cache_dir_str))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello");
+        assert!(cache_entry_path(cache_dir_str, &hello, "hello.zip").exists());
+        assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn fetch_every_lock_file_entry() {
+        let (base, requests) = serve_hello();
+        let dir = TempDir::new("fetch_lock_file");
+        let cache_dir = dir.join("cache");
+        let cache_dir_str = cache_dir.to_str().unwrap();
+        let lock_file_path = dir.join("msvcup.lock");
+        std::fs::write(
+            &lock_file_path,
+            format!(
+                r#"{{"version": 1,
+                    "cabs": {{"a.cab": {{"url": "{base}/a.cab", "sha256": "{HELLO_SHA256}"}}}},
+                    "packages": [{{"name": "msvc-14.43.17.13", "payloads": [
+                        {{"url": "{base}/x.msi", "sha256": "{HELLO_SHA256}"}}]}}]}}"#
+            ),
+        )
+        .unwrap();
+        let lock_file_path = lock_file_path.to_str().unwrap();
+        let client = reqwest::Client::new();
+
+        fetch_lock_file(&client, cache_dir_str, lock_file_path, true)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+        let hello = Sha256::parse_hex(HELLO_SHA256).unwrap();
+        assert!(cache_entry_path(cache_dir_str, &hello, "x.msi").exists());
+        assert!(cache_entry_path(cache_dir_str, &hello, "a.cab").exists());
+
+        // A second run finds everything cached
+        fetch_lock_file(&client, cache_dir_str, lock_file_path, true)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
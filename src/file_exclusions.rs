@@ -0,0 +1,115 @@
+use crate::error::{ErrorKind, bail_kind};
+use anyhow::Result;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// The prefix of a `.files` install manifest line for a file `install --exclude`
+/// left out, so a later install with other exclusions knows it's missing.
+pub const SKIP_PREFIX: &str = "skip ";
+
+/// The files `install --exclude` leaves out of the extracted payloads.
+///
+/// Each pattern is a glob with `globset` semantics (`*` also matches `/`, so
+/// `*.winmd` matches in every directory) matched against the `/`-separated
+/// path of a file relative to its package directory, e.g.
+/// `Windows Kits/10/Lib/*/um/arm64/*`. Matching ignores case on Windows.
+#[derive(Debug, Clone, Default)]
+pub struct FileExclusions {
+    set: GlobSet,
+    patterns: Vec<String>,
+}
+
+impl FileExclusions {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = match GlobBuilder::new(pattern.trim_start_matches('/'))
+                .case_insensitive(cfg!(windows))
+                .build()
+            {
+                Ok(glob) => glob,
+                Err(e) => bail_kind!(ErrorKind::Usage, "invalid --exclude '{}': {}", pattern, e),
+            };
+            builder.add(glob);
+        }
+        let set = match builder.build() {
+            Ok(set) => set,
+            Err(e) => bail_kind!(ErrorKind::Usage, "invalid --exclude: {}", e),
+        };
+        Ok(Self {
+            set,
+            patterns: patterns.to_vec(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Whether the file at `sub_path` (relative to the package directory, with
+    /// either separator) is left out.
+    pub fn excludes(&self, sub_path: &str) -> bool {
+        !self.is_empty()
+            && self
+                .set
+                .is_match(sub_path.replace('\\', "/").trim_start_matches('/'))
+    }
+}
+
+/// The files an extraction left out, see [`FileExclusions`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SkippedFiles {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl SkippedFiles {
+    pub fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_relative_paths() {
+        let exclusions = FileExclusions::new(&[
+            "*.winmd".to_string(),
+            "/Windows Kits/10/Lib/*/um/arm64/*".to_string(),
+            "VC/Tools/MSVC/*/lib/onecore/**".to_string(),
+        ])
+        .unwrap();
+        for (path, excluded) in [
+            (
+                "Windows Kits/10/UnionMetadata/10.0.26100.0/Windows.winmd",
+                true,
+            ),
+            (
+                "Windows Kits\\10\\Lib\\10.0.26100.0\\um\\arm64\\kernel32.Lib",
+                true,
+            ),
+            (
+                "/Windows Kits/10/Lib/10.0.26100.0/um/x64/kernel32.Lib",
+                false,
+            ),
+            ("VC/Tools/MSVC/14.43.34808/lib/onecore/x64/msvcrt.lib", true),
+            ("VC/Tools/MSVC/14.43.34808/lib/x64/msvcrt.lib", false),
+        ] {
+            assert_eq!(exclusions.excludes(path), excluded, "{}", path);
+        }
+        assert_eq!(
+            exclusions.excludes("windows kits/10/lib/1/um/arm64/a.lib"),
+            cfg!(windows)
+        );
+        assert!(!FileExclusions::default().excludes("a.winmd"));
+    }
+
+    #[test]
+    fn invalid_glob_is_usage_error() {
+        let err = FileExclusions::new(&["lib/[x64".to_string()]).unwrap_err();
+        assert!(err.to_string().contains("lib/[x64"), "{}", err);
+        assert_eq!(crate::error::exit_code(&err), 2);
+    }
+}
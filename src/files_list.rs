@@ -0,0 +1,179 @@
+use crate::install::installed_files;
+use crate::manifest::MsvcupDir;
+use crate::packages::MsvcupPackage;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use std::path::Path;
+
+/// How `install --files-list` writes the installed files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilesListFormat {
+    /// One path per line
+    Plain,
+    /// `.gitignore` patterns anchored at the install directory, also covering
+    /// each package's `install` metadata directory
+    Gitignore,
+}
+
+impl FilesListFormat {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "plain" => Some(Self::Plain),
+            "gitignore" => Some(Self::Gitignore),
+            _ => None,
+        }
+    }
+}
+
+/// The files installed for `msvcup_pkgs`, read from their `.files` manifests, as
+/// sorted `/`-separated paths relative to the install directory.
+pub fn collect_files_list(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for msvcup_pkg in msvcup_pkgs {
+        let install_path = msvcup_dir.package_path(msvcup_pkg);
+        for file in installed_files(&install_path)? {
+            // Manifests from older versions can hold absolute paths of another root
+            let Ok(rel) = file.strip_prefix(&msvcup_dir.root_path) else {
+                log::warn!(
+                    "{}: '{}' is outside of the install directory, not listing it",
+                    msvcup_pkg,
+                    file.display()
+                );
+                continue;
+            };
+            files.push(rel.to_string_lossy().replace('\\', "/"));
+        }
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+/// Render the files list. In `.gitignore` syntax every path is anchored with a
+/// leading `/` and the characters git would interpret are escaped, so the file
+/// is meant to be placed in the install directory.
+pub fn render_files_list(
+    files: &[String],
+    msvcup_pkgs: &[MsvcupPackage],
+    format: FilesListFormat,
+) -> String {
+    let mut out = String::new();
+    match format {
+        FilesListFormat::Plain => {
+            for file in files {
+                out.push_str(file);
+                out.push('\n');
+            }
+        }
+        FilesListFormat::Gitignore => {
+            out.push_str("# Files installed by msvcup, regenerated by every install\n");
+            for msvcup_pkg in msvcup_pkgs {
+                out.push_str(&format!(
+                    "/{}/install/\n",
+                    escape_gitignore(&msvcup_pkg.pool_string())
+                ));
+            }
+            for file in files {
+                out.push('/');
+                out.push_str(&escape_gitignore(file));
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+fn escape_gitignore(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\' | '!' | '#') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    // Trailing spaces are ignored unless escaped
+    if out.ends_with(' ') {
+        out.pop();
+        out.push_str("\\ ");
+    }
+    out
+}
+
+pub fn write_files_list(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    path: &Path,
+    format: FilesListFormat,
+) -> Result<()> {
+    let files = collect_files_list(msvcup_dir, msvcup_pkgs)?;
+    fs::write(path, render_files_list(&files, msvcup_pkgs, format))
+        .with_context(|| format!("writing files list '{}'", path.display()))?;
+    log::info!(
+        "listed {} installed files in '{}'",
+        files.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::MsvcupPackageKind;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn files_list_from_manifests() {
+        let root = TempDir::new("files_list");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Ninja, "1.12.1");
+        let install_meta_dir = root.join(pkg.pool_string()).join("install");
+        std::fs::create_dir_all(&install_meta_dir).unwrap();
+        let sep = std::path::MAIN_SEPARATOR;
+        std::fs::write(
+            install_meta_dir.join("abc-ninja-win.zip.files"),
+            format!("ninja.exe\ndoc{sep}[draft] #1.txt\n"),
+        )
+        .unwrap();
+        std::fs::write(
+            install_meta_dir.join("def-other.zip.files.pending"),
+            "def-other.zip\nnew other\n",
+        )
+        .unwrap();
+
+        let pkgs = [pkg];
+        let files = collect_files_list(&msvcup_dir, &pkgs).unwrap();
+        assert_eq!(
+            files,
+            ["ninja-1.12.1/doc/[draft] #1.txt", "ninja-1.12.1/ninja.exe"]
+        );
+        assert_eq!(
+            render_files_list(&files, &pkgs, FilesListFormat::Plain),
+            "ninja-1.12.1/doc/[draft] #1.txt\nninja-1.12.1/ninja.exe\n"
+        );
+        assert_eq!(
+            render_files_list(&files, &pkgs, FilesListFormat::Gitignore),
+            "# Files installed by msvcup, regenerated by every install\n\
+             /ninja-1.12.1/install/\n\
+             /ninja-1.12.1/doc/\\[draft\\] \\#1.txt\n\
+             /ninja-1.12.1/ninja.exe\n"
+        );
+
+        let out = root.join("installed.gitignore");
+        write_files_list(&msvcup_dir, &pkgs, &out, FilesListFormat::Gitignore).unwrap();
+        assert!(
+            std::fs::read_to_string(&out)
+                .unwrap()
+                .ends_with("/ninja-1.12.1/ninja.exe\n")
+        );
+    }
+
+    #[test]
+    fn escape_trailing_space() {
+        assert_eq!(escape_gitignore("a b "), "a b\\ ");
+        assert_eq!(escape_gitignore("!x"), "\\!x");
+    }
+}
@@ -0,0 +1,394 @@
+use crate::arch::Arch;
+use crate::channel_kind::ChannelKind;
+use crate::config::{CONFIG_FILE_NAME, MsvcupConfig, MsvcupSettings};
+use crate::error::{ErrorKind, bail_kind};
+use crate::install;
+use crate::list_cmd::{PackageEntry, list_packages};
+use crate::manifest::{self, MsvcupDir};
+use crate::packages::{
+    DEFAULT_LANGUAGES, ManifestUpdate, MsvcupPackage, MsvcupPackageKind, Packages,
+    PayloadExclusions,
+};
+use anyhow::{Context, Result};
+use fs_err as fs;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The lock file name `init` pins the packages in, next to the config.
+pub const LOCK_FILE_NAME: &str = "msvcup.lock";
+
+/// Which version of a package `init` pins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionSpec {
+    Latest,
+    /// A full version, or a prefix like `14.43` that picks the newest match
+    Prefix(String),
+}
+
+/// The newest package of `kind` in `entries` (sorted, as [`list_packages`]
+/// returns them) that matches `spec`.
+pub fn pick_version(
+    entries: &[PackageEntry],
+    kind: MsvcupPackageKind,
+    spec: &VersionSpec,
+) -> Result<MsvcupPackage> {
+    let mut versions = entries
+        .iter()
+        .filter(|e| e.kind == kind)
+        .map(|e| &e.version);
+    let found = match spec {
+        VersionSpec::Latest => versions.next_back(),
+        VersionSpec::Prefix(prefix) => versions.rfind(|version| {
+            version
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        }),
+    };
+    match found {
+        Some(version) => Ok(MsvcupPackage::new(kind, version.clone())),
+        None => {
+            let available: Vec<&str> = entries
+                .iter()
+                .filter(|e| e.kind == kind)
+                .map(|e| e.version.as_str())
+                .collect();
+            match spec {
+                VersionSpec::Latest => {
+                    bail_kind!(ErrorKind::Usage, "the manifest has no {} packages", kind)
+                }
+                VersionSpec::Prefix(prefix) => bail_kind!(
+                    ErrorKind::Usage,
+                    "no {} version matches '{}', available: {}",
+                    kind,
+                    prefix,
+                    available.join(", ")
+                ),
+            }
+        }
+    }
+}
+
+/// The version of each kind in `specs` that [`pick_version`] picks.
+pub fn pick_packages(
+    entries: &[PackageEntry],
+    specs: &[(MsvcupPackageKind, VersionSpec)],
+) -> Result<Vec<MsvcupPackage>> {
+    specs
+        .iter()
+        .map(|(kind, spec)| pick_version(entries, *kind, spec))
+        .collect()
+}
+
+/// The package kinds `init` can pin, in the order `--interactive` offers them.
+pub const INIT_KINDS: [MsvcupPackageKind; 4] = [
+    MsvcupPackageKind::Msvc,
+    MsvcupPackageKind::Sdk,
+    MsvcupPackageKind::Cmake,
+    MsvcupPackageKind::Ninja,
+];
+
+/// The packages `--interactive` offers: every kind of [`INIT_KINDS`] with the
+/// version its spec in `specs` picks, or the latest one, and whether it's
+/// selected up front (it has a spec). Kinds the manifest doesn't have are left
+/// out unless they have a spec, which fails like without `--interactive`.
+pub fn init_candidates(
+    entries: &[PackageEntry],
+    specs: &[(MsvcupPackageKind, VersionSpec)],
+) -> Result<Vec<(MsvcupPackage, bool)>> {
+    let mut candidates = Vec::new();
+    for kind in INIT_KINDS {
+        match specs.iter().find(|(k, _)| *k == kind) {
+            Some((_, spec)) => candidates.push((pick_version(entries, kind, spec)?, true)),
+            None => {
+                if let Ok(pkg) = pick_version(entries, kind, &VersionSpec::Latest) {
+                    candidates.push((pkg, false));
+                }
+            }
+        }
+    }
+    Ok(candidates)
+}
+
+/// Let the user check which of the `candidates` to pin.
+fn prompt_packages(candidates: Vec<(MsvcupPackage, bool)>) -> Result<Vec<MsvcupPackage>> {
+    let items: Vec<String> = candidates.iter().map(|(pkg, _)| pkg.to_string()).collect();
+    let defaults: Vec<bool> = candidates.iter().map(|(_, selected)| *selected).collect();
+    let selection = dialoguer::MultiSelect::new()
+        .with_prompt("Packages to pin (space to toggle, enter to confirm)")
+        .items(&items)
+        .defaults(&defaults)
+        .interact()
+        .context("prompting for the packages to pin")?;
+    if selection.is_empty() {
+        bail_kind!(ErrorKind::Usage, "no packages selected");
+    }
+    let mut candidates: Vec<Option<MsvcupPackage>> =
+        candidates.into_iter().map(|(pkg, _)| Some(pkg)).collect();
+    Ok(selection
+        .into_iter()
+        .filter_map(|i| candidates[i].take())
+        .collect())
+}
+
+/// Let the user pick one of the versions of `kind` in `entries` (sorted, as
+/// [`list_packages`] returns them), newest first.
+pub fn prompt_version(entries: &[PackageEntry], kind: MsvcupPackageKind) -> Result<MsvcupPackage> {
+    let versions: Vec<&str> = entries
+        .iter()
+        .rev()
+        .filter(|e| e.kind == kind)
+        .map(|e| e.version.as_str())
+        .collect();
+    if versions.is_empty() {
+        bail_kind!(ErrorKind::Usage, "the manifest has no {} packages", kind);
+    }
+    let index = dialoguer::Select::new()
+        .with_prompt(format!("{} version", kind))
+        .items(&versions)
+        .default(0)
+        .interact()
+        .with_context(|| format!("prompting for the {} version", kind))?;
+    Ok(MsvcupPackage::new(kind, versions[index]))
+}
+
+/// Write an `msvcup.toml` pinning `msvcup_pkgs` to `dir`, and resolve its lock
+/// file from `pkgs`. Refuses to replace an existing config or lock file unless
+/// `force` is given. Both are written under temporary names and only moved into
+/// place once the lock file resolved, so a failure leaves `dir` as it was.
+pub fn init_project(
+    dir: &Path,
+    pkgs: &Packages,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_arch: Arch,
+    force: bool,
+) -> Result<MsvcupConfig> {
+    let config_path = dir.join(CONFIG_FILE_NAME);
+    let lock_file_path = dir.join(LOCK_FILE_NAME);
+    if !force {
+        for path in [&config_path, &lock_file_path] {
+            if path.exists() {
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "'{}' already exists, pass --force to replace it",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    let config = MsvcupConfig {
+        msvcup: MsvcupSettings {
+            cache_dir: None,
+            install_dir: None,
+            install_roots: BTreeMap::new(),
+            lock_file: LOCK_FILE_NAME.to_string(),
+            target_arch: target_arch.to_string(),
+            vcvars_path: None,
+            exclude: Vec::new(),
+            out_dir: Some(format!("autoenv-{}", target_arch)),
+        },
+        packages: msvcup_pkgs
+            .iter()
+            .map(|p| (p.kind.to_string(), p.version.clone()))
+            .collect::<BTreeMap<_, _>>(),
+    };
+
+    fs::create_dir_all(dir)?;
+    let new_config_path = dir.join(format!("{}.new", CONFIG_FILE_NAME));
+    let new_lock_file_path = dir.join(format!("{}.new", LOCK_FILE_NAME));
+    let written = (|| -> Result<()> {
+        install::update_lock_file(
+            msvcup_pkgs,
+            crate::util::path_str(&new_lock_file_path)?,
+            pkgs,
+            DEFAULT_LANGUAGES,
+            target_arch,
+            PayloadExclusions::default(),
+        )?;
+        let content = toml::to_string_pretty(&config).context("serializing the config")?;
+        fs::write(&new_config_path, content)?;
+        Ok(())
+    })();
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&new_lock_file_path);
+        let _ = std::fs::remove_file(&new_config_path);
+        return Err(e);
+    }
+    fs::rename(&new_lock_file_path, &lock_file_path)?;
+    fs::rename(&new_config_path, &config_path)?;
+    Ok(config)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn init_command(
+    client: &reqwest::Client,
+    msvcup_dir: &MsvcupDir,
+    channel_kind: &ChannelKind,
+    manifest_update: ManifestUpdate,
+    dir: &Path,
+    specs: &[(MsvcupPackageKind, VersionSpec)],
+    interactive: bool,
+    target_arch: Arch,
+    force: bool,
+) -> Result<()> {
+    let pkgs =
+        manifest::read_vs_manifest(client, msvcup_dir, channel_kind, manifest_update).await?;
+    let entries = list_packages(&pkgs);
+    let msvcup_pkgs = if interactive {
+        prompt_packages(init_candidates(&entries, specs)?)?
+    } else {
+        pick_packages(&entries, specs)?
+    };
+    let config = init_project(dir, &pkgs, &msvcup_pkgs, target_arch, force)?;
+    for (name, version) in &config.packages {
+        log::info!("pinned {}-{}", name, version);
+    }
+    log::info!(
+        "wrote '{}' and '{}'",
+        dir.join(CONFIG_FILE_NAME).display(),
+        dir.join(LOCK_FILE_NAME).display()
+    );
+    log::info!("run 'msvcup install' to install the packages");
+    log::info!(
+        "run 'msvcup resolve' to place the autoenv shims in '{}'",
+        config.msvcup.out_dir.as_deref().unwrap_or_default()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::get_packages;
+    use crate::test_support::{FixtureManifest, TempDir};
+
+    fn fixture_packages() -> Packages {
+        let manifest = FixtureManifest::new()
+            .package(
+                "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.base",
+                "14.43.34808",
+                &[("tools43.vsix", "https://example.com/tools43.vsix", 1000)],
+            )
+            .package(
+                "Microsoft.VC.14.44.17.14.Tools.HostX64.TargetX64.base",
+                "14.44.35207",
+                &[("tools44.vsix", "https://example.com/tools44.vsix", 1000)],
+            )
+            .package(
+                "Win11SDK_10.0.22621",
+                "10.0.22621.7",
+                &[(
+                    "Installers\\Universal CRT Headers Libraries and Sources-x86_en-us.msi",
+                    "https://example.com/sdk.msi",
+                    200,
+                )],
+            )
+            .build();
+        get_packages("fixture.json", &manifest).unwrap()
+    }
+
+    #[test]
+    fn pick_versions() {
+        let entries = list_packages(&fixture_packages());
+        let pick = |kind, spec: &VersionSpec| pick_version(&entries, kind, spec);
+        assert_eq!(
+            pick(MsvcupPackageKind::Msvc, &VersionSpec::Latest)
+                .unwrap()
+                .to_string(),
+            "msvc-14.44.17.14"
+        );
+        let prefix = |s: &str| VersionSpec::Prefix(s.to_string());
+        assert_eq!(
+            pick(MsvcupPackageKind::Msvc, &prefix("14.43"))
+                .unwrap()
+                .to_string(),
+            "msvc-14.43.17.13"
+        );
+        let err = pick(MsvcupPackageKind::Msvc, &prefix("14.4")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no msvc version matches '14.4', available: 14.43.17.13, 14.44.17.14"
+        );
+        assert!(pick(MsvcupPackageKind::Cmake, &VersionSpec::Latest).is_err());
+    }
+
+    #[test]
+    fn interactive_candidates() {
+        let entries = list_packages(&fixture_packages());
+        let older = VersionSpec::Prefix("14.43".to_string());
+        let candidates =
+            init_candidates(&entries, &[(MsvcupPackageKind::Msvc, older.clone())]).unwrap();
+        let candidates: Vec<(String, bool)> = candidates
+            .into_iter()
+            .map(|(pkg, selected)| (pkg.to_string(), selected))
+            .collect();
+        // No cmake or ninja in the manifest, and the sdk isn't preselected
+        assert_eq!(
+            candidates,
+            [
+                ("msvc-14.43.17.13".to_string(), true),
+                ("sdk-10.0.22621.7".to_string(), false)
+            ]
+        );
+        assert!(
+            init_candidates(&entries, &[(MsvcupPackageKind::Cmake, VersionSpec::Latest)]).is_err()
+        );
+        // Nothing to pick from fails without prompting
+        let err = prompt_version(&entries, MsvcupPackageKind::Cmake).unwrap_err();
+        assert_eq!(err.to_string(), "the manifest has no cmake packages");
+    }
+
+    #[test]
+    fn init_writes_config_and_lock_file() {
+        let dir = TempDir::new("init");
+        let pkgs = fixture_packages();
+        let entries = list_packages(&pkgs);
+        let pick = |msvc: &VersionSpec| {
+            pick_packages(
+                &entries,
+                &[
+                    (MsvcupPackageKind::Msvc, msvc.clone()),
+                    (MsvcupPackageKind::Sdk, VersionSpec::Latest),
+                ],
+            )
+            .unwrap()
+        };
+
+        // A package the manifest doesn't have leaves nothing behind
+        let missing = [MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.30.17.0")];
+        assert!(init_project(&dir, &pkgs, &missing, Arch::X64, false).is_err());
+        assert!(!dir.join(CONFIG_FILE_NAME).exists());
+        assert!(!dir.join(LOCK_FILE_NAME).exists());
+
+        let config =
+            init_project(&dir, &pkgs, &pick(&VersionSpec::Latest), Arch::X64, false).unwrap();
+        assert_eq!(config.packages["msvc"], "14.44.17.14");
+        assert_eq!(config.packages["sdk"], "10.0.22621.7");
+
+        let (_, read_back) = MsvcupConfig::discover(&dir).unwrap().unwrap();
+        assert_eq!(read_back.packages, config.packages);
+        assert_eq!(read_back.msvcup.out_dir.as_deref(), Some("autoenv-x64"));
+        let lock_file = std::fs::read_to_string(dir.join(LOCK_FILE_NAME)).unwrap();
+        assert!(lock_file.contains("tools44.vsix"), "{}", lock_file);
+        assert!(!lock_file.contains("tools43.vsix"), "{}", lock_file);
+
+        let older = pick(&VersionSpec::Prefix("14.43".to_string()));
+        let err = init_project(&dir, &pkgs, &older, Arch::X64, false).unwrap_err();
+        assert!(err.to_string().contains("pass --force"), "{}", err);
+        // With --force, a failed resolution keeps the existing files
+        let config_content = std::fs::read_to_string(dir.join(CONFIG_FILE_NAME)).unwrap();
+        assert!(init_project(&dir, &pkgs, &missing, Arch::X64, true).is_err());
+        assert_eq!(
+            std::fs::read_to_string(dir.join(CONFIG_FILE_NAME)).unwrap(),
+            config_content
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join(LOCK_FILE_NAME)).unwrap(),
+            lock_file
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        let config = init_project(&dir, &pkgs, &older, Arch::X64, true).unwrap();
+        assert_eq!(config.packages["msvc"], "14.43.17.13");
+    }
+}
@@ -1,16 +1,32 @@
 use crate::arch::Arch;
+use crate::cache_index::CacheIndex;
+use crate::channel_kind::ChannelKind;
+use crate::disk_space;
+use crate::error::{ErrorKind, bail_kind};
+use crate::file_exclusions::{FileExclusions, SKIP_PREFIX, SkippedFiles};
+use crate::install_journal::InstallJournal;
 use crate::lock_file::LockFile;
 use crate::lockfile_parse::{
-    CabEntry, LockFileJson, LockFilePackage, LockFilePayloadEntry, check_lock_file_pkgs,
-    parse_lock_file,
+    CabEntry, LOCK_FILE_VERSION, LockFileJson, LockFilePackage, LockFilePayloadEntry,
+    check_lock_file_pkgs, check_lock_file_version, lock_file_age_seconds, merge_lock_files,
+    parse_lock_file, write_lock_file,
 };
-use crate::manifest::{MsvcupDir, fetch};
+use crate::manifest::{InFlightDownloads, MsvcupDir, fetch};
 use crate::packages::{
-    InstallPkgKind, LockFileUrlKind, ManifestUpdate, MsvcupPackage, MsvcupPackageKind, Packages,
-    PayloadId, get_install_pkg, get_lock_file_url_kind, get_packages, identify_payload,
+    InstallPkgKind, Language, LockFileUrlKind, ManifestUpdate, MsvcupPackage, MsvcupPackageKind,
+    Packages, PayloadExclusions, PayloadId, get_lock_file_url_kind, identify_payload,
+    is_debug_payload, is_optional_payload,
 };
+use crate::payload_filter::PayloadFilter;
 use crate::sha::Sha256;
-use crate::util::{basename_from_url, insert_sorted};
+use crate::tar_extract::{self, TarKind};
+use crate::telemetry::InstallStats;
+use crate::util::{
+    basename_from_url, basename_without_extension, build_sorted, extension_from_url, is_inside,
+    order_dotted_numeric, order_numeric, remove_files_and_prune_dirs,
+    resolve_install_manifest_entry,
+};
+use crate::vcvars_template::{VcvarsTemplate, VcvarsValues};
 use crate::zip_extract::{self, ZipKind};
 use anyhow::{Context, Result, bail};
 use fs_err as fs;
@@ -31,20 +47,71 @@ fn max_concurrent_extractions() -> usize {
         .unwrap_or(4)
 }
 
+/// Install packages from one or more lock files. The lock files are merged with
+/// [`merge_lock_files`], so earlier ones take precedence. When they don't match
+/// `msvcup_pkgs`, they're updated from the VS manifest: by default only the first
+/// (primary) lock file is rewritten, with the packages the others don't provide;
+/// with `update_all` every lock file is rewritten with the packages it lists.
+/// With `locked` a lock file that doesn't match is an error instead.
 #[allow(clippy::too_many_arguments)]
 pub async fn install_command(
     client: &reqwest::Client,
     msvcup_dir: &MsvcupDir,
     msvcup_pkgs: &[MsvcupPackage],
-    lock_file_path: &str,
+    lock_file_paths: &[String],
+    locked: bool,
+    update_all: bool,
+    channel_kind: &ChannelKind,
     manifest_update: ManifestUpdate,
+    max_manifest_age: Option<u64>,
     cache_dir: Option<&str>,
+    only: &[MsvcupPackageKind],
+    payload_filter: Option<&PayloadFilter>,
+    file_exclusions: &FileExclusions,
+    strip_debug: bool,
+    transactional: bool,
+    keep_going: bool,
+    vcvars_path: bool,
+    vcvars_template: Option<&VcvarsTemplate>,
+    check_disk_space: bool,
+    retry_corrupt: u32,
+    languages: &[Language],
     target_arch: Arch,
+    exclusions: PayloadExclusions,
+    stats: &InstallStats,
     mp: &MultiProgress,
 ) -> Result<()> {
     if msvcup_pkgs.is_empty() {
-        bail!("no packages were given to install, use 'list' to list the available packages");
+        bail_kind!(
+            ErrorKind::Usage,
+            "no packages were given to install, use 'list' to list the available packages"
+        );
+    }
+    if let Some(kind) = only
+        .iter()
+        .find(|kind| !msvcup_pkgs.iter().any(|p| p.kind == **kind))
+    {
+        bail_kind!(
+            ErrorKind::Usage,
+            "--only {} doesn't match any of the given packages",
+            kind
+        );
     }
+    if let Some(template) = vcvars_template {
+        let finish_kinds: Vec<FinishKind> = msvcup_pkgs
+            .iter()
+            .filter_map(|p| FinishKind::from_package_kind(p.kind))
+            .collect();
+        template
+            .check(&finish_kinds)
+            .context("checking the vcvars template")?;
+    }
+    let Some(primary_lock_file) = lock_file_paths.first() else {
+        bail_kind!(
+            ErrorKind::Usage,
+            "no lock file was given, pass --lock-file or add an msvcup.toml"
+        );
+    };
 
     let cache_dir = cache_dir
         .map(PathBuf::from)
@@ -53,53 +120,111 @@ pub async fn install_command(
 
     let try_no_update = match manifest_update {
         ManifestUpdate::Off | ManifestUpdate::Daily => true,
-        ManifestUpdate::Always => false,
+        // A lock file that was just written is as current as the manifest
+        ManifestUpdate::Always => max_manifest_age.is_some_and(|max_age| {
+            lock_file_age_seconds(primary_lock_file).is_some_and(|age| {
+                log::debug!(
+                    "lock file '{}' was written {}s ago (--max-manifest-age {}s)",
+                    primary_lock_file,
+                    age,
+                    max_age
+                );
+                age <= max_age
+            })
+        }),
     };
 
-    if try_no_update {
-        if let Ok(content) = fs::read_to_string(lock_file_path) {
-            log::debug!("lock file found: '{}'", lock_file_path);
-            if let Some(mismatch) = check_lock_file_pkgs(lock_file_path, &content, msvcup_pkgs) {
-                log::debug!("{}", mismatch);
-            } else {
-                install_from_lock_file(
-                    client,
-                    msvcup_pkgs,
-                    msvcup_dir,
-                    cache_dir_str,
-                    lock_file_path,
-                    &content,
-                    mp,
-                )
-                .await?;
-                return Ok(());
+    if (try_no_update || locked)
+        && let Some(contents) = read_lock_files(lock_file_paths)?
+    {
+        let content = merged_lock_file_content(lock_file_paths, &contents)?;
+        if let Some(mismatch) =
+            lock_files_mismatch(lock_file_paths, &contents, &content, msvcup_pkgs)
+        {
+            if locked {
+                bail_kind!(
+                    ErrorKind::LockFileMismatch,
+                    "lock file '{}' needs updating but --locked was given: {}",
+                    lock_file_paths.join("' + '"),
+                    mismatch
+                );
             }
+            log::debug!("{}", mismatch);
         } else {
-            log::debug!("lock file NOT found: '{}'", lock_file_path);
+            return install_from_lock_file(
+                client,
+                msvcup_pkgs,
+                msvcup_dir,
+                cache_dir_str,
+                primary_lock_file,
+                &content,
+                only,
+                payload_filter,
+                file_exclusions,
+                strip_debug,
+                transactional,
+                keep_going,
+                vcvars_path,
+                vcvars_template,
+                check_disk_space,
+                retry_corrupt,
+                stats,
+                mp,
+            )
+            .await;
         }
     }
 
-    // Read VS manifest and update lock file
-    let (vsman_path, vsman_content) = crate::manifest::read_vs_manifest(
-        client,
-        msvcup_dir,
-        crate::channel_kind::ChannelKind::Release,
-        ManifestUpdate::Off,
-    )
-    .await?;
+    if locked {
+        bail_kind!(
+            ErrorKind::LockFileMismatch,
+            "lock file '{}' doesn't exist but --locked was given",
+            lock_file_paths.join("' + '")
+        );
+    }
 
-    let pkgs = get_packages(vsman_path.to_str().unwrap(), &vsman_content)?;
+    // Read VS manifest and update lock file(s)
+    let pkgs =
+        crate::manifest::read_vs_manifest(client, msvcup_dir, channel_kind, ManifestUpdate::Off)
+            .await?;
+    if *channel_kind == ChannelKind::Release {
+        check_preview_only(client, msvcup_dir, &pkgs, msvcup_pkgs).await?;
+    }
 
-    update_lock_file(msvcup_pkgs, lock_file_path, &pkgs, target_arch)?;
+    for (lock_file_path, lock_file_pkgs) in
+        lock_file_assignments(lock_file_paths, msvcup_pkgs, update_all)
+    {
+        update_lock_file(
+            &lock_file_pkgs,
+            lock_file_path,
+            &pkgs,
+            languages,
+            target_arch,
+            exclusions,
+        )?;
+    }
 
-    let lock_file_content = fs::read_to_string(lock_file_path)
-        .with_context(|| format!("reading lock file '{}' after update", lock_file_path))?;
+    let contents = read_lock_files(lock_file_paths)?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "lock files '{}' are missing after update",
+            lock_file_paths.join("', '")
+        )
+    })?;
+    let lock_file_content = merged_lock_file_content(lock_file_paths, &contents)?;
 
-    if let Some(mismatch) = check_lock_file_pkgs(lock_file_path, &lock_file_content, msvcup_pkgs) {
-        bail!(
-            "lock file '{}' still doesn't match after update: {}",
-            lock_file_path,
-            mismatch
+    if let Some(mismatch) =
+        lock_files_mismatch(lock_file_paths, &contents, &lock_file_content, msvcup_pkgs)
+    {
+        bail_kind!(
+            ErrorKind::LockFileMismatch,
+            "lock file '{}' still doesn't match after update: {}{}",
+            lock_file_paths.join("' + '"),
+            mismatch,
+            if update_all || lock_file_paths.len() == 1 {
+                ""
+            } else {
+                ", use --update-all to update the other lock files too"
+            }
         );
     }
 
@@ -108,13 +233,233 @@ pub async fn install_command(
         msvcup_pkgs,
         msvcup_dir,
         cache_dir_str,
-        lock_file_path,
+        primary_lock_file,
         &lock_file_content,
+        only,
+        payload_filter,
+        file_exclusions,
+        strip_debug,
+        transactional,
+        keep_going,
+        vcvars_path,
+        vcvars_template,
+        check_disk_space,
+        retry_corrupt,
+        stats,
         mp,
     )
     .await
 }
 
+/// The bytes installing `install_entries` will download into the cache (the
+/// payloads and, with MSIs among them, the cabs that aren't cached yet) and
+/// extract, assuming a 2x compression ratio like
+/// [`Packages::total_installed_size`](crate::packages::Packages::total_installed_size).
+/// Payloads from lock files without sizes aren't counted.
+fn estimate_install_size(
+    install_entries: &[(MsvcupPackage, String, Sha256, Option<u64>)],
+    cabs: &HashMap<String, CabEntry>,
+    cab_info: &HashMap<String, (String, Sha256, Option<u64>)>,
+    cache_index: &CacheIndex,
+) -> (u64, u64) {
+    let has_msi = install_entries
+        .iter()
+        .any(|(_, url, _, _)| get_lock_file_url_kind(url) == Some(LockFileUrlKind::Msi));
+    let payloads = install_entries
+        .iter()
+        .map(|(_, url, sha256, size)| (basename_from_url(url), sha256, *size));
+    let cab_payloads = cabs
+        .iter()
+        .filter(|_| has_msi)
+        .filter_map(|(name, cab)| Some((name.as_str(), &cab_info.get(name)?.1, cab.size)));
+
+    let (mut download_size, mut install_size, mut unknown) = (0, 0, 0usize);
+    for (name, sha256, size) in payloads.chain(cab_payloads) {
+        let Some(size) = size else {
+            unknown += 1;
+            continue;
+        };
+        if !cache_index.contains(sha256, name) {
+            download_size += size;
+        }
+        install_size += size * 2;
+    }
+    if unknown > 0 {
+        log::debug!(
+            "{} payload(s) have no size in the lock file, not counted for the disk space check",
+            unknown
+        );
+    }
+    (download_size, install_size)
+}
+
+/// Read every lock file, checking that msvcup understands its version. Returns
+/// None if any of them doesn't exist yet.
+fn read_lock_files(lock_file_paths: &[String]) -> Result<Option<Vec<String>>> {
+    let mut contents = Vec::new();
+    for lock_file_path in lock_file_paths {
+        match fs::read_to_string(lock_file_path) {
+            Ok(content) => {
+                log::debug!("lock file found: '{}'", lock_file_path);
+                check_lock_file_version(lock_file_path, &content)?;
+                contents.push(content);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::debug!("lock file NOT found: '{}'", lock_file_path);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(contents))
+}
+
+/// The content to install from: a single lock file as-is, several merged.
+fn merged_lock_file_content(lock_file_paths: &[String], contents: &[String]) -> Result<String> {
+    if let [content] = contents {
+        return Ok(content.clone());
+    }
+    let lock_files: Vec<(&str, &str)> = lock_file_paths
+        .iter()
+        .map(String::as_str)
+        .zip(contents.iter().map(String::as_str))
+        .collect();
+    Ok(serde_json::to_string_pretty(&merge_lock_files(
+        &lock_files,
+    )?)?)
+}
+
+/// Why the lock files don't match `msvcup_pkgs`, if they don't. Each package has
+/// to be in one of them, but only the primary lock file, which an update
+/// rewrites, must not list others: a shared base lock file may have packages for
+/// other projects, which are left alone.
+fn lock_files_mismatch(
+    lock_file_paths: &[String],
+    contents: &[String],
+    merged_content: &str,
+    msvcup_pkgs: &[MsvcupPackage],
+) -> Option<String> {
+    let primary_lock_file = &lock_file_paths[0];
+    if let [content] = contents {
+        return check_lock_file_pkgs(primary_lock_file, content, msvcup_pkgs);
+    }
+    let merged = match parse_lock_file(primary_lock_file, merged_content) {
+        Ok(lock_file) => lock_file,
+        Err(e) => return Some(format!("parse error: {}", e)),
+    };
+    if let Some(msvcup_pkg) = msvcup_pkgs.iter().find(|p| {
+        let name = p.pool_string();
+        !merged.packages.iter().any(|lock_pkg| lock_pkg.name == name)
+    }) {
+        return Some(format!("lock files are missing package '{}'", msvcup_pkg));
+    }
+    let primary = match parse_lock_file(primary_lock_file, &contents[0]) {
+        Ok(lock_file) => lock_file,
+        Err(e) => return Some(format!("parse error: {}", e)),
+    };
+    primary
+        .packages
+        .iter()
+        .find(|lock_pkg| !msvcup_pkgs.iter().any(|p| p.pool_string() == lock_pkg.name))
+        .map(|lock_pkg| format!("lock file has extra package '{}'", lock_pkg.name))
+}
+
+/// Fail with the flag to pass if a package missing from the release manifest
+/// `pkgs` is listed in the preview one. The preview manifest is only read when
+/// a package is missing, and not being able to read it leaves the error to
+/// [`update_lock_file`].
+async fn check_preview_only(
+    client: &reqwest::Client,
+    msvcup_dir: &MsvcupDir,
+    pkgs: &Packages,
+    msvcup_pkgs: &[MsvcupPackage],
+) -> Result<()> {
+    let release = crate::list_cmd::list_packages(pkgs);
+    let missing: Vec<&MsvcupPackage> = msvcup_pkgs
+        .iter()
+        .filter(|p| !release.iter().any(|e| e.name == p.to_string()))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let preview = match crate::manifest::read_vs_manifest(
+        client,
+        msvcup_dir,
+        &ChannelKind::Preview,
+        ManifestUpdate::Off,
+    )
+    .await
+    {
+        Ok(preview) => crate::list_cmd::list_packages(&preview),
+        Err(e) => {
+            log::debug!("not checking the preview channel: {:#}", e);
+            return Ok(());
+        }
+    };
+    if let Some(msvcup_pkg) = missing
+        .iter()
+        .find(|p| preview.iter().any(|e| e.name == p.to_string()))
+    {
+        bail_kind!(
+            ErrorKind::Usage,
+            "package '{}' is only in the preview channel, pass --channel preview to install it",
+            msvcup_pkg
+        );
+    }
+    Ok(())
+}
+
+/// The lock files to rewrite during an update, with the packages each should list.
+/// A package stays in the first lock file that currently lists it, and packages
+/// no lock file lists go to the primary one. Without `update_all` only the
+/// primary lock file is rewritten, and the others that don't exist yet are
+/// created.
+fn lock_file_assignments<'a>(
+    lock_file_paths: &'a [String],
+    msvcup_pkgs: &[MsvcupPackage],
+    update_all: bool,
+) -> Vec<(&'a str, Vec<MsvcupPackage>)> {
+    let listed: Vec<Vec<String>> = lock_file_paths
+        .iter()
+        .map(|path| {
+            fs::read_to_string(path)
+                .ok()
+                .and_then(|content| parse_lock_file(path, &content).ok())
+                .map(|lock_file| lock_file.packages.into_iter().map(|p| p.name).collect())
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let mut assignments: Vec<(&str, Vec<MsvcupPackage>)> = lock_file_paths
+        .iter()
+        .map(|path| (path.as_str(), Vec::new()))
+        .collect();
+    for msvcup_pkg in msvcup_pkgs {
+        let name = msvcup_pkg.pool_string();
+        let index = listed
+            .iter()
+            .position(|names| names.contains(&name))
+            .unwrap_or(0);
+        assignments[index].1.push(msvcup_pkg.clone());
+    }
+    if !update_all {
+        let primary_lock_file = lock_file_paths[0].as_str();
+        assignments.retain(|(path, _)| *path == primary_lock_file || !Path::new(path).exists());
+    }
+    assignments
+}
+
+/// Install the payloads listed in the lock file. If `only` is non-empty, packages
+/// of other kinds are skipped entirely; the lock file is still used as-is. With a
+/// `payload_filter`, only the payloads it allows are installed, and the files
+/// `file_exclusions` matches are left out of them. Without `vcvars_path`, vcvars
+/// and env files that leave `PATH` alone are generated next to the default ones,
+/// and a `vcvars_template` adds vcvars scripts of its own. With `check_disk_space`,
+/// the cache and install volumes must have room for the estimated download and
+/// install size. With `keep_going`, a payload that fails to install is recorded
+/// in `stats` instead of failing the install, and the other payloads and
+/// packages are installed anyway.
+#[allow(clippy::too_many_arguments)]
 async fn install_from_lock_file(
     client: &reqwest::Client,
     msvcup_pkgs: &[MsvcupPackage],
@@ -122,12 +467,25 @@ async fn install_from_lock_file(
     cache_dir: &str,
     lock_file_path: &str,
     lock_file_content: &str,
+    only: &[MsvcupPackageKind],
+    payload_filter: Option<&PayloadFilter>,
+    file_exclusions: &FileExclusions,
+    strip_debug: bool,
+    transactional: bool,
+    keep_going: bool,
+    vcvars_path: bool,
+    vcvars_template: Option<&VcvarsTemplate>,
+    check_disk_space: bool,
+    retry_corrupt: u32,
+    stats: &InstallStats,
     mp: &MultiProgress,
 ) -> Result<()> {
+    let is_selected = |kind: MsvcupPackageKind| only.is_empty() || only.contains(&kind);
+
     let lock_file = parse_lock_file(lock_file_path, lock_file_content)?;
 
     // --- Build cab info lookup from lock file ---
-    let cab_info: HashMap<String, (String, Sha256)> = {
+    let cab_info: HashMap<String, (String, Sha256, Option<u64>)> = {
         let mut m = HashMap::new();
         for (cab_filename, cab_entry) in &lock_file.cabs {
             let sha256 = Sha256::parse_hex(&cab_entry.sha256).ok_or_else(|| {
@@ -137,17 +495,36 @@ async fn install_from_lock_file(
                     cab_entry.sha256
                 )
             })?;
-            m.insert(cab_filename.clone(), (cab_entry.url.clone(), sha256));
+            m.insert(
+                cab_filename.clone(),
+                (cab_entry.url.clone(), sha256, cab_entry.size),
+            );
         }
         m
     };
     let cab_info = std::sync::Arc::new(cab_info);
 
+    let journal = std::sync::Arc::new(InstallJournal::open(msvcup_dir, lock_file_content)?);
+    // The payload tasks' counters, added to `stats` once every task is done
+    let payload_stats = std::sync::Arc::new(InstallStats::default());
+
     // --- Collect install entries (payloads to download and extract) ---
-    let mut install_entries: Vec<(MsvcupPackage, String, Sha256)> = Vec::new();
+    let mut install_entries: Vec<(MsvcupPackage, String, Sha256, Option<u64>)> = Vec::new();
+    let mut outcomes: HashMap<MsvcupPackage, PackageOutcome> = HashMap::new();
+    let mut journaled_count = 0usize;
     for lock_pkg in &lock_file.packages {
         let msvcup_pkg = MsvcupPackage::from_string(&lock_pkg.name)
             .map_err(|e| anyhow::anyhow!("invalid package name '{}': {}", lock_pkg.name, e))?;
+        if !is_selected(msvcup_pkg.kind) {
+            log::debug!("{}: skipped (not selected by --only)", msvcup_pkg);
+            continue;
+        }
+        // A shared base lock file can list packages of other projects
+        if !msvcup_pkgs.contains(&msvcup_pkg) {
+            log::debug!("{}: skipped (not requested)", msvcup_pkg);
+            continue;
+        }
+        let outcome = outcomes.entry(msvcup_pkg.clone()).or_default();
 
         for entry in &lock_pkg.payloads {
             let sha256 = Sha256::parse_hex(&entry.sha256).ok_or_else(|| {
@@ -162,10 +539,45 @@ async fn install_from_lock_file(
             if let Some(arch) = crate::lockfile_parse::host_arch_limit(msvcup_pkg.kind, &entry.url)
                 && Arch::native() != Some(arch)
             {
+                outcome.arch_skipped += 1;
+                continue;
+            }
+
+            if payload_filter
+                .is_some_and(|filter| !filter.allows(&sha256, basename_from_url(&entry.url)))
+            {
+                log::debug!(
+                    "{}: skipped, not in payload filter",
+                    basename_from_url(&entry.url)
+                );
+                outcome.filtered += 1;
+                continue;
+            }
+            if strip_debug && is_debug_payload(basename_from_url(&entry.url)) {
+                log::debug!(
+                    "{}: skipped, debug symbols (--strip-debug)",
+                    basename_from_url(&entry.url)
+                );
+                outcome.filtered += 1;
+                continue;
+            }
+
+            let install_path = msvcup_dir.package_path(&msvcup_pkg);
+            if journal.is_installed(
+                &install_path,
+                &sha256,
+                basename_from_url(&entry.url),
+                file_exclusions,
+            ) {
+                outcome
+                    .installed
+                    .push(basename_from_url(&entry.url).to_string());
+                journaled_count += 1;
+                stats.record_already_installed();
                 continue;
             }
 
-            install_entries.push((msvcup_pkg.clone(), entry.url.clone(), sha256));
+            install_entries.push((msvcup_pkg.clone(), entry.url.clone(), sha256, entry.size));
         }
     }
 
@@ -176,7 +588,11 @@ async fn install_from_lock_file(
     // and then the MSI is extracted.
 
     let install_start = std::time::Instant::now();
-    log::debug!("{} payloads to install", install_entries.len());
+    log::debug!(
+        "{} payloads to install ({} skipped via install journal)",
+        install_entries.len(),
+        journaled_count
+    );
 
     let total = install_entries.len() as u64;
     let pb = mp.add(ProgressBar::new(total));
@@ -189,21 +605,62 @@ async fn install_from_lock_file(
     pb.set_prefix("Installing");
     pb.set_message("");
 
+    let cache_index = std::sync::Arc::new(if install_entries.is_empty() {
+        CacheIndex::default()
+    } else {
+        CacheIndex::scan(Path::new(cache_dir))?
+    });
+    if check_disk_space && !install_entries.is_empty() {
+        let (download_size, _) =
+            estimate_install_size(&install_entries, &lock_file.cabs, &cab_info, &cache_index);
+        let mut needs = vec![(Path::new(cache_dir), download_size)];
+        // Each --install-root needs room for the packages extracted into it
+        for (msvcup_pkg, ..) in &install_entries {
+            let root = msvcup_dir.package_root(msvcup_pkg.kind);
+            if needs[1..].iter().any(|(path, _)| *path == root) {
+                continue;
+            }
+            let entries: Vec<_> = install_entries
+                .iter()
+                .filter(|(pkg, ..)| msvcup_dir.package_root(pkg.kind) == root)
+                .cloned()
+                .collect();
+            let (_, install_size) =
+                estimate_install_size(&entries, &lock_file.cabs, &cab_info, &cache_index);
+            needs.push((root, install_size));
+        }
+        disk_space::check_available_space(&needs)?;
+    }
     let download_sem = std::sync::Arc::new(Semaphore::new(MAX_CONCURRENT_DOWNLOADS));
+    let in_flight = InFlightDownloads::default();
+    let file_exclusions = std::sync::Arc::new(file_exclusions.clone());
     let extract_sem = std::sync::Arc::new(Semaphore::new(max_concurrent_extractions()));
     let mut handles = Vec::new();
+    // (package, payload name, install path, cache path) of each handle
+    let mut attempted: Vec<(MsvcupPackage, String, PathBuf, PathBuf)> = Vec::new();
 
-    for (msvcup_pkg, url, sha256) in install_entries {
+    for (msvcup_pkg, url, sha256, size) in install_entries {
         let client = client.clone();
         let mp = mp.clone();
         let pb = pb.clone();
         let download_sem = download_sem.clone();
         let extract_sem = extract_sem.clone();
         let cab_info = cab_info.clone();
-        let install_path = msvcup_dir.path(&[&msvcup_pkg.pool_string()]);
+        let cache_index = cache_index.clone();
+        let journal = journal.clone();
+        let stats = payload_stats.clone();
+        let in_flight = in_flight.clone();
+        let file_exclusions = file_exclusions.clone();
+        let install_path = msvcup_dir.package_path(&msvcup_pkg);
         let cache_dir = cache_dir.to_string();
         let strip_root_dir = crate::lockfile_parse::strip_root_dir(msvcup_pkg.kind);
         let payload_name = basename_from_url(&url).to_string();
+        attempted.push((
+            msvcup_pkg.clone(),
+            payload_name.clone(),
+            install_path.clone(),
+            cache_entry_path(&cache_dir, &sha256, &payload_name),
+        ));
 
         handles.push(tokio::spawn(async move {
             let t_start = std::time::Instant::now();
@@ -213,7 +670,19 @@ async fn install_from_lock_file(
             // Step 1: Download the payload
             {
                 let _permit = download_sem.acquire().await.unwrap();
-                fetch_payload_async(&client, &sha256, &url, &cache_path, &mp).await?;
+                fetch_payload_async(
+                    &client,
+                    &sha256,
+                    size,
+                    &url,
+                    &cache_path,
+                    &cache_index,
+                    retry_corrupt,
+                    &mp,
+                    &stats,
+                    &in_flight,
+                )
+                .await?;
             }
             let t_download = t_start.elapsed();
             log::debug!("{}: downloaded in {:.1?}", payload_name, t_download);
@@ -241,25 +710,50 @@ async fn install_from_lock_file(
                 );
 
                 let mut cab_handles = Vec::new();
-                for (cab_url, cab_sha256) in needed {
+                for (cab_url, cab_sha256, cab_size) in needed {
                     let client = client.clone();
                     let mp = mp.clone();
                     let download_sem = download_sem.clone();
                     let cab_url = cab_url.clone();
                     let cab_sha256 = *cab_sha256;
+                    let cab_size = *cab_size;
                     let cache_dir = cache_dir.clone();
+                    let cache_index = cache_index.clone();
+                    let stats = stats.clone();
+                    let in_flight = in_flight.clone();
                     cab_handles.push(tokio::spawn(async move {
                         let _permit = download_sem.acquire().await.unwrap();
                         let cab_cache_name = basename_from_url(&cab_url);
                         let cab_cache_path =
                             cache_entry_path(&cache_dir, &cab_sha256, cab_cache_name);
-                        fetch_payload_async(&client, &cab_sha256, &cab_url, &cab_cache_path, &mp)
-                            .await
+                        fetch_payload_async(
+                            &client,
+                            &cab_sha256,
+                            cab_size,
+                            &cab_url,
+                            &cab_cache_path,
+                            &cache_index,
+                            retry_corrupt,
+                            &mp,
+                            &stats,
+                            &in_flight,
+                        )
+                        .await
                     }));
                 }
+                // Wait for every cab, even after one fails, so no detached
+                // download outlives this payload's task
+                let mut cabs_fetched = Ok(());
                 for h in cab_handles {
-                    h.await.unwrap()?;
+                    let fetched = h
+                        .await
+                        .context("the cab download task failed")
+                        .and_then(|fetched| fetched);
+                    if cabs_fetched.is_ok() {
+                        cabs_fetched = fetched;
+                    }
                 }
+                cabs_fetched?;
                 log::debug!(
                     "{}: CABs fetched in {:.1?}",
                     payload_name,
@@ -269,18 +763,22 @@ async fn install_from_lock_file(
 
             // Step 3: Extract
             let t_before_extract = std::time::Instant::now();
-            {
+            let new_files = {
                 let _permit = extract_sem.acquire().await.unwrap();
                 let t_extract_start = std::time::Instant::now();
-                tokio::task::spawn_blocking(move || {
-                    install_payload(
+                let new_files = tokio::task::spawn_blocking(move || {
+                    let new_files = install_payload(
                         &install_path,
                         &cache_dir,
                         &url,
                         &sha256,
                         strip_root_dir,
                         &cab_info,
-                    )
+                        &file_exclusions,
+                        &stats,
+                    )?;
+                    journal.record(&install_path, &sha256, basename_from_url(&url))?;
+                    Ok::<_, anyhow::Error>(new_files)
                 })
                 .await
                 .unwrap()
@@ -291,70 +789,267 @@ async fn install_from_lock_file(
                     t_extract_start.elapsed(),
                     t_before_extract.elapsed() - t_extract_start.elapsed()
                 );
-            }
+                new_files
+            };
 
             log::debug!("{}: total {:.1?}", payload_name, t_start.elapsed());
             pb.inc(1);
-            Ok::<(), anyhow::Error>(())
+            Ok::<_, anyhow::Error>((msvcup_pkg, payload_name, new_files))
         }));
     }
 
-    for handle in handles {
-        handle.await.unwrap()?;
+    // The files each payload added to its package this run, for a rollback
+    let mut new_files_written: Vec<Option<Vec<String>>> = vec![None; handles.len()];
+    let mut failure: Option<anyhow::Error> = None;
+    let mut failed_pkgs: Vec<MsvcupPackage> = Vec::new();
+    for (i, handle) in handles.into_iter().enumerate() {
+        // A panicking task fails its payload like an error would
+        let installed = handle
+            .await
+            .context("the payload install task failed")
+            .and_then(|installed| installed);
+        match installed {
+            Ok((msvcup_pkg, payload_name, new_files)) => {
+                if transactional {
+                    new_files_written[i] = new_files;
+                }
+                outcomes
+                    .entry(msvcup_pkg)
+                    .or_default()
+                    .installed
+                    .push(payload_name);
+            }
+            // Let the other payloads finish, a rollback can't race their extraction
+            Err(e) if transactional => {
+                let msvcup_pkg = &attempted[i].0;
+                if !failed_pkgs.contains(msvcup_pkg) {
+                    failed_pkgs.push(msvcup_pkg.clone());
+                }
+                failure.get_or_insert(e);
+            }
+            Err(e) if keep_going => {
+                let (msvcup_pkg, payload_name, ..) = &attempted[i];
+                let optional = is_optional_payload(payload_name);
+                log::error!(
+                    "{}: {} payload '{}' failed to install, continuing: {:#}",
+                    msvcup_pkg,
+                    if optional { "optional" } else { "essential" },
+                    payload_name,
+                    e
+                );
+                outcomes.entry(msvcup_pkg.clone()).or_default().failed += 1;
+                stats.record_failure(msvcup_pkg, payload_name, optional, e);
+            }
+            Err(e) => return Err(e),
+        }
     }
     pb.finish_and_clear();
+    stats.merge(&payload_stats);
+    if let Some(err) = failure {
+        // The journal would skip the rolled back payloads on the next install
+        journal.remove()?;
+        for msvcup_pkg in &failed_pkgs {
+            for (i, (_, _, install_path, cache_path)) in attempted
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, (pkg, ..))| pkg == msvcup_pkg)
+            {
+                roll_back_payload(install_path, cache_path, new_files_written[i].as_deref())
+                    .with_context(|| format!("{}: rolling back a failed install", msvcup_pkg))?;
+            }
+            log::warn!(
+                "{}: rolled back the payloads installed by this run",
+                msvcup_pkg
+            );
+        }
+        return Err(err);
+    }
     log::debug!("install completed in {:.1?}", install_start.elapsed());
 
+    let outcomes_failed: usize = outcomes.values().map(|outcome| outcome.failed).sum();
+
+    // A template rendered for one package can use the version of the other kind
+    let package_versions = match vcvars_template {
+        Some(_) => package_install_versions(msvcup_dir, msvcup_pkgs),
+        None => Vec::new(),
+    };
+
     // Finish packages (generate vcvars bat files and env JSON)
     for msvcup_pkg in msvcup_pkgs {
-        finish_package(msvcup_dir, msvcup_pkg)?;
+        if is_selected(msvcup_pkg.kind) {
+            let outcome = outcomes.remove(msvcup_pkg).unwrap_or_default();
+            if let Some(install_version) = finish_package(
+                msvcup_dir,
+                msvcup_pkg,
+                &outcome,
+                vcvars_path,
+                vcvars_template,
+                &package_versions,
+            )? {
+                stats.record_install_version(msvcup_pkg, &install_version);
+            }
+        }
+    }
+
+    // After failures the journal is kept so the next install only retries the
+    // failed payloads
+    if outcomes_failed == 0 {
+        journal.remove()?;
     }
 
     Ok(())
 }
 
+/// What happened to a package's payloads during [`install_from_lock_file`].
+#[derive(Debug, Default)]
+struct PackageOutcome {
+    /// Payloads installed by this run or a previous interrupted one
+    installed: Vec<String>,
+    /// Payloads skipped because they're for a different host architecture
+    arch_skipped: usize,
+    /// Payloads skipped because the `--payload-filter-file` doesn't list them or
+    /// `--strip-debug` left them out
+    filtered: usize,
+    /// Payloads that failed to install with `--keep-going`
+    failed: usize,
+}
+
+/// Download a payload into the cache unless it's there already. A download that
+/// arrives complete but with another sha256 is downloaded again up to
+/// `retry_corrupt` times, since caching proxies and flaky links sometimes hand
+/// out corrupted bytes that a clean request doesn't.
+#[allow(clippy::too_many_arguments)]
 async fn fetch_payload_async(
     client: &reqwest::Client,
     sha256: &Sha256,
+    size: Option<u64>,
     url_decoded: &str,
     cache_path: &Path,
+    cache_index: &CacheIndex,
+    retry_corrupt: u32,
     mp: &MultiProgress,
+    stats: &InstallStats,
+    in_flight: &InFlightDownloads,
 ) -> Result<()> {
-    let cache_lock_path = format!("{}.lock", cache_path.display());
-    let _cache_lock = LockFile::lock(&cache_lock_path)?;
+    let name = basename_from_url(url_decoded);
+    if cache_index.contains(sha256, name) {
+        log::debug!("ALREADY FETCHED  | {} {}", url_decoded, sha256);
+        stats.record_cache_hit(fs::metadata(cache_path).map_or(0, |m| m.len()));
+        return Ok(());
+    }
+
+    // Another task fetching the same URL (a cab shared by several MSIs) leaves
+    // it in the cache for this one
+    let _in_flight = in_flight.claim(url_decoded).await;
+
+    let mut cache_lock_path = cache_path.as_os_str().to_owned();
+    cache_lock_path.push(".lock");
+    let _cache_lock = LockFile::lock(Path::new(&cache_lock_path))?;
 
     if cache_path.exists() {
         log::debug!("ALREADY FETCHED  | {} {}", url_decoded, sha256);
+        stats.record_cache_hit(fs::metadata(cache_path).map_or(0, |m| m.len()));
     } else {
         log::debug!("FETCHING         | {} {}", url_decoded, sha256);
         let fetch_path = PathBuf::from(format!("{}.fetching", cache_path.display()));
-        let actual_sha256 = fetch(client, url_decoded, &fetch_path, Some(mp)).await?;
-        if actual_sha256 != *sha256 {
-            bail!(
-                "SHA256 mismatch for '{}':\nexpected: {}\nactual  : {}",
+        let mut retries = 0;
+        loop {
+            let actual_sha256 = fetch(client, url_decoded, &fetch_path, Some(mp)).await?;
+            let Err(e) = verify_download(&fetch_path, url_decoded, size, sha256, &actual_sha256)
+            else {
+                break;
+            };
+            let _ = fs::remove_file(&fetch_path);
+            let corrupt = e
+                .downcast_ref::<crate::error::Error>()
+                .is_some_and(|e| e.kind == ErrorKind::HashMismatch);
+            if !corrupt || retries == retry_corrupt {
+                return Err(e);
+            }
+            retries += 1;
+            log::warn!(
+                "{}: SHA256 mismatch (expected {}, got {}), downloading again ({}/{})",
                 url_decoded,
                 sha256,
-                actual_sha256
+                actual_sha256,
+                retries,
+                retry_corrupt
             );
         }
         fs::rename(&fetch_path, cache_path)?;
+        stats.record_download(fs::metadata(cache_path)?.len());
+    }
+    cache_index.insert(sha256, name);
+    Ok(())
+}
+
+/// Check a downloaded payload against the size and sha256 the lock file declares.
+/// The size is checked first: a download of the wrong size was usually cut short
+/// or rewritten by a proxy, while one of the right size with another hash is
+/// corrupted or not the file the lock file pinned. Lock files written by older
+/// versions have no sizes, so only the hash is checked for them.
+fn verify_download(
+    path: &Path,
+    url_decoded: &str,
+    expected_size: Option<u64>,
+    expected_sha256: &Sha256,
+    actual_sha256: &Sha256,
+) -> Result<()> {
+    if let Some(expected_size) = expected_size {
+        let actual_size = fs::metadata(path)?.len();
+        if actual_size != expected_size {
+            bail_kind!(
+                ErrorKind::Network,
+                "size mismatch for '{}':\nexpected: {} bytes\nactual  : {} bytes\n\
+                 the download was truncated or altered on the way (e.g. by a proxy), try again",
+                url_decoded,
+                expected_size,
+                actual_size
+            );
+        }
+    }
+    if actual_sha256 != expected_sha256 {
+        bail_kind!(
+            ErrorKind::HashMismatch,
+            "SHA256 mismatch for '{}':\nexpected: {}\nactual  : {}\n\
+             the download is corrupted or was substituted",
+            url_decoded,
+            expected_sha256,
+            actual_sha256
+        );
     }
     Ok(())
 }
 
-fn cache_entry_path(cache_dir: &str, sha256: &Sha256, name: &str) -> PathBuf {
-    let basename = format!("{}-{}", sha256, name);
-    PathBuf::from(cache_dir).join(basename)
+/// The file name of a payload in the cache.
+pub fn cache_entry_name(sha256: &Sha256, name: &str) -> String {
+    format!("{}-{}", sha256, name)
 }
 
+pub fn cache_entry_path(cache_dir: &str, sha256: &Sha256, name: &str) -> PathBuf {
+    PathBuf::from(cache_dir).join(cache_entry_name(sha256, name))
+}
+
+/// The `.files` manifest a payload gets in its package's install directory once
+/// it's installed.
+pub fn installed_manifest_path(install_dir_path: &Path, sha256: &Sha256, name: &str) -> PathBuf {
+    install_dir_path
+        .join("install")
+        .join(format!("{}.files", cache_entry_name(sha256, name)))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn install_payload(
     install_dir_path: &Path,
     cache_dir: &str,
     url_decoded: &str,
     sha256: &Sha256,
     strip_root_dir: bool,
-    cab_info: &HashMap<String, (String, Sha256)>,
-) -> Result<()> {
+    cab_info: &HashMap<String, (String, Sha256, Option<u64>)>,
+    exclusions: &FileExclusions,
+    stats: &InstallStats,
+) -> Result<Option<Vec<String>>> {
     let url_kind = get_lock_file_url_kind(url_decoded).ok_or_else(|| {
         anyhow::anyhow!(
             "unable to determine install kind from URL '{}'",
@@ -364,20 +1059,37 @@ fn install_payload(
 
     let cache_path = cache_entry_path(cache_dir, sha256, basename_from_url(url_decoded));
 
-    let installed_basename = format!(
-        "{}.files",
-        cache_path.file_name().unwrap().to_str().unwrap()
-    );
     let install_meta_dir = install_dir_path.join("install");
-    let installed_manifest_path = install_meta_dir.join(&installed_basename);
+    let installed_manifest_path =
+        installed_manifest_path(install_dir_path, sha256, basename_from_url(url_decoded));
 
     if installed_manifest_path.exists() {
-        log::debug!(
-            "ALREADY INSTALLED | {} {}",
-            basename_from_url(url_decoded),
-            sha256
-        );
-        return Ok(());
+        match excluded_file_now_wanted(&installed_manifest_path, exclusions)? {
+            Some(entry) => log::info!(
+                "{}: '{}' was left out by an earlier --exclude, installing again",
+                basename_from_url(url_decoded),
+                entry
+            ),
+            None => {
+                log::debug!(
+                    "ALREADY INSTALLED | {} {}",
+                    basename_from_url(url_decoded),
+                    sha256
+                );
+                stats.record_already_installed();
+                return Ok(None);
+            }
+        }
+    }
+
+    // Check an archive's entries before anything is written for it
+    let zip_kind = match url_kind {
+        LockFileUrlKind::Vsix => Some(ZipKind::Vsix),
+        LockFileUrlKind::Zip => Some(ZipKind::Zip),
+        _ => None,
+    };
+    if let Some(zip_kind) = zip_kind {
+        zip_extract::check_zip_entries(&cache_path, zip_kind, strip_root_dir)?;
     }
 
     fs::create_dir_all(install_dir_path)?;
@@ -385,10 +1097,10 @@ fn install_payload(
 
     // Use a per-payload temp manifest file to avoid races with the shared "current" file.
     // Each payload writes to its own unique temp file based on the hash.
-    let pending_path = install_meta_dir.join(format!("{}.pending", installed_basename));
+    let pending_path = installed_manifest_path.with_extension("files.pending");
 
     // Clean up any leftover pending file from a previous interrupted install
-    clean_up_pending(&pending_path)?;
+    clean_up_pending(install_dir_path, &pending_path)?;
 
     // Write install manifest
     let mut manifest_file = fs::File::create(&pending_path)?;
@@ -398,46 +1110,99 @@ fn install_payload(
         cache_path.file_name().unwrap().to_str().unwrap()
     )?;
 
-    match url_kind {
-        LockFileUrlKind::Vsix => {
-            zip_extract::extract_zip_to_dir(
-                &cache_path,
-                install_dir_path,
-                ZipKind::Vsix,
-                strip_root_dir,
-                &mut manifest_file,
-            )?;
-        }
-        LockFileUrlKind::Zip => {
-            zip_extract::extract_zip_to_dir(
-                &cache_path,
-                install_dir_path,
-                ZipKind::Zip,
-                strip_root_dir,
-                &mut manifest_file,
-            )?;
-        }
-        LockFileUrlKind::Msi => {
-            install_msi(
-                &cache_path,
-                install_dir_path,
-                cache_dir,
-                cab_info,
-                &mut manifest_file,
-            )?;
-        }
+    let skipped = match url_kind {
+        LockFileUrlKind::Vsix => zip_extract::extract_zip_to_dir(
+            &cache_path,
+            install_dir_path,
+            ZipKind::Vsix,
+            strip_root_dir,
+            exclusions,
+            &mut manifest_file,
+        )?,
+        LockFileUrlKind::Zip => zip_extract::extract_zip_to_dir(
+            &cache_path,
+            install_dir_path,
+            ZipKind::Zip,
+            strip_root_dir,
+            exclusions,
+            &mut manifest_file,
+        )?,
+        LockFileUrlKind::TarGz | LockFileUrlKind::TarXz => tar_extract::extract_tar_to_dir(
+            &cache_path,
+            install_dir_path,
+            if url_kind == LockFileUrlKind::TarGz {
+                TarKind::Gz
+            } else {
+                TarKind::Xz
+            },
+            strip_root_dir,
+            exclusions,
+            &mut manifest_file,
+        )?,
+        LockFileUrlKind::Msi => install_msi(
+            &cache_path,
+            install_dir_path,
+            cache_dir,
+            cab_info,
+            exclusions,
+            &mut manifest_file,
+        )?,
         LockFileUrlKind::Cab => unreachable!(),
-    }
+    };
 
     drop(manifest_file);
-    finalize_manifest(&installed_manifest_path, &pending_path)?;
+    let (new_files, added) = finalize_manifest(&installed_manifest_path, &pending_path)?;
+    stats.record_extracted(new_files.len() as u64, added);
+    stats.record_skipped(skipped);
 
-    Ok(())
+    Ok(Some(new_files))
+}
+
+/// Paths of every file recorded in the finalized `.files` manifests of a package.
+pub fn installed_files(install_dir_path: &Path) -> Result<Vec<PathBuf>> {
+    let install_meta_dir = install_dir_path.join("install");
+    let entries = match std::fs::read_dir(&install_meta_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("reading directory '{}'", install_meta_dir.display()));
+        }
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "files") {
+            continue;
+        }
+        let content = fs::read_to_string(&path)?;
+        files.extend(
+            content
+                .lines()
+                .filter(|l| !l.is_empty() && !l.starts_with(SKIP_PREFIX))
+                .map(|l| resolve_install_manifest_entry(install_dir_path, l)),
+        );
+    }
+    Ok(files)
+}
+
+/// The first file a `.files` manifest records as left out by `--exclude` that
+/// `exclusions` doesn't exclude, so the payload has to be installed again.
+pub fn excluded_file_now_wanted(
+    installed_manifest_path: &Path,
+    exclusions: &FileExclusions,
+) -> Result<Option<String>> {
+    let content = fs::read_to_string(installed_manifest_path)?;
+    Ok(content
+        .lines()
+        .filter_map(|l| l.strip_prefix(SKIP_PREFIX))
+        .find(|entry| !exclusions.excludes(entry))
+        .map(str::to_string))
 }
 
 /// Clean up a pending manifest from a previous interrupted install.
 /// Removes any files that were newly created by the interrupted payload.
-fn clean_up_pending(pending_path: &Path) -> Result<()> {
+fn clean_up_pending(install_dir_path: &Path, pending_path: &Path) -> Result<()> {
     if let Ok(content) = fs::read_to_string(pending_path) {
         log::debug!(
             "found interrupted install manifest '{}', cleaning up...",
@@ -445,16 +1210,29 @@ fn clean_up_pending(pending_path: &Path) -> Result<()> {
         );
         let mut lines = content.lines();
         let _cache_basename = lines.next(); // skip first line (cache basename)
+        let mut new_files = Vec::new();
         for line in lines {
             if line.is_empty() {
                 continue;
             }
             if let Some(sub_path) = line.strip_prefix("new ") {
-                log::debug!("removing file '{}'", sub_path);
-                let _ = fs::remove_file(sub_path);
+                let path = resolve_install_manifest_entry(install_dir_path, sub_path);
+                // Old absolute manifests may point into a root that has since
+                // moved, and a damaged line must not fail the whole cleanup
+                if !is_inside(&path, install_dir_path) {
+                    log::warn!(
+                        "not removing '{}', it is outside of '{}'",
+                        path.display(),
+                        install_dir_path.display()
+                    );
+                    continue;
+                }
+                log::debug!("removing file '{}'", path.display());
+                new_files.push(path);
             }
             // "add " lines: don't remove, file was added by another payload
         }
+        remove_files_and_prune_dirs(&new_files, install_dir_path)?;
         let _ = fs::remove_file(pending_path);
     }
     Ok(())
@@ -462,7 +1240,12 @@ fn clean_up_pending(pending_path: &Path) -> Result<()> {
 
 /// Finalize installation by converting the pending manifest into the installed manifest.
 /// Strips the cache basename header and the "new "/"add " prefixes, writing just the file paths.
-fn finalize_manifest(installed_manifest_path: &Path, pending_path: &Path) -> Result<()> {
+/// "skip " lines are kept as they are.
+/// Returns the "new " entries and the number of "add " entries.
+fn finalize_manifest(
+    installed_manifest_path: &Path,
+    pending_path: &Path,
+) -> Result<(Vec<String>, u64)> {
     let content = fs::read_to_string(pending_path).with_context(|| {
         format!(
             "reading pending install manifest '{}'",
@@ -470,6 +1253,7 @@ fn finalize_manifest(installed_manifest_path: &Path, pending_path: &Path) -> Res
         )
     })?;
 
+    let (mut new_files, mut added) = (Vec::new(), 0);
     let tmp_path = PathBuf::from(format!("{}.tmp", installed_manifest_path.display()));
     {
         let mut out = BufWriter::new(
@@ -484,8 +1268,12 @@ fn finalize_manifest(installed_manifest_path: &Path, pending_path: &Path) -> Res
             }
             if let Some(sub_path) = line.strip_prefix("new ") {
                 writeln!(out, "{}", sub_path)?;
+                new_files.push(sub_path.to_string());
             } else if let Some(sub_path) = line.strip_prefix("add ") {
                 writeln!(out, "{}", sub_path)?;
+                added += 1;
+            } else if line.starts_with(SKIP_PREFIX) {
+                writeln!(out, "{}", line)?;
             }
         }
         out.flush()?;
@@ -501,16 +1289,45 @@ fn finalize_manifest(installed_manifest_path: &Path, pending_path: &Path) -> Res
         )
     })?;
 
-    Ok(())
+    Ok((new_files, added))
+}
+
+/// Undo a payload this run installed, or started to install, into
+/// `install_dir_path`. A finalized manifest is turned back into a pending one
+/// holding the files the payload created (`new_files`), which
+/// [`clean_up_pending`] then removes. Files it added to are left to their
+/// earlier payload. If the rollback is interrupted, the next install of the
+/// payload finishes it the way it cleans up any interrupted payload.
+fn roll_back_payload(
+    install_dir_path: &Path,
+    cache_path: &Path,
+    new_files: Option<&[String]>,
+) -> Result<()> {
+    let cache_basename = cache_path.file_name().unwrap().to_str().unwrap();
+    let install_meta_dir = install_dir_path.join("install");
+    let installed_manifest_path = install_meta_dir.join(format!("{}.files", cache_basename));
+    let pending_path = install_meta_dir.join(format!("{}.files.pending", cache_basename));
+    if let Some(new_files) = new_files {
+        let tmp_path = PathBuf::from(format!("{}.tmp", pending_path.display()));
+        let mut content = format!("{}\n", cache_basename);
+        for sub_path in new_files {
+            content.push_str(&format!("new {}\n", sub_path));
+        }
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &pending_path)?;
+        fs::remove_file(&installed_manifest_path)?;
+    }
+    clean_up_pending(install_dir_path, &pending_path)
 }
 
 fn install_msi(
     msi_path: &Path,
     install_dir_path: &Path,
     cache_dir: &str,
-    cab_info: &HashMap<String, (String, Sha256)>,
+    cab_info: &HashMap<String, (String, Sha256, Option<u64>)>,
+    exclusions: &FileExclusions,
     manifest_file: &mut fs::File,
-) -> Result<()> {
+) -> Result<SkippedFiles> {
     let msi_name = msi_path.file_name().unwrap_or_default().to_string_lossy();
     log::debug!(
         "installing MSI '{}' from '{}'",
@@ -534,7 +1351,7 @@ fn install_msi(
     // Stage only the needed CAB files (already pre-fetched in Pass 2)
     // Use a unique staging dir per MSI to avoid races when multiple MSIs
     // for the same package are extracted in parallel.
-    let msi_stem = msi_path.file_stem().unwrap_or_default().to_string_lossy();
+    let msi_stem = basename_without_extension(&msi_name);
     let staging_dir = install_dir_path.join(format!(".msi-staging-{}", msi_stem));
     let _ = fs::remove_dir_all(&staging_dir);
     fs::create_dir_all(&staging_dir)?;
@@ -548,7 +1365,7 @@ fn install_msi(
             );
             continue;
         }
-        if let Some((url, sha256)) = cab_info.get(cab_name.as_str()) {
+        if let Some((url, sha256, _)) = cab_info.get(cab_name.as_str()) {
             let name = basename_from_url(url);
             let cab_cache_path = cache_entry_path(cache_dir, sha256, name);
             if !cab_cache_path.exists() {
@@ -578,95 +1395,565 @@ fn install_msi(
         msi_name
     );
 
-    crate::msi_extract::extract_msi(msi_path, install_dir_path, &staging_dir, manifest_file)
-        .with_context(|| format!("extracting MSI '{}'", msi_name))?;
+    let skipped = crate::msi_extract::extract_msi(
+        msi_path,
+        install_dir_path,
+        &staging_dir,
+        exclusions,
+        manifest_file,
+    )
+    .with_context(|| format!("extracting MSI '{}'", msi_name))?;
 
     let _ = fs::remove_dir_all(&staging_dir);
-    Ok(())
+    Ok(skipped)
+}
+
+/// The install versions of the msvc and sdk packages among `msvcup_pkgs` that
+/// are installed, also those this install didn't select with `--only`.
+fn package_install_versions(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+) -> Vec<(FinishKind, String)> {
+    msvcup_pkgs
+        .iter()
+        .filter_map(|msvcup_pkg| {
+            let finish_kind = FinishKind::from_package_kind(msvcup_pkg.kind)?;
+            let install_path = msvcup_dir.package_path(msvcup_pkg);
+            if !version_query_path(finish_kind, &install_path).is_dir() {
+                return None;
+            }
+            match query_install_version(finish_kind, &install_path, &msvcup_pkg.version) {
+                Ok(install_version) => Some((finish_kind, install_version)),
+                Err(e) => {
+                    log::debug!("{}: no install version: {:#}", msvcup_pkg, e);
+                    None
+                }
+            }
+        })
+        .collect()
 }
 
-fn finish_package(msvcup_dir: &MsvcupDir, msvcup_pkg: &MsvcupPackage) -> Result<()> {
-    let finish_kind = match msvcup_pkg.kind {
-        MsvcupPackageKind::Msvc => FinishKind::Msvc,
-        MsvcupPackageKind::Sdk => FinishKind::Sdk,
-        MsvcupPackageKind::Msbuild
-        | MsvcupPackageKind::Diasdk
-        | MsvcupPackageKind::Ninja
-        | MsvcupPackageKind::Cmake => return Ok(()),
+/// Generate a package's vcvars and env files. Returns the install version found
+/// in the install directory, if the package kind has one. A `vcvars_template`
+/// gets the version of the other package kind from `package_versions`.
+fn finish_package(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkg: &MsvcupPackage,
+    outcome: &PackageOutcome,
+    vcvars_path: bool,
+    vcvars_template: Option<&VcvarsTemplate>,
+    package_versions: &[(FinishKind, String)],
+) -> Result<Option<String>> {
+    if msvcup_pkg.kind == MsvcupPackageKind::Python {
+        finish_python(msvcup_dir, msvcup_pkg, outcome, vcvars_path)?;
+        return Ok(None);
+    }
+    let Some(finish_kind) = FinishKind::from_package_kind(msvcup_pkg.kind) else {
+        return Ok(None);
     };
 
-    let install_path = msvcup_dir.path(&[&msvcup_pkg.pool_string()]);
-    let install_version = query_install_version(finish_kind, &install_path)?;
+    if outcome.installed.is_empty() {
+        log::warn!(
+            "{}: no payloads were installed ({} skipped for another host architecture, \
+             {} filtered out, {} failed), not generating vcvars or env files",
+            msvcup_pkg,
+            outcome.arch_skipped,
+            outcome.filtered,
+            outcome.failed
+        );
+        return Ok(None);
+    }
+
+    let install_path = msvcup_dir.package_path(msvcup_pkg);
+    // Another process finishing the same package would otherwise race on the
+    // vcvars and env files.
+    let _install_lock = LockFile::lock(&install_path.join(".lock"))?;
+    let query_path = version_query_path(finish_kind, &install_path);
+    if !query_path.is_dir() && outcome.failed > 0 {
+        log::warn!(
+            "{}: '{}' does not exist, {} payload(s) failed to install, \
+             not generating vcvars or env files",
+            msvcup_pkg,
+            query_path.display(),
+            outcome.failed
+        );
+        return Ok(None);
+    }
+    if !query_path.is_dir() && outcome.filtered > 0 {
+        log::warn!(
+            "{}: '{}' does not exist, {} payload(s) were filtered out, \
+             not generating vcvars or env files",
+            msvcup_pkg,
+            query_path.display(),
+            outcome.filtered
+        );
+        return Ok(None);
+    }
+    if !query_path.is_dir() {
+        bail!(
+            "{}: expected directory '{}' does not exist after installing {} payload(s): {}\n\
+             the install may be incomplete, delete '{}' and install again",
+            msvcup_pkg,
+            query_path.display(),
+            outcome.installed.len(),
+            outcome.installed.join(", "),
+            install_path.display()
+        );
+    }
+    let install_version = query_install_version(finish_kind, &install_path, &msvcup_pkg.version)?;
     log::debug!("{} install version '{}'", msvcup_pkg, install_version);
 
-    // Generate vcvars bat files and env JSON files
-    fs::create_dir_all(&install_path)?;
-    for arch in Arch::ALL {
-        let bat = generate_vcvars_bat(finish_kind, &install_version, arch);
-        let basename = format!("vcvars-{}.bat", arch);
-        let bat_path = install_path.join(&basename);
-        crate::util::update_file(&bat_path, bat.as_bytes())?;
+    let native_arch = Arch::native().unwrap_or(Arch::X64);
+    let host_arch =
+        match select_host_arch(finish_kind, &install_version, &install_path, native_arch) {
+            Some(host_arch) => {
+                if host_arch != native_arch {
+                    log::info!(
+                        "{}: no {}-hosted tools installed, using {}-hosted tools",
+                        msvcup_pkg,
+                        native_arch,
+                        host_arch
+                    );
+                }
+                host_arch
+            }
+            None => {
+                log::warn!(
+                    "{}: no host tools found in '{}'",
+                    msvcup_pkg,
+                    host_bin_path(finish_kind, &install_version, &install_path, native_arch)
+                        .display()
+                );
+                native_arch
+            }
+        };
 
-        let env_json = generate_env_json(finish_kind, &install_version, arch, &install_path);
-        let json_basename = format!("env-{}.json", arch);
-        let json_path = install_path.join(&json_basename);
-        crate::util::update_file(&json_path, env_json.as_bytes())?;
+    // Generate vcvars bat files and env JSON files for each target arch that has libs
+    fs::create_dir_all(&install_path)?;
+    let target_archs = supported_target_archs(finish_kind, &install_version, &install_path);
+    if target_archs.is_empty() {
+        log::warn!("{}: no target architecture libraries found", msvcup_pkg);
     }
+    for arch in target_archs {
+        if finish_kind == FinishKind::Msvc {
+            let target_bin_path =
+                host_bin_path(finish_kind, &install_version, &install_path, host_arch)
+                    .join(arch.payload_arch().as_str());
+            if !target_bin_path.is_dir() {
+                log::warn!(
+                    "{}: '{}' does not exist, not generating vcvars for {}",
+                    msvcup_pkg,
+                    target_bin_path.display(),
+                    arch
+                );
+                continue;
+            }
+        }
 
-    Ok(())
-}
+        // Other projects may share the package directory, so the files that
+        // depend on this install's options get names of their own instead of
+        // replacing the default ones
+        let mut path_modes = vec![true];
+        if !vcvars_path {
+            path_modes.push(false);
+        }
+        for path_mode in path_modes {
+            let bat =
+                generate_vcvars_bat(finish_kind, &install_version, host_arch, arch, path_mode);
+            crate::util::update_file(
+                &install_path.join(vcvars_file_name(arch, None, path_mode)),
+                bat.as_bytes(),
+            )?;
 
-#[derive(Debug, Clone, Copy)]
-enum FinishKind {
-    Msvc,
+            let env_json = generate_env_json(
+                finish_kind,
+                &install_version,
+                host_arch,
+                arch,
+                &install_path,
+                path_mode,
+            );
+            crate::util::update_file(
+                &install_path.join(env_file_name(arch, path_mode)),
+                env_json.as_bytes(),
+            )?;
+        }
+        if let Some(template) = vcvars_template {
+            let version_of = |kind: FinishKind| {
+                if kind == finish_kind {
+                    return Some(install_version.as_str());
+                }
+                match package_versions
+                    .iter()
+                    .filter(|(k, _)| *k == kind)
+                    .collect::<Vec<_>>()
+                    .as_slice()
+                {
+                    [(_, version)] => Some(version.as_str()),
+                    _ => None,
+                }
+            };
+            let bat = template.render(&VcvarsValues {
+                install_path: &install_path,
+                install_version: &install_version,
+                msvc_version: version_of(FinishKind::Msvc),
+                sdk_version: version_of(FinishKind::Sdk),
+                host_arch,
+                target_arch: arch,
+                vcvars_path,
+            })?;
+            crate::util::update_file(
+                &install_path.join(vcvars_file_name(arch, Some(template.name()), vcvars_path)),
+                bat.as_bytes(),
+            )?;
+        }
+    }
+
+    Ok(Some(install_version))
+}
+
+/// Check that a python package holds `python.exe` and write a `vcvars.bat` that
+/// puts it and its `Scripts` directory on PATH. The embeddable distribution
+/// runs on any target, so unlike msvc and sdk there's one file for all archs.
+fn finish_python(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkg: &MsvcupPackage,
+    outcome: &PackageOutcome,
+    vcvars_path: bool,
+) -> Result<()> {
+    if outcome.installed.is_empty() {
+        log::warn!(
+            "{}: no payloads were installed ({} skipped for another host architecture, \
+             {} failed), not generating vcvars",
+            msvcup_pkg,
+            outcome.arch_skipped,
+            outcome.failed
+        );
+        return Ok(());
+    }
+    let install_path = msvcup_dir.package_path(msvcup_pkg);
+    let python_exe = install_path.join("python.exe");
+    if !python_exe.is_file() {
+        bail!(
+            "{}: '{}' does not exist after installing {}, expected the CPython \
+             embeddable zip\nthe install may be incomplete, delete '{}' and install again",
+            msvcup_pkg,
+            python_exe.display(),
+            outcome.installed.join(", "),
+            install_path.display()
+        );
+    }
+    let path_var = if vcvars_path { "PATH" } else { BIN_VAR };
+    let bat = format!(
+        "set \"{path}=%~dp0;%~dp0Scripts;%{path}%\"\n",
+        path = path_var
+    );
+    crate::util::update_file(&install_path.join("vcvars.bat"), bat.as_bytes())
+}
+
+/// Packages that get vcvars and env files once installed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishKind {
+    Msvc,
     Sdk,
 }
 
-fn query_install_version(finish_kind: FinishKind, install_path: &Path) -> Result<String> {
-    let query_path = match finish_kind {
+impl FinishKind {
+    pub fn from_package_kind(kind: MsvcupPackageKind) -> Option<Self> {
+        match kind {
+            MsvcupPackageKind::Msvc => Some(Self::Msvc),
+            MsvcupPackageKind::Sdk => Some(Self::Sdk),
+            MsvcupPackageKind::Msbuild
+            | MsvcupPackageKind::Diasdk
+            | MsvcupPackageKind::Ninja
+            | MsvcupPackageKind::Cmake
+            | MsvcupPackageKind::Nasm
+            | MsvcupPackageKind::Python => None,
+        }
+    }
+}
+
+/// Directory holding the tools for a host arch: `bin\Host{host}` for msvc,
+/// `bin\{version}\{host}` for the sdk.
+fn host_bin_path(
+    finish_kind: FinishKind,
+    install_version: &str,
+    install_path: &Path,
+    host_arch: Arch,
+) -> PathBuf {
+    match finish_kind {
+        FinishKind::Msvc => install_path
+            .join("VC")
+            .join("Tools")
+            .join("MSVC")
+            .join(install_version)
+            .join("bin")
+            .join(format!("Host{}", host_arch)),
+        FinishKind::Sdk => install_path
+            .join("Windows Kits")
+            .join("10")
+            .join("bin")
+            .join(install_version)
+            .join(host_arch.as_str()),
+    }
+}
+
+/// Pick the host tools to put on PATH, preferring the native arch, then x64, then x86.
+/// Returns None if none of them are installed.
+pub fn select_host_arch(
+    finish_kind: FinishKind,
+    install_version: &str,
+    install_path: &Path,
+    native_arch: Arch,
+) -> Option<Arch> {
+    [native_arch, Arch::X64, Arch::X86]
+        .into_iter()
+        .find(|&host_arch| {
+            host_bin_path(finish_kind, install_version, install_path, host_arch).is_dir()
+        })
+}
+
+/// Target archs whose library directory exists in an installed package.
+fn supported_target_archs(
+    finish_kind: FinishKind,
+    install_version: &str,
+    install_path: &Path,
+) -> Vec<Arch> {
+    Arch::ALL
+        .into_iter()
+        .filter(|arch| {
+            let lib_path = match finish_kind {
+                FinishKind::Msvc => install_path
+                    .join("VC")
+                    .join("Tools")
+                    .join("MSVC")
+                    .join(install_version)
+                    .join("lib")
+                    .join(arch.as_str()),
+                FinishKind::Sdk => install_path
+                    .join("Windows Kits")
+                    .join("10")
+                    .join("Lib")
+                    .join(install_version)
+                    .join("um")
+                    .join(arch.payload_arch().as_str()),
+            };
+            lib_path.is_dir()
+        })
+        .collect()
+}
+
+/// Look up the toolchain version installed in a package's pool directory, returning
+/// it along with its versioned subdirectory. Returns None for package kinds that
+/// don't have a versioned layout.
+pub fn query_package_version(
+    msvcup_pkg: &MsvcupPackage,
+    install_path: &Path,
+) -> Result<Option<(String, PathBuf)>> {
+    let Some(finish_kind) = FinishKind::from_package_kind(msvcup_pkg.kind) else {
+        return Ok(None);
+    };
+    let version = query_install_version(finish_kind, install_path, &msvcup_pkg.version)?;
+    let versioned_path = version_query_path(finish_kind, install_path).join(&version);
+    Ok(Some((version, versioned_path)))
+}
+
+fn version_query_path(finish_kind: FinishKind, install_path: &Path) -> PathBuf {
+    match finish_kind {
         FinishKind::Msvc => install_path.join("VC").join("Tools").join("MSVC"),
         FinishKind::Sdk => install_path.join("Windows Kits").join("10").join("Include"),
-    };
+    }
+}
 
-    let mut version_entry: Option<String> = None;
+/// Find the versioned toolset/SDK directory in a pool directory.
+///
+/// A pool holds one version: when its directory has more than one (e.g. the
+/// leftovers of a servicing update whose directory version differs), the one
+/// the installed `.files` manifests list files in is used, and files of several
+/// versions being installed is an error. Without manifests (packages installed
+/// by older versions), the candidate sharing the most leading dotted components
+/// with `package_version` wins (newest on a tie); if none share any, the newest
+/// is used with a warning.
+fn query_install_version(
+    finish_kind: FinishKind,
+    install_path: &Path,
+    package_version: &str,
+) -> Result<String> {
+    let query_path = version_query_path(finish_kind, install_path);
+
+    let mut candidates: Vec<String> = Vec::new();
     for entry in fs::read_dir(&query_path)
         .with_context(|| format!("reading directory '{}'", query_path.display()))?
     {
         let entry = entry?;
         let name = entry.file_name().to_string_lossy().to_string();
         if crate::util::is_valid_version(&name) {
-            if version_entry.is_some() {
-                bail!(
-                    "directory '{}' has multiple version entries",
-                    query_path.display()
+            candidates.push(name);
+        }
+    }
+    if candidates.len() > 1 {
+        log::info!(
+            "directory '{}' has multiple version entries: {}",
+            query_path.display(),
+            candidates.join(", ")
+        );
+        let installed = installed_versions(install_path, &query_path, &candidates)?;
+        match installed.as_slice() {
+            [] => {}
+            [version] => {
+                log::info!(
+                    "selected version '{}', the only one with installed files",
+                    version
                 );
+                return Ok(version.clone());
             }
-            version_entry = Some(name);
+            _ => bail!(
+                "'{}' has files of several versions installed ({}), but a package \
+                 directory holds one version, remove '{}' and install again",
+                query_path.display(),
+                installed.join(", "),
+                install_path.display()
+            ),
         }
     }
-    version_entry.ok_or_else(|| {
-        anyhow::anyhow!(
+
+    let Some((matched, version)) = select_install_version(&candidates, package_version) else {
+        bail!(
             "directory '{}' did not contain any version subdirectories",
             query_path.display()
-        )
-    })
+        );
+    };
+    if candidates.len() > 1 {
+        if matched {
+            log::info!("selected version '{}' for '{}'", version, package_version);
+        } else {
+            log::warn!(
+                "no version in '{}' matches '{}', using the newest '{}'",
+                query_path.display(),
+                package_version,
+                version
+            );
+        }
+    }
+    Ok(version.to_string())
+}
+
+/// The `candidates` (version directories under `query_path`) that the finalized
+/// `.files` manifests of the package list files in.
+fn installed_versions(
+    install_path: &Path,
+    query_path: &Path,
+    candidates: &[String],
+) -> Result<Vec<String>> {
+    let mut versions: Vec<String> = Vec::new();
+    for file in installed_files(install_path)? {
+        let Ok(rel) = file.strip_prefix(query_path) else {
+            continue;
+        };
+        let Some(std::path::Component::Normal(version)) = rel.components().next() else {
+            continue;
+        };
+        let version = version.to_string_lossy();
+        if candidates.iter().any(|c| *c == version) && !versions.iter().any(|v| *v == version) {
+            versions.push(version.into_owned());
+        }
+    }
+    versions.sort_by(|a, b| order_dotted_numeric(a, b));
+    Ok(versions)
+}
+
+/// Pick the candidate closest to `package_version`, see [`query_install_version`].
+/// Returns whether any leading component matched, along with the selected version.
+fn select_install_version<'a>(
+    candidates: &'a [String],
+    package_version: &str,
+) -> Option<(bool, &'a str)> {
+    let common_prefix_len = |candidate: &str| {
+        candidate
+            .split('.')
+            .zip(package_version.split('.'))
+            .take_while(|(c, p)| order_numeric(c, p) == Ordering::Equal)
+            .count()
+    };
+    candidates
+        .iter()
+        .map(|c| (common_prefix_len(c), c.as_str()))
+        .max_by(|(lhs_len, lhs), (rhs_len, rhs)| {
+            lhs_len
+                .cmp(rhs_len)
+                .then_with(|| order_dotted_numeric(lhs, rhs))
+        })
+        .map(|(len, version)| (len > 0, version))
+}
+
+/// MSVC `lib` subdirectories for a target arch, in LIB order. ARM64EC links
+/// against its own libs first, then falls back to the arm64 ones.
+fn msvc_lib_archs(target_arch: Arch) -> Vec<Arch> {
+    match target_arch {
+        Arch::Arm64Ec => vec![Arch::Arm64Ec, Arch::Arm64],
+        other => vec![other],
+    }
+}
+
+/// Environment variable that lists the tool directories instead of `PATH` when
+/// the vcvars and env files are generated with `install --no-vcvars-path`.
+pub const BIN_VAR: &str = "MSVCUP_BIN";
+
+/// The suffix of the vcvars and env files generated with `install --no-vcvars-path`.
+pub const NO_PATH_SUFFIX: &str = "-nopath";
+
+/// `vcvars-{arch}.bat`, with the name of the `--vcvars-template` it was rendered
+/// from and [`NO_PATH_SUFFIX`] without `vcvars_path`.
+pub fn vcvars_file_name(
+    target_arch: Arch,
+    template_name: Option<&str>,
+    vcvars_path: bool,
+) -> String {
+    format!(
+        "vcvars-{}{}{}.bat",
+        target_arch,
+        template_name
+            .map(|name| format!("-{}", name))
+            .unwrap_or_default(),
+        if vcvars_path { "" } else { NO_PATH_SUFFIX }
+    )
+}
+
+/// `env-{arch}.json`, with [`NO_PATH_SUFFIX`] without `vcvars_path`.
+pub fn env_file_name(target_arch: Arch, vcvars_path: bool) -> String {
+    format!(
+        "env-{}{}.json",
+        target_arch,
+        if vcvars_path { "" } else { NO_PATH_SUFFIX }
+    )
 }
 
 fn generate_vcvars_bat(
     finish_kind: FinishKind,
     install_version: &str,
+    host_arch: Arch,
     target_arch: Arch,
+    vcvars_path: bool,
 ) -> String {
-    let native_arch = Arch::native().unwrap_or(Arch::X64);
+    let payload_arch = target_arch.payload_arch();
+    let path_var = if vcvars_path { "PATH" } else { BIN_VAR };
     match finish_kind {
-        FinishKind::Msvc => format!(
-            "set \"INCLUDE=%~dp0VC\\Tools\\MSVC\\{v}\\include;%INCLUDE%\"\n\
-             set \"PATH=%~dp0VC\\Tools\\MSVC\\{v}\\bin\\Host{host}\\{target};%PATH%\"\n\
-             set \"LIB=%~dp0VC\\Tools\\MSVC\\{v}\\lib\\{target};%LIB%\"\n",
-            v = install_version,
-            host = native_arch,
-            target = target_arch,
-        ),
+        FinishKind::Msvc => {
+            let lib: String = msvc_lib_archs(target_arch)
+                .iter()
+                .map(|arch| format!("%~dp0VC\\Tools\\MSVC\\{}\\lib\\{};", install_version, arch))
+                .collect();
+            format!(
+                "set \"INCLUDE=%~dp0VC\\Tools\\MSVC\\{v}\\include;%INCLUDE%\"\n\
+                 set \"{path}=%~dp0VC\\Tools\\MSVC\\{v}\\bin\\Host{host}\\{target};%{path}%\"\n\
+                 set \"LIB={lib}%LIB%\"\n",
+                path = path_var,
+                v = install_version,
+                host = host_arch,
+                target = payload_arch,
+                lib = lib,
+            )
+        }
         FinishKind::Sdk => format!(
             "set \"INCLUDE=%~dp0Windows Kits\\10\\Include\\{v}\\ucrt;\
              %~dp0Windows Kits\\10\\Include\\{v}\\shared;\
@@ -674,25 +1961,50 @@ fn generate_vcvars_bat(
              %~dp0Windows Kits\\10\\Include\\{v}\\winrt;\
              %~dp0Windows Kits\\10\\Include\\{v}\\cppwinrt;\
              %INCLUDE%\"\n\
-             set \"PATH=%~dp0Windows Kits\\10\\bin\\{v}\\{host};%PATH%\"\n\
+             set \"{path}=%~dp0Windows Kits\\10\\bin\\{v}\\{host};%{path}%\"\n\
              set \"LIB=%~dp0Windows Kits\\10\\Lib\\{v}\\ucrt\\{target};\
              %~dp0Windows Kits\\10\\Lib\\{v}\\um\\{target};%LIB%\"\n",
+            path = path_var,
             v = install_version,
-            host = native_arch,
-            target = target_arch,
+            host = host_arch,
+            target = payload_arch,
         ),
     }
 }
 
 /// Generate a JSON file with resolved environment variable entries for a given arch.
 /// The JSON maps env var names to arrays of absolute path entries to prepend.
+/// Without `vcvars_path` the tool directories go to [`BIN_VAR`] instead of `PATH`.
 fn generate_env_json(
     finish_kind: FinishKind,
     install_version: &str,
+    host_arch: Arch,
     target_arch: Arch,
     install_path: &Path,
+    vcvars_path: bool,
 ) -> String {
-    let native_arch = Arch::native().unwrap_or(Arch::X64);
+    let mut env = env_entries(
+        finish_kind,
+        install_version,
+        host_arch,
+        target_arch,
+        install_path,
+    );
+    if !vcvars_path && let Some(bin) = env.remove("PATH") {
+        env.insert(BIN_VAR.to_string(), bin);
+    }
+    serde_json::to_string_pretty(&env).unwrap()
+}
+
+/// The absolute `INCLUDE`, `PATH` and `LIB` entries a package adds for a target arch.
+pub fn env_entries(
+    finish_kind: FinishKind,
+    install_version: &str,
+    host_arch: Arch,
+    target_arch: Arch,
+    install_path: &Path,
+) -> HashMap<String, Vec<String>> {
+    let payload_arch = target_arch.payload_arch();
     let root = install_path.to_string_lossy();
 
     let mut env: HashMap<String, Vec<String>> = HashMap::new();
@@ -710,15 +2022,20 @@ fn generate_env_json(
                 "PATH".to_string(),
                 vec![format!(
                     "{}\\VC\\Tools\\MSVC\\{}\\bin\\Host{}\\{}",
-                    root, install_version, native_arch, target_arch
+                    root, install_version, host_arch, payload_arch
                 )],
             );
             env.insert(
                 "LIB".to_string(),
-                vec![format!(
-                    "{}\\VC\\Tools\\MSVC\\{}\\lib\\{}",
-                    root, install_version, target_arch
-                )],
+                msvc_lib_archs(target_arch)
+                    .iter()
+                    .map(|arch| {
+                        format!(
+                            "{}\\VC\\Tools\\MSVC\\{}\\lib\\{}",
+                            root, install_version, arch
+                        )
+                    })
+                    .collect(),
             );
         }
         FinishKind::Sdk => {
@@ -751,7 +2068,7 @@ fn generate_env_json(
                 "PATH".to_string(),
                 vec![format!(
                     "{}\\Windows Kits\\10\\bin\\{}\\{}",
-                    root, install_version, native_arch
+                    root, install_version, host_arch
                 )],
             );
             env.insert(
@@ -759,44 +2076,46 @@ fn generate_env_json(
                 vec![
                     format!(
                         "{}\\Windows Kits\\10\\Lib\\{}\\ucrt\\{}",
-                        root, install_version, target_arch
+                        root, install_version, payload_arch
                     ),
                     format!(
                         "{}\\Windows Kits\\10\\Lib\\{}\\um\\{}",
-                        root, install_version, target_arch
+                        root, install_version, payload_arch
                     ),
                 ],
             );
         }
     }
 
-    serde_json::to_string_pretty(&env).unwrap()
+    env
 }
 
-pub fn update_lock_file(
+/// The payloads a lock file for `msvcup_pkgs` holds, as (package, payload
+/// index) sorted by package and index. Packages without any payload in the
+/// manifest are simply missing.
+pub fn select_payloads(
     msvcup_pkgs: &[MsvcupPackage],
-    lock_file_path: &str,
     pkgs: &Packages,
+    languages: &[Language],
     target_arch: Arch,
-) -> Result<()> {
+    exclusions: PayloadExclusions,
+) -> Vec<(MsvcupPackage, usize)> {
     let host_arch = Arch::native().unwrap_or(Arch::X64);
+    let install_index = pkgs.build_install_index(host_arch, target_arch);
     // Collect install payloads
     let mut install_payloads: Vec<(MsvcupPackage, usize)> = Vec::new(); // (target, payload_index)
 
-    for (pkg_index, pkg) in pkgs.packages.iter().enumerate() {
-        match pkg.language {
-            crate::packages::Language::Neutral | crate::packages::Language::EnUs => {}
-            crate::packages::Language::Other => continue,
-        }
-
+    for (pkg_index, pkg) in pkgs.filter_by_language(languages) {
         // Check if this package should be installed
-        if let Some(install_pkg) = get_install_pkg(&pkg.id, host_arch, target_arch) {
-            let (target_kind, target_version) = match &install_pkg {
+        if let Some(install_pkg) = install_index.get(pkg_index) {
+            let (target_kind, target_version) = match install_pkg {
                 InstallPkgKind::Msvc(v) => (MsvcupPackageKind::Msvc, v.as_str()),
                 InstallPkgKind::Msbuild(v) => (MsvcupPackageKind::Msbuild, v.as_str()),
                 InstallPkgKind::Diasdk => (MsvcupPackageKind::Diasdk, pkg.version.as_str()),
                 InstallPkgKind::Ninja(v) => (MsvcupPackageKind::Ninja, v.as_str()),
                 InstallPkgKind::Cmake(v) => (MsvcupPackageKind::Cmake, v.as_str()),
+                InstallPkgKind::Nasm(v) => (MsvcupPackageKind::Nasm, v.as_str()),
+                InstallPkgKind::Python(v) => (MsvcupPackageKind::Python, v.as_str()),
             };
 
             if let Some(msvcup_pkg) = msvcup_pkgs
@@ -805,12 +2124,16 @@ pub fn update_lock_file(
             {
                 let range = pkgs.payload_range_from_pkg_index(pkg_index);
                 for pi in range {
-                    insert_sorted(&mut install_payloads, (msvcup_pkg.clone(), pi), |a, b| {
-                        match MsvcupPackage::order(&a.0, &b.0) {
-                            Ordering::Equal => a.1.cmp(&b.1),
-                            other => other,
-                        }
-                    });
+                    if exclusions.excludes(&pkg.id, &pkgs.payloads[pi].file_name, target_arch) {
+                        log::debug!(
+                            "{}: leaving out '{}' of '{}'",
+                            msvcup_pkg,
+                            pkgs.payloads[pi].file_name,
+                            pkg.id
+                        );
+                        continue;
+                    }
+                    install_payloads.push((msvcup_pkg.clone(), pi));
                 }
             }
         }
@@ -824,37 +2147,31 @@ pub fn update_lock_file(
                     if msvcup_pkg.kind == MsvcupPackageKind::Sdk
                         && msvcup_pkg.version == pkg.version
                     {
-                        insert_sorted(&mut install_payloads, (msvcup_pkg.clone(), pi), |a, b| {
-                            match MsvcupPackage::order(&a.0, &b.0) {
-                                Ordering::Equal => a.1.cmp(&b.1),
-                                other => other,
-                            }
-                        });
+                        install_payloads.push((msvcup_pkg.clone(), pi));
                         break;
                     }
                 }
             }
         }
     }
-
-    // Verify every requested package has at least one payload
-    for msvcup_pkg in msvcup_pkgs {
-        let has_payload = install_payloads.iter().any(|(pkg, _)| pkg == msvcup_pkg);
-        if !has_payload {
-            bail!(
-                "package '{}' not found in the VS manifest. \
-                 Run 'msvcup list' to see available versions.",
-                msvcup_pkg
-            );
+    build_sorted(install_payloads, |a, b| {
+        match MsvcupPackage::order(&a.0, &b.0) {
+            Ordering::Equal => a.1.cmp(&b.1),
+            other => other,
         }
-    }
+    })
+}
 
-    // Collect unique cab payloads for MSI payloads from the VS manifest.
-    // Each VS manifest package lists MSIs and CABs as sibling payloads.
+/// The cabs the MSIs among `install_payloads` need, by cab file name. Each VS
+/// manifest package lists its MSIs and CABs as sibling payloads.
+pub fn select_cabs(
+    pkgs: &Packages,
+    install_payloads: &[(MsvcupPackage, usize)],
+) -> HashMap<String, CabEntry> {
     let mut cabs: HashMap<String, CabEntry> = HashMap::new();
     let mut seen_pkg_indices: std::collections::HashSet<usize> = std::collections::HashSet::new();
 
-    for (_, payload_index) in &install_payloads {
+    for (_, payload_index) in install_payloads {
         let payload = &pkgs.payloads[*payload_index];
         if get_lock_file_url_kind(&payload.url_decoded) != Some(LockFileUrlKind::Msi) {
             continue;
@@ -866,7 +2183,7 @@ pub fn update_lock_file(
         let pkg_payload_range = pkgs.payload_range_from_pkg_index(pkg_index);
         for pi in pkg_payload_range {
             let sibling = &pkgs.payloads[pi];
-            if sibling.file_name.ends_with(".cab") {
+            if extension_from_url(&sibling.file_name) == Some("cab") {
                 let cab_filename = sibling
                     .file_name
                     .rfind('\\')
@@ -876,10 +2193,38 @@ pub fn update_lock_file(
                     .or_insert_with(|| CabEntry {
                         url: sibling.url_decoded.clone(),
                         sha256: sibling.sha256.to_hex(),
+                        size: (sibling.size > 0).then_some(sibling.size),
                     });
             }
         }
     }
+    cabs
+}
+
+pub fn update_lock_file(
+    msvcup_pkgs: &[MsvcupPackage],
+    lock_file_path: &str,
+    pkgs: &Packages,
+    languages: &[Language],
+    target_arch: Arch,
+    exclusions: PayloadExclusions,
+) -> Result<()> {
+    let install_payloads = select_payloads(msvcup_pkgs, pkgs, languages, target_arch, exclusions);
+
+    // Verify every requested package has at least one payload
+    for msvcup_pkg in msvcup_pkgs {
+        let has_payload = install_payloads.iter().any(|(pkg, _)| pkg == msvcup_pkg);
+        if !has_payload {
+            bail_kind!(
+                ErrorKind::LockFileMismatch,
+                "package '{}' not found in the VS manifest. \
+                 Run 'msvcup list' to see available versions.",
+                msvcup_pkg
+            );
+        }
+    }
+
+    let cabs = select_cabs(pkgs, &install_payloads);
 
     // Build JSON packages list
     let mut json_packages: Vec<LockFilePackage> = Vec::new();
@@ -903,6 +2248,7 @@ pub fn update_lock_file(
         current_payloads.push(LockFilePayloadEntry {
             url: payload.url_decoded.clone(),
             sha256: payload.sha256.to_hex(),
+            size: (payload.size > 0).then_some(payload.size),
         });
     }
     if let Some(name) = current_pkg_name {
@@ -913,16 +2259,781 @@ pub fn update_lock_file(
     }
 
     let lock_file_json = LockFileJson {
+        version: LOCK_FILE_VERSION,
         cabs,
         packages: json_packages,
     };
 
-    log::debug!("{} payloads:", install_payloads.len());
-    if let Some(dir) = Path::new(lock_file_path).parent() {
-        fs::create_dir_all(dir)?;
+    let payload_indices: Vec<usize> = install_payloads.iter().map(|(_, pi)| *pi).collect();
+    log::debug!(
+        "{} payloads: {} bytes to download, ~{} bytes installed",
+        install_payloads.len(),
+        pkgs.total_download_size(&payload_indices),
+        pkgs.total_installed_size(&payload_indices)
+    );
+    write_lock_file(lock_file_path, &lock_file_json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{FixtureManifest, HELLO_SHA256, TempDir};
+
+    #[test]
+    fn vcvars_bat_msvc_x64() {
+        let bat = generate_vcvars_bat(FinishKind::Msvc, "14.43.34808", Arch::X64, Arch::X64, true);
+        assert_eq!(
+            bat,
+            "set \"INCLUDE=%~dp0VC\\Tools\\MSVC\\14.43.34808\\include;%INCLUDE%\"\n\
+             set \"PATH=%~dp0VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\x64;%PATH%\"\n\
+             set \"LIB=%~dp0VC\\Tools\\MSVC\\14.43.34808\\lib\\x64;%LIB%\"\n"
+        );
     }
-    let json_str = serde_json::to_string_pretty(&lock_file_json)?;
-    fs::write(lock_file_path, json_str)?;
 
-    Ok(())
+    #[test]
+    fn vcvars_bat_msvc_arm64ec() {
+        // Matches a VS arm64ec prompt: arm64-targeting tools, arm64ec libs before arm64
+        let bat = generate_vcvars_bat(
+            FinishKind::Msvc,
+            "14.43.34808",
+            Arch::X64,
+            Arch::Arm64Ec,
+            true,
+        );
+        assert_eq!(
+            bat,
+            "set \"INCLUDE=%~dp0VC\\Tools\\MSVC\\14.43.34808\\include;%INCLUDE%\"\n\
+             set \"PATH=%~dp0VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\arm64;%PATH%\"\n\
+             set \"LIB=%~dp0VC\\Tools\\MSVC\\14.43.34808\\lib\\arm64ec;\
+             %~dp0VC\\Tools\\MSVC\\14.43.34808\\lib\\arm64;%LIB%\"\n"
+        );
+    }
+
+    #[test]
+    fn vcvars_bat_sdk_arm64ec_uses_arm64_libs() {
+        let bat = generate_vcvars_bat(
+            FinishKind::Sdk,
+            "10.0.22621.0",
+            Arch::X64,
+            Arch::Arm64Ec,
+            true,
+        );
+        assert!(bat.contains("Lib\\10.0.22621.0\\ucrt\\arm64;"));
+        assert!(bat.contains("Lib\\10.0.22621.0\\um\\arm64;"));
+        assert!(!bat.contains("arm64ec"));
+    }
+
+    #[test]
+    fn env_json_msvc_arm64ec() {
+        let json = generate_env_json(
+            FinishKind::Msvc,
+            "14.43.34808",
+            Arch::X64,
+            Arch::Arm64Ec,
+            Path::new("C:\\msvcup\\msvc-14.43.34808"),
+            true,
+        );
+        let env: HashMap<String, Vec<String>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            env["LIB"],
+            vec![
+                "C:\\msvcup\\msvc-14.43.34808\\VC\\Tools\\MSVC\\14.43.34808\\lib\\arm64ec",
+                "C:\\msvcup\\msvc-14.43.34808\\VC\\Tools\\MSVC\\14.43.34808\\lib\\arm64",
+            ]
+        );
+        assert!(env["PATH"][0].ends_with("\\arm64"));
+    }
+
+    #[test]
+    fn vcvars_bat_uses_given_host() {
+        let bat = generate_vcvars_bat(FinishKind::Msvc, "14.43.34808", Arch::X86, Arch::X64, true);
+        assert!(bat.contains("bin\\Hostx86\\x64;%PATH%"));
+        let bat = generate_vcvars_bat(FinishKind::Sdk, "10.0.22621.0", Arch::X86, Arch::X64, true);
+        assert!(bat.contains("bin\\10.0.22621.0\\x86;%PATH%"));
+    }
+
+    #[test]
+    fn no_vcvars_path_leaves_path_alone() {
+        let bat = generate_vcvars_bat(FinishKind::Sdk, "10.0.22621.0", Arch::X64, Arch::X64, false);
+        assert!(!bat.contains("PATH"), "{}", bat);
+        assert!(bat.contains(
+            "set \"MSVCUP_BIN=%~dp0Windows Kits\\10\\bin\\10.0.22621.0\\x64;%MSVCUP_BIN%\""
+        ));
+        assert!(bat.contains("set \"INCLUDE="));
+        assert!(bat.contains("set \"LIB="));
+
+        let json = generate_env_json(
+            FinishKind::Msvc,
+            "14.43.34808",
+            Arch::X64,
+            Arch::X64,
+            Path::new("C:\\msvcup\\msvc-14.43.34808"),
+            false,
+        );
+        let env: HashMap<String, Vec<String>> = serde_json::from_str(&json).unwrap();
+        assert!(!env.contains_key("PATH"));
+        assert_eq!(
+            env[BIN_VAR],
+            ["C:\\msvcup\\msvc-14.43.34808\\VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\x64"]
+        );
+        assert_eq!(env.len(), 3);
+    }
+
+    fn make_host_dirs(root: &Path, hosts: &[&str]) {
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root).unwrap();
+        for host in hosts {
+            std::fs::create_dir_all(
+                root.join("VC/Tools/MSVC/14.43.34808/bin")
+                    .join(format!("Host{}", host))
+                    .join("x64"),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn select_host_arch_prefers_native() {
+        let root = TempDir::new("host_native");
+        make_host_dirs(&root, &["arm64", "x64", "x86"]);
+        assert_eq!(
+            select_host_arch(FinishKind::Msvc, "14.43.34808", &root, Arch::Arm64),
+            Some(Arch::Arm64)
+        );
+    }
+
+    #[test]
+    fn select_host_arch_falls_back_to_x64_then_x86() {
+        let root = TempDir::new("host_fallback");
+        make_host_dirs(&root, &["x64", "x86"]);
+        assert_eq!(
+            select_host_arch(FinishKind::Msvc, "14.43.34808", &root, Arch::Arm64),
+            Some(Arch::X64)
+        );
+        make_host_dirs(&root, &["x86"]);
+        assert_eq!(
+            select_host_arch(FinishKind::Msvc, "14.43.34808", &root, Arch::Arm64),
+            Some(Arch::X86)
+        );
+        make_host_dirs(&root, &[]);
+        assert_eq!(
+            select_host_arch(FinishKind::Msvc, "14.43.34808", &root, Arch::Arm64),
+            None
+        );
+    }
+
+    #[test]
+    fn query_install_version_picks_closest_of_multiple() {
+        let root = TempDir::new("multi_version");
+        let msvc_dir = root.join("VC").join("Tools").join("MSVC");
+        for v in ["14.43.34808", "14.44.35207", "14.44.35211"] {
+            std::fs::create_dir_all(msvc_dir.join(v)).unwrap();
+        }
+
+        // Closest by leading components, newest on a tie
+        assert_eq!(
+            query_install_version(FinishKind::Msvc, &root, "14.43.17.13").unwrap(),
+            "14.43.34808"
+        );
+        assert_eq!(
+            query_install_version(FinishKind::Msvc, &root, "14.44.17.14").unwrap(),
+            "14.44.35211"
+        );
+        // An exact match wins over newer entries
+        assert_eq!(
+            query_install_version(FinishKind::Msvc, &root, "14.44.35207").unwrap(),
+            "14.44.35207"
+        );
+        // Nothing in common falls back to the newest
+        assert_eq!(
+            query_install_version(FinishKind::Msvc, &root, "15.0").unwrap(),
+            "14.44.35211"
+        );
+    }
+
+    #[test]
+    fn query_install_version_uses_installed_files() {
+        let root = TempDir::new("installed_version");
+        let msvc_dir = root.join("VC").join("Tools").join("MSVC");
+        for v in ["14.44.35207", "14.44.35211"] {
+            std::fs::create_dir_all(msvc_dir.join(v).join("include")).unwrap();
+        }
+        let meta_dir = root.join("install");
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        let entry = |version: &str| {
+            crate::util::install_manifest_entry(
+                &root,
+                &msvc_dir.join(version).join("include").join("vector"),
+            )
+        };
+        // The older version's files are the installed ones, the newer is a leftover
+        std::fs::write(
+            meta_dir.join("a.vsix.files"),
+            format!("{}\n", entry("14.44.35207")),
+        )
+        .unwrap();
+        assert_eq!(
+            query_install_version(FinishKind::Msvc, &root, "14.44.17.14").unwrap(),
+            "14.44.35207"
+        );
+
+        std::fs::write(
+            meta_dir.join("b.vsix.files"),
+            format!("{}\n", entry("14.44.35211")),
+        )
+        .unwrap();
+        let err = query_install_version(FinishKind::Msvc, &root, "14.44.17.14").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("several versions installed (14.44.35207, 14.44.35211)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn select_install_version_empty() {
+        assert_eq!(select_install_version(&[], "14.43"), None);
+    }
+
+    #[test]
+    fn select_payloads_exclusions() {
+        let host = Arch::native().unwrap_or(Arch::X64);
+        let manifest = FixtureManifest::new()
+            .package(
+                &format!(
+                    "Microsoft.VC.14.43.17.13.Tools.Host{}.TargetX64.base",
+                    host.as_str().to_uppercase()
+                ),
+                "14.43.34808",
+                &[
+                    ("tools.vsix", "https://example.com/tools.vsix", 1),
+                    ("c2.pdb", "https://example.com/c2.pdb", 1),
+                ],
+            )
+            .package(
+                "Microsoft.VC.14.43.17.13.CRT.Redist.X64.base",
+                "14.43.34808",
+                &[("payload.vsix", "https://example.com/redist.vsix", 1)],
+            )
+            .build();
+        let pkgs = crate::packages::get_packages("fixture.json", &manifest).unwrap();
+        let msvc = [MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13")];
+        let file_names = |exclusions| -> Vec<String> {
+            select_payloads(
+                &msvc,
+                &pkgs,
+                crate::packages::DEFAULT_LANGUAGES,
+                Arch::X64,
+                exclusions,
+            )
+            .iter()
+            .map(|(_, pi)| pkgs.payloads[*pi].file_name.clone())
+            .collect()
+        };
+        assert_eq!(
+            file_names(PayloadExclusions::default()),
+            ["tools.vsix", "c2.pdb", "payload.vsix"]
+        );
+        assert_eq!(
+            file_names(PayloadExclusions {
+                debug_info: true,
+                redist: true,
+            }),
+            ["tools.vsix"]
+        );
+    }
+
+    #[test]
+    fn lock_file_assignments_keep_packages_in_place() {
+        let root = TempDir::new("lock_file_assignments");
+        let base = root.join("base.json").to_str().unwrap().to_string();
+        let project = root.join("project.json").to_str().unwrap().to_string();
+        std::fs::write(
+            &base,
+            r#"{"packages": [{"name": "sdk-10.0.22621.7", "payloads": []}]}"#,
+        )
+        .unwrap();
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13");
+        let pkgs = [msvc.clone(), sdk.clone()];
+        let paths = [project.clone(), base.clone()];
+
+        // The project lock file doesn't exist yet: it gets everything the base lacks
+        let assignments = lock_file_assignments(&paths, &pkgs, false);
+        assert_eq!(assignments, [(project.as_str(), vec![msvc.clone()])]);
+
+        let assignments = lock_file_assignments(&paths, &pkgs, true);
+        assert_eq!(
+            assignments,
+            [
+                (project.as_str(), vec![msvc.clone()]),
+                (base.as_str(), vec![sdk.clone()])
+            ]
+        );
+
+        // A single lock file gets every package
+        let assignments = lock_file_assignments(&paths[..1], &pkgs, false);
+        assert_eq!(assignments, [(project.as_str(), pkgs.to_vec())]);
+
+        // A secondary lock file that doesn't exist yet is created
+        let missing = root.join("missing.json").to_str().unwrap().to_string();
+        let paths = [project.clone(), base.clone(), missing.clone()];
+        let assignments = lock_file_assignments(&paths, &pkgs, false);
+        assert_eq!(
+            assignments,
+            [
+                (project.as_str(), vec![msvc.clone()]),
+                (missing.as_str(), Vec::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn lock_files_mismatch_ignores_other_projects_packages() {
+        let lock_json = |names: &[&str]| {
+            let packages: Vec<String> = names
+                .iter()
+                .map(|name| format!(r#"{{"name": "{}", "payloads": []}}"#, name))
+                .collect();
+            format!(r#"{{"packages": [{}]}}"#, packages.join(","))
+        };
+        let paths = ["project.json".to_string(), "base.json".to_string()];
+        let pkgs = [
+            MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13"),
+            MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7"),
+        ];
+        let mismatch = |project: &[&str], base: &[&str]| {
+            let contents = [lock_json(project), lock_json(base)];
+            let merged = merged_lock_file_content(&paths, &contents).unwrap();
+            lock_files_mismatch(&paths, &contents, &merged, &pkgs)
+        };
+
+        assert_eq!(
+            mismatch(&["msvc-14.43.17.13"], &["sdk-10.0.22621.7", "cmake-3.31.4"]),
+            None
+        );
+        assert_eq!(
+            mismatch(&["msvc-14.43.17.13", "cmake-3.31.4"], &["sdk-10.0.22621.7"]).unwrap(),
+            "lock file has extra package 'cmake-3.31.4'"
+        );
+        assert_eq!(
+            mismatch(&["msvc-14.43.17.13"], &["cmake-3.31.4"]).unwrap(),
+            "lock files are missing package 'sdk-10.0.22621.7'"
+        );
+        // A single lock file has to match exactly
+        let content = lock_json(&["msvc-14.43.17.13", "sdk-10.0.22621.7", "cmake-3.31.4"]);
+        assert!(
+            lock_files_mismatch(&paths[..1], std::slice::from_ref(&content), &content, &pkgs)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn finish_package_reports_skipped_and_partial_installs() {
+        let root = TempDir::new("finish_outcome");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13");
+
+        // Nothing installed: warn and skip instead of failing on a missing directory
+        let skipped = PackageOutcome {
+            installed: Vec::new(),
+            arch_skipped: 2,
+            filtered: 0,
+            failed: 0,
+        };
+        finish_package(&msvcup_dir, &pkg, &skipped, true, None, &[]).unwrap();
+
+        let partial = PackageOutcome {
+            installed: vec!["a.vsix".to_string(), "b.vsix".to_string()],
+            arch_skipped: 0,
+            filtered: 0,
+            failed: 0,
+        };
+        let err = finish_package(&msvcup_dir, &pkg, &partial, true, None, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("VC"), "{}", err);
+        assert!(err.contains("a.vsix, b.vsix"), "{}", err);
+        assert!(err.contains("install again"), "{}", err);
+        // The package lock is released even when finishing fails
+        let lock_path = root.join(pkg.pool_string()).join(".lock");
+        assert_eq!(std::fs::read_to_string(&lock_path).unwrap(), "");
+
+        // A filtered partial install is expected to be incomplete
+        let filtered = PackageOutcome {
+            installed: vec!["a.vsix".to_string()],
+            arch_skipped: 0,
+            filtered: 3,
+            failed: 0,
+        };
+        finish_package(&msvcup_dir, &pkg, &filtered, true, None, &[]).unwrap();
+
+        // So is one where payloads failed with --keep-going
+        let failed = PackageOutcome {
+            installed: vec!["a.vsix".to_string()],
+            arch_skipped: 0,
+            filtered: 0,
+            failed: 1,
+        };
+        finish_package(&msvcup_dir, &pkg, &failed, true, None, &[]).unwrap();
+    }
+
+    #[test]
+    fn finish_package_keeps_default_files_for_other_projects() {
+        let root = TempDir::new("finish_variants");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        let install_path = msvcup_dir.package_path(&pkg);
+        let kit = install_path.join("Windows Kits").join("10");
+        std::fs::create_dir_all(kit.join("Include").join("10.0.22621.0")).unwrap();
+        std::fs::create_dir_all(kit.join("Lib").join("10.0.22621.0").join("um").join("x64"))
+            .unwrap();
+        let template_path = root.join("bazel.bat.in");
+        std::fs::write(&template_path, "set {path_var}={version}\n").unwrap();
+        let template = VcvarsTemplate::read(&template_path).unwrap();
+        let outcome = PackageOutcome {
+            installed: vec!["sdk.msi".to_string()],
+            ..Default::default()
+        };
+        let read = |name: &str| std::fs::read_to_string(install_path.join(name)).unwrap();
+        let read_json =
+            |name: &str| serde_json::from_str::<serde_json::Value>(&read(name)).unwrap();
+
+        finish_package(&msvcup_dir, &pkg, &outcome, true, None, &[]).unwrap();
+        let (default_bat, default_env) = (read("vcvars-x64.bat"), read_json("env-x64.json"));
+        assert!(!install_path.join("vcvars-x64-nopath.bat").exists());
+
+        // Another project's options add files instead of replacing the default ones
+        finish_package(&msvcup_dir, &pkg, &outcome, false, Some(&template), &[]).unwrap();
+        assert_eq!(read("vcvars-x64.bat"), default_bat);
+        assert_eq!(read_json("env-x64.json"), default_env);
+        assert!(read("vcvars-x64-nopath.bat").contains(BIN_VAR));
+        assert!(read("env-x64-nopath.json").contains(BIN_VAR));
+        assert_eq!(
+            read("vcvars-x64-bazel-nopath.bat"),
+            "set MSVCUP_BIN=10.0.22621.0\n"
+        );
+
+        // The sdk package's script gets the version of the msvc package
+        std::fs::write(&template_path, "{msvc_version} {sdk_version}").unwrap();
+        let template = VcvarsTemplate::read(&template_path).unwrap();
+        let package_versions = [(FinishKind::Msvc, "14.43.34808".to_string())];
+        finish_package(
+            &msvcup_dir,
+            &pkg,
+            &outcome,
+            true,
+            Some(&template),
+            &package_versions,
+        )
+        .unwrap();
+        assert_eq!(read("vcvars-x64-bazel.bat"), "14.43.34808 10.0.22621.0");
+    }
+
+    #[test]
+    fn finish_python_checks_python_exe() {
+        let root = TempDir::new("finish_python");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Python, "3.13.1");
+        let install_path = root.join(pkg.pool_string());
+        std::fs::create_dir_all(&install_path).unwrap();
+        let outcome = PackageOutcome {
+            installed: vec!["python-3.13.1-embed-amd64.zip".to_string()],
+            arch_skipped: 0,
+            filtered: 0,
+            failed: 0,
+        };
+
+        let err = finish_package(&msvcup_dir, &pkg, &outcome, true, None, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("python.exe"), "{}", err);
+        assert!(!install_path.join("vcvars.bat").exists());
+
+        std::fs::write(install_path.join("python.exe"), "").unwrap();
+        finish_package(&msvcup_dir, &pkg, &outcome, true, None, &[]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(install_path.join("vcvars.bat")).unwrap(),
+            "set \"PATH=%~dp0;%~dp0Scripts;%PATH%\"\n"
+        );
+        finish_package(&msvcup_dir, &pkg, &outcome, false, None, &[]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(install_path.join("vcvars.bat")).unwrap(),
+            "set \"MSVCUP_BIN=%~dp0;%~dp0Scripts;%MSVCUP_BIN%\"\n"
+        );
+    }
+
+    /// Serve `bodies` to successive requests, repeating the last one.
+    fn serve_bodies(bodies: &'static [&'static str]) -> String {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let Ok(mut stream) = stream else { continue };
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf);
+                let body = bodies[i.min(bodies.len() - 1)];
+                let _ = stream.write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                );
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn fetch_payload_retries_corrupt_downloads() {
+        let cache_dir = TempDir::new("retry_corrupt");
+        let client = reqwest::Client::new();
+        let mp = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let stats = InstallStats::default();
+        let hello = Sha256::parse_hex(HELLO_SHA256).unwrap();
+        let cache_path = cache_entry_path(cache_dir.to_str().unwrap(), &hello, "a.vsix");
+        let fetch_payload = |url: String, retry_corrupt| {
+            let (client, mp, stats, cache_path) = (&client, &mp, &stats, &cache_path);
+            async move {
+                fetch_payload_async(
+                    client,
+                    &hello,
+                    Some(5),
+                    &url,
+                    cache_path,
+                    &CacheIndex::default(),
+                    retry_corrupt,
+                    mp,
+                    stats,
+                    &InFlightDownloads::default(),
+                )
+                .await
+            }
+        };
+
+        // Without retries the first corrupt download fails
+        let base = serve_bodies(&["jello", "hello"]);
+        let err = fetch_payload(format!("{}/a.vsix", base), 0)
+            .await
+            .unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 5);
+        assert!(!cache_path.exists());
+
+        let base = serve_bodies(&["jello", "hello"]);
+        fetch_payload(format!("{}/a.vsix", base), 1).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&cache_path).unwrap(), "hello");
+        std::fs::remove_file(&cache_path).unwrap();
+
+        // Retries run out
+        let base = serve_bodies(&["jello", "yello", "hello"]);
+        let err = fetch_payload(format!("{}/a.vsix", base), 1)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("SHA256 mismatch"), "{}", err);
+
+        // A truncated download isn't retried as corrupt
+        let base = serve_bodies(&["hell", "hello"]);
+        let err = fetch_payload(format!("{}/a.vsix", base), 3)
+            .await
+            .unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 4);
+    }
+
+    #[test]
+    fn clean_up_pending_handles_old_absolute_manifests() {
+        let root = TempDir::new("pending_absolute");
+        let install_dir = root.join("msvc-14.43.17.13");
+        std::fs::create_dir_all(install_dir.join("install")).unwrap();
+        let new_file = install_dir.join("new.txt");
+        let added_file = install_dir.join("added.txt");
+        std::fs::write(&new_file, "").unwrap();
+        std::fs::write(&added_file, "").unwrap();
+        let pending = install_dir.join("install").join("x.vsix.files.pending");
+        std::fs::write(
+            &pending,
+            format!(
+                "x.vsix\nnew {}\nadd {}\n",
+                new_file.display(),
+                added_file.display()
+            ),
+        )
+        .unwrap();
+
+        clean_up_pending(&install_dir, &pending).unwrap();
+        assert!(!new_file.exists());
+        assert!(added_file.exists());
+        assert!(!pending.exists());
+    }
+
+    #[test]
+    fn roll_back_payload_spares_added_files() {
+        let root = TempDir::new("roll_back_payload");
+        let install_dir = root.join("msvc-14.43.17.13");
+        let meta_dir = install_dir.join("install");
+        std::fs::create_dir_all(install_dir.join("VC")).unwrap();
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        let sep = std::path::MAIN_SEPARATOR;
+        std::fs::write(install_dir.join("VC").join("cl.exe"), "").unwrap();
+        std::fs::write(install_dir.join("shared.txt"), "").unwrap();
+        let manifest = meta_dir.join("abc-a.vsix.files");
+        std::fs::write(&manifest, format!("VC{sep}cl.exe\nshared.txt\n")).unwrap();
+        let cache_path = root.join("cache").join("abc-a.vsix");
+
+        // shared.txt was there before the payload ("add"), so it stays
+        let new_files = [format!("VC{sep}cl.exe")];
+        roll_back_payload(&install_dir, &cache_path, Some(&new_files)).unwrap();
+        assert!(!manifest.exists());
+        assert!(!meta_dir.join("abc-a.vsix.files.pending").exists());
+        assert!(!install_dir.join("VC").exists());
+        assert!(install_dir.join("shared.txt").exists());
+
+        // A payload that failed part way only has its pending manifest, and a
+        // line escaping the install directory is skipped, not fatal
+        std::fs::write(install_dir.join("partial.txt"), "").unwrap();
+        std::fs::write(root.join("outside.txt"), "").unwrap();
+        let pending = meta_dir.join("def-b.vsix.files.pending");
+        std::fs::write(
+            &pending,
+            format!("def-b.vsix\nnew ..{sep}outside.txt\nnew partial.txt\n"),
+        )
+        .unwrap();
+        roll_back_payload(&install_dir, &root.join("cache").join("def-b.vsix"), None).unwrap();
+        assert!(!install_dir.join("partial.txt").exists());
+        assert!(root.join("outside.txt").exists());
+        assert!(!pending.exists());
+    }
+
+    #[test]
+    fn excluded_files_are_recorded_and_installed_later() {
+        let root = TempDir::new("install_exclude");
+        let cache_dir = root.join("cache");
+        std::fs::create_dir_all(&cache_dir).unwrap();
+        let cache_dir_str = cache_dir.to_str().unwrap();
+        let install_dir = root.join("sdk-10.0.22621.7");
+        let url = "https://example.com/sdk.zip";
+        let sha256 = Sha256::parse_hex(&"a".repeat(64)).unwrap();
+        let mut zip = zip::ZipWriter::new(
+            std::fs::File::create(cache_entry_path(cache_dir_str, &sha256, "sdk.zip")).unwrap(),
+        );
+        for (name, content) in [("Lib/um/x64/a.lib", "x64"), ("Lib/um/arm64/a.lib", "arm")] {
+            zip.start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let arm64_lib = install_dir
+            .join("Lib")
+            .join("um")
+            .join("arm64")
+            .join("a.lib");
+        let install = |exclude: &[&str], stats: &InstallStats| {
+            let patterns: Vec<String> = exclude.iter().map(|p| p.to_string()).collect();
+            install_payload(
+                &install_dir,
+                cache_dir_str,
+                url,
+                &sha256,
+                false,
+                &HashMap::new(),
+                &FileExclusions::new(&patterns).unwrap(),
+                stats,
+            )
+            .unwrap()
+        };
+
+        let stats = InstallStats::default();
+        assert_eq!(install(&["*/arm64/*"], &stats).unwrap().len(), 1);
+        assert!(!arm64_lib.exists());
+        assert!(
+            stats
+                .summary()
+                .contains("files excluded:    1 file(s), 3 B\n")
+        );
+        assert_eq!(installed_files(&install_dir).unwrap().len(), 1);
+        let manifest = install_dir
+            .join("install")
+            .join(format!("{}-sdk.zip.files", sha256));
+        let sep = std::path::MAIN_SEPARATOR;
+        assert!(
+            std::fs::read_to_string(&manifest)
+                .unwrap()
+                .contains(&format!("skip Lib{sep}um{sep}arm64{sep}a.lib\n"))
+        );
+
+        // Still excluded: nothing to do. Not anymore: the payload is installed again.
+        assert!(install(&["*.lib"], &InstallStats::default()).is_none());
+        assert_eq!(install(&[], &InstallStats::default()).unwrap().len(), 1);
+        assert_eq!(std::fs::read_to_string(&arm64_lib).unwrap(), "arm");
+        assert_eq!(installed_files(&install_dir).unwrap().len(), 2);
+        assert!(install(&[], &InstallStats::default()).is_none());
+    }
+
+    #[test]
+    fn relative_manifests_survive_root_relocation() {
+        let root = TempDir::new("manifest_relocation");
+        let old_install_dir = root.join("old").join("msvc-14.43.17.13");
+        let new_install_dir = root.join("new").join("msvc-14.43.17.13");
+        let meta_dir = old_install_dir.join("install");
+        std::fs::create_dir_all(&meta_dir).unwrap();
+        let entry = crate::util::install_manifest_entry(
+            &old_install_dir,
+            &old_install_dir.join("VC").join("cl.exe"),
+        );
+        std::fs::create_dir_all(old_install_dir.join("VC")).unwrap();
+        std::fs::write(old_install_dir.join("VC").join("cl.exe"), "").unwrap();
+        std::fs::write(meta_dir.join("a.vsix.files"), format!("{}\n", entry)).unwrap();
+        std::fs::write(
+            meta_dir.join("b.vsix.files.pending"),
+            format!("b.vsix\nnew {}\n", entry),
+        )
+        .unwrap();
+
+        std::fs::create_dir_all(root.join("new")).unwrap();
+        std::fs::rename(&old_install_dir, &new_install_dir).unwrap();
+
+        assert_eq!(
+            installed_files(&new_install_dir).unwrap(),
+            [new_install_dir.join("VC").join("cl.exe")]
+        );
+        clean_up_pending(
+            &new_install_dir,
+            &new_install_dir.join("install").join("b.vsix.files.pending"),
+        )
+        .unwrap();
+        assert!(!new_install_dir.join("VC").join("cl.exe").exists());
+    }
+
+    #[test]
+    fn verify_download_tells_truncated_from_corrupted() {
+        let dir = TempDir::new("verify_download");
+        let path = dir.join("payload.vsix");
+        let url = "https://example.com/payload.vsix";
+        let hello = Sha256::parse_hex(HELLO_SHA256).unwrap();
+
+        std::fs::write(&path, "hello").unwrap();
+        verify_download(&path, url, Some(5), &hello, &hello).unwrap();
+        verify_download(&path, url, None, &hello, &hello).unwrap();
+
+        // Truncated
+        std::fs::write(&path, "hel").unwrap();
+        let truncated = Sha256::hash_file(&path).unwrap();
+        let err = verify_download(&path, url, Some(5), &hello, &truncated).unwrap_err();
+        assert!(err.to_string().starts_with("size mismatch"), "{}", err);
+        assert!(err.to_string().contains("expected: 5 bytes"), "{}", err);
+        assert_eq!(crate::error::exit_code(&err), 4);
+
+        // Corrupted: the right size but other content
+        std::fs::write(&path, "jello").unwrap();
+        let corrupted = Sha256::hash_file(&path).unwrap();
+        let err = verify_download(&path, url, Some(5), &hello, &corrupted).unwrap_err();
+        assert!(err.to_string().starts_with("SHA256 mismatch"), "{}", err);
+        assert_eq!(crate::error::exit_code(&err), 5);
+        // Without a declared size only the hash can fail
+        let err = verify_download(&path, url, None, &hello, &truncated).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 5);
+    }
 }
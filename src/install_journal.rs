@@ -0,0 +1,177 @@
+use crate::file_exclusions::FileExclusions;
+use crate::install::{excluded_file_now_wanted, installed_manifest_path};
+use crate::manifest::MsvcupDir;
+use crate::sha::Sha256;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Records which payloads of a lock file have been fully installed, so an install
+/// that crashed part way through can skip straight to the remaining work on restart.
+///
+/// The journal lives at `{msvcup_dir}/journal/{sha256 of lock file}` and contains one
+/// `{pool dir}/{cache basename}` line per installed payload. It is only a hint: the
+/// per-payload `.files` manifests remain the source of truth, entries are only trusted
+/// while the payload's manifest still exists, and the journal is removed once the
+/// install completes.
+pub struct InstallJournal {
+    path: PathBuf,
+    entries: HashSet<String>,
+    file: Mutex<Option<fs::File>>,
+}
+
+impl InstallJournal {
+    /// Open the journal for the lock file with the given content, loading any
+    /// entries left behind by a previous interrupted install.
+    pub fn open(msvcup_dir: &MsvcupDir, lock_file_content: &str) -> Result<Self> {
+        let path = Self::path_for(msvcup_dir, lock_file_content);
+        let entries = match fs::read_to_string(&path) {
+            Ok(content) => content
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(str::to_string)
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e).with_context(|| "reading install journal"),
+        };
+        if !entries.is_empty() {
+            log::debug!(
+                "{}: resuming, {} payload(s) already installed",
+                path.display(),
+                entries.len()
+            );
+        }
+        Ok(Self {
+            path,
+            entries,
+            file: Mutex::new(None),
+        })
+    }
+
+    fn path_for(msvcup_dir: &MsvcupDir, lock_file_content: &str) -> PathBuf {
+        let sha256 = Sha256::of_bytes(lock_file_content.as_bytes());
+        msvcup_dir.path(&["journal", &sha256.to_hex()])
+    }
+
+    fn key(install_path: &Path, sha256: &Sha256, name: &str) -> String {
+        let pool = install_path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+        format!("{}/{}-{}", pool, sha256, name)
+    }
+
+    /// Whether the journal says this payload was installed, its `.files` manifest
+    /// still exists and `exclusions` still leaves out the files it left out.
+    pub fn is_installed(
+        &self,
+        install_path: &Path,
+        sha256: &Sha256,
+        name: &str,
+        exclusions: &FileExclusions,
+    ) -> bool {
+        if !self
+            .entries
+            .contains(&Self::key(install_path, sha256, name))
+        {
+            return false;
+        }
+        let manifest_path = installed_manifest_path(install_path, sha256, name);
+        manifest_path.is_file()
+            && excluded_file_now_wanted(&manifest_path, exclusions).is_ok_and(|e| e.is_none())
+    }
+
+    /// Append an entry for a payload whose `.files` manifest has been finalized.
+    pub fn record(&self, install_path: &Path, sha256: &Sha256, name: &str) -> Result<()> {
+        let mut guard = self.file.lock().unwrap();
+        if guard.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            *guard = Some(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let file = guard.as_mut().unwrap();
+        writeln!(file, "{}", Self::key(install_path, sha256, name))?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Remove the journal after a successful install, or before a rollback so
+    /// the rolled back payloads aren't skipped next time.
+    pub fn remove(&self) -> Result<()> {
+        drop(self.file.lock().unwrap().take());
+        match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{HELLO_SHA256, TempDir};
+
+    #[test]
+    fn journal_roundtrip() {
+        let root = TempDir::new("install_journal");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let install_path = root.join("msvc-14.43.34808");
+        let sha256 = Sha256::parse_hex(HELLO_SHA256).unwrap();
+        let winmd = FileExclusions::new(&["*.winmd".to_string()]).unwrap();
+
+        let journal = InstallJournal::open(&msvcup_dir, "lock content").unwrap();
+        assert!(!journal.is_installed(&install_path, &sha256, "a.vsix", &winmd));
+        journal.record(&install_path, &sha256, "a.vsix").unwrap();
+        drop(journal);
+
+        // Entries are ignored while the payload has no .files manifest
+        let journal = InstallJournal::open(&msvcup_dir, "lock content").unwrap();
+        assert!(!journal.is_installed(&install_path, &sha256, "a.vsix", &winmd));
+        std::fs::create_dir_all(install_path.join("install")).unwrap();
+        assert!(!journal.is_installed(&install_path, &sha256, "a.vsix", &winmd));
+
+        std::fs::write(
+            installed_manifest_path(&install_path, &sha256, "a.vsix"),
+            "lib/a.lib\nskip lib/a.winmd\n",
+        )
+        .unwrap();
+        assert!(journal.is_installed(&install_path, &sha256, "a.vsix", &winmd));
+        assert!(!journal.is_installed(&install_path, &sha256, "b.vsix", &winmd));
+        // A file the payload left out is wanted now
+        assert!(!journal.is_installed(
+            &install_path,
+            &sha256,
+            "a.vsix",
+            &FileExclusions::default()
+        ));
+
+        // A different lock file gets a different journal
+        let other = InstallJournal::open(&msvcup_dir, "other content").unwrap();
+        assert!(!other.is_installed(&install_path, &sha256, "a.vsix", &winmd));
+
+        journal.remove().unwrap();
+        let journal = InstallJournal::open(&msvcup_dir, "lock content").unwrap();
+        assert!(!journal.is_installed(&install_path, &sha256, "a.vsix", &winmd));
+
+        // Removal doesn't wait for other references to the journal to go away
+        let journal = std::sync::Arc::new(journal);
+        let task_journal = journal.clone();
+        task_journal
+            .record(&install_path, &sha256, "a.vsix")
+            .unwrap();
+        journal.remove().unwrap();
+        let reopened = InstallJournal::open(&msvcup_dir, "lock content").unwrap();
+        assert!(!reopened.is_installed(&install_path, &sha256, "a.vsix", &winmd));
+        drop(task_journal);
+    }
+}
@@ -0,0 +1,661 @@
+use crate::arch::Arch;
+use crate::channel_kind::ChannelKind;
+use crate::disk_space::format_size;
+use crate::error::{ErrorKind, bail_kind};
+use crate::extra;
+use crate::install::{select_cabs, select_payloads};
+use crate::manifest::{self, MsvcupDir};
+use crate::output::{OutputFormat, print_records};
+use crate::packages::{
+    DEFAULT_LANGUAGES, ManifestUpdate, MsvcupPackage, MsvcupPackageKind, PackageId, Packages,
+    PayloadExclusions, PayloadId, identify_package, identify_payload,
+};
+use crate::util;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A `list` record.
+#[derive(Debug, Serialize)]
+pub struct PackageEntry {
+    /// Package name as passed to `install`, e.g. `msvc-14.43.17.13`
+    pub name: String,
+    pub kind: MsvcupPackageKind,
+    pub version: String,
+    /// Whether the package is installed, see [`mark_installed`]
+    pub installed: bool,
+    /// Whether the package has a pool directory but its install didn't finish
+    pub partial: bool,
+    /// Bytes `install` downloads for the package, with `list --sizes`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_size: Option<u64>,
+    /// Number of payloads and cabs `install` downloads, with `list --sizes`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload_count: Option<usize>,
+    /// The channel the package is listed from, with `list --channel all`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<&'static str>,
+}
+
+impl PackageEntry {
+    fn text(&self) -> String {
+        let mut text = self.name.clone();
+        if self.installed {
+            text.push_str(" [installed]");
+        } else if self.partial {
+            text.push_str(" [partial]");
+        }
+        if self.channel == Some("preview") {
+            text.push_str(" (preview)");
+        }
+        if let (Some(size), Some(count)) = (self.download_size, self.payload_count) {
+            text.push_str(&format!(" ({}, {} payload(s))", format_size(size), count));
+        }
+        text
+    }
+}
+
+/// A `list-payloads` record.
+#[derive(Debug, Serialize)]
+pub struct PayloadEntry {
+    pub file_name: String,
+    /// Id of the VS manifest package the payload belongs to
+    pub package_id: String,
+    pub url: String,
+    pub sha256: String,
+    /// Download size in bytes (0 if the manifest doesn't say)
+    pub size: u64,
+}
+
+impl PayloadEntry {
+    /// The text line, with the size (in bytes if `bytes`) if `sizes`.
+    fn text(&self, sizes: bool, bytes: bool) -> String {
+        if !sizes {
+            return format!("{} ({})", self.file_name, self.package_id);
+        }
+        format!(
+            "{} ({}, {})",
+            self.file_name,
+            self.package_id,
+            size_text(self.size, bytes)
+        )
+    }
+}
+
+fn size_text(size: u64, bytes: bool) -> String {
+    if bytes {
+        format!("{} bytes", size)
+    } else {
+        format_size(size)
+    }
+}
+
+/// The `list-payloads --sizes` summary line of `payloads`.
+pub fn payloads_summary(payloads: &[PayloadEntry], bytes: bool) -> String {
+    let total: u64 = payloads.iter().map(|p| p.size).sum();
+    format!(
+        "{} payload(s), {} in total",
+        payloads.len(),
+        size_text(total, bytes)
+    )
+}
+
+/// A `list --list-archs` record.
+#[derive(Debug, Serialize)]
+pub struct ArchEntry {
+    pub host: &'static str,
+    pub target: &'static str,
+    /// The host/target packages of the version, e.g. `base` and `Res.base`
+    pub variants: Vec<String>,
+}
+
+/// The host/target combinations the VS manifest has for an msvc package, sorted
+/// by host and then target.
+pub fn list_archs(pkgs: &Packages, msvcup_pkg: &MsvcupPackage) -> Result<Vec<ArchEntry>> {
+    if msvcup_pkg.kind != MsvcupPackageKind::Msvc {
+        bail_kind!(
+            ErrorKind::Usage,
+            "--list-archs only supports msvc packages, not '{}'",
+            msvcup_pkg
+        );
+    }
+    let mut combos: BTreeMap<(Arch, Arch), Vec<String>> = BTreeMap::new();
+    for pkg in &pkgs.packages {
+        if let PackageId::MsvcVersionHostTarget {
+            build_version,
+            host_arch,
+            target_arch,
+            name,
+        } = identify_package(&pkg.id)
+            && build_version == msvcup_pkg.version
+        {
+            let variants = combos.entry((host_arch, target_arch)).or_default();
+            util::insert_sorted(variants, name.to_string(), |a, b| a.cmp(b));
+        }
+    }
+    if combos.is_empty() {
+        bail_kind!(
+            ErrorKind::Usage,
+            "package '{}' not found in the VS manifest. \
+             Run 'msvcup list' to see available versions.",
+            msvcup_pkg
+        );
+    }
+    Ok(combos
+        .into_iter()
+        .map(|((host, target), variants)| ArchEntry {
+            host: host.as_str(),
+            target: target.as_str(),
+            variants,
+        })
+        .collect())
+}
+
+/// The msvcup packages available in a VS manifest, sorted.
+pub fn list_packages(pkgs: &Packages) -> Vec<PackageEntry> {
+    let mut msvcup_pkgs: Vec<MsvcupPackage> = Vec::new();
+    for (pkg_index, pkg) in pkgs.packages.iter().enumerate() {
+        let maybe_pkg = match identify_package(&pkg.id) {
+            PackageId::MsvcVersionHostTarget { build_version, .. } => {
+                Some(MsvcupPackage::new(MsvcupPackageKind::Msvc, build_version))
+            }
+            PackageId::Msbuild(version) => {
+                Some(MsvcupPackage::new(MsvcupPackageKind::Msbuild, version))
+            }
+            PackageId::Diasdk => Some(MsvcupPackage::new(
+                MsvcupPackageKind::Diasdk,
+                pkg.version.clone(),
+            )),
+            PackageId::Ninja(version) => {
+                Some(MsvcupPackage::new(MsvcupPackageKind::Ninja, version))
+            }
+            PackageId::Cmake(version) => {
+                Some(MsvcupPackage::new(MsvcupPackageKind::Cmake, version))
+            }
+            PackageId::Nasm(version) => Some(MsvcupPackage::new(MsvcupPackageKind::Nasm, version)),
+            PackageId::Python(version) => {
+                Some(MsvcupPackage::new(MsvcupPackageKind::Python, version))
+            }
+            _ => None,
+        };
+        if let Some(msvcup_pkg) = maybe_pkg {
+            util::insert_sorted(&mut msvcup_pkgs, msvcup_pkg, MsvcupPackage::order);
+        }
+
+        for payload in pkgs.payloads_from_pkg_index(pkg_index) {
+            if identify_payload(&payload.file_name, Arch::X64) == PayloadId::Sdk {
+                let msvcup_pkg = MsvcupPackage::new(MsvcupPackageKind::Sdk, pkg.version.clone());
+                util::insert_sorted(&mut msvcup_pkgs, msvcup_pkg, MsvcupPackage::order);
+            }
+        }
+    }
+
+    msvcup_pkgs
+        .into_iter()
+        .map(|p| PackageEntry {
+            name: p.to_string(),
+            kind: p.kind,
+            version: p.version,
+            installed: false,
+            partial: false,
+            download_size: None,
+            payload_count: None,
+            channel: None,
+        })
+        .collect()
+}
+
+/// Which channels `list --channel` reads.
+#[derive(Debug, Clone)]
+pub enum ChannelSelection {
+    One(ChannelKind),
+    /// Release and preview, see [`merge_channels`]
+    All,
+}
+
+/// Merge the packages of the release and preview manifests: a package in both
+/// is listed once, from release, and the ones only in preview are tagged so.
+pub fn merge_channels(release: Vec<PackageEntry>, preview: Vec<PackageEntry>) -> Vec<PackageEntry> {
+    let mut entries = release;
+    for entry in &mut entries {
+        entry.channel = Some("release");
+    }
+    for mut entry in preview {
+        if !entries.iter().any(|e| e.name == entry.name) {
+            entry.channel = Some("preview");
+            entries.push(entry);
+        }
+    }
+    entries.sort_by(|a, b| {
+        MsvcupPackage::order(
+            &MsvcupPackage::new(a.kind, a.version.clone()),
+            &MsvcupPackage::new(b.kind, b.version.clone()),
+        )
+    });
+    entries
+}
+
+/// Fill in the download size and payload count of each entry: the payloads a
+/// lock file for it would pin (see [`select_payloads`]) and the cabs of its
+/// MSIs. Payloads without a size in the manifest count as 0 bytes.
+pub fn add_sizes(entries: &mut [PackageEntry], pkgs: &Packages, target_arch: Arch) {
+    let msvcup_pkgs: Vec<MsvcupPackage> = entries
+        .iter()
+        .map(|entry| MsvcupPackage::new(entry.kind, entry.version.clone()))
+        .collect();
+    let selected = select_payloads(
+        &msvcup_pkgs,
+        pkgs,
+        DEFAULT_LANGUAGES,
+        target_arch,
+        PayloadExclusions::default(),
+    );
+    for (entry, msvcup_pkg) in entries.iter_mut().zip(&msvcup_pkgs) {
+        let payloads: Vec<(MsvcupPackage, usize)> = selected
+            .iter()
+            .filter(|(pkg, _)| pkg == msvcup_pkg)
+            .cloned()
+            .collect();
+        let cabs = select_cabs(pkgs, &payloads);
+        let payload_size: u64 = payloads.iter().map(|(_, pi)| pkgs.payloads[*pi].size).sum();
+        let cab_size: u64 = cabs.values().filter_map(|cab| cab.size).sum();
+        entry.download_size = Some(payload_size + cab_size);
+        entry.payload_count = Some(payloads.len() + cabs.len());
+    }
+}
+
+/// Mark the entries that are installed in `msvcup_dir`. This reads the root
+/// directory once and only looks inside the pool directories of listed
+/// packages: a package is installed when its `install` directory has a `.files`
+/// manifest and no `.files.pending` one left by an interrupted install, and
+/// partially installed otherwise. Failing to read the root only logs a warning.
+pub fn mark_installed(entries: &mut [PackageEntry], msvcup_dir: &MsvcupDir) {
+    let installed = match msvcup_dir.list_installed_packages() {
+        Ok(installed) => installed,
+        Err(e) => {
+            log::warn!(
+                "not marking installed packages, reading '{}' failed: {:#}",
+                msvcup_dir.root_path.display(),
+                e
+            );
+            return;
+        }
+    };
+    for entry in entries {
+        let Some(pkg) = installed.iter().find(|p| p.to_string() == entry.name) else {
+            continue;
+        };
+        let install_meta_dir = msvcup_dir.package_path(pkg).join("install");
+        let (mut finished, mut pending) = (false, false);
+        for dir_entry in std::fs::read_dir(&install_meta_dir).into_iter().flatten() {
+            let Ok(dir_entry) = dir_entry else { continue };
+            let file_name = dir_entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            finished |= file_name.ends_with(".files");
+            pending |= file_name.ends_with(".files.pending");
+        }
+        entry.installed = finished && !pending;
+        entry.partial = !entry.installed;
+    }
+}
+
+/// The payloads of the default-language packages in a VS manifest, sorted by
+/// name and filtered by download size.
+pub fn list_payloads(
+    pkgs: &Packages,
+    min_download_size: Option<u64>,
+    max_download_size: Option<u64>,
+) -> Vec<PayloadEntry> {
+    let mut payload_indices: Vec<usize> = Vec::new();
+    for (pkg_index, _) in pkgs.filter_by_language(DEFAULT_LANGUAGES) {
+        let range = pkgs.payload_range_from_pkg_index(pkg_index);
+        for pi in range {
+            let size = pkgs.payloads[pi].size;
+            if min_download_size.is_some_and(|min| size < min)
+                || max_download_size.is_some_and(|max| size > max)
+            {
+                continue;
+            }
+            util::insert_sorted(&mut payload_indices, pi, |a, b| {
+                let pa = &pkgs.payloads[*a];
+                let pb = &pkgs.payloads[*b];
+                pa.name_decoded()
+                    .cmp(pb.name_decoded())
+                    .then_with(|| a.cmp(b))
+            });
+        }
+    }
+
+    payload_indices
+        .into_iter()
+        .map(|pi| {
+            let payload = &pkgs.payloads[pi];
+            let pkg = &pkgs.packages[pkgs.pkg_index_from_payload_index(pi)];
+            PayloadEntry {
+                file_name: payload.file_name.clone(),
+                package_id: pkg.id.clone(),
+                url: payload.url_decoded.clone(),
+                sha256: payload.sha256.to_hex(),
+                size: payload.size,
+            }
+        })
+        .collect()
+}
+
+pub async fn list_command(
+    client: &reqwest::Client,
+    msvcup_dir: &MsvcupDir,
+    channels: &ChannelSelection,
+    installed_only: bool,
+    sizes: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let channel_kinds = match channels {
+        ChannelSelection::One(channel_kind) => vec![channel_kind.clone()],
+        ChannelSelection::All => vec![ChannelKind::Release, ChannelKind::Preview],
+    };
+    let mut per_channel = Vec::new();
+    for channel_kind in &channel_kinds {
+        let pkgs =
+            manifest::read_vs_manifest(client, msvcup_dir, channel_kind, ManifestUpdate::Off)
+                .await?;
+        let mut entries = list_packages(&pkgs);
+        if sizes {
+            add_sizes(&mut entries, &pkgs, Arch::native().unwrap_or(Arch::X64));
+        }
+        per_channel.push(entries);
+    }
+    let mut entries = per_channel.remove(0);
+    if let Some(preview) = per_channel.pop() {
+        entries = merge_channels(entries, preview);
+    }
+    mark_installed(&mut entries, msvcup_dir);
+    if installed_only {
+        entries.retain(|entry| entry.installed);
+    }
+    print_records(format, &entries, PackageEntry::text)?;
+    for spec in extra::supported_packages() {
+        log::info!(
+            "{} can also be fetched from {} with 'msvcup fetch {}<version>/...'",
+            spec.name,
+            spec.source,
+            spec.url_prefix()
+        );
+    }
+    Ok(())
+}
+
+pub async fn list_archs_command(
+    client: &reqwest::Client,
+    msvcup_dir: &MsvcupDir,
+    channel_kind: &ChannelKind,
+    msvcup_pkg: &MsvcupPackage,
+    format: OutputFormat,
+) -> Result<()> {
+    let pkgs =
+        manifest::read_vs_manifest(client, msvcup_dir, channel_kind, ManifestUpdate::Off).await?;
+    let archs = list_archs(&pkgs, msvcup_pkg)?;
+    print_records(format, &archs, |a| {
+        format!(
+            "host {} target {} ({})",
+            a.host,
+            a.target,
+            a.variants.join(", ")
+        )
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn list_payloads_command(
+    client: &reqwest::Client,
+    msvcup_dir: &MsvcupDir,
+    channel_kind: &ChannelKind,
+    min_download_size: Option<u64>,
+    max_download_size: Option<u64>,
+    sizes: bool,
+    bytes: bool,
+    format: OutputFormat,
+) -> Result<()> {
+    let pkgs =
+        manifest::read_vs_manifest(client, msvcup_dir, channel_kind, ManifestUpdate::Off).await?;
+    let payloads = list_payloads(&pkgs, min_download_size, max_download_size);
+    print_records(format, &payloads, |p| p.text(sizes, bytes))?;
+    // JSON records have the sizes to sum already
+    if sizes && format == OutputFormat::Text {
+        println!("{}", payloads_summary(&payloads, bytes));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::write_records;
+    use crate::packages::get_packages;
+    use crate::test_support::{FixtureManifest, HELLO_SHA256, TempDir};
+
+    fn fixture_manifest() -> String {
+        FixtureManifest::new()
+            .package(
+                "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.base",
+                "14.43.34808",
+                &[("tools.vsix", "https://example.com/tools.vsix", 1000)],
+            )
+            .package(
+                "Win11SDK_10.0.22621",
+                "10.0.22621.7",
+                &[
+                    (
+                        "Installers\\Universal CRT Headers Libraries and Sources-x86_en-us.msi",
+                        "https://example.com/sdk.msi",
+                        200,
+                    ),
+                    ("Installers\\abc.cab", "https://example.com/abc.cab", 50),
+                ],
+            )
+            .package("Microsoft.VisualCpp.DIA.SDK", "14.43.34808", &[])
+            .localized(
+                "Localized.Resources",
+                "1.0",
+                "ja-JP",
+                &[("ja.vsix", "https://example.com/ja.vsix", 5)],
+            )
+            .build()
+    }
+
+    fn render<T: Serialize>(
+        format: OutputFormat,
+        records: &[T],
+        text: impl Fn(&T) -> String,
+    ) -> String {
+        let mut out = Vec::new();
+        write_records(&mut out, format, records, text).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn list_packages_golden() {
+        let pkgs = get_packages("fixture.json", &fixture_manifest()).unwrap();
+        let entries = list_packages(&pkgs);
+        assert_eq!(
+            render(OutputFormat::Text, &entries, |p| p.name.clone()),
+            "msvc-14.43.17.13\nsdk-10.0.22621.7\ndiasdk-14.43.34808\n"
+        );
+        assert_eq!(
+            render(OutputFormat::Ndjson, &entries, |p| p.name.clone()),
+            concat!(
+                r#"{"name":"msvc-14.43.17.13","kind":"msvc","version":"14.43.17.13","installed":false,"partial":false}"#,
+                "\n",
+                r#"{"name":"sdk-10.0.22621.7","kind":"sdk","version":"10.0.22621.7","installed":false,"partial":false}"#,
+                "\n",
+                r#"{"name":"diasdk-14.43.34808","kind":"diasdk","version":"14.43.34808","installed":false,"partial":false}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn list_sizes_golden() {
+        let pkgs = get_packages("fixture.json", &fixture_manifest()).unwrap();
+        let mut entries = list_packages(&pkgs);
+        add_sizes(&mut entries, &pkgs, Arch::X64);
+        assert_eq!(
+            render(OutputFormat::Text, &entries, PackageEntry::text),
+            "msvc-14.43.17.13 (1000 B, 1 payload(s))\n\
+             sdk-10.0.22621.7 (250 B, 2 payload(s))\n\
+             diasdk-14.43.34808 (0 B, 0 payload(s))\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&render(
+            OutputFormat::Json,
+            &entries[1..2],
+            PackageEntry::text,
+        ))
+        .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "name": "sdk-10.0.22621.7",
+                "kind": "sdk",
+                "version": "10.0.22621.7",
+                "installed": false,
+                "partial": false,
+                "download_size": 250,
+                "payload_count": 2
+            }])
+        );
+    }
+
+    #[test]
+    fn list_archs_of_msvc_version() {
+        let manifest = r#"{"packages": [
+            {"id": "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.base", "version": "14.43.34808"},
+            {"id": "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.Res.base", "version": "14.43.34808"},
+            {"id": "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetARM64.base", "version": "14.43.34808"},
+            {"id": "Microsoft.VC.14.43.17.13.Tools.HostARM64.TargetARM64.base", "version": "14.43.34808"},
+            {"id": "Microsoft.VC.14.44.17.14.Tools.HostX64.TargetX86.base", "version": "14.44.35207"}
+        ]}"#;
+        let pkgs = get_packages("fixture.json", manifest).unwrap();
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13");
+        let archs = list_archs(&pkgs, &msvc).unwrap();
+        let combos: Vec<String> = archs
+            .iter()
+            .map(|a| format!("{} {} {}", a.host, a.target, a.variants.join(",")))
+            .collect();
+        assert_eq!(
+            combos,
+            [
+                "x64 x64 Res.base,base",
+                "x64 arm64 base",
+                "arm64 arm64 base"
+            ]
+        );
+
+        let missing = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.30.17.6");
+        assert!(list_archs(&pkgs, &missing).is_err());
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        let err = list_archs(&pkgs, &sdk).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 2);
+    }
+
+    #[test]
+    fn merge_release_and_preview() {
+        let release = list_packages(&get_packages("fixture.json", &fixture_manifest()).unwrap());
+        let preview_manifest = fixture_manifest()
+            .replace("14.43.17.13", "14.44.17.14")
+            .replace("14.43.34808", "14.44.35207");
+        let preview = list_packages(&get_packages("preview.json", &preview_manifest).unwrap());
+        let entries = merge_channels(release, preview);
+        assert_eq!(
+            render(OutputFormat::Text, &entries, PackageEntry::text),
+            "msvc-14.43.17.13\n\
+             msvc-14.44.17.14 (preview)\n\
+             sdk-10.0.22621.7\n\
+             diasdk-14.43.34808\n\
+             diasdk-14.44.35207 (preview)\n"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&render(OutputFormat::Json, &entries[1..3], |p| {
+                p.name.clone()
+            }))
+            .unwrap();
+        assert_eq!(json[0]["channel"], "preview");
+        assert_eq!(json[1]["channel"], "release");
+    }
+
+    #[test]
+    fn list_marks_installed_packages() {
+        let pkgs = get_packages("fixture.json", &fixture_manifest()).unwrap();
+        let mut entries = list_packages(&pkgs);
+        let root = TempDir::new("list_installed");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+
+        // No install directory yet
+        mark_installed(&mut entries, &msvcup_dir);
+        assert!(entries.iter().all(|e| !e.installed && !e.partial));
+
+        let msvc_meta = root.join("msvc-14.43.17.13").join("install");
+        std::fs::create_dir_all(&msvc_meta).unwrap();
+        std::fs::write(msvc_meta.join("abc-tools.vsix.files"), "tools.vsix\n").unwrap();
+        let sdk_meta = root.join("sdk-10.0.22621.7").join("install");
+        std::fs::create_dir_all(&sdk_meta).unwrap();
+        std::fs::write(sdk_meta.join("abc-sdk.msi.files"), "sdk.msi\n").unwrap();
+        std::fs::write(sdk_meta.join("def-sdk2.msi.files.pending"), "sdk2.msi\n").unwrap();
+        std::fs::create_dir_all(root.join("cache")).unwrap();
+
+        mark_installed(&mut entries, &msvcup_dir);
+        assert_eq!(
+            render(OutputFormat::Text, &entries, PackageEntry::text),
+            "msvc-14.43.17.13 [installed]\nsdk-10.0.22621.7 [partial]\ndiasdk-14.43.34808\n"
+        );
+    }
+
+    #[test]
+    fn list_payloads_golden() {
+        let pkgs = get_packages("fixture.json", &fixture_manifest()).unwrap();
+        let payloads = list_payloads(&pkgs, Some(100), None);
+        let json: serde_json::Value =
+            serde_json::from_str(&render(OutputFormat::Json, &payloads, |p| {
+                p.file_name.clone()
+            }))
+            .unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "file_name": "Installers\\Universal CRT Headers Libraries and Sources-x86_en-us.msi",
+                    "package_id": "Win11SDK_10.0.22621",
+                    "url": "https://example.com/sdk.msi",
+                    "sha256": HELLO_SHA256,
+                    "size": 200
+                },
+                {
+                    "file_name": "tools.vsix",
+                    "package_id": "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.base",
+                    "url": "https://example.com/tools.vsix",
+                    "sha256": HELLO_SHA256,
+                    "size": 1000
+                }
+            ])
+        );
+        assert_eq!(
+            render(OutputFormat::Text, &payloads, |p| p.text(true, false)),
+            "Installers\\Universal CRT Headers Libraries and Sources-x86_en-us.msi \
+             (Win11SDK_10.0.22621, 200 B)\n\
+             tools.vsix (Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.base, 1000 B)\n"
+        );
+        assert_eq!(
+            payloads_summary(&payloads, false),
+            "2 payload(s), 1.2 KiB in total"
+        );
+        assert_eq!(
+            payloads_summary(&payloads, true),
+            "2 payload(s), 1200 bytes in total"
+        );
+        // The ja-JP payload is excluded by language, not just by size
+        assert!(
+            list_payloads(&pkgs, None, None)
+                .iter()
+                .all(|p| p.file_name != "ja.vsix")
+        );
+    }
+}
@@ -1,39 +1,270 @@
+use crate::error::{ErrorKind, bail_kind};
 use anyhow::{Context, Result};
 use fs2::FileExt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
+/// The default lock timeout of commands that only read, which shouldn't hang
+/// behind an install.
+pub const INFORMATIONAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long [`LockFile::lock`] waits for another holder, forever if unset or None.
+static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+
+/// Set how long every lock of this process waits for another holder. Only the
+/// first call counts.
+pub fn set_timeout(timeout: Option<Duration>) {
+    let _ = TIMEOUT.set(timeout);
+}
+
+/// An exclusive advisory lock on a file path, held until dropped.
+///
+/// Lock files are intentionally never deleted. Deleting on drop races with other
+/// lockers: a process blocked on the old handle could acquire a lock on the deleted
+/// file while a newcomer creates and locks a fresh file at the same path, leaving
+/// both believing they hold the lock. Leftover lock files are empty once released.
+///
+/// A process that dies while holding the lock can't leave it behind: the lock
+/// belongs to its open handle, and the OS releases it when the process exits,
+/// however it exits. Only its PID stays in the file, and the next locker
+/// overwrites it.
 pub struct LockFile {
-    path: PathBuf,
     file: fs::File,
 }
 
 impl LockFile {
-    pub fn lock(path: &str) -> Result<LockFile> {
-        let path = PathBuf::from(path);
+    /// Lock `path`, waiting up to the timeout from [`set_timeout`] when another
+    /// process holds it.
+    pub fn lock(path: &Path) -> Result<LockFile> {
+        let timeout = TIMEOUT.get().copied().flatten();
+        Self::lock_with_timeout(path, timeout, |msg| log::info!("{}", msg))
+    }
+
+    /// Lock `path`, calling `on_wait` once with a message naming the holder if
+    /// it's locked already. Without a timeout this waits for as long as it takes,
+    /// otherwise it polls with backoff and fails once `timeout` has passed.
+    fn lock_with_timeout(
+        path: &Path,
+        timeout: Option<Duration>,
+        on_wait: impl FnOnce(&str),
+    ) -> Result<LockFile> {
         if let Some(dir) = path.parent() {
             fs::create_dir_all(dir)
                 .with_context(|| format!("creating lock file directory '{}'", dir.display()))?;
         }
-        let file = fs::File::create(&path)
+        // Don't truncate on open: that would clobber the PID of the current holder.
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
             .with_context(|| format!("creating lock file '{}'", path.display()))?;
-        file.lock_exclusive()
-            .with_context(|| format!("locking file '{}'", path.display()))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                on_wait(&format!(
+                    "waiting for lock '{}' held by {}",
+                    path.display(),
+                    holder(path)
+                ));
+                wait_for_lock(&file, path, timeout)?;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("locking file '{}'", path.display()));
+            }
+        }
 
-        // Write PID to lock file for debugging
+        // Write PID to lock file for debugging, replacing any stale PID
         let pid = std::process::id();
-        use std::io::Write;
+        use std::io::{Seek, Write};
         let mut f = &file;
+        let _ = f.set_len(0);
+        let _ = f.seek(std::io::SeekFrom::Start(0));
         let _ = write!(f, "{}", pid);
         let _ = f.flush();
 
-        Ok(LockFile { path, file })
+        Ok(LockFile { file })
+    }
+}
+
+/// Who holds the lock on `path`, from the PID its holder wrote.
+fn holder(path: &Path) -> String {
+    match fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok())
+    {
+        Some(pid) => format!("PID {}", pid),
+        None => "another process".to_string(),
+    }
+}
+
+fn wait_for_lock(file: &fs::File, path: &Path, timeout: Option<Duration>) -> Result<()> {
+    let Some(timeout) = timeout else {
+        return file
+            .lock_exclusive()
+            .with_context(|| format!("locking file '{}'", path.display()));
+    };
+    let start = Instant::now();
+    let mut delay = Duration::from_millis(10);
+    loop {
+        match file.try_lock_exclusive() {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("locking file '{}'", path.display()));
+            }
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            bail_kind!(
+                ErrorKind::Filesystem,
+                "timed out after {:.0?} waiting for lock '{}' held by {}, \
+                 if that process is stuck or gone (e.g. on a network filesystem) stop it \
+                 or pass a longer --lock-timeout",
+                timeout,
+                path.display(),
+                holder(path)
+            );
+        }
+        std::thread::sleep(delay.min(timeout - elapsed));
+        delay = (delay * 2).min(Duration::from_secs(1));
     }
 }
 
 impl Drop for LockFile {
     fn drop(&mut self) {
+        let _ = self.file.set_len(0);
         let _ = self.file.unlock();
-        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn lock_writes_pid_and_clears_on_drop() {
+        let dir = TempDir::new("lock_pid");
+        let path = dir.join("test.lock");
+
+        let lock = LockFile::lock(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, std::process::id().to_string());
+        drop(lock);
+
+        assert!(path.exists());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn lock_left_by_a_dead_process_is_free() {
+        let dir = TempDir::new("lock_dead_holder");
+        let path = dir.join("test.lock");
+        // What a crashed holder leaves: its PID, but no lock on the file
+        std::fs::write(&path, "4294967295").unwrap();
+
+        let _lock = LockFile::lock(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            std::process::id().to_string()
+        );
+    }
+
+    #[test]
+    fn contended_lock_waits_for_its_holder() {
+        let dir = TempDir::new("lock_wait");
+        let path = dir.join("test.lock");
+
+        let held = LockFile::lock(&path).unwrap();
+        let waiter = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut message = String::new();
+                let lock =
+                    LockFile::lock_with_timeout(&path, Some(Duration::from_secs(30)), |msg| {
+                        message = msg.to_string()
+                    })
+                    .unwrap();
+                drop(lock);
+                message
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        drop(held);
+        let message = waiter.join().unwrap();
+        assert_eq!(
+            message,
+            format!(
+                "waiting for lock '{}' held by PID {}",
+                path.display(),
+                std::process::id()
+            )
+        );
+
+        // Without a wait there's no message
+        let mut waited = false;
+        drop(LockFile::lock_with_timeout(&path, None, |_| waited = true).unwrap());
+        assert!(!waited);
+    }
+
+    #[test]
+    fn contended_lock_times_out() {
+        let dir = TempDir::new("lock_timeout");
+        let path = dir.join("test.lock");
+
+        let _held = LockFile::lock(&path).unwrap();
+        let err = LockFile::lock_with_timeout(&path, Some(Duration::from_millis(50)), |_| {})
+            .err()
+            .unwrap();
+        let msg = err.to_string();
+        assert!(msg.contains("timed out"), "{}", msg);
+        assert!(
+            msg.contains(&format!("held by PID {}", std::process::id())),
+            "{}",
+            msg
+        );
+        assert_eq!(crate::error::exit_code(&err), 6);
+    }
+
+    #[test]
+    fn lock_is_mutually_exclusive() {
+        const THREADS: usize = 8;
+        const ITERATIONS: usize = 25;
+
+        let dir = TempDir::new("lock_contention");
+        let lock_path = dir.join("counter.lock");
+        let counter_path = dir.join("counter");
+        std::fs::write(&counter_path, "0").unwrap();
+
+        let handles: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let lock_path = lock_path.clone();
+                let counter_path = counter_path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..ITERATIONS {
+                        let _lock = LockFile::lock(&lock_path).unwrap();
+                        let n: usize = std::fs::read_to_string(&counter_path)
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        std::thread::yield_now();
+                        std::fs::write(&counter_path, (n + 1).to_string()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let total: usize = std::fs::read_to_string(&counter_path)
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(total, THREADS * ITERATIONS);
     }
 }
@@ -1,11 +1,27 @@
+use crate::error::{ErrorKind, bail_kind};
 use crate::packages::{MsvcupPackage, MsvcupPackageKind};
-use anyhow::Result;
+use anyhow::{Context, Result};
+use fs_err as fs;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Version of the lock file format written by this msvcup. Bump it when a change
+/// would be misread by older readers and add a reader branch in [`parse_lock_file`].
+pub const LOCK_FILE_VERSION: u64 = 1;
+
+/// Lock files written before the `version` field existed are version 1.
+fn legacy_lock_file_version() -> u64 {
+    1
+}
 
 /// JSON lock file schema
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LockFileJson {
+    /// Lock file format version, see [`LOCK_FILE_VERSION`]
+    #[serde(default = "legacy_lock_file_version")]
+    pub version: u64,
     /// CAB files shared by MSI payloads: filename -> CabEntry
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub cabs: HashMap<String, CabEntry>,
@@ -17,6 +33,9 @@ pub struct LockFileJson {
 pub struct CabEntry {
     pub url: String,
     pub sha256: String,
+    /// Download size in bytes, missing in lock files written by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,11 +48,14 @@ pub struct LockFilePackage {
 pub struct LockFilePayloadEntry {
     pub url: String,
     pub sha256: String,
+    /// Download size in bytes, missing in lock files written by older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
 }
 
 /// Whether this package type requires stripping the root directory during extraction.
 pub fn strip_root_dir(pkg_kind: MsvcupPackageKind) -> bool {
-    matches!(pkg_kind, MsvcupPackageKind::Cmake)
+    matches!(pkg_kind, MsvcupPackageKind::Cmake | MsvcupPackageKind::Nasm)
 }
 
 /// If this package type is host-architecture-specific, parse the arch from the URL.
@@ -43,22 +65,166 @@ pub fn host_arch_limit(pkg_kind: MsvcupPackageKind, url: &str) -> Option<crate::
         | MsvcupPackageKind::Sdk
         | MsvcupPackageKind::Msbuild
         | MsvcupPackageKind::Diasdk => None,
-        MsvcupPackageKind::Ninja | MsvcupPackageKind::Cmake => match crate::extra::parse_url(url) {
-            crate::extra::ParseUrlResult::Ok { arch } => Some(arch),
-            crate::extra::ParseUrlResult::Unexpected { .. } => None,
-        },
+        MsvcupPackageKind::Ninja
+        | MsvcupPackageKind::Cmake
+        | MsvcupPackageKind::Nasm
+        | MsvcupPackageKind::Python => Some(crate::extra::parse_url(url).ok()?.arch),
     }
 }
 
 pub fn parse_lock_file(lock_file_path: &str, content: &str) -> Result<LockFileJson> {
-    serde_json::from_str(content)
-        .map_err(|e| anyhow::anyhow!("{}: failed to parse JSON lock file: {}", lock_file_path, e))
+    let value: serde_json::Value = serde_json::from_str(content).map_err(|e| {
+        anyhow::anyhow!("{}: failed to parse JSON lock file: {}", lock_file_path, e)
+    })?;
+    match lock_file_version(lock_file_path, &value)? {
+        1 => serde_json::from_value(value).map_err(|e| {
+            anyhow::anyhow!("{}: failed to parse JSON lock file: {}", lock_file_path, e)
+        }),
+        version => unsupported_version(lock_file_path, version),
+    }
+}
+
+/// Fail if the lock file was written in a format newer than this msvcup understands,
+/// so it's reported instead of being treated as a mismatch and overwritten.
+/// Content that doesn't parse is left for [`check_lock_file_pkgs`] to report.
+pub fn check_lock_file_version(lock_file_path: &str, content: &str) -> Result<()> {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(content) else {
+        return Ok(());
+    };
+    match lock_file_version(lock_file_path, &value)? {
+        version if version > LOCK_FILE_VERSION => unsupported_version(lock_file_path, version),
+        _ => Ok(()),
+    }
+}
+
+fn lock_file_version(lock_file_path: &str, value: &serde_json::Value) -> Result<u64> {
+    match value.get("version") {
+        None => Ok(legacy_lock_file_version()),
+        Some(version) => version.as_u64().ok_or_else(|| {
+            anyhow::anyhow!(
+                "{}: lock file 'version' must be a number, got {}",
+                lock_file_path,
+                version
+            )
+        }),
+    }
+}
+
+fn unsupported_version<T>(lock_file_path: &str, version: u64) -> Result<T> {
+    bail_kind!(
+        ErrorKind::LockFileMismatch,
+        "{}: lock file version {} is not supported by this msvcup (supports up to {}), \
+         upgrade msvcup or regenerate the lock file",
+        lock_file_path,
+        version,
+        LOCK_FILE_VERSION
+    );
+}
+
+/// Merge several lock files, e.g. a shared base lock file with the SDK and a
+/// per-project one with the MSVC version, into one. Earlier lock files take
+/// precedence: a package or cab listed in more than one is taken from the first.
+/// `lock_files` are `(path, content)` pairs.
+pub fn merge_lock_files(lock_files: &[(&str, &str)]) -> Result<LockFileJson> {
+    let mut merged = LockFileJson {
+        version: LOCK_FILE_VERSION,
+        cabs: HashMap::new(),
+        packages: Vec::new(),
+    };
+    for (lock_file_path, content) in lock_files {
+        let lock_file = parse_lock_file(lock_file_path, content)?;
+        for (cab_filename, cab_entry) in lock_file.cabs {
+            merged.cabs.entry(cab_filename).or_insert(cab_entry);
+        }
+        for lock_pkg in lock_file.packages {
+            if merged.packages.iter().any(|p| p.name == lock_pkg.name) {
+                log::debug!(
+                    "{}: '{}' is already in an earlier lock file, ignoring it",
+                    lock_file_path,
+                    lock_pkg.name
+                );
+                continue;
+            }
+            merged.packages.push(lock_pkg);
+        }
+    }
+    Ok(merged)
+}
+
+/// Seconds since the lock file was last written, None if it doesn't exist or
+/// its modification time can't be read. A modification time in the future
+/// counts as just written.
+pub fn lock_file_age_seconds(path: &str) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(
+        std::time::SystemTime::now()
+            .duration_since(modified)
+            .map(|age| age.as_secs())
+            .unwrap_or(0),
+    )
+}
+
+/// Write a lock file, creating its directory if needed. The JSON is written to
+/// `{path}.tmp` and renamed over `path`, so a reader never sees a partial lock file.
+pub fn write_lock_file(lock_file_path: &str, lock_file: &LockFileJson) -> Result<()> {
+    if let Some(dir) = Path::new(lock_file_path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = format!("{}.tmp", lock_file_path);
+    let mut out = BufWriter::new(fs::File::create(&tmp_path)?);
+    serde_json::to_writer_pretty(&mut out, lock_file)
+        .with_context(|| format!("writing '{}'", tmp_path))?;
+    out.flush()
+        .with_context(|| format!("writing '{}'", tmp_path))?;
+    drop(out);
+    fs::rename(&tmp_path, lock_file_path)?;
+    Ok(())
+}
+
+/// Add packages and cabs to a lock file, creating it if it doesn't exist. Payloads
+/// of a package the lock file already has are added to it unless already listed,
+/// and cabs it already has are kept. The JSON can't be appended to in place, so
+/// the merged lock file is rewritten with [`write_lock_file`]'s tmp file and rename.
+#[allow(dead_code)]
+pub fn append_to_lock_file(
+    lock_file_path: &str,
+    packages: Vec<LockFilePackage>,
+    cabs: HashMap<String, CabEntry>,
+) -> Result<()> {
+    let mut lock_file = match fs::read_to_string(lock_file_path) {
+        Ok(content) => parse_lock_file(lock_file_path, &content)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => LockFileJson {
+            version: LOCK_FILE_VERSION,
+            cabs: HashMap::new(),
+            packages: Vec::new(),
+        },
+        Err(e) => return Err(e.into()),
+    };
+    for (cab_filename, cab_entry) in cabs {
+        lock_file.cabs.entry(cab_filename).or_insert(cab_entry);
+    }
+    for lock_pkg in packages {
+        let Some(existing) = lock_file
+            .packages
+            .iter_mut()
+            .find(|p| p.name == lock_pkg.name)
+        else {
+            lock_file.packages.push(lock_pkg);
+            continue;
+        };
+        for payload in lock_pkg.payloads {
+            if !existing.payloads.iter().any(|p| p.sha256 == payload.sha256) {
+                existing.payloads.push(payload);
+            }
+        }
+    }
+    write_lock_file(lock_file_path, &lock_file)
 }
 
 /// Check if the lock file's packages match what we want to install.
 /// Returns None if they match, Some(reason) if they don't.
 pub fn check_lock_file_pkgs(
-    _lock_file_path: &str,
+    lock_file_path: &str,
     lock_file_content: &str,
     msvcup_pkgs: &[MsvcupPackage],
 ) -> Option<String> {
@@ -66,7 +232,7 @@ pub fn check_lock_file_pkgs(
         return Some("no packages to check against".to_string());
     }
 
-    let lock_file: LockFileJson = match serde_json::from_str(lock_file_content) {
+    let lock_file = match parse_lock_file(lock_file_path, lock_file_content) {
         Ok(lf) => lf,
         Err(e) => return Some(format!("parse error: {}", e)),
     };
@@ -94,6 +260,7 @@ pub fn check_lock_file_pkgs(
 mod tests {
     use super::*;
     use crate::arch::Arch;
+    use crate::test_support::TempDir;
 
     fn make_lock_json(packages: &[&str]) -> String {
         let pkgs: Vec<String> = packages
@@ -103,6 +270,100 @@ mod tests {
         format!(r#"{{"packages": [{}]}}"#, pkgs.join(","))
     }
 
+    #[test]
+    fn merge_lock_files_first_wins() {
+        let base = r#"{
+            "cabs": {"a.cab": {"url": "https://example.com/base/a.cab", "sha256": "aa"}},
+            "packages": [
+                {"name": "sdk-10.0.22621.7", "payloads": [{"url": "https://example.com/sdk.msi", "sha256": "11"}]}
+            ]
+        }"#;
+        let project = r#"{
+            "cabs": {
+                "a.cab": {"url": "https://example.com/project/a.cab", "sha256": "bb"},
+                "b.cab": {"url": "https://example.com/b.cab", "sha256": "cc"}
+            },
+            "packages": [
+                {"name": "msvc-14.43.17.13", "payloads": []},
+                {"name": "sdk-10.0.22621.7", "payloads": []}
+            ]
+        }"#;
+        let merged = merge_lock_files(&[("base.json", base), ("project.json", project)]).unwrap();
+        let names: Vec<&str> = merged.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["sdk-10.0.22621.7", "msvc-14.43.17.13"]);
+        assert_eq!(merged.packages[0].payloads.len(), 1);
+        assert_eq!(merged.cabs["a.cab"].sha256, "aa");
+        assert_eq!(merged.cabs["b.cab"].sha256, "cc");
+        assert_eq!(merged.version, LOCK_FILE_VERSION);
+    }
+
+    #[test]
+    fn write_and_append_lock_file() {
+        let dir = TempDir::new("write_lock_file");
+        let path = dir.join("nested").join("msvcup.lock");
+        let path_str = path.to_str().unwrap();
+        let payload = |url: &str, sha256: &str| LockFilePayloadEntry {
+            url: url.to_string(),
+            sha256: sha256.to_string(),
+            size: None,
+        };
+
+        append_to_lock_file(
+            path_str,
+            vec![LockFilePackage {
+                name: "sdk-10.0.22621.7".to_string(),
+                payloads: vec![payload("https://example.com/a.msi", "11")],
+            }],
+            HashMap::from([(
+                "a.cab".to_string(),
+                CabEntry {
+                    url: "https://example.com/a.cab".to_string(),
+                    sha256: "aa".to_string(),
+                    size: None,
+                },
+            )]),
+        )
+        .unwrap();
+        append_to_lock_file(
+            path_str,
+            vec![
+                LockFilePackage {
+                    name: "sdk-10.0.22621.7".to_string(),
+                    payloads: vec![
+                        payload("https://example.com/a.msi", "11"),
+                        payload("https://example.com/b.msi", "22"),
+                    ],
+                },
+                LockFilePackage {
+                    name: "ninja-1.12.1".to_string(),
+                    payloads: vec![payload("https://example.com/ninja.zip", "33")],
+                },
+            ],
+            HashMap::from([(
+                "a.cab".to_string(),
+                CabEntry {
+                    url: "https://example.com/other/a.cab".to_string(),
+                    sha256: "bb".to_string(),
+                    size: None,
+                },
+            )]),
+        )
+        .unwrap();
+
+        let lock_file =
+            parse_lock_file(path_str, &std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(!dir.join("nested").join("msvcup.lock.tmp").exists());
+        let names: Vec<&str> = lock_file.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["sdk-10.0.22621.7", "ninja-1.12.1"]);
+        let sdk_shas: Vec<&str> = lock_file.packages[0]
+            .payloads
+            .iter()
+            .map(|p| p.sha256.as_str())
+            .collect();
+        assert_eq!(sdk_shas, ["11", "22"]);
+        assert_eq!(lock_file.cabs["a.cab"].sha256, "aa");
+    }
+
     #[test]
     fn parse_lock_file_valid() {
         let json = r#"{
@@ -232,12 +493,14 @@ mod tests {
     #[test]
     fn lockfile_json_serialization_roundtrip() {
         let lock_file = LockFileJson {
+            version: LOCK_FILE_VERSION,
             cabs: HashMap::new(),
             packages: vec![LockFilePackage {
                 name: "msvc-14.43.34808".to_string(),
                 payloads: vec![LockFilePayloadEntry {
                     url: "https://example.com/file.vsix".to_string(),
                     sha256: "abc123".to_string(),
+                    size: Some(1024),
                 }],
             }],
         };
@@ -245,5 +508,53 @@ mod tests {
         let parsed: LockFileJson = serde_json::from_str(&json).unwrap();
         assert_eq!(parsed.packages.len(), 1);
         assert_eq!(parsed.packages[0].name, "msvc-14.43.34808");
+        assert_eq!(parsed.packages[0].payloads[0].size, Some(1024));
+    }
+
+    #[test]
+    fn parse_lock_file_versions() {
+        // Unversioned lock files are version 1
+        let lock_file = parse_lock_file("test.lock", r#"{"packages": []}"#).unwrap();
+        assert_eq!(lock_file.version, 1);
+        let lock_file = parse_lock_file("test.lock", r#"{"version": 1, "packages": []}"#).unwrap();
+        assert_eq!(lock_file.version, 1);
+
+        let err = parse_lock_file("test.lock", r#"{"version": 99, "packages": []}"#).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 3);
+        assert!(err.to_string().contains("version 99"));
+        assert!(parse_lock_file("test.lock", r#"{"version": "1", "packages": []}"#).is_err());
+    }
+
+    #[test]
+    fn check_lock_file_version_only_rejects_newer() {
+        assert!(check_lock_file_version("test.lock", r#"{"packages": []}"#).is_ok());
+        assert!(check_lock_file_version("test.lock", "not json").is_ok());
+        assert!(check_lock_file_version("test.lock", r#"{"version": 2, "packages": []}"#).is_err());
+    }
+
+    #[test]
+    fn written_lock_file_has_version() {
+        let lock_file = LockFileJson {
+            version: LOCK_FILE_VERSION,
+            cabs: HashMap::new(),
+            packages: Vec::new(),
+        };
+        let json = serde_json::to_string(&lock_file).unwrap();
+        assert!(json.starts_with(&format!("{{\"version\":{}", LOCK_FILE_VERSION)));
+    }
+
+    #[test]
+    fn lock_file_age() {
+        let dir = TempDir::new("lock_file_age");
+        let path = dir.join("msvcup.lock");
+        let path_str = path.to_str().unwrap();
+        assert_eq!(lock_file_age_seconds(path_str), None);
+
+        std::fs::write(&path, make_lock_json(&[])).unwrap();
+        assert!(lock_file_age_seconds(path_str).unwrap() < 60);
+        let hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(hour_ago)).unwrap();
+        let age = lock_file_age_seconds(path_str).unwrap();
+        assert!((3600..3660).contains(&age), "{}", age);
     }
 }
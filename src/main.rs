@@ -1,27 +1,54 @@
 mod arch;
 mod autoenv_cmd;
+mod bazel_toolchain_cmd;
+mod cache_index;
+mod cache_key_cmd;
+mod cargo_config_cmd;
+mod casefix_cmd;
 mod channel_kind;
 mod config;
+mod disk_space;
+mod env_cmd;
+mod error;
 mod extra;
 mod fetch_cmd;
+mod file_exclusions;
+mod files_list;
+mod init_cmd;
 mod install;
+mod install_journal;
+mod list_cmd;
 mod lock_file;
 mod lockfile_parse;
 mod manifest;
 mod msi_extract;
+mod output;
 mod packages;
+mod paths_cmd;
+mod payload_filter;
 mod resolve_cmd;
+mod sbom_cmd;
+mod self_check_cmd;
 mod sha;
+mod splat_cmd;
+mod tar_extract;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod tool_version_cmd;
 mod util;
+mod vcvars_template;
+mod vswhere_cmd;
 mod zip_extract;
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use indicatif::MultiProgress;
-use packages::{
-    ManifestUpdate, MsvcupPackage, MsvcupPackageKind, PackageId, PayloadId, get_packages,
-    identify_package, identify_payload,
-};
+use error::{ErrorKind, bail_kind};
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use output::OutputFormat;
+use packages::{ManifestUpdate, MsvcupPackage, MsvcupPackageKind};
+use sha::Sha256;
+use std::io::IsTerminal;
 
 /// Writer that routes output through MultiProgress::suspend() so log lines
 /// don't clobber progress bars.
@@ -45,55 +72,524 @@ struct Cli {
     #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Only log warnings and errors and hide progress bars. Results and the
+    /// install summary are still printed.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Seconds to wait for a lock held by another msvcup process before failing.
+    /// Commands that install or download wait forever by default, the others
+    /// give up after 60 seconds.
+    #[arg(long, global = true)]
+    lock_timeout: Option<u64>,
+
+    /// Download cache shared by every installation directory (overrides
+    /// MSVCUP_GLOBAL_CACHE env var). A command's --cache-dir still takes precedence.
+    #[arg(long, global = true)]
+    global_cache: Option<String>,
+
+    /// Channel manifest URL to use instead of the Visual Studio release channel
+    /// (e.g. a private mirror or an archived channel)
+    #[arg(long, global = true)]
+    channel_url: Option<String>,
+
+    /// Output format of list, list-payloads, paths, self-check and cache key: text, json
+    /// (a single JSON array) or ndjson (one JSON record per line)
+    #[arg(long, global = true, value_parser = parse_output_format, default_value = "text")]
+    format: OutputFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+// Parsed once per run, so the size of the install variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
-    /// List all available packages
-    List,
+    /// List all available packages, marking the installed ones
+    List {
+        /// Only list the packages that are installed
+        #[arg(long)]
+        installed_only: bool,
+        /// Show how much each package downloads and in how many payloads
+        #[arg(long)]
+        sizes: bool,
+        /// Channel to list: release, preview, a channel manifest URL or all (release
+        /// and preview, tagging the packages only in preview)
+        #[arg(long, value_parser = parse_list_channel)]
+        channel: Option<list_cmd::ChannelSelection>,
+        /// List the host/target architecture combinations an msvc package (e.g.
+        /// msvc-14.43.17.13) provides instead of the packages
+        #[arg(long, value_name = "PACKAGE", conflicts_with_all = ["installed_only", "sizes"])]
+        list_archs: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+    },
     /// List all payloads
-    ListPayloads,
+    ListPayloads {
+        /// Only list payloads with a download size of at least this many bytes
+        #[arg(long)]
+        min_download_size: Option<u64>,
+        /// Only list payloads with a download size of at most this many bytes
+        #[arg(long)]
+        max_download_size: Option<u64>,
+        /// Show each payload's download size and the total of the listed payloads
+        #[arg(long)]
+        sizes: bool,
+        /// Show the sizes in bytes instead of KiB, MiB or GiB
+        #[arg(long, requires = "sizes")]
+        bytes: bool,
+    },
     /// Install packages
+    ///
+    /// Packages and settings that aren't given are read from the msvcup.toml in
+    /// the current directory or the closest of its parents.
     Install {
-        /// Packages to install (e.g. msvc-14.30.17.6)
+        /// Packages to install (e.g. msvc-14.30.17.6). On a terminal, a kind
+        /// without a version (e.g. msvc) asks which version to install.
+        /// `@<file>` reads the packages from a file, one per line, `@-` from stdin.
+        /// Without packages, MSVCUP_PACKAGES is read as a `:`-separated list
         packages: Vec<String>,
-        /// Path to lock file
+        /// Fail on a package kind without a version instead of asking for one
         #[arg(long)]
-        lock_file: String,
+        non_interactive: bool,
+        /// Channel to install from: release, preview or a channel manifest URL
+        #[arg(long, value_parser = parse_channel)]
+        channel: Option<channel_kind::ChannelKind>,
+        /// Path to lock file, can be repeated to combine lock files (e.g. a shared
+        /// SDK lock file and a per-project MSVC one); earlier ones take precedence
+        #[arg(long, env = "MSVCUP_LOCK_FILE")]
+        lock_file: Vec<String>,
+        /// Path to msvcup.toml config file (default: found from the current directory up)
+        #[arg(long)]
+        config: Option<String>,
+        /// Fail instead of updating the lock file when it doesn't match the packages,
+        /// so the VS manifest is never fetched
+        #[arg(long)]
+        locked: bool,
+        /// When the lock files need updating, rewrite all of them instead of only
+        /// the first one
+        #[arg(long)]
+        update_all: bool,
         /// Manifest update policy
-        #[arg(long, value_parser = parse_manifest_update)]
+        #[arg(long, value_parser = parse_manifest_update, default_value = "off", env = "MSVCUP_MANIFEST_UPDATE")]
         manifest_update: ManifestUpdate,
+        /// With --manifest-update always, keep using a matching lock file that
+        /// was written at most this many seconds ago
+        #[arg(long, value_name = "SECONDS")]
+        max_manifest_age: Option<u64>,
         /// Cache directory
         #[arg(long)]
         cache_dir: Option<String>,
         /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
         #[arg(long)]
         install_dir: Option<String>,
+        /// Install packages of one kind in another directory (e.g. sdk=D:\msvcup),
+        /// can be repeated
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+        /// Print each package's install directory after installing
+        #[arg(long)]
+        print_paths: bool,
+        /// Write a JSON summary of the installed packages, files and downloads to this path
+        #[arg(long)]
+        telemetry_output: Option<String>,
+        /// Write the paths of the installed files, relative to the installation
+        /// directory, to this file (e.g. to gitignore a vendored toolchain)
+        #[arg(long)]
+        files_list: Option<String>,
+        /// Format of --files-list: plain (one path per line) or gitignore (patterns
+        /// for a .gitignore in the installation directory)
+        #[arg(long, value_parser = parse_files_list_format, default_value = "plain", requires = "files_list")]
+        files_list_format: files_list::FilesListFormat,
+        /// Only install packages of this kind from the lock file (e.g. sdk), can be repeated
+        #[arg(long, value_parser = parse_package_kind)]
+        only: Vec<MsvcupPackageKind>,
+        /// Only install the payloads listed in this file, one sha256 or payload file
+        /// name per line
+        #[arg(long)]
+        payload_filter_file: Option<String>,
+        /// Don't extract the files matching this glob, relative to the package
+        /// directory (e.g. '*.winmd'), can be repeated
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+        /// Skip the payloads that only hold debug symbols (.pdb files)
+        #[arg(long)]
+        strip_debug: bool,
+        /// Leave the debug symbol payloads out of the lock file when it's written
+        #[arg(long)]
+        exclude_debug_info: bool,
+        /// Leave the VC++ runtime redistributables out of the lock file when it's
+        /// written
+        #[arg(long)]
+        exclude_redist: bool,
+        /// If a payload fails to install, remove what this run installed of its
+        /// package, so the package directory is left as it was before the run
+        #[arg(long)]
+        transactional: bool,
+        /// If a payload fails to install, install the other payloads and packages
+        /// anyway, then list the failures and exit with an error. Debug and
+        /// redistributable payloads are optional, a package missing others may
+        /// get no vcvars or env files
+        #[arg(long, conflicts_with = "transactional")]
+        keep_going: bool,
+        /// Also generate vcvars and env files that only set INCLUDE and LIB, listing
+        /// the tool directories in MSVCUP_BIN instead of prepending them to PATH
+        /// (vcvars-{arch}-nopath.bat and env-{arch}-nopath.json)
+        #[arg(long)]
+        no_vcvars_path: bool,
+        /// Also generate vcvars scripts from this template, named after it
+        /// (vcvars-{arch}-{name}.bat), see the README for its placeholders
+        #[arg(long)]
+        vcvars_template: Option<String>,
+        /// Target CPU architecture of the lock file and --rsp-dir (default: the
+        /// config's target_arch, else the native one)
+        #[arg(long, value_parser = parse_arch)]
+        target_cpu: Option<arch::Arch>,
+        /// Write cl.rsp and link.rsp with the include and library directories of
+        /// the installed msvc and sdk packages to this directory
+        #[arg(long, value_name = "DIR")]
+        rsp_dir: Option<String>,
+        /// Don't check that the cache and install volumes have room for the
+        /// estimated download and install size
+        #[arg(long)]
+        skip_disk_check: bool,
+        /// Download a payload whose sha256 doesn't match the lock file again, up to
+        /// N times (1 if N is omitted), before failing
+        #[arg(long, value_name = "N", num_args = 0..=1, default_value = "0", default_missing_value = "1")]
+        retry_corrupt: u32,
+        /// Also include packages for this language (e.g. ja-JP) when updating the
+        /// lock file, in addition to neutral and en-US, can be repeated
+        #[arg(long, value_parser = parse_language)]
+        language: Vec<packages::Language>,
+    },
+    /// Delete the cached channel and VS manifests so the next command fetches them again
+    ClearManifestCache {
+        /// Channel to clear: release, preview or a channel manifest URL
+        /// (clears every cached channel if omitted)
+        #[arg(value_parser = parse_channel)]
+        channel: Option<channel_kind::ChannelKind>,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+    },
+    /// Shrink the cached VS manifest to the packages msvcup uses, so reading it is
+    /// faster. Lossy: list-payloads only shows their payloads until the next fetch.
+    PruneManifest {
+        /// Channel whose manifest to prune: release, preview or a channel manifest URL
+        #[arg(value_parser = parse_channel)]
+        channel: Option<channel_kind::ChannelKind>,
+        /// Keep the unpruned manifest next to it as `latest.full`
+        #[arg(long)]
+        keep_full: bool,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+    },
+    /// Write an msvcup.toml and lock file pinning the newest (or given) msvc and sdk
+    Init {
+        /// msvc version to pin: latest, a full version or a prefix like 14.43
+        #[arg(long, value_parser = parse_version_spec, default_value = "latest")]
+        msvc: init_cmd::VersionSpec,
+        /// sdk version to pin: latest, a full version or a prefix like 10.0.22621
+        #[arg(long, value_parser = parse_version_spec, default_value = "latest")]
+        sdk: init_cmd::VersionSpec,
+        /// Also pin cmake: latest, a full version or a prefix like 3.31
+        #[arg(long, value_parser = parse_version_spec)]
+        cmake: Option<init_cmd::VersionSpec>,
+        /// Also pin ninja: latest, a full version or a prefix like 1.12
+        #[arg(long, value_parser = parse_version_spec)]
+        ninja: Option<init_cmd::VersionSpec>,
+        /// Pick the packages to pin from a checklist of the versions found
+        #[arg(long)]
+        interactive: bool,
+        /// Target CPU architecture (default: the native one)
+        #[arg(long, value_parser = parse_arch)]
+        target_cpu: Option<arch::Arch>,
+        /// Directory to write msvcup.toml and msvcup.lock to
+        #[arg(long, default_value = ".")]
+        dir: String,
+        /// Manifest update policy
+        #[arg(long, value_parser = parse_manifest_update, default_value = "daily")]
+        manifest_update: ManifestUpdate,
+        /// Replace an existing msvcup.toml and msvcup.lock
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the install directories of installed packages
+    Paths {
+        /// Packages to print paths for (e.g. msvc-14.30.17.6)
+        packages: Vec<String>,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+        /// Print the paths as JSON (same as --format json)
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the toolchain environment (INCLUDE, LIB, PATH, ...) of installed packages
+    Env {
+        /// Packages to print the environment of (e.g. msvc-14.30.17.6)
+        packages: Vec<String>,
+        /// Target CPU architecture
+        #[arg(long, value_parser = parse_arch)]
+        target_cpu: Option<arch::Arch>,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+        /// Append the environment to the GitHub Actions GITHUB_ENV and GITHUB_PATH
+        /// files instead of printing it
+        #[arg(long, conflicts_with = "azure")]
+        github: bool,
+        /// Print the environment as Azure Pipelines logging commands (the default
+        /// when TF_BUILD is set)
+        #[arg(long)]
+        azure: bool,
+    },
+    /// Print or update a .cargo/config.toml that builds Rust's MSVC target with the packages
+    CargoConfig {
+        /// Packages providing the toolchain (e.g. msvc-14.30.17.6 sdk-10.0.22621.7)
+        packages: Vec<String>,
+        /// Target CPU architecture
+        #[arg(long, value_parser = parse_arch)]
+        target_cpu: Option<arch::Arch>,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+        /// Merge the settings into this file (e.g. .cargo/config.toml) instead of
+        /// printing them, keeping its other settings
+        #[arg(long)]
+        out: Option<String>,
+    },
+    /// Write a Bazel package that registers the packages as a C++ toolchain
+    BazelToolchain {
+        /// The msvc and sdk packages (e.g. msvc-14.30.17.6 sdk-10.0.22621.7)
+        packages: Vec<String>,
+        /// Output directory of the Bazel package
+        #[arg(long)]
+        out: String,
+        /// Target CPU architecture
+        #[arg(long, value_parser = parse_arch)]
+        target_cpu: Option<arch::Arch>,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+    },
+    /// Lay out the headers and libraries of msvc and sdk packages the way
+    /// cargo-xwin and clang cross setups expect them
+    Splat {
+        /// The msvc and sdk packages (e.g. msvc-14.30.17.6 sdk-10.0.22621.7)
+        packages: Vec<String>,
+        /// Output directory
+        #[arg(long)]
+        out: String,
+        /// Target CPU architectures, comma separated
+        #[arg(long, value_parser = parse_arch, value_delimiter = ',', required = true)]
+        target_cpu: Vec<arch::Arch>,
+        /// Symlink the files instead of copying them
+        #[arg(long)]
+        link: bool,
+        /// Put the libraries directly in crt/lib and sdk/lib/{ucrt,um}, without
+        /// an arch subdirectory (needs exactly one --target-cpu)
+        #[arg(long)]
+        copy_into_single_arch: bool,
+        /// Add lowercase symlinks for mixed-case headers and libraries (see casefix)
+        #[arg(long)]
+        casefix: bool,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+    },
+    /// Make the files of a package or splat directory resolvable in lowercase on
+    /// a case-sensitive filesystem
+    Casefix {
+        /// A package directory (e.g. <install-dir>/sdk-10.0.22621.7) or splat output directory
+        dir: String,
+        /// lowercase renames every file and directory, symlinks adds lowercase aliases
+        #[arg(long, value_parser = parse_casefix_mode, default_value = "symlinks")]
+        mode: casefix_cmd::CasefixMode,
     },
     /// Resolve packages and place shim executables that install on first use
     Resolve {
-        /// Path to msvcup.toml config file
+        /// Path to msvcup.toml config file (default: found from the current directory up)
         #[arg(long)]
-        config: String,
-        /// Output directory for shim executables
+        config: Option<String>,
+        /// Output directory for shim executables (default: out_dir from the config)
         #[arg(long)]
-        out_dir: String,
+        out_dir: Option<String>,
         /// Manifest update policy
         #[arg(long, value_parser = parse_manifest_update, default_value = "off")]
         manifest_update: ManifestUpdate,
     },
-    /// Fetch a package URL
+    /// Check whether a newer version of msvcup is available
+    SelfCheck {
+        /// URL of a JSON version feed with `version` and `url` fields
+        /// (overrides MSVCUP_VERSION_FEED_URL env var and the GitHub releases default)
+        #[arg(long)]
+        feed_url: Option<String>,
+    },
+    /// Write a software bill of materials for the payloads in a lock file
+    Sbom {
+        /// Path to lock file
+        #[arg(long)]
+        lock_file: String,
+        /// Document format: cyclonedx or spdx-json
+        #[arg(long, value_parser = parse_sbom_format)]
+        sbom_format: sbom_cmd::SbomFormat,
+        /// Path to write the document to
+        #[arg(long)]
+        out: String,
+        /// Cache directory to read MSIs from, to list their cabs under them
+        #[arg(long)]
+        cache_dir: Option<String>,
+    },
+    /// Print the version of an installed package's tool (cl.exe, cmake, ninja, nasm or python)
+    ///
+    /// Off Windows the tool runs under Wine if it's in PATH. Without Wine the file
+    /// version is read from the version resource of the installed executable;
+    /// --archive reads it from an executable inside a cached VSIX or ZIP instead.
+    ToolVersion {
+        /// Installed package (e.g. msvc-14.30.17.6)
+        #[arg(required_unless_present = "archive")]
+        package: Option<String>,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+        /// Instead of a package, read the file version of an executable inside this
+        /// cached VSIX or ZIP
+        #[arg(long, requires = "file", conflicts_with = "package")]
+        archive: Option<String>,
+        /// Path of the executable inside the archive (e.g. VC/Tools/MSVC/14.43.34808/bin/Hostx64/x64/cl.exe)
+        #[arg(long, requires = "archive")]
+        file: Option<String>,
+    },
+    /// Print vswhere-compatible output for the installed msvc toolsets, so tools that
+    /// locate MSVC with vswhere find them. Supports -latest, -products, -requires,
+    /// -requiresAny, -property and -format json|value|text; other flags are ignored
+    Vswhere {
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+        /// vswhere arguments (e.g. -latest -property installationPath)
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Commands for caching msvcup's directories in CI
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+    /// Fetch a URL, or every payload and cab of a lock file, into the cache
     Fetch {
-        /// URL to fetch
-        url: String,
+        /// http(s) URL to fetch
+        #[arg(required_unless_present = "from_lock_file")]
+        url: Option<String>,
+        /// Expected sha256 of the URL's content, a download with another hash
+        /// fails and is removed
+        #[arg(long, value_parser = parse_sha256, requires = "url")]
+        sha256: Option<Sha256>,
+        /// Fetch every payload and cab listed in this lock file
+        #[arg(long, conflicts_with = "url")]
+        from_lock_file: Option<String>,
+        /// Write the file to this path instead of the cache, or to stdout with
+        /// `-` (the hash is then printed to stderr)
+        #[arg(long, requires = "url")]
+        output: Option<String>,
+        /// With --output, also put the file in the cache
+        #[arg(long, requires = "output")]
+        also_cache: bool,
+        /// Allow plain http URLs, e.g. to fetch from a local test server
+        #[arg(long)]
+        allow_http: bool,
+        /// Cache directory
+        #[arg(long)]
+        cache_dir: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Print a key for a CI cache of the downloads or installs of a lock file
+    ///
+    /// The key only changes when the lock file's content (not its formatting),
+    /// the msvcup version, the channel or, for the install scope, the host
+    /// architecture changes.
+    Key {
+        /// Path to lock file
+        #[arg(long)]
+        lock_file: String,
+        /// What the cache holds: download (the cache directory) or install (the
+        /// package directories)
+        #[arg(long, value_parser = parse_cache_scope, default_value = "download")]
+        scope: cache_key_cmd::CacheScope,
         /// Cache directory
         #[arg(long)]
         cache_dir: Option<String>,
+        /// Installation directory (overrides MSVCUP_INSTALL_DIR env var and platform default)
+        #[arg(long)]
+        install_dir: Option<String>,
+        /// Packages of this kind are installed in another directory (e.g.
+        /// sdk=D:\msvcup), can be repeated (default: the install_roots of msvcup.toml)
+        #[arg(long, value_name = "KIND=PATH", value_parser = parse_install_root)]
+        install_root: Vec<(MsvcupPackageKind, String)>,
+        /// Print the key and the directories to cache as JSON (same as --format json)
+        #[arg(long)]
+        json: bool,
     },
 }
 
+fn parse_files_list_format(s: &str) -> Result<files_list::FilesListFormat, String> {
+    files_list::FilesListFormat::from_name(s).ok_or_else(|| {
+        format!(
+            "invalid files list format '{}', expected 'plain' or 'gitignore'",
+            s
+        )
+    })
+}
+
+fn parse_cache_scope(s: &str) -> Result<cache_key_cmd::CacheScope, String> {
+    cache_key_cmd::CacheScope::from_name(s).ok_or_else(|| {
+        format!(
+            "invalid cache scope '{}', expected 'download' or 'install'",
+            s
+        )
+    })
+}
+
+fn parse_sha256(s: &str) -> Result<Sha256, String> {
+    Sha256::parse_hex(s).ok_or_else(|| format!("invalid sha256 '{}', expected 64 hex digits", s))
+}
+
 fn parse_manifest_update(s: &str) -> Result<ManifestUpdate, String> {
     match s {
         "off" => Ok(ManifestUpdate::Off),
@@ -106,6 +602,172 @@ fn parse_manifest_update(s: &str) -> Result<ManifestUpdate, String> {
     }
 }
 
+fn parse_output_format(s: &str) -> Result<OutputFormat, String> {
+    OutputFormat::from_name(s).ok_or_else(|| {
+        format!(
+            "invalid output format '{}', expected 'text', 'json' or 'ndjson'",
+            s
+        )
+    })
+}
+
+fn parse_sbom_format(s: &str) -> Result<sbom_cmd::SbomFormat, String> {
+    sbom_cmd::SbomFormat::from_name(s).ok_or_else(|| {
+        format!(
+            "invalid SBOM format '{}', expected 'cyclonedx' or 'spdx-json'",
+            s
+        )
+    })
+}
+
+fn parse_casefix_mode(s: &str) -> Result<casefix_cmd::CasefixMode, String> {
+    casefix_cmd::CasefixMode::from_name(s).ok_or_else(|| {
+        format!(
+            "invalid casefix mode '{}', expected 'lowercase' or 'symlinks'",
+            s
+        )
+    })
+}
+
+fn parse_version_spec(s: &str) -> Result<init_cmd::VersionSpec, String> {
+    if s == "latest" {
+        return Ok(init_cmd::VersionSpec::Latest);
+    }
+    if !util::is_valid_version(s) {
+        return Err(format!(
+            "invalid version '{}', expected 'latest' or a version",
+            s
+        ));
+    }
+    Ok(init_cmd::VersionSpec::Prefix(s.to_string()))
+}
+
+fn parse_arch(s: &str) -> Result<arch::Arch, String> {
+    arch::Arch::from_str_ignore_case(s).ok_or_else(|| {
+        format!(
+            "invalid architecture '{}', expected 'x64', 'x86', 'arm', 'arm64' or 'arm64ec'",
+            s
+        )
+    })
+}
+
+fn parse_package_kind(s: &str) -> Result<MsvcupPackageKind, String> {
+    s.parse::<MsvcupPackageKind>().map_err(|e| e.to_string())
+}
+
+fn parse_install_root(s: &str) -> Result<(MsvcupPackageKind, String), String> {
+    let Some((kind, path)) = s.split_once('=') else {
+        return Err("expected KIND=PATH (e.g. sdk=D:\\msvcup)".to_string());
+    };
+    if path.is_empty() {
+        return Err(format!("no path given for '{}'", kind));
+    }
+    Ok((parse_package_kind(kind)?, path.to_string()))
+}
+
+fn parse_language(s: &str) -> Result<packages::Language, String> {
+    if s.is_empty() {
+        return Err("language must not be empty".to_string());
+    }
+    Ok(packages::Language::from_str(s))
+}
+
+fn parse_channel(s: &str) -> Result<channel_kind::ChannelKind, String> {
+    match s {
+        "release" => Ok(channel_kind::ChannelKind::Release),
+        "preview" => Ok(channel_kind::ChannelKind::Preview),
+        url => channel_kind::ChannelKind::custom(url).map_err(|e| e.to_string()),
+    }
+}
+
+/// Replace every `@<file>` argument with the packages listed in the file, one
+/// per line with `#` starting a comment, like the response files of rustc and
+/// link.exe. `@-` reads the list from stdin.
+fn expand_package_args(args: &[String]) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for arg in args {
+        let Some(path) = arg.strip_prefix('@') else {
+            expanded.push(arg.clone());
+            continue;
+        };
+        let content = if path == "-" {
+            std::io::read_to_string(std::io::stdin()).context("reading packages from stdin")?
+        } else {
+            fs_err::read_to_string(path)
+                .with_context(|| format!("reading packages from '{}'", path))?
+        };
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                expanded.push(line.to_string());
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+fn parse_list_channel(s: &str) -> Result<list_cmd::ChannelSelection, String> {
+    match s {
+        "all" => Ok(list_cmd::ChannelSelection::All),
+        _ => parse_channel(s).map(list_cmd::ChannelSelection::One),
+    }
+}
+
+/// A command's `--channel`, or the channel of the global `--channel-url`.
+fn command_channel<T>(channel: Option<T>, channel_url: Option<&str>, default: T) -> Result<T> {
+    match (channel, channel_url) {
+        (Some(_), Some(_)) => bail_kind!(
+            ErrorKind::Usage,
+            "--channel and --channel-url can't be combined"
+        ),
+        (Some(channel), None) => Ok(channel),
+        (None, _) => Ok(default),
+    }
+}
+
+/// The installation directory of a command reading installed packages:
+/// `--install-dir` and `--install-root`, with the ones left out taken from the
+/// msvcup.toml found from the current directory up, like `install` does. For
+/// `informational` commands a config that can't be read is only a warning, so a
+/// broken msvcup.toml in a parent directory doesn't stop them.
+fn installed_msvcup_dir(
+    default_msvcup_dir: manifest::MsvcupDir,
+    install_dir: Option<String>,
+    install_roots: Vec<(MsvcupPackageKind, String)>,
+    informational: bool,
+) -> Result<manifest::MsvcupDir> {
+    let mut args = config::InstallArgs {
+        install_dir,
+        install_roots,
+        ..Default::default()
+    };
+    match config::MsvcupConfig::discover(&std::env::current_dir()?) {
+        Ok(Some((_, config))) => args.merge_install_dirs(&config),
+        Ok(None) => {}
+        Err(e) if informational => log::warn!("ignoring the project config: {:#}", e),
+        Err(e) => return Err(e),
+    }
+    let mut msvcup_dir = match args.install_dir {
+        Some(dir) => manifest::MsvcupDir::with_path(dir.into()),
+        None => default_msvcup_dir,
+    };
+    for (kind, root) in args.install_roots {
+        msvcup_dir.set_package_root(kind, root.into());
+    }
+    Ok(msvcup_dir)
+}
+
+/// The packages of MSVCUP_PACKAGES, separated by `:`.
+fn env_package_args() -> Vec<String> {
+    std::env::var("MSVCUP_PACKAGES")
+        .unwrap_or_default()
+        .split(':')
+        .map(str::trim)
+        .filter(|pkg| !pkg.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
 fn parse_msvcup_packages(pkg_strings: &[String]) -> Result<Vec<MsvcupPackage>> {
     let mut pkgs = Vec::new();
     for s in pkg_strings {
@@ -113,162 +775,667 @@ fn parse_msvcup_packages(pkg_strings: &[String]) -> Result<Vec<MsvcupPackage>> {
             Ok(pkg) => {
                 util::insert_sorted(&mut pkgs, pkg, MsvcupPackage::order);
             }
-            Err(e) => bail!("invalid package '{}': {}", s, e),
+            Err(e) => bail_kind!(ErrorKind::Usage, "invalid package '{}': {}", s, e),
         }
     }
     Ok(pkgs)
 }
 
+/// [`parse_msvcup_packages`], asking which version to use for each package kind
+/// given without one (e.g. `msvc`) from the versions in the manifest.
+async fn pick_bare_kinds(
+    client: &reqwest::Client,
+    msvcup_dir: &manifest::MsvcupDir,
+    channel_kind: &channel_kind::ChannelKind,
+    manifest_update: ManifestUpdate,
+    pkg_strings: &[String],
+) -> Result<Vec<MsvcupPackage>> {
+    let (bare, versioned): (Vec<&String>, Vec<&String>) = pkg_strings
+        .iter()
+        .partition(|s| s.parse::<MsvcupPackageKind>().is_ok());
+    let versioned: Vec<String> = versioned.into_iter().cloned().collect();
+    let mut pkgs = parse_msvcup_packages(&versioned)?;
+    if bare.is_empty() {
+        return Ok(pkgs);
+    }
+    let manifest =
+        manifest::read_vs_manifest(client, msvcup_dir, channel_kind, manifest_update).await?;
+    let entries = list_cmd::list_packages(&manifest);
+    for kind in bare {
+        let kind = kind
+            .parse::<MsvcupPackageKind>()
+            .expect("partitioned above");
+        let pkg = init_cmd::prompt_version(&entries, kind)?;
+        util::insert_sorted(&mut pkgs, pkg, MsvcupPackage::order);
+    }
+    Ok(pkgs)
+}
+
+/// [`parse_msvcup_packages`] for commands that build one environment from the
+/// packages, where two versions of a kind (e.g. two msvc toolsets) would
+/// conflict. Fails before anything is read instead of mixing their paths.
+fn parse_env_packages(command: &str, pkg_strings: &[String]) -> Result<Vec<MsvcupPackage>> {
+    let pkgs = parse_msvcup_packages(pkg_strings)?;
+    if let Some((a, b)) = packages::find_same_kind(&pkgs) {
+        bail_kind!(
+            ErrorKind::Usage,
+            "'{}' can only use one {} package, got {} and {}",
+            command,
+            a.kind,
+            a,
+            b
+        );
+    }
+    Ok(pkgs)
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> std::process::ExitCode {
+    match run().await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            std::process::ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+async fn run() -> Result<()> {
     let mp = MultiProgress::new();
 
     let cli = Cli::parse();
 
     // Route log output through MultiProgress so logs don't clobber progress bars
     let mp_writer = IndicatifWriter(mp.clone());
-    let default_filter = if cli.verbose { "debug" } else { "info" };
+    let default_filter = if cli.verbose {
+        "debug"
+    } else if cli.quiet {
+        mp.set_draw_target(ProgressDrawTarget::hidden());
+        "warn"
+    } else {
+        "info"
+    };
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
         .target(env_logger::Target::Pipe(Box::new(mp_writer)))
         .init();
+    let informational = matches!(
+        cli.command,
+        Commands::List { .. }
+            | Commands::ListPayloads { .. }
+            | Commands::Paths { .. }
+            | Commands::Env { .. }
+            | Commands::SelfCheck { .. }
+            | Commands::Sbom { .. }
+            | Commands::ToolVersion { .. }
+            | Commands::Vswhere { .. }
+            | Commands::Cache { .. }
+    );
+    lock_file::set_timeout(match cli.lock_timeout {
+        Some(secs) => Some(std::time::Duration::from_secs(secs)),
+        None if informational => Some(lock_file::INFORMATIONAL_TIMEOUT),
+        None => None,
+    });
     let client = reqwest::Client::builder().build()?;
     let default_msvcup_dir = manifest::MsvcupDir::new()?;
+    let global_cache = cli.global_cache.clone().or_else(|| {
+        std::env::var("MSVCUP_GLOBAL_CACHE")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+    });
+    let channel_kind = match &cli.channel_url {
+        Some(url) => channel_kind::ChannelKind::custom(url)?,
+        None => channel_kind::ChannelKind::Release,
+    };
 
     match cli.command {
-        Commands::List => list_command(&client, &default_msvcup_dir).await,
-        Commands::ListPayloads => list_payloads_command(&client, &default_msvcup_dir).await,
+        Commands::List {
+            installed_only,
+            sizes,
+            channel,
+            list_archs,
+            install_root,
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, None, install_root, informational)?;
+            let channels = command_channel(
+                channel,
+                cli.channel_url.as_deref(),
+                list_cmd::ChannelSelection::One(channel_kind),
+            )?;
+            if let Some(pkg_string) = list_archs {
+                let list_cmd::ChannelSelection::One(channel_kind) = channels else {
+                    bail_kind!(ErrorKind::Usage, "--list-archs needs a single channel");
+                };
+                let msvcup_pkg = parse_msvcup_packages(&[pkg_string])?.remove(0);
+                return list_cmd::list_archs_command(
+                    &client,
+                    &msvcup_dir,
+                    &channel_kind,
+                    &msvcup_pkg,
+                    cli.format,
+                )
+                .await;
+            }
+            list_cmd::list_command(
+                &client,
+                &msvcup_dir,
+                &channels,
+                installed_only,
+                sizes,
+                cli.format,
+            )
+            .await
+        }
+        Commands::ListPayloads {
+            min_download_size,
+            max_download_size,
+            sizes,
+            bytes,
+        } => {
+            list_cmd::list_payloads_command(
+                &client,
+                &default_msvcup_dir,
+                &channel_kind,
+                min_download_size,
+                max_download_size,
+                sizes,
+                bytes,
+                cli.format,
+            )
+            .await
+        }
         Commands::Install {
             packages: pkg_strings,
+            non_interactive,
+            channel,
             lock_file,
+            config,
+            locked,
+            update_all,
             manifest_update,
+            max_manifest_age,
             cache_dir,
             install_dir,
+            install_root,
+            print_paths,
+            telemetry_output,
+            files_list,
+            files_list_format,
+            only,
+            payload_filter_file,
+            exclude,
+            strip_debug,
+            exclude_debug_info,
+            exclude_redist,
+            transactional,
+            keep_going,
+            no_vcvars_path,
+            vcvars_template,
+            target_cpu,
+            rsp_dir,
+            skip_disk_check,
+            retry_corrupt,
+            language,
         } => {
-            let msvcup_dir = match install_dir {
+            let channel_kind = command_channel(channel, cli.channel_url.as_deref(), channel_kind)?;
+            let pkg_strings = if pkg_strings.is_empty() {
+                env_package_args()
+            } else {
+                pkg_strings
+            };
+            // Prompting can't use a stdin the package list was read from
+            let interactive = !non_interactive
+                && !pkg_strings.iter().any(|s| s == "@-")
+                && std::io::stdin().is_terminal()
+                && std::io::stderr().is_terminal();
+            let pkg_strings = expand_package_args(&pkg_strings)?;
+            let packages = if interactive {
+                // The manifest cache of the --install-dir, a config's isn't read yet
+                let msvcup_dir = install_dir
+                    .as_ref()
+                    .map(|dir| manifest::MsvcupDir::with_path(dir.into()));
+                pick_bare_kinds(
+                    &client,
+                    msvcup_dir.as_ref().unwrap_or(&default_msvcup_dir),
+                    &channel_kind,
+                    manifest_update,
+                    &pkg_strings,
+                )
+                .await?
+            } else {
+                parse_msvcup_packages(&pkg_strings)?
+            };
+            let mut args = config::InstallArgs {
+                packages,
+                lock_files: lock_file,
+                cache_dir,
+                install_dir,
+                install_roots: install_root,
+                target_arch: target_cpu,
+                vcvars_path: no_vcvars_path.then_some(false),
+                exclude,
+            };
+            let project_config = match config {
+                Some(path) => {
+                    let path = std::path::PathBuf::from(path);
+                    let config = config::MsvcupConfig::from_file(&path)?;
+                    Some((path, config))
+                }
+                None if args.packages.is_empty() || args.lock_files.is_empty() => {
+                    config::MsvcupConfig::discover(&std::env::current_dir()?)?
+                }
+                None => None,
+            };
+            if let Some((config_path, config)) = &project_config {
+                args.merge_config(config, config_path)?;
+            }
+            let config::InstallArgs {
+                packages: pkgs,
+                lock_files: lock_file,
+                cache_dir,
+                install_dir,
+                install_roots,
+                target_arch,
+                vcvars_path,
+                exclude,
+            } = args;
+            let cache_dir = cache_dir.or(global_cache);
+            let mut msvcup_dir = match install_dir {
                 Some(dir) => manifest::MsvcupDir::with_path(dir.into()),
                 None => default_msvcup_dir,
             };
-            let pkgs = parse_msvcup_packages(&pkg_strings)?;
-            let target_arch = arch::Arch::native().unwrap_or(arch::Arch::X64);
+            for (kind, root) in install_roots {
+                msvcup_dir.set_package_root(kind, root.into());
+            }
+            let target_arch =
+                target_arch.unwrap_or_else(|| arch::Arch::native().unwrap_or(arch::Arch::X64));
+            let payload_filter = payload_filter_file
+                .map(|path| payload_filter::PayloadFilter::read(std::path::Path::new(&path)))
+                .transpose()?;
+            let file_exclusions = file_exclusions::FileExclusions::new(&exclude)?;
+            let vcvars_template = vcvars_template
+                .map(|path| vcvars_template::VcvarsTemplate::read(std::path::Path::new(&path)))
+                .transpose()?;
+            let mut languages = packages::DEFAULT_LANGUAGES.to_vec();
+            languages.extend(language);
+            let install_start = std::time::Instant::now();
+            let mut stats = telemetry::InstallStats::default();
             install::install_command(
                 &client,
                 &msvcup_dir,
                 &pkgs,
                 &lock_file,
+                locked,
+                update_all,
+                &channel_kind,
                 manifest_update,
+                max_manifest_age,
                 cache_dir.as_deref(),
+                &only,
+                payload_filter.as_ref(),
+                &file_exclusions,
+                strip_debug,
+                transactional,
+                keep_going,
+                vcvars_path.unwrap_or(true),
+                vcvars_template.as_ref(),
+                !skip_disk_check,
+                retry_corrupt,
+                &languages,
                 target_arch,
+                packages::PayloadExclusions {
+                    debug_info: exclude_debug_info,
+                    redist: exclude_redist,
+                },
+                &stats,
                 &mp,
             )
+            .await?;
+            stats.set_elapsed(install_start.elapsed());
+            if cli.format == OutputFormat::Text {
+                print!("{}", stats.summary());
+            }
+            if print_paths {
+                paths_cmd::paths_command(&msvcup_dir, &pkgs, cli.format)?;
+            }
+            let installed_pkgs: Vec<_> = pkgs
+                .iter()
+                .filter(|p| only.is_empty() || only.contains(&p.kind))
+                .cloned()
+                .collect();
+            if let Some(telemetry_output) = telemetry_output {
+                telemetry::Telemetry::collect(&msvcup_dir, &installed_pkgs, &stats)?
+                    .write(std::path::Path::new(&telemetry_output))?;
+            }
+            if let Some(rsp_dir) = rsp_dir {
+                autoenv_cmd::write_rsp_files(
+                    &msvcup_dir,
+                    &installed_pkgs,
+                    target_arch,
+                    std::path::Path::new(&rsp_dir),
+                )?;
+            }
+            if let Some(files_list) = files_list {
+                files_list::write_files_list(
+                    &msvcup_dir,
+                    &installed_pkgs,
+                    std::path::Path::new(&files_list),
+                    files_list_format,
+                )?;
+            }
+            if let Some(table) = stats.failure_table() {
+                eprint!("{}", table);
+            }
+            stats.into_result()
+        }
+        Commands::Init {
+            msvc,
+            sdk,
+            cmake,
+            ninja,
+            interactive,
+            target_cpu,
+            dir,
+            manifest_update,
+            force,
+        } => {
+            let target_arch =
+                target_cpu.unwrap_or_else(|| arch::Arch::native().unwrap_or(arch::Arch::X64));
+            let mut specs = vec![
+                (MsvcupPackageKind::Msvc, msvc),
+                (MsvcupPackageKind::Sdk, sdk),
+            ];
+            specs.extend(cmake.map(|spec| (MsvcupPackageKind::Cmake, spec)));
+            specs.extend(ninja.map(|spec| (MsvcupPackageKind::Ninja, spec)));
+            init_cmd::init_command(
+                &client,
+                &default_msvcup_dir,
+                &channel_kind,
+                manifest_update,
+                std::path::Path::new(&dir),
+                &specs,
+                interactive,
+                target_arch,
+                force,
+            )
             .await
         }
+        Commands::ClearManifestCache {
+            channel,
+            install_dir,
+        } => {
+            let msvcup_dir = match install_dir {
+                Some(dir) => manifest::MsvcupDir::with_path(dir.into()),
+                None => default_msvcup_dir,
+            };
+            match channel {
+                Some(channel) => {
+                    msvcup_dir.clear_manifest_cache(&channel)?;
+                    log::info!("cleared the manifest cache of {}", channel.https_url());
+                }
+                None => {
+                    let count = msvcup_dir.clear_all_manifest_caches()?;
+                    log::info!("cleared {} manifest cache(s)", count);
+                }
+            }
+            Ok(())
+        }
+        Commands::PruneManifest {
+            channel,
+            keep_full,
+            install_dir,
+        } => {
+            let msvcup_dir = match install_dir {
+                Some(dir) => manifest::MsvcupDir::with_path(dir.into()),
+                None => default_msvcup_dir,
+            };
+            let channel = channel.unwrap_or(channel_kind);
+            match msvcup_dir.prune_manifest_cache(&channel, keep_full)? {
+                Some((kept, total)) => log::info!(
+                    "kept {} of {} packages in the manifest of {}",
+                    kept,
+                    total,
+                    channel.https_url()
+                ),
+                None => log::info!("no cached manifest for {}", channel.https_url()),
+            }
+            Ok(())
+        }
+        Commands::Paths {
+            packages: pkg_strings,
+            install_dir,
+            install_root,
+            json,
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let pkgs = parse_msvcup_packages(&pkg_strings)?;
+            let format = if json { OutputFormat::Json } else { cli.format };
+            paths_cmd::paths_command(&msvcup_dir, &pkgs, format)
+        }
+        Commands::Env {
+            packages: pkg_strings,
+            target_cpu,
+            install_dir,
+            install_root,
+            github,
+            azure,
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let pkgs = parse_env_packages("env", &pkg_strings)?;
+            let target_arch =
+                target_cpu.unwrap_or_else(|| arch::Arch::native().unwrap_or(arch::Arch::X64));
+            if let Some(deprecation) = target_arch.deprecation() {
+                log::warn!("--target-cpu {}: {}", target_arch, deprecation);
+            }
+            let output = env_cmd::EnvOutput::from_flags(
+                github,
+                azure,
+                std::env::var_os("TF_BUILD").is_some(),
+            );
+            env_cmd::env_command(&msvcup_dir, &pkgs, target_arch, output)
+        }
+        Commands::CargoConfig {
+            packages: pkg_strings,
+            target_cpu,
+            install_dir,
+            install_root,
+            out,
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let pkgs = parse_env_packages("cargo-config", &pkg_strings)?;
+            let target_arch =
+                target_cpu.unwrap_or_else(|| arch::Arch::native().unwrap_or(arch::Arch::X64));
+            cargo_config_cmd::cargo_config_command(
+                &msvcup_dir,
+                &pkgs,
+                target_arch,
+                out.as_deref().map(std::path::Path::new),
+            )
+        }
+        Commands::BazelToolchain {
+            packages: pkg_strings,
+            out,
+            target_cpu,
+            install_dir,
+            install_root,
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let pkgs = parse_env_packages("bazel-toolchain", &pkg_strings)?;
+            let target_arch =
+                target_cpu.unwrap_or_else(|| arch::Arch::native().unwrap_or(arch::Arch::X64));
+            bazel_toolchain_cmd::bazel_toolchain_command(
+                &msvcup_dir,
+                &pkgs,
+                target_arch,
+                std::path::Path::new(&out),
+            )
+        }
+        Commands::Splat {
+            packages: pkg_strings,
+            out,
+            target_cpu,
+            link,
+            copy_into_single_arch,
+            casefix,
+            install_dir,
+            install_root,
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let pkgs = parse_env_packages("splat", &pkg_strings)?;
+            splat_cmd::splat_command(
+                &msvcup_dir,
+                &pkgs,
+                &target_cpu,
+                std::path::Path::new(&out),
+                if link {
+                    splat_cmd::SplatMode::Link
+                } else {
+                    splat_cmd::SplatMode::Copy
+                },
+                copy_into_single_arch,
+                casefix,
+            )
+        }
+        Commands::Casefix { dir, mode } => {
+            casefix_cmd::casefix_command(std::path::Path::new(&dir), mode)
+        }
         Commands::Resolve {
             config,
             out_dir,
             manifest_update,
         } => {
+            let config_path = match config {
+                Some(path) => std::path::PathBuf::from(path),
+                None => match config::find_config(&std::env::current_dir()?) {
+                    Some(path) => path,
+                    None => bail_kind!(
+                        ErrorKind::Usage,
+                        "no {} found in the current directory or its parents, pass --config",
+                        config::CONFIG_FILE_NAME
+                    ),
+                },
+            };
             resolve_cmd::resolve_command(
                 &client,
                 &default_msvcup_dir,
-                &config,
-                &out_dir,
+                &config_path,
+                out_dir.as_deref().map(std::path::Path::new),
+                &channel_kind,
                 manifest_update,
             )
             .await
         }
-        Commands::Fetch { url, cache_dir } => {
-            fetch_cmd::fetch_command(&client, &url, cache_dir.as_deref()).await
+        Commands::SelfCheck { feed_url } => {
+            let feed_url = feed_url
+                .or_else(|| std::env::var("MSVCUP_VERSION_FEED_URL").ok())
+                .unwrap_or_else(|| self_check_cmd::DEFAULT_FEED_URL.to_string());
+            self_check_cmd::self_check_command(&client, &feed_url, cli.format).await
         }
-    }
-}
-
-async fn list_command(client: &reqwest::Client, msvcup_dir: &manifest::MsvcupDir) -> Result<()> {
-    let (vsman_path, vsman_content) = manifest::read_vs_manifest(
-        client,
-        msvcup_dir,
-        channel_kind::ChannelKind::Release,
-        ManifestUpdate::Off,
-    )
-    .await?;
-
-    let pkgs = get_packages(vsman_path.to_str().unwrap(), &vsman_content)?;
-
-    let mut msvcup_pkgs: Vec<MsvcupPackage> = Vec::new();
-    for (pkg_index, pkg) in pkgs.packages.iter().enumerate() {
-        let maybe_pkg = match identify_package(&pkg.id) {
-            PackageId::MsvcVersionHostTarget { build_version, .. } => {
-                Some(MsvcupPackage::new(MsvcupPackageKind::Msvc, build_version))
-            }
-            PackageId::Msbuild(version) => {
-                Some(MsvcupPackage::new(MsvcupPackageKind::Msbuild, version))
-            }
-            PackageId::Diasdk => Some(MsvcupPackage::new(
-                MsvcupPackageKind::Diasdk,
-                pkg.version.clone(),
-            )),
-            PackageId::Ninja(version) => {
-                Some(MsvcupPackage::new(MsvcupPackageKind::Ninja, version))
-            }
-            PackageId::Cmake(version) => {
-                Some(MsvcupPackage::new(MsvcupPackageKind::Cmake, version))
+        Commands::Sbom {
+            lock_file,
+            sbom_format,
+            out,
+            cache_dir,
+        } => {
+            let cache_dir = cache_dir
+                .or(global_cache)
+                .map(std::path::PathBuf::from)
+                .unwrap_or_else(|| default_msvcup_dir.path(&["cache"]));
+            sbom_cmd::sbom_command(
+                &lock_file,
+                &cache_dir,
+                sbom_format,
+                std::path::Path::new(&out),
+            )
+        }
+        Commands::ToolVersion {
+            package,
+            install_dir,
+            install_root,
+            archive,
+            file,
+        } => {
+            if let (Some(archive), Some(file)) = (archive, file) {
+                return tool_version_cmd::archive_tool_version_command(
+                    std::path::Path::new(&archive),
+                    &file,
+                );
             }
-            _ => None,
-        };
-        if let Some(msvcup_pkg) = maybe_pkg {
-            util::insert_sorted(&mut msvcup_pkgs, msvcup_pkg, MsvcupPackage::order);
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let pkgs = parse_msvcup_packages(&package.into_iter().collect::<Vec<_>>())?;
+            tool_version_cmd::tool_version_command(&msvcup_dir, &pkgs[0])
         }
-
-        for payload in pkgs.payloads_from_pkg_index(pkg_index) {
-            if identify_payload(&payload.file_name, arch::Arch::X64) == PayloadId::Sdk {
-                let msvcup_pkg = MsvcupPackage::new(MsvcupPackageKind::Sdk, pkg.version.clone());
-                util::insert_sorted(&mut msvcup_pkgs, msvcup_pkg, MsvcupPackage::order);
+        Commands::Vswhere {
+            install_dir,
+            install_root,
+            args,
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let mut args = vswhere_cmd::VswhereArgs::parse(&args);
+            if args.format.is_none() && cli.format != OutputFormat::Text {
+                args.format = Some(vswhere_cmd::VswhereFormat::Json);
             }
+            vswhere_cmd::vswhere_command(&msvcup_dir, &args)
         }
-    }
-
-    for pkg in &msvcup_pkgs {
-        println!("{}", pkg);
-    }
-    Ok(())
-}
-
-async fn list_payloads_command(
-    client: &reqwest::Client,
-    msvcup_dir: &manifest::MsvcupDir,
-) -> Result<()> {
-    let (vsman_path, vsman_content) = manifest::read_vs_manifest(
-        client,
-        msvcup_dir,
-        channel_kind::ChannelKind::Release,
-        ManifestUpdate::Off,
-    )
-    .await?;
-
-    let pkgs = get_packages(vsman_path.to_str().unwrap(), &vsman_content)?;
-
-    let mut payload_indices: Vec<usize> = Vec::new();
-    for (pkg_index, pkg) in pkgs.packages.iter().enumerate() {
-        match pkg.language {
-            packages::Language::Neutral | packages::Language::EnUs => {}
-            packages::Language::Other => continue,
+        Commands::Cache {
+            command:
+                CacheCommands::Key {
+                    lock_file,
+                    scope,
+                    cache_dir,
+                    install_dir,
+                    install_root,
+                    json,
+                },
+        } => {
+            let msvcup_dir =
+                installed_msvcup_dir(default_msvcup_dir, install_dir, install_root, informational)?;
+            let cache_dir = cache_dir
+                .or(global_cache)
+                .map(std::path::PathBuf::from)
+                .unwrap_or_else(|| msvcup_dir.path(&["cache"]));
+            let format = if json { OutputFormat::Json } else { cli.format };
+            cache_key_cmd::cache_key_command(
+                &msvcup_dir,
+                &lock_file,
+                scope,
+                &cache_dir,
+                &channel_kind,
+                format,
+            )
         }
-        let range = pkgs.payload_range_from_pkg_index(pkg_index);
-        for pi in range {
-            util::insert_sorted(&mut payload_indices, pi, |a, b| {
-                let pa = &pkgs.payloads[*a];
-                let pb = &pkgs.payloads[*b];
-                pa.name_decoded()
-                    .cmp(pb.name_decoded())
-                    .then_with(|| a.cmp(b))
-            });
+        Commands::Fetch {
+            url,
+            sha256,
+            from_lock_file,
+            output,
+            also_cache,
+            allow_http,
+            cache_dir,
+        } => {
+            let source = match from_lock_file {
+                Some(lock_file_path) => fetch_cmd::FetchSource::LockFile(lock_file_path),
+                None => fetch_cmd::FetchSource::Url {
+                    url: url.expect("clap requires url without --from-lock-file"),
+                    sha256,
+                    output: output.as_deref().map(fetch_cmd::FetchOutput::from_arg),
+                    also_cache,
+                },
+            };
+            fetch_cmd::fetch_command(
+                &client,
+                &source,
+                cache_dir.or(global_cache).as_deref(),
+                allow_http,
+            )
+            .await
         }
     }
-
-    for &pi in &payload_indices {
-        let pkg_index = pkgs.pkg_index_from_payload_index(pi);
-        let payload = &pkgs.payloads[pi];
-        let pkg = &pkgs.packages[pkg_index];
-        println!("{} ({})", payload.file_name, pkg.id);
-    }
-    Ok(())
 }
@@ -1,13 +1,21 @@
 use crate::channel_kind::ChannelKind;
+use crate::error::{ErrorKind, bail_kind};
+use crate::fetch_cmd::require_https;
 use crate::lock_file::LockFile;
-use crate::packages::ManifestUpdate;
+use crate::packages::{
+    ManifestUpdate, MsvcupPackage, MsvcupPackageKind, Packages, get_packages_streaming,
+    prune_manifest,
+};
 use crate::sha::{Sha256, Sha256Streaming};
 use anyhow::{Context, Result, bail};
 use fs_err as fs;
 use futures::StreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::io::Write;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
 
 /// The msvcup data directory.
 ///
@@ -15,25 +23,49 @@ use std::path::{Path, PathBuf};
 /// 1. Explicit path passed via [`MsvcupDir::with_path`] (from `--install-dir` CLI arg)
 /// 2. `MSVCUP_INSTALL_DIR` environment variable
 /// 3. Platform default: `%USERPROFILE%\.msvcup` on Windows, `{data_dir}/msvcup` elsewhere
+///
+/// Packages are installed under the root path unless their kind was given a
+/// root of its own with [`MsvcupDir::set_package_root`] (`--install-root`).
 pub struct MsvcupDir {
     pub root_path: PathBuf,
+    package_roots: Vec<(MsvcupPackageKind, PathBuf)>,
 }
 
 impl MsvcupDir {
     /// Create from the default location, checking `MSVCUP_INSTALL_DIR` env var first.
     pub fn new() -> Result<Self> {
         if let Ok(dir) = std::env::var("MSVCUP_INSTALL_DIR") {
-            return Ok(Self {
-                root_path: PathBuf::from(dir),
-            });
+            return Ok(Self::with_path(PathBuf::from(dir)));
         }
-        let root_path = Self::platform_default()?;
-        Ok(Self { root_path })
+        Ok(Self::with_path(Self::platform_default()?))
     }
 
     /// Create with an explicit root path (e.g. from `--install-dir`).
     pub fn with_path(root_path: PathBuf) -> Self {
-        Self { root_path }
+        Self {
+            root_path,
+            package_roots: Vec::new(),
+        }
+    }
+
+    /// Install packages of `kind` under `root` instead of the root path.
+    pub fn set_package_root(&mut self, kind: MsvcupPackageKind, root: PathBuf) {
+        self.package_roots.retain(|(k, _)| *k != kind);
+        self.package_roots.push((kind, root));
+    }
+
+    /// The directory packages of `kind` are installed in.
+    pub fn package_root(&self, kind: MsvcupPackageKind) -> &Path {
+        self.package_roots
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map_or(&self.root_path, |(_, root)| root)
+    }
+
+    /// The pool directory of `msvcup_pkg`, e.g. `<root>/msvc-14.43.17.13`.
+    pub fn package_path(&self, msvcup_pkg: &MsvcupPackage) -> PathBuf {
+        self.package_root(msvcup_pkg.kind)
+            .join(msvcup_pkg.pool_string())
     }
 
     /// Platform default: `%USERPROFILE%\.msvcup` on Windows, `{data_dir}/msvcup` elsewhere.
@@ -58,6 +90,136 @@ impl MsvcupDir {
         }
         p
     }
+
+    /// List the packages that have a pool directory under their kind's root, sorted.
+    /// Entries that don't parse as a package (e.g. `cache`, `manifest`) are skipped.
+    pub fn list_installed_packages(&self) -> Result<Vec<MsvcupPackage>> {
+        let mut pkgs = Vec::new();
+        let mut roots = vec![self.root_path.as_path()];
+        for (_, root) in &self.package_roots {
+            if !roots.contains(&root.as_path()) {
+                roots.push(root);
+            }
+        }
+        for root in roots {
+            let entries = match fs::read_dir(root) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                if let Some(pkg) = MsvcupPackage::try_from_path(&entry.path())
+                    && self.package_root(pkg.kind) == root
+                {
+                    crate::util::insert_sorted(&mut pkgs, pkg, MsvcupPackage::order);
+                }
+            }
+        }
+        Ok(pkgs)
+    }
+
+    /// Remove the cached channel manifest, its resolved URL and the VS manifest of
+    /// `channel_kind`, so the next command fetches them again.
+    pub fn clear_manifest_cache(&self, channel_kind: &ChannelKind) -> Result<()> {
+        for subdir in [
+            channel_kind.channel_url_subdir(),
+            channel_kind.channel_subdir(),
+            channel_kind.subdir(),
+        ] {
+            self.clear_manifest_subdir(&subdir)?;
+        }
+        Ok(())
+    }
+
+    /// [`MsvcupDir::clear_manifest_cache`] for every channel that has a cache,
+    /// including custom channels. Returns the number of cache directories cleared.
+    pub fn clear_all_manifest_caches(&self) -> Result<usize> {
+        let entries = match fs::read_dir(self.path(&["manifest"])) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut count = 0;
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            self.clear_manifest_subdir(&entry.file_name().to_string_lossy())?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Rewrite the cached VS manifest of `channel_kind` with only the packages
+    /// msvcup identifies (see [`prune_manifest`]), so reading it is faster. This
+    /// is lossy: `list-payloads` only shows the payloads of the kept packages
+    /// until the manifest is fetched again. With `keep_full` the original is
+    /// kept next to it as `latest.full`. The modification time is kept so the
+    /// daily refresh still sees the manifest's real age. Returns the number of
+    /// packages kept and the number there were, or None without a cached
+    /// manifest.
+    pub fn prune_manifest_cache(
+        &self,
+        channel_kind: &ChannelKind,
+        keep_full: bool,
+    ) -> Result<Option<(usize, usize)>> {
+        let subdir = channel_kind.subdir();
+        let latest_path = self.path(&["manifest", &subdir, "latest"]);
+        let lock_path = self.path(&["manifest", &subdir, ".lock"]);
+        let _lock = LockFile::lock(&lock_path)?;
+        let Some(content) = read_file_opt(&latest_path)? else {
+            return Ok(None);
+        };
+        let modified = fs::metadata(&latest_path)?.modified()?;
+        let mut manifest: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| format!("parsing '{}'", latest_path.display()))?;
+        let counts = prune_manifest(&mut manifest)
+            .with_context(|| format!("pruning '{}'", latest_path.display()))?;
+        drop(content);
+
+        if keep_full {
+            let full_path = self.path(&["manifest", &subdir, "latest.full"]);
+            fs::copy(&latest_path, &full_path)?;
+        }
+        let tmp_path = self.path(&["manifest", &subdir, "latest.pruning"]);
+        fs::write(&tmp_path, serde_json::to_vec(&manifest)?)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&tmp_path)
+            .and_then(|f| f.set_modified(modified))
+            .with_context(|| format!("setting mtime of '{}'", tmp_path.display()))?;
+        fs::rename(&tmp_path, &latest_path)?;
+        Ok(Some(counts))
+    }
+
+    /// Delete `latest`, its HTTP cache sidecar and a pruned manifest's
+    /// `latest.full` under the subdir's lock. The
+    /// `.lock` file itself stays, see [`LockFile`].
+    fn clear_manifest_subdir(&self, subdir: &str) -> Result<()> {
+        if !self.path(&["manifest", subdir]).is_dir() {
+            return Ok(());
+        }
+        let latest_path = self.path(&["manifest", subdir, "latest"]);
+        let lock_path = self.path(&["manifest", subdir, ".lock"]);
+        let _lock = LockFile::lock(&lock_path)?;
+        for path in [
+            HttpCacheValidators::path_for(&latest_path),
+            self.path(&["manifest", subdir, "latest.full"]),
+            latest_path.clone(),
+        ] {
+            match fs::remove_file(&path) {
+                Ok(()) => log::debug!("removed '{}'", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Read a file, returning None if it doesn't exist
@@ -71,9 +233,47 @@ fn read_file_opt(path: &Path) -> Result<Option<String>> {
 
 /// Read a file only if it exists and was modified less than 24 hours ago.
 fn read_file_if_fresh(path: &Path) -> Result<Option<String>> {
+    if !is_fresh(path)? {
+        return Ok(None);
+    }
+    read_file_opt(path)
+}
+
+/// Parse the packages of a VS manifest file without reading it into memory
+/// first, returning None if it doesn't exist
+fn read_packages_opt(path: &Path) -> Result<Option<Packages>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(anyhow::anyhow!(e))
+                .with_context(|| format!("opening '{}'", path.display()));
+        }
+    };
+    get_packages_streaming(file)
+        .with_context(|| format!("parsing '{}'", path.display()))
+        .map(Some)
+}
+
+/// [`read_packages_opt`] on the blocking thread pool, parsing the VS manifest
+/// takes long enough to hold up other tasks on a runtime worker.
+async fn read_packages_async(path: &Path) -> Result<Option<Packages>> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || read_packages_opt(&path)).await?
+}
+
+/// [`LockFile::lock`] on the blocking thread pool, so waiting for another msvcup
+/// process doesn't block a runtime worker.
+async fn lock_async(path: &Path) -> Result<LockFile> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || LockFile::lock(&path)).await?
+}
+
+/// Whether a file exists and was modified less than 24 hours ago.
+fn is_fresh(path: &Path) -> Result<bool> {
     let metadata = match std::fs::metadata(path) {
         Ok(m) => m,
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
         Err(e) => {
             return Err(anyhow::anyhow!(e))
                 .with_context(|| format!("reading metadata of '{}'", path.display()));
@@ -91,9 +291,57 @@ fn read_file_if_fresh(path: &Path) -> Result<Option<String>> {
             path.display(),
             age.as_secs()
         );
-        return Ok(None);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// The URLs tasks of this process are downloading, so concurrent fetches of one
+/// URL (e.g. a cab shared by several SDK MSIs) download it once. Other
+/// processes are kept out by the cache entry's [`LockFile`].
+#[derive(Debug, Clone, Default)]
+pub struct InFlightDownloads(Arc<Mutex<HashMap<String, Arc<Notify>>>>);
+
+impl InFlightDownloads {
+    /// Wait until no other task is downloading `url`, then claim it until the
+    /// returned guard is dropped. A task that had to wait should look for the
+    /// download in the cache before fetching it.
+    pub async fn claim(&self, url: &str) -> InFlightDownload {
+        loop {
+            // Created under the mutex, so the owner's notify_waiters can't be missed
+            let notified = {
+                let mut in_flight = self.0.lock().unwrap();
+                match in_flight.get(url) {
+                    Some(notify) => notify.clone().notified_owned(),
+                    None => {
+                        in_flight.insert(url.to_string(), Arc::new(Notify::new()));
+                        return InFlightDownload {
+                            downloads: self.clone(),
+                            url: url.to_string(),
+                        };
+                    }
+                }
+            };
+            log::debug!("waiting for another task downloading '{}'", url);
+            notified.await;
+        }
+    }
+}
+
+/// A claim on a URL from [`InFlightDownloads::claim`]. Dropping it, also when
+/// the download failed, wakes the tasks waiting for the URL.
+pub struct InFlightDownload {
+    downloads: InFlightDownloads,
+    url: String,
+}
+
+impl Drop for InFlightDownload {
+    fn drop(&mut self) {
+        let notify = self.downloads.0.lock().unwrap().remove(&self.url);
+        if let Some(notify) = notify {
+            notify.notify_waiters();
+        }
     }
-    read_file_opt(path)
 }
 
 /// Fetch a URL to a file, returning the SHA256 hash
@@ -108,9 +356,23 @@ pub async fn fetch(
         .send()
         .await
         .with_context(|| format!("fetching '{}'", url))?;
+    write_response(response, url, out_path, mp).await
+}
 
+/// Stream a successful response body to a file, returning the SHA256 hash
+async fn write_response(
+    response: reqwest::Response,
+    url: &str,
+    out_path: &Path,
+    mp: Option<&MultiProgress>,
+) -> Result<Sha256> {
     if !response.status().is_success() {
-        bail!("fetch '{}': HTTP status {}", url, response.status());
+        bail_kind!(
+            ErrorKind::Network,
+            "fetch '{}': HTTP status {}",
+            url,
+            response.status()
+        );
     }
 
     let total_size = response.content_length();
@@ -144,8 +406,9 @@ pub async fn fetch(
             .with_context(|| format!("creating directory '{}'", dir.display()))?;
     }
 
-    let mut file =
-        fs::File::create(out_path).with_context(|| format!("creating '{}'", out_path.display()))?;
+    let mut file = tokio::fs::File::create(out_path)
+        .await
+        .with_context(|| format!("creating '{}'", out_path.display()))?;
     let mut hasher = Sha256Streaming::new();
     let mut stream = response.bytes_stream();
 
@@ -153,73 +416,210 @@ pub async fn fetch(
         let chunk = chunk.with_context(|| format!("reading response from '{}'", url))?;
         hasher.update(&chunk);
         file.write_all(&chunk)
+            .await
             .with_context(|| format!("writing to '{}'", out_path.display()))?;
         pb.inc(chunk.len() as u64);
     }
+    // tokio's File finishes its last write in the background unless flushed
+    file.flush()
+        .await
+        .with_context(|| format!("writing to '{}'", out_path.display()))?;
 
     pb.finish_and_clear();
 
     Ok(hasher.finalize())
 }
 
-/// Fetch a URL, following redirects only to capture the redirect URL
-pub async fn resolve_redirect(_client: &reqwest::Client, url: &str, out_path: &Path) -> Result<()> {
+/// `ETag`/`Last-Modified` of a fetched manifest, stored next to it in
+/// `{manifest}.http-cache` so the next fetch can be a conditional request.
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct HttpCacheValidators {
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+}
+
+impl HttpCacheValidators {
+    fn path_for(out_path: &Path) -> PathBuf {
+        PathBuf::from(format!("{}.http-cache", out_path.display()))
+    }
+
+    /// Validators for `url`, if `out_path` still exists and was fetched from it.
+    fn read(url: &str, out_path: &Path) -> Option<Self> {
+        if !out_path.exists() {
+            return None;
+        }
+        let content = std::fs::read_to_string(Self::path_for(out_path)).ok()?;
+        let validators: Self = serde_json::from_str(&content).ok()?;
+        (validators.url == url).then_some(validators)
+    }
+
+    fn from_response(url: &str, response: &reqwest::Response) -> Self {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            url: url.to_string(),
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+
+    fn write(&self, out_path: &Path) -> Result<()> {
+        let path = Self::path_for(out_path);
+        if self.etag.is_none() && self.last_modified.is_none() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        fs::write(&path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Fetch a manifest to a file, sending `If-None-Match`/`If-Modified-Since` from the
+/// previous fetch of the same URL. On `304 Not Modified` the existing file is kept
+/// and its mtime bumped so it counts as fresh again.
+async fn fetch_manifest(client: &reqwest::Client, url: &str, out_path: &Path) -> Result<()> {
+    let mut request = client.get(url);
+    if let Some(validators) = HttpCacheValidators::read(url, out_path) {
+        if let Some(etag) = &validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("fetching '{}'", url))?;
+
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        log::debug!("{}: not modified, keeping cached copy", out_path.display());
+        std::fs::File::options()
+            .write(true)
+            .open(out_path)
+            .and_then(|f| f.set_modified(std::time::SystemTime::now()))
+            .with_context(|| format!("updating mtime of '{}'", out_path.display()))?;
+        return Ok(());
+    }
+
+    // Drop the old validators first so a failed download can't be "confirmed" later
+    let validators = HttpCacheValidators::from_response(url, &response);
+    HttpCacheValidators::default().write(out_path)?;
+    write_response(response, url, out_path, None).await?;
+    validators.write(out_path)?;
+    Ok(())
+}
+
+/// Fetch a URL, following redirects only to capture the redirect URL. Unless
+/// `allow_http` is given, `url` and every redirect must be https.
+pub async fn resolve_redirect(
+    _client: &reqwest::Client,
+    url: &str,
+    out_path: &Path,
+    allow_http: bool,
+) -> Result<()> {
     log::info!("resolving URL '{}'...", url);
+    let final_url = follow_redirects(url, allow_http).await?;
+    if let Some(dir) = out_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(out_path, &final_url)
+        .with_context(|| format!("writing redirect URL to '{}'", out_path.display()))?;
+    Ok(())
+}
+
+/// How many redirects [`follow_redirects`] follows before giving up.
+const MAX_REDIRECTS: usize = 5;
 
-    // Use a client that doesn't follow redirects
+/// Follow the redirects from `url` one hop at a time, returning the URL that
+/// finally answers with 200 (which is `url` itself if it isn't redirected).
+async fn follow_redirects(url: &str, allow_http: bool) -> Result<String> {
+    // Use a client that doesn't follow redirects, to see each Location
     let no_redirect_client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .build()?;
 
-    let response = no_redirect_client
-        .get(url)
-        .send()
-        .await
-        .with_context(|| format!("resolving '{}'", url))?;
-
-    if response.status().is_redirection() {
-        if let Some(location) = response.headers().get("location") {
-            let redirect_url = location.to_str().with_context(|| "invalid redirect URL")?;
-            if let Some(dir) = out_path.parent() {
-                fs::create_dir_all(dir)?;
-            }
-            fs::write(out_path, redirect_url)
-                .with_context(|| format!("writing redirect URL to '{}'", out_path.display()))?;
-            return Ok(());
+    let mut current = reqwest::Url::parse(url).with_context(|| format!("invalid URL '{}'", url))?;
+    for _ in 0..=MAX_REDIRECTS {
+        if !allow_http {
+            require_https(&current)
+                .with_context(|| format!("following the redirects from '{}'", url))?;
+        }
+        let response = no_redirect_client
+            .get(current.clone())
+            .send()
+            .await
+            .with_context(|| format!("resolving '{}'", current))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(current.to_string());
+        }
+        if !status.is_redirection() {
+            bail_kind!(
+                ErrorKind::Network,
+                "GET '{}' HTTP status {} (expected redirect)",
+                current,
+                status
+            );
         }
-        bail!("redirect response missing Location header");
+        let Some(location) = response.headers().get(reqwest::header::LOCATION) else {
+            bail!(
+                "redirect response from '{}' missing Location header",
+                current
+            );
+        };
+        let location = location.to_str().with_context(|| "invalid redirect URL")?;
+        // Location can be relative to the URL that redirected
+        let next = current
+            .join(location)
+            .with_context(|| format!("invalid redirect URL '{}'", location))?;
+        log::debug!("'{}' redirects to '{}'", current, next);
+        current = next;
     }
-
-    bail!(
-        "GET '{}' HTTP status {} (expected redirect)",
+    bail_kind!(
+        ErrorKind::Network,
+        "'{}' redirected more than {} times",
         url,
-        response.status()
+        MAX_REDIRECTS
     );
 }
 
-/// Read the VS manifest, fetching if necessary
+/// Read the packages of the VS manifest, fetching it if necessary. The manifest
+/// is parsed as it's read, under the lock, so it's never held in memory whole.
 pub async fn read_vs_manifest(
     client: &reqwest::Client,
     msvcup_dir: &MsvcupDir,
-    channel_kind: ChannelKind,
+    channel_kind: &ChannelKind,
     update: ManifestUpdate,
-) -> Result<(PathBuf, String)> {
-    let subdir = channel_kind.subdir();
+) -> Result<Packages> {
+    let subdir = &channel_kind.subdir();
     let vsman_latest_path = msvcup_dir.path(&["manifest", subdir, "latest"]);
     let vsman_lock_path = msvcup_dir.path(&["manifest", subdir, ".lock"]);
 
     // First check with lock
     {
-        let _lock = LockFile::lock(vsman_lock_path.to_str().unwrap())?;
+        let _lock = lock_async(&vsman_lock_path).await?;
         match update {
             ManifestUpdate::Off => {
-                if let Some(content) = read_file_opt(&vsman_latest_path)? {
-                    return Ok((vsman_latest_path, content));
+                if let Some(pkgs) = read_packages_async(&vsman_latest_path).await? {
+                    return Ok(pkgs);
                 }
             }
             ManifestUpdate::Daily => {
-                if let Some(content) = read_file_if_fresh(&vsman_latest_path)? {
-                    return Ok((vsman_latest_path, content));
+                if is_fresh(&vsman_latest_path)?
+                    && let Some(pkgs) = read_packages_async(&vsman_latest_path).await?
+                {
+                    return Ok(pkgs);
                 }
             }
             ManifestUpdate::Always => {}
@@ -232,16 +632,18 @@ pub async fn read_vs_manifest(
 
     // Re-acquire lock and check again (another process may have refreshed)
     {
-        let _lock = LockFile::lock(vsman_lock_path.to_str().unwrap())?;
+        let _lock = lock_async(&vsman_lock_path).await?;
         match update {
             ManifestUpdate::Off => {
-                if let Some(content) = read_file_opt(&vsman_latest_path)? {
-                    return Ok((vsman_latest_path, content));
+                if let Some(pkgs) = read_packages_async(&vsman_latest_path).await? {
+                    return Ok(pkgs);
                 }
             }
             ManifestUpdate::Daily => {
-                if let Some(content) = read_file_if_fresh(&vsman_latest_path)? {
-                    return Ok((vsman_latest_path, content));
+                if is_fresh(&vsman_latest_path)?
+                    && let Some(pkgs) = read_packages_async(&vsman_latest_path).await?
+                {
+                    return Ok(pkgs);
                 }
             }
             ManifestUpdate::Always => {}
@@ -250,11 +652,10 @@ pub async fn read_vs_manifest(
         // Parse channel manifest to find VS manifest URL
         let payload =
             vs_manifest_payload_from_ch_manifest(channel_kind, &chman_path, &chman_content)?;
-        let _sha256 = fetch(client, &payload.url, &vsman_latest_path, None).await?;
-        let content = read_file_opt(&vsman_latest_path)?.ok_or_else(|| {
-            anyhow::anyhow!("{} still doesn't exist", vsman_latest_path.display())
-        })?;
-        Ok((vsman_latest_path, content))
+        fetch_manifest(client, &payload.url, &vsman_latest_path).await?;
+        read_packages_async(&vsman_latest_path)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("{} still doesn't exist", vsman_latest_path.display()))
     }
 }
 
@@ -262,15 +663,15 @@ pub async fn read_vs_manifest(
 async fn read_ch_manifest(
     client: &reqwest::Client,
     msvcup_dir: &MsvcupDir,
-    channel_kind: ChannelKind,
+    channel_kind: &ChannelKind,
     update: ManifestUpdate,
 ) -> Result<(PathBuf, String)> {
-    let subdir = channel_kind.channel_subdir();
+    let subdir = &channel_kind.channel_subdir();
     let chman_latest_path = msvcup_dir.path(&["manifest", subdir, "latest"]);
     let chman_lock_path = msvcup_dir.path(&["manifest", subdir, ".lock"]);
 
     {
-        let _lock = LockFile::lock(chman_lock_path.to_str().unwrap())?;
+        let _lock = lock_async(&chman_lock_path).await?;
         match update {
             ManifestUpdate::Off => {
                 if let Some(content) = read_file_opt(&chman_latest_path)? {
@@ -287,11 +688,16 @@ async fn read_ch_manifest(
     }
 
     // Resolve the channel manifest URL
-    let (_url_path, url_content) =
-        resolve_ch_manifest_url(client, msvcup_dir, channel_kind, update).await?;
+    let url_content = if channel_kind.is_redirect() {
+        resolve_ch_manifest_url(client, msvcup_dir, channel_kind, update)
+            .await?
+            .1
+    } else {
+        channel_kind.https_url().to_string()
+    };
 
     {
-        let _lock = LockFile::lock(chman_lock_path.to_str().unwrap())?;
+        let _lock = lock_async(&chman_lock_path).await?;
         match update {
             ManifestUpdate::Off => {
                 if let Some(content) = read_file_opt(&chman_latest_path)? {
@@ -306,7 +712,7 @@ async fn read_ch_manifest(
             ManifestUpdate::Always => {}
         }
 
-        let _sha256 = fetch(client, &url_content, &chman_latest_path, None).await?;
+        fetch_manifest(client, &url_content, &chman_latest_path).await?;
         let content = read_file_opt(&chman_latest_path)?.ok_or_else(|| {
             anyhow::anyhow!("{} still doesn't exist", chman_latest_path.display())
         })?;
@@ -318,14 +724,14 @@ async fn read_ch_manifest(
 async fn resolve_ch_manifest_url(
     client: &reqwest::Client,
     msvcup_dir: &MsvcupDir,
-    channel_kind: ChannelKind,
+    channel_kind: &ChannelKind,
     update: ManifestUpdate,
 ) -> Result<(PathBuf, String)> {
-    let subdir = channel_kind.channel_url_subdir();
+    let subdir = &channel_kind.channel_url_subdir();
     let url_path = msvcup_dir.path(&["manifest", subdir, "latest"]);
     let url_lock_path = msvcup_dir.path(&["manifest", subdir, ".lock"]);
 
-    let _lock = LockFile::lock(url_lock_path.to_str().unwrap())?;
+    let _lock = LockFile::lock(&url_lock_path)?;
     match update {
         ManifestUpdate::Off => {
             if let Some(content) = read_file_opt(&url_path)? {
@@ -340,7 +746,9 @@ async fn resolve_ch_manifest_url(
         ManifestUpdate::Always => {}
     }
 
-    resolve_redirect(client, channel_kind.https_url(), &url_path).await?;
+    // A custom http:// channel URL is an explicit opt-in to plain http
+    let allow_http = channel_kind.https_url().starts_with("http://");
+    resolve_redirect(client, channel_kind.https_url(), &url_path, allow_http).await?;
     let content = read_file_opt(&url_path)?
         .ok_or_else(|| anyhow::anyhow!("{} still doesn't exist", url_path.display()))?;
     Ok((url_path, content))
@@ -351,7 +759,7 @@ struct VsManifestPayload {
 }
 
 fn vs_manifest_payload_from_ch_manifest(
-    channel_kind: ChannelKind,
+    channel_kind: &ChannelKind,
     chman_path: &Path,
     chman_content: &str,
 ) -> Result<VsManifestPayload> {
@@ -363,11 +771,11 @@ fn vs_manifest_payload_from_ch_manifest(
         .and_then(|v| v.as_array())
         .ok_or_else(|| anyhow::anyhow!("{}: missing 'channelItems' array", chman_path.display()))?;
 
-    let vs_manifest_id = channel_kind.vs_manifest_channel_id();
+    let vs_manifest_ids = channel_kind.vs_manifest_channel_ids();
 
     for item in channel_items {
         let id = item.get("id").and_then(|v| v.as_str()).unwrap_or_default();
-        if id == vs_manifest_id {
+        if vs_manifest_ids.contains(&id) {
             let payloads = item
                 .get("payloads")
                 .and_then(|v| v.as_array())
@@ -399,13 +807,121 @@ fn vs_manifest_payload_from_ch_manifest(
     bail!(
         "channel manifest '{}' is missing vs manifest id '{}'",
         chman_path.display(),
-        vs_manifest_id
+        vs_manifest_ids.join("' or '")
     );
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::TempDir;
+
+    #[tokio::test]
+    async fn in_flight_downloads_wait_for_the_owner() {
+        let downloads = InFlightDownloads::default();
+        let owner = downloads.claim("https://example.com/a.cab").await;
+        // Other URLs aren't held up
+        drop(downloads.claim("https://example.com/b.cab").await);
+
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let downloads = downloads.clone();
+                tokio::spawn(async move {
+                    let _claim = downloads.claim("https://example.com/a.cab").await;
+                })
+            })
+            .collect();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(waiters.iter().all(|w| !w.is_finished()));
+
+        drop(owner);
+        for waiter in waiters {
+            tokio::time::timeout(std::time::Duration::from_secs(5), waiter)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(downloads.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_manifest_cache() {
+        let root = TempDir::new("prune_manifest_cache");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        assert_eq!(
+            msvcup_dir
+                .prune_manifest_cache(&ChannelKind::Release, false)
+                .unwrap(),
+            None
+        );
+
+        let dir = root.join("manifest").join(ChannelKind::Release.subdir());
+        std::fs::create_dir_all(&dir).unwrap();
+        let latest = dir.join("latest");
+        let content = r#"{"packages": [
+            {"id": "Microsoft.Build", "version": "17.13.0"},
+            {"id": "Microsoft.VisualStudio.Debugger", "version": "17.13.0"}]}"#;
+        std::fs::write(&latest, content).unwrap();
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(48 * 60 * 60);
+        filetime::set_file_mtime(&latest, filetime::FileTime::from_system_time(old)).unwrap();
+
+        assert_eq!(
+            msvcup_dir
+                .prune_manifest_cache(&ChannelKind::Release, true)
+                .unwrap(),
+            Some((1, 2))
+        );
+        let pkgs = read_packages_opt(&latest).unwrap().unwrap();
+        assert_eq!(pkgs.packages.len(), 1);
+        assert_eq!(pkgs.packages[0].id, "Microsoft.Build");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("latest.full")).unwrap(),
+            content
+        );
+        // Still as stale as before
+        assert!(!is_fresh(&latest).unwrap());
+        assert!(!dir.join("latest.pruning").exists());
+    }
+
+    #[test]
+    fn clear_manifest_cache() {
+        let root = TempDir::new("clear_manifest_cache");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let custom = ChannelKind::custom("https://mirror.example.com/vs/channel").unwrap();
+        let subdirs = [
+            ChannelKind::Release.subdir(),
+            ChannelKind::Release.channel_subdir(),
+            ChannelKind::Release.channel_url_subdir(),
+            custom.subdir(),
+        ];
+        for subdir in &subdirs {
+            let dir = root.join("manifest").join(subdir);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("latest"), "{}").unwrap();
+            std::fs::write(dir.join("latest.http-cache"), "{}").unwrap();
+        }
+        let exists = |subdir: &str| root.join("manifest").join(subdir).join("latest").exists();
+
+        msvcup_dir
+            .clear_manifest_cache(&ChannelKind::Release)
+            .unwrap();
+        assert!(!exists(&subdirs[0]) && !exists(&subdirs[1]) && !exists(&subdirs[2]));
+        assert!(
+            !root
+                .join("manifest")
+                .join(&subdirs[0])
+                .join("latest.http-cache")
+                .exists()
+        );
+        assert!(exists(&subdirs[3]));
+        // Clearing an empty cache is fine
+        msvcup_dir
+            .clear_manifest_cache(&ChannelKind::Preview)
+            .unwrap();
+
+        assert_eq!(msvcup_dir.clear_all_manifest_caches().unwrap(), 4);
+        assert!(!exists(&subdirs[3]));
+    }
 
     #[test]
     fn read_file_opt_nonexistent() {
@@ -415,16 +931,12 @@ mod tests {
 
     #[test]
     fn read_file_opt_existing() {
-        let dir = std::env::temp_dir().join("msvcup_test_read_file_opt");
-        let _ = std::fs::remove_dir_all(&dir);
-        std::fs::create_dir_all(&dir).unwrap();
+        let dir = TempDir::new("read_file_opt");
         let path = dir.join("test.txt");
         std::fs::write(&path, "hello").unwrap();
 
         let result = read_file_opt(&path).unwrap();
         assert_eq!(result.as_deref(), Some("hello"));
-
-        let _ = std::fs::remove_dir_all(&dir);
     }
 
     #[test]
@@ -435,24 +947,18 @@ mod tests {
 
     #[test]
     fn read_file_if_fresh_recent_file() {
-        let dir = std::env::temp_dir().join("msvcup_test_fresh");
-        let _ = std::fs::remove_dir_all(&dir);
-        std::fs::create_dir_all(&dir).unwrap();
+        let dir = TempDir::new("fresh");
         let path = dir.join("fresh.txt");
         std::fs::write(&path, "content").unwrap();
 
         // Just-written file should be fresh
         let result = read_file_if_fresh(&path).unwrap();
         assert_eq!(result.as_deref(), Some("content"));
-
-        let _ = std::fs::remove_dir_all(&dir);
     }
 
     #[test]
     fn read_file_if_fresh_stale_file() {
-        let dir = std::env::temp_dir().join("msvcup_test_stale");
-        let _ = std::fs::remove_dir_all(&dir);
-        std::fs::create_dir_all(&dir).unwrap();
+        let dir = TempDir::new("stale");
         let path = dir.join("stale.txt");
         std::fs::write(&path, "old content").unwrap();
 
@@ -463,8 +969,6 @@ mod tests {
 
         let result = read_file_if_fresh(&path).unwrap();
         assert!(result.is_none());
-
-        let _ = std::fs::remove_dir_all(&dir);
     }
 
     #[test]
@@ -482,9 +986,163 @@ mod tests {
         );
     }
 
+    #[test]
+    fn msvcup_dir_list_installed_packages() {
+        let root = TempDir::new("list_installed_dirs");
+        std::fs::create_dir_all(root.join("sdk-10.0.22621.7")).unwrap();
+        std::fs::create_dir_all(root.join("msvc-14.43.34808")).unwrap();
+        std::fs::create_dir_all(root.join("cache")).unwrap();
+        std::fs::write(root.join("msvc-14.30.17.6"), "not a dir").unwrap();
+
+        let dir = MsvcupDir::with_path(root.to_path_buf());
+        let pkgs = dir.list_installed_packages().unwrap();
+        let names: Vec<String> = pkgs.iter().map(|p| p.pool_string()).collect();
+        assert_eq!(names, vec!["msvc-14.43.34808", "sdk-10.0.22621.7"]);
+    }
+
+    #[test]
+    fn msvcup_dir_package_roots() {
+        let root = TempDir::new("package_roots");
+        let sdk_root = root.join("slow");
+        std::fs::create_dir_all(root.join("msvc-14.43.34808")).unwrap();
+        std::fs::create_dir_all(sdk_root.join("sdk-10.0.22621.7")).unwrap();
+        // Left behind from before sdk had its own root
+        std::fs::create_dir_all(root.join("sdk-10.0.26100.3")).unwrap();
+        std::fs::create_dir_all(sdk_root.join("msvc-14.30.17.6")).unwrap();
+
+        let mut dir = MsvcupDir::with_path(root.to_path_buf());
+        dir.set_package_root(MsvcupPackageKind::Sdk, sdk_root.clone());
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.34808");
+        assert_eq!(dir.package_path(&sdk), sdk_root.join("sdk-10.0.22621.7"));
+        assert_eq!(dir.package_path(&msvc), root.join("msvc-14.43.34808"));
+
+        let pkgs = dir.list_installed_packages().unwrap();
+        let names: Vec<String> = pkgs.iter().map(|p| p.pool_string()).collect();
+        assert_eq!(names, vec!["msvc-14.43.34808", "sdk-10.0.22621.7"]);
+    }
+
+    #[test]
+    fn msvcup_dir_list_installed_packages_missing_root() {
+        let dir = MsvcupDir::with_path(PathBuf::from("/nonexistent/msvcup"));
+        assert!(dir.list_installed_packages().unwrap().is_empty());
+    }
+
     #[test]
     fn msvcup_dir_path_empty() {
         let dir = MsvcupDir::with_path(PathBuf::from("/root"));
         assert_eq!(dir.path(&[]), PathBuf::from("/root"));
     }
+
+    /// Serve a manifest with an ETag, answering conditional requests with 304.
+    /// Returns the base URL and a counter of full (200) responses.
+    fn serve_etag_fixture() -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let full_responses = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = full_responses.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+                let response = if request.contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+                } else {
+                    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 2\r\n\
+                     Connection: close\r\n\r\n{}"
+                        .to_string()
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (format!("http://{}/channel", addr), full_responses)
+    }
+
+    #[tokio::test]
+    async fn follow_redirect_chain() {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut buf = [0u8; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let response = match path {
+                    "/aka" => "HTTP/1.1 302 Found\r\nLocation: /hop\r\n".to_string(),
+                    "/hop" => format!(
+                        "HTTP/1.1 301 Moved\r\nLocation: http://{}/channel\r\n",
+                        addr
+                    ),
+                    "/channel" => "HTTP/1.1 200 OK\r\n".to_string(),
+                    "/loop" => "HTTP/1.1 302 Found\r\nLocation: /loop\r\n".to_string(),
+                    _ => "HTTP/1.1 404 Not Found\r\n".to_string(),
+                };
+                let response =
+                    format!("{}Content-Length: 0\r\nConnection: close\r\n\r\n", response);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        let base = format!("http://{}", addr);
+
+        let channel = format!("{}/channel", base);
+        assert_eq!(
+            follow_redirects(&format!("{}/aka", base), true)
+                .await
+                .unwrap(),
+            channel
+        );
+        assert_eq!(follow_redirects(&channel, true).await.unwrap(), channel);
+
+        let err = follow_redirects(&format!("{}/loop", base), true)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("redirected more than 5 times"),
+            "{}",
+            err
+        );
+        let err = follow_redirects(&format!("{}/missing", base), true)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("404"), "{}", err);
+        assert_eq!(crate::error::exit_code(&err), 4);
+
+        let err = follow_redirects(&format!("{}/aka", base), false)
+            .await
+            .unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 2);
+    }
+
+    #[tokio::test]
+    async fn fetch_manifest_uses_etag() {
+        let dir = TempDir::new("fetch_manifest_etag");
+        let out_path = dir.join("latest");
+        let (url, full_responses) = serve_etag_fixture();
+        let client = reqwest::Client::new();
+
+        fetch_manifest(&client, &url, &out_path).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "{}");
+        assert_eq!(
+            HttpCacheValidators::read(&url, &out_path).unwrap().etag,
+            Some("\"v1\"".to_string())
+        );
+
+        // Make the cached copy stale, a 304 should keep it and make it fresh again
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(48 * 60 * 60);
+        filetime::set_file_mtime(&out_path, filetime::FileTime::from_system_time(old)).unwrap();
+        assert!(read_file_if_fresh(&out_path).unwrap().is_none());
+        fetch_manifest(&client, &url, &out_path).await.unwrap();
+        assert_eq!(full_responses.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(read_file_if_fresh(&out_path).unwrap().unwrap(), "{}");
+
+        // Validators from another URL aren't sent
+        assert!(HttpCacheValidators::read("http://other/channel", &out_path).is_none());
+    }
 }
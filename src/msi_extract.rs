@@ -1,3 +1,5 @@
+use crate::file_exclusions::{FileExclusions, SKIP_PREFIX, SkippedFiles};
+use crate::util::install_manifest_entry;
 use anyhow::{Context, Result};
 use fs_err as fs;
 use std::collections::HashMap;
@@ -15,11 +17,11 @@ pub fn extract_msi(
     msi_path: &Path,
     install_dir: &Path,
     cab_dir: &Path,
+    exclusions: &FileExclusions,
     manifest_file: &mut fs::File,
-) -> Result<()> {
+) -> Result<SkippedFiles> {
     let msi_name = msi_path.file_name().unwrap_or_default().to_string_lossy();
-    let mut package = msi::open(msi_path)
-        .with_context(|| format!("opening MSI file '{}'", msi_path.display()))?;
+    let mut package = open_msi(msi_path)?;
 
     // Parse the Directory table: directory_id -> (parent_id, default_dir)
     let directory_table = read_directory_table(&mut package)?;
@@ -43,6 +45,7 @@ pub fn extract_msi(
     );
 
     let mut extracted_count = 0u32;
+    let mut skipped = SkippedFiles::default();
 
     // Try external CABs first (referenced in Media table)
     let mut found_external = false;
@@ -62,6 +65,8 @@ pub fn extract_msi(
                 &file_table,
                 &component_table,
                 &directory_table,
+                exclusions,
+                &mut skipped,
                 manifest_file,
             )
             .with_context(|| format!("extracting CAB '{}'", cab_path.display()))?;
@@ -89,7 +94,7 @@ pub fn extract_msi(
             msi_name,
             extracted_count
         );
-        return Ok(());
+        return Ok(skipped);
     }
 
     // Fall back to embedded CAB streams
@@ -128,6 +133,8 @@ pub fn extract_msi(
                 &file_table,
                 &component_table,
                 &directory_table,
+                exclusions,
+                &mut skipped,
                 manifest_file,
             )
             .with_context(|| format!("extracting embedded CAB '{}'", stream_name))?;
@@ -141,7 +148,7 @@ pub fn extract_msi(
         }
     }
 
-    if extracted_count == 0 {
+    if extracted_count == 0 && skipped.files == 0 {
         // Try any stream that looks like a CAB (check for MSCF signature)
         for name in &stream_names {
             let mut reader = match package.read_stream(name) {
@@ -167,6 +174,8 @@ pub fn extract_msi(
                 &file_table,
                 &component_table,
                 &directory_table,
+                exclusions,
+                &mut skipped,
                 manifest_file,
             )?;
             log::debug!(
@@ -179,17 +188,26 @@ pub fn extract_msi(
         }
     }
 
-    if extracted_count == 0 {
+    if extracted_count == 0 && skipped.files == 0 {
         if file_table.is_empty() {
             log::debug!(
                 "  [{}] no files in File table, nothing to extract (metadata-only MSI)",
                 msi_name
             );
         } else {
+            let missing: Vec<&str> = media_entries
+                .iter()
+                .map(|m| m.cabinet.as_str())
+                .filter(|c| !c.is_empty() && !c.starts_with('#'))
+                .collect();
             log::warn!(
-                "  [{}] File table has {} entries but no CAB files found (neither external nor embedded)",
+                "  [{}] File table has {} entries but no CAB files were found: \
+                 external CAB(s) [{}] are missing from '{}' and no embedded CAB streams exist; \
+                 if the CABs aren't in the lock file, updating it with --manifest-update always may help",
                 msi_name,
-                file_table.len()
+                file_table.len(),
+                missing.join(", "),
+                cab_dir.display()
             );
         }
     } else {
@@ -199,7 +217,19 @@ pub fn extract_msi(
             extracted_count
         );
     }
-    Ok(())
+    Ok(skipped)
+}
+
+/// Open an MSI database. Failures almost always mean a truncated or corrupt cache
+/// entry (the sha256 is only checked on download), so say how to recover.
+fn open_msi(msi_path: &Path) -> Result<msi::Package<std::fs::File>> {
+    msi::open(msi_path).with_context(|| {
+        format!(
+            "opening MSI file '{}': not a readable MSI database, \
+             delete it from the cache to download it again",
+            msi_path.display()
+        )
+    })
 }
 
 struct FileEntry {
@@ -325,8 +355,7 @@ fn read_media_table(package: &mut msi::Package<std::fs::File>) -> Result<Vec<Med
 
 /// Read the cabinet names from an MSI's Media table without extracting.
 pub fn read_msi_cab_names(msi_path: &Path) -> Result<Vec<String>> {
-    let mut package = msi::open(msi_path)
-        .with_context(|| format!("opening MSI file '{}'", msi_path.display()))?;
+    let mut package = open_msi(msi_path)?;
     let entries = read_media_table(&mut package)?;
     Ok(entries
         .into_iter()
@@ -397,12 +426,15 @@ fn get_long_filename(filename_field: &str) -> &str {
 }
 
 /// Extract files from a CAB archive using MSI metadata for path resolution.
+#[allow(clippy::too_many_arguments)]
 fn extract_cab<R: Read + io::Seek>(
     reader: R,
     install_dir: &Path,
     file_table: &HashMap<String, FileEntry>,
     component_table: &HashMap<String, String>,
     directory_table: &HashMap<String, (String, String)>,
+    exclusions: &FileExclusions,
+    skipped: &mut SkippedFiles,
     manifest_file: &mut fs::File,
 ) -> Result<u32> {
     let mut cabinet = cab::Cabinet::new(reader).context("parsing CAB file")?;
@@ -410,13 +442,13 @@ fn extract_cab<R: Read + io::Seek>(
     let mut extracted = 0u32;
 
     // Collect all file names from the cabinet first
-    let file_names: Vec<String> = cabinet
+    let file_entries: Vec<(String, u64)> = cabinet
         .folder_entries()
         .flat_map(|folder| folder.file_entries())
-        .map(|entry| entry.name().to_string())
+        .map(|entry| (entry.name().to_string(), entry.uncompressed_size() as u64))
         .collect();
 
-    for cab_file_name in &file_names {
+    for (cab_file_name, size) in &file_entries {
         // Look up this file in the MSI File table
         let (target_dir, actual_name) =
             if let Some(file_entry) = file_table.get(cab_file_name.as_str()) {
@@ -441,14 +473,19 @@ fn extract_cab<R: Read + io::Seek>(
             install_dir.join(&target_dir)
         };
 
-        fs::create_dir_all(&full_dir)?;
-
         let full_path = full_dir.join(&actual_name);
+        let manifest_entry = install_manifest_entry(install_dir, &full_path);
+        if exclusions.excludes(&manifest_entry) {
+            writeln!(manifest_file, "{}{}", SKIP_PREFIX, manifest_entry)?;
+            skipped.add(*size);
+            continue;
+        }
 
+        fs::create_dir_all(&full_dir)?;
         if full_path.exists() {
-            writeln!(manifest_file, "add {}", full_path.display())?;
+            writeln!(manifest_file, "add {}", manifest_entry)?;
         } else {
-            writeln!(manifest_file, "new {}", full_path.display())?;
+            writeln!(manifest_file, "new {}", manifest_entry)?;
             let mut reader = cabinet
                 .read_file(cab_file_name)
                 .with_context(|| format!("reading '{}' from CAB", cab_file_name))?;
@@ -461,3 +498,23 @@ fn extract_cab<R: Read + io::Seek>(
 
     Ok(extracted)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn corrupt_msi_error_explains_recovery() {
+        let dir = TempDir::new("corrupt_msi");
+        let msi_path = dir.join("truncated.msi");
+        std::fs::write(&msi_path, b"\xD0\xCF\x11\xE0 truncated").unwrap();
+
+        let err = read_msi_cab_names(&msi_path).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("delete it from the cache"),
+            "{:#}",
+            err
+        );
+    }
+}
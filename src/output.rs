@@ -0,0 +1,103 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+
+/// How informational commands print their results to stdout. Diagnostics always
+/// go to stderr through the logger, so stdout only ever carries the results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable lines
+    Text,
+    /// A single pretty-printed JSON array of records
+    Json,
+    /// One compact JSON record per line
+    Ndjson,
+}
+
+impl OutputFormat {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            "ndjson" => Some(Self::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+/// Write `records` in the given format. `text` formats one record for the
+/// `text` format and may return several lines.
+pub fn write_records<T: Serialize>(
+    out: &mut impl Write,
+    format: OutputFormat,
+    records: &[T],
+    text: impl Fn(&T) -> String,
+) -> Result<()> {
+    match format {
+        OutputFormat::Text => {
+            for record in records {
+                writeln!(out, "{}", text(record))?;
+            }
+        }
+        OutputFormat::Json => writeln!(out, "{}", serde_json::to_string_pretty(records)?)?,
+        OutputFormat::Ndjson => {
+            for record in records {
+                writeln!(out, "{}", serde_json::to_string(record)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// [`write_records`] to stdout.
+pub fn print_records<T: Serialize>(
+    format: OutputFormat,
+    records: &[T],
+    text: impl Fn(&T) -> String,
+) -> Result<()> {
+    write_records(&mut std::io::stdout().lock(), format, records, text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Record {
+        name: &'static str,
+        size: u64,
+    }
+
+    fn render(format: OutputFormat) -> String {
+        let records = [Record { name: "a", size: 1 }, Record { name: "b", size: 2 }];
+        let mut out = Vec::new();
+        write_records(&mut out, format, &records, |r| {
+            format!("{} {}", r.name, r.size)
+        })
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(render(OutputFormat::Text), "a 1\nb 2\n");
+        assert_eq!(
+            render(OutputFormat::Ndjson),
+            "{\"name\":\"a\",\"size\":1}\n{\"name\":\"b\",\"size\":2}\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&render(OutputFormat::Json)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{"name": "a", "size": 1}, {"name": "b", "size": 2}])
+        );
+    }
+
+    #[test]
+    fn from_name() {
+        assert_eq!(
+            OutputFormat::from_name("ndjson"),
+            Some(OutputFormat::Ndjson)
+        );
+        assert_eq!(OutputFormat::from_name("JSON"), None);
+    }
+}
@@ -1,14 +1,18 @@
 use crate::arch::Arch;
 use crate::sha::Sha256;
 use crate::util::{
-    alloc_url_percent_decoded, basename_from_url, order_dotted_numeric, scan_id_part,
-    scan_id_version,
+    alloc_url_percent_decoded, basename_from_url, extension_from_url, order_dotted_numeric,
+    scan_id_part, scan_id_version,
 };
-use anyhow::{Context, Result};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
+use std::path::Path;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MsvcupPackageKind {
     Msvc,
     Sdk,
@@ -16,9 +20,22 @@ pub enum MsvcupPackageKind {
     Diasdk,
     Ninja,
     Cmake,
+    Nasm,
+    Python,
 }
 
 impl MsvcupPackageKind {
+    pub const ALL: [MsvcupPackageKind; 8] = [
+        Self::Msvc,
+        Self::Sdk,
+        Self::Msbuild,
+        Self::Diasdk,
+        Self::Ninja,
+        Self::Cmake,
+        Self::Nasm,
+        Self::Python,
+    ];
+
     pub fn as_str(&self) -> &'static str {
         match self {
             Self::Msvc => "msvc",
@@ -27,6 +44,8 @@ impl MsvcupPackageKind {
             Self::Diasdk => "diasdk",
             Self::Ninja => "ninja",
             Self::Cmake => "cmake",
+            Self::Nasm => "nasm",
+            Self::Python => "python",
         }
     }
 
@@ -49,6 +68,12 @@ impl MsvcupPackageKind {
         if let Some(v) = s.strip_prefix("cmake-") {
             return Some((Self::Cmake, v));
         }
+        if let Some(v) = s.strip_prefix("nasm-") {
+            return Some((Self::Nasm, v));
+        }
+        if let Some(v) = s.strip_prefix("python-") {
+            return Some((Self::Python, v));
+        }
         None
     }
 }
@@ -59,6 +84,48 @@ impl fmt::Display for MsvcupPackageKind {
     }
 }
 
+impl std::str::FromStr for MsvcupPackageKind {
+    type Err = UnknownPackageKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| UnknownPackageKindError {
+                input: s.to_string(),
+                valid: Self::ALL.iter().map(|kind| kind.as_str()).collect(),
+            })
+    }
+}
+
+/// Comma separated list of the package kinds, for error messages.
+fn valid_kinds_list() -> String {
+    MsvcupPackageKind::ALL
+        .iter()
+        .map(|kind| kind.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug)]
+pub struct UnknownPackageKindError {
+    pub input: String,
+    pub valid: Vec<&'static str>,
+}
+
+impl fmt::Display for UnknownPackageKindError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown package kind '{}', expected one of: {}",
+            self.input,
+            self.valid.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownPackageKindError {}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct MsvcupPackage {
     pub kind: MsvcupPackageKind,
@@ -89,6 +156,20 @@ impl MsvcupPackage {
         format!("{}", self)
     }
 
+    /// Parse a pool directory name (e.g. `msvc-14.38.33130`), the inverse of [`pool_string`].
+    ///
+    /// [`pool_string`]: MsvcupPackage::pool_string
+    pub fn from_pool_string(s: &str) -> Result<Self, MsvcupPackageParseError> {
+        Self::from_string(s)
+    }
+
+    /// Parse the last component of `path` as a pool directory name.
+    /// Returns None if the path has no final component or it isn't a valid package.
+    pub fn try_from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        Self::from_pool_string(name).ok()
+    }
+
     pub fn order(lhs: &MsvcupPackage, rhs: &MsvcupPackage) -> Ordering {
         match lhs.kind.cmp(&rhs.kind) {
             Ordering::Equal => order_dotted_numeric(&lhs.version, &rhs.version),
@@ -97,6 +178,15 @@ impl MsvcupPackage {
     }
 }
 
+/// The first two packages of the same kind in `pkgs`, which must be sorted with
+/// [`MsvcupPackage::order`]. Installing several versions of a kind side by side
+/// is fine, but an environment can only be built from one of them.
+pub fn find_same_kind(pkgs: &[MsvcupPackage]) -> Option<(&MsvcupPackage, &MsvcupPackage)> {
+    pkgs.windows(2)
+        .find(|pair| pair[0].kind == pair[1].kind)
+        .map(|pair| (&pair[0], &pair[1]))
+}
+
 impl fmt::Display for MsvcupPackage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}-{}", self.kind, self.version)
@@ -112,7 +202,11 @@ pub enum MsvcupPackageParseError {
 impl fmt::Display for MsvcupPackageParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::UnknownName => write!(f, "unknown package name"),
+            Self::UnknownName => write!(
+                f,
+                "unknown package name, expected <kind>-<version> where kind is one of: {}",
+                valid_kinds_list()
+            ),
             Self::InvalidVersion(v) => write!(f, "invalid version '{}'", v),
         }
     }
@@ -146,6 +240,13 @@ pub enum PackageId<'a> {
     Diasdk,
     Ninja(&'a str),
     Cmake(&'a str),
+    Nasm(&'a str),
+    Python(&'a str),
+    /// A `Microsoft.VisualStudio.Component.<name>` package, e.g. `VC.Tools.x86.x64`.
+    /// Components have no payloads of their own, only `dependencies` on the
+    /// packages that do; the manifest parser doesn't keep those yet, so
+    /// components can't be installed by name.
+    Component(&'a str),
 }
 
 pub fn identify_package(id: &str) -> PackageId<'_> {
@@ -161,6 +262,14 @@ pub fn identify_package(id: &str) -> PackageId<'_> {
         }
     }
 
+    // Components
+    if let Some(name) = id
+        .strip_prefix("Microsoft.VisualStudio.Component.")
+        .filter(|name| !name.is_empty())
+    {
+        return PackageId::Component(name);
+    }
+
     // DIA SDK
     if id == "Microsoft.VisualCpp.DIA.SDK" {
         return PackageId::Diasdk;
@@ -277,6 +386,42 @@ pub fn identify_package(id: &str) -> PackageId<'_> {
         return PackageId::Cmake(version);
     }
 
+    // NASM
+    if let Some(rest) = id.strip_prefix("nasm-") {
+        let (version, version_end) = scan_id_version(rest, 0);
+        if version.is_empty() {
+            return PackageId::Unexpected {
+                offset: 5,
+                expected: "version",
+            };
+        }
+        if version_end != rest.len() {
+            return PackageId::Unexpected {
+                offset: 5 + version_end,
+                expected: "end",
+            };
+        }
+        return PackageId::Nasm(version);
+    }
+
+    // Python (the CPython embeddable distribution)
+    if let Some(rest) = id.strip_prefix("python-") {
+        let (version, version_end) = scan_id_version(rest, 0);
+        if version.is_empty() {
+            return PackageId::Unexpected {
+                offset: 7,
+                expected: "version",
+            };
+        }
+        if version_end != rest.len() {
+            return PackageId::Unexpected {
+                offset: 7 + version_end,
+                expected: "end",
+            };
+        }
+        return PackageId::Python(version);
+    }
+
     PackageId::Unknown
 }
 
@@ -286,9 +431,89 @@ pub fn identify_package(id: &str) -> PackageId<'_> {
 pub enum PayloadId {
     Unknown,
     Sdk,
+    /// An MSVC installer vsix (`Installers\Microsoft Visual C++ *.vsix`)
+    Msvc,
+    /// Debug symbols, see [`is_debug_payload`]
+    DebugInfo,
+    /// The VC++ redistributable installers (`Installers\vc_redist.*`)
+    Redist,
+    /// SDK tools msvcup doesn't install (`Installers\Windows SDK Desktop Tools *`)
+    Tools,
+}
+
+/// Whether a payload only holds debug symbols: a `.pdb` or a payload with
+/// `symbols` in its file name. The debug CRT libraries aren't symbols, debug
+/// builds link them.
+pub fn is_debug_payload(payload_filename: &str) -> bool {
+    let name = payload_filename
+        .rsplit(['\\', '/'])
+        .next()
+        .unwrap_or(payload_filename)
+        .to_ascii_lowercase();
+    name.ends_with(".pdb") || name.contains("symbols")
+}
+
+/// Whether a VS manifest package holds the MSVC runtime redistributables
+/// (`Microsoft.VC.<version>.CRT.Redist.<arch>.base`). Their payloads are all
+/// named `payload.vsix`, so the package id is what tells them apart.
+pub fn is_redist_package(pkg_id: &str) -> bool {
+    pkg_id.contains(".CRT.Redist.")
+}
+
+/// Whether an install can do without a payload, judged by the file name of its
+/// URL (VS payload URLs end in their package id): debug symbols, the debug CRT
+/// and the runtime redistributables. Everything else is essential.
+pub fn is_optional_payload(url_name: &str) -> bool {
+    is_debug_payload(url_name)
+        || is_redist_package(url_name)
+        || url_name.contains(".Desktop.debug.")
+        || url_name
+            .get(.."vc_redist.".len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("vc_redist."))
+}
+
+/// Payload kinds `update_lock_file` leaves out of the lock file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PayloadExclusions {
+    /// `--exclude-debug-info`: [`PayloadId::DebugInfo`] payloads
+    pub debug_info: bool,
+    /// `--exclude-redist`: [`PayloadId::Redist`] payloads and the payloads of
+    /// redist packages (see [`is_redist_package`])
+    pub redist: bool,
 }
 
+impl PayloadExclusions {
+    pub fn excludes(&self, pkg_id: &str, payload_filename: &str, target_arch: Arch) -> bool {
+        match identify_payload(payload_filename, target_arch) {
+            PayloadId::DebugInfo => self.debug_info,
+            PayloadId::Redist => self.redist,
+            _ => self.redist && is_redist_package(pkg_id),
+        }
+    }
+}
+
+/// Classify a payload by its manifest `fileName`. Some manifests separate the
+/// `Installers` directory with `/` instead of `\`, both are accepted.
 pub fn identify_payload(payload_filename: &str, target_arch: Arch) -> PayloadId {
+    if is_debug_payload(payload_filename) {
+        return PayloadId::DebugInfo;
+    }
+    let payload_filename = &payload_filename.replace('/', "\\");
+    const REDIST_PREFIX: &str = "Installers\\vc_redist.";
+    if payload_filename
+        .get(..REDIST_PREFIX.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(REDIST_PREFIX))
+    {
+        return PayloadId::Redist;
+    }
+    if payload_filename.starts_with("Installers\\Microsoft Visual C++ ")
+        && payload_filename.ends_with(".vsix")
+    {
+        return PayloadId::Msvc;
+    }
+    if payload_filename.starts_with("Installers\\Windows SDK Desktop Tools ") {
+        return PayloadId::Tools;
+    }
     if payload_filename.starts_with("Installers\\Universal CRT Headers Libraries and Sources-") {
         return PayloadId::Sdk;
     }
@@ -328,7 +553,7 @@ fn sdk_payload_arch_matches(rest: &str, target_arch: Arch) -> bool {
     if let Some(dash_pos) = rest.find('-') {
         let arch_str = &rest[..dash_pos];
         match Arch::from_str_ignore_case(arch_str) {
-            Some(arch) => arch == target_arch,
+            Some(arch) => target_arch.accepts_payload_arch(arch),
             None => true, // unknown arch pattern, include to be safe
         }
     } else {
@@ -344,51 +569,79 @@ pub enum LockFileUrlKind {
     Msi,
     Cab,
     Zip,
+    TarGz,
+    TarXz,
 }
 
 pub fn get_lock_file_url_kind(url: &str) -> Option<LockFileUrlKind> {
-    if url.ends_with(".vsix") {
-        Some(LockFileUrlKind::Vsix)
-    } else if url.ends_with(".msi") {
-        Some(LockFileUrlKind::Msi)
-    } else if url.ends_with(".cab") {
-        Some(LockFileUrlKind::Cab)
-    } else if url.ends_with(".zip") {
-        Some(LockFileUrlKind::Zip)
-    } else {
-        None
+    let basename = basename_from_url(url);
+    match extension_from_url(url)? {
+        "vsix" => Some(LockFileUrlKind::Vsix),
+        "msi" => Some(LockFileUrlKind::Msi),
+        "cab" => Some(LockFileUrlKind::Cab),
+        "zip" => Some(LockFileUrlKind::Zip),
+        "tgz" => Some(LockFileUrlKind::TarGz),
+        "gz" if basename.ends_with(".tar.gz") => Some(LockFileUrlKind::TarGz),
+        "txz" => Some(LockFileUrlKind::TarXz),
+        "xz" if basename.ends_with(".tar.xz") => Some(LockFileUrlKind::TarXz),
+        _ => None,
     }
 }
 
 // --- Language ---
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Language {
     Neutral,
     EnUs,
-    Other,
+    /// Any other language, with the tag as it appeared in the manifest
+    Localized(String),
 }
 
+/// Languages installed by default.
+pub const DEFAULT_LANGUAGES: &[Language] = &[Language::Neutral, Language::EnUs];
+
 const OTHER_LANGUAGES: &[&str] = &[
     "cs-CZ", "de-DE", "es-ES", "fr-FR", "it-IT", "ja-JP", "ko-KR", "pl-PL", "pt-BR", "ru-RU",
     "tr-TR", "zh-CN", "zh-TW",
 ];
 
 impl Language {
+    /// Parse a manifest language tag, case-insensitively. Tags other than
+    /// `neutral` and `en-US` are `Localized`; only malformed tags are warned about.
     pub fn from_str(s: &str) -> Language {
-        if s == "neutral" {
+        if s.eq_ignore_ascii_case("neutral") {
             Language::Neutral
         } else if s.eq_ignore_ascii_case("en-US") {
             Language::EnUs
-        } else if OTHER_LANGUAGES.contains(&s) {
-            Language::Other
         } else {
-            log::warn!("unknown language '{}'", s);
-            Language::Other
+            if !OTHER_LANGUAGES.iter().any(|l| l.eq_ignore_ascii_case(s)) && !is_language_tag(s) {
+                log::warn!("unknown language '{}'", s);
+            }
+            Language::Localized(s.to_string())
+        }
+    }
+
+    /// Whether two languages are the same, ignoring the case of localized tags.
+    pub fn matches(&self, other: &Language) -> bool {
+        match (self, other) {
+            (Language::Localized(lhs), Language::Localized(rhs)) => lhs.eq_ignore_ascii_case(rhs),
+            _ => self == other,
         }
     }
 }
 
+/// Whether `s` looks like a BCP-47 language tag: a 2-3 letter (or 4-8 letter)
+/// primary subtag followed by any number of 1-8 character alphanumeric subtags.
+fn is_language_tag(s: &str) -> bool {
+    let mut parts = s.split('-');
+    let primary = parts.next().unwrap_or_default();
+    if !(2..=8).contains(&primary.len()) || !primary.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return false;
+    }
+    parts.all(|p| (1..=8).contains(&p.len()) && p.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
 // --- Package and Payload structs for parsed VS manifest ---
 
 #[derive(Debug, Clone)]
@@ -404,6 +657,10 @@ pub struct Payload {
     pub url_decoded: String,
     pub sha256: Sha256,
     pub file_name: String,
+    /// Download size in bytes (0 if the manifest doesn't say)
+    pub size: u64,
+    /// Extracted size in bytes, if the manifest provides an `installSize`
+    pub install_size: Option<u64>,
 }
 
 impl Payload {
@@ -434,6 +691,49 @@ impl Packages {
         &self.payloads[range]
     }
 
+    /// Packages whose language is one of `allowed`, along with their index.
+    pub fn filter_by_language<'a>(
+        &'a self,
+        allowed: &[Language],
+    ) -> impl Iterator<Item = (usize, &'a Package)> {
+        self.packages
+            .iter()
+            .enumerate()
+            .filter(move |(_, pkg)| allowed.iter().any(|l| l.matches(&pkg.language)))
+    }
+
+    /// Sum of the download sizes of the given payloads.
+    pub fn total_download_size(&self, payload_indices: &[usize]) -> u64 {
+        payload_indices.iter().map(|&i| self.payloads[i].size).sum()
+    }
+
+    /// Estimated extracted size of the given payloads. Uses the manifest's
+    /// `installSize` when present, otherwise assumes a 2x compression ratio.
+    pub fn total_installed_size(&self, payload_indices: &[usize]) -> u64 {
+        payload_indices
+            .iter()
+            .map(|&i| {
+                let payload = &self.payloads[i];
+                payload.install_size.unwrap_or(payload.size * 2)
+            })
+            .sum()
+    }
+
+    /// Identify every package once with [`get_install_pkg`], so callers that go
+    /// over the packages more than once look the result up instead of scanning
+    /// the id again.
+    pub fn build_install_index(&self, host_arch: Arch, target_arch: Arch) -> InstallPkgIndex {
+        let map = self
+            .packages
+            .iter()
+            .enumerate()
+            .filter_map(|(pkg_index, pkg)| {
+                get_install_pkg(&pkg.id, host_arch, target_arch).map(|kind| (pkg_index, kind))
+            })
+            .collect();
+        InstallPkgIndex { map }
+    }
+
     pub fn pkg_index_from_payload_index(&self, payload_index: usize) -> usize {
         assert!(!self.packages.is_empty());
         let mut min = 0;
@@ -462,87 +762,304 @@ impl Packages {
             }
         }
     }
+
+    /// Check the invariants the rest of msvcup relies on without verifying
+    /// them: the packages' payload offsets never decrease, no two packages share
+    /// an id, version and language, payload URLs decoded to an http(s) URL
+    /// without invalid escapes or control characters, and payload file names
+    /// stay inside the directory they are extracted to. Sha256 hashes are
+    /// already checked when parsing. Returns every violation found.
+    pub fn check_consistency(&self) -> Vec<ConsistencyError> {
+        let mut errors = Vec::new();
+        let mut prev_offset = 0;
+        for (pkg_index, pkg) in self.packages.iter().enumerate() {
+            if pkg.payloads_offset < prev_offset || pkg.payloads_offset > self.payloads.len() {
+                errors.push(ConsistencyError::PayloadsOffset {
+                    pkg_index,
+                    offset: pkg.payloads_offset,
+                });
+            }
+            prev_offset = prev_offset.max(pkg.payloads_offset);
+        }
+
+        let mut seen: HashMap<(&str, &str, String), usize> = HashMap::new();
+        for (pkg_index, pkg) in self.packages.iter().enumerate() {
+            let language = match &pkg.language {
+                Language::Neutral => "neutral".to_string(),
+                Language::EnUs => "en-us".to_string(),
+                Language::Localized(tag) => tag.to_ascii_lowercase(),
+            };
+            if let Some(&first) =
+                seen.get(&(pkg.id.as_str(), pkg.version.as_str(), language.clone()))
+            {
+                errors.push(ConsistencyError::DuplicatePackage {
+                    id: pkg.id.clone(),
+                    version: pkg.version.clone(),
+                    first,
+                    second: pkg_index,
+                });
+            } else {
+                seen.insert((&pkg.id, &pkg.version, language), pkg_index);
+            }
+        }
+
+        for (payload_index, payload) in self.payloads.iter().enumerate() {
+            let url = &payload.url_decoded;
+            let has_scheme = url.starts_with("https://") || url.starts_with("http://");
+            // Invalid UTF-8 escapes decode to the replacement character
+            if !has_scheme || url.chars().any(|c| c.is_control() || c == '\u{FFFD}') {
+                errors.push(ConsistencyError::InvalidUrl {
+                    payload_index,
+                    url: url.clone(),
+                });
+            }
+            if !is_safe_file_name(&payload.file_name) {
+                errors.push(ConsistencyError::UnsafeFileName {
+                    payload_index,
+                    file_name: payload.file_name.clone(),
+                });
+            }
+        }
+        errors
+    }
 }
 
-/// Parse the VS manifest JSON into Packages
-pub fn get_packages(vsman_path: &str, vsman_content: &str) -> Result<Packages> {
-    let parsed: serde_json::Value =
-        serde_json::from_str(vsman_content).with_context(|| format!("parsing '{}'", vsman_path))?;
-
-    let packages_arr = parsed
-        .get("packages")
-        .and_then(|v| v.as_array())
-        .ok_or_else(|| anyhow::anyhow!("{}: missing 'packages' array", vsman_path))?;
-
-    let mut out_packages = Vec::with_capacity(packages_arr.len());
-    let mut out_payloads = Vec::new();
-
-    for pkg_val in packages_arr {
-        let pkg_obj = pkg_val
-            .as_object()
-            .ok_or_else(|| anyhow::anyhow!("{}: package is not an object", vsman_path))?;
-
-        let id = pkg_obj
-            .get("id")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow::anyhow!("{}: package missing 'id'", vsman_path))?;
-        let version = pkg_obj
-            .get("version")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow::anyhow!("{}: package missing 'version'", vsman_path))?;
-
-        let language = match pkg_obj.get("language").and_then(|v| v.as_str()) {
-            Some(lang) => Language::from_str(lang),
-            None => Language::Neutral,
-        };
+/// Whether a manifest `fileName` is a relative path without `..` components,
+/// with either separator.
+fn is_safe_file_name(file_name: &str) -> bool {
+    !file_name.is_empty()
+        && !file_name.contains('\0')
+        && !file_name.starts_with(['/', '\\'])
+        && !file_name.contains(':')
+        && file_name.split(['/', '\\']).all(|part| part != "..")
+}
+
+/// A violated invariant of a parsed manifest, see [`Packages::check_consistency`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsistencyError {
+    /// A package's payloads start before the previous package's, or past the end
+    PayloadsOffset {
+        pkg_index: usize,
+        offset: usize,
+    },
+    /// Two packages with the same id, version and language
+    DuplicatePackage {
+        id: String,
+        version: String,
+        first: usize,
+        second: usize,
+    },
+    InvalidUrl {
+        payload_index: usize,
+        url: String,
+    },
+    /// A file name that is absolute, contains a NUL or a `..` component
+    UnsafeFileName {
+        payload_index: usize,
+        file_name: String,
+    },
+}
+
+impl fmt::Display for ConsistencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsistencyError::PayloadsOffset { pkg_index, offset } => write!(
+                f,
+                "package #{} has payloads offset {} out of order",
+                pkg_index, offset
+            ),
+            ConsistencyError::DuplicatePackage {
+                id,
+                version,
+                first,
+                second,
+            } => write!(
+                f,
+                "packages #{} and #{} are both '{}' version {}",
+                first, second, id, version
+            ),
+            ConsistencyError::InvalidUrl { payload_index, url } => {
+                write!(f, "payload #{} has invalid url '{}'", payload_index, url)
+            }
+            ConsistencyError::UnsafeFileName {
+                payload_index,
+                file_name,
+            } => write!(
+                f,
+                "payload #{} has unsafe file name {:?}",
+                payload_index, file_name
+            ),
+        }
+    }
+}
+
+/// A VS manifest package as it appears in the JSON. Only one is held in memory
+/// at a time while parsing, see [`get_packages_streaming`].
+#[derive(Deserialize)]
+struct ManifestPackage {
+    id: String,
+    version: String,
+    #[serde(default)]
+    language: Option<String>,
+    #[serde(default)]
+    payloads: Vec<ManifestPayload>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestPayload {
+    file_name: String,
+    sha256: String,
+    url: String,
+    #[serde(default)]
+    size: u64,
+    #[serde(default)]
+    install_size: Option<u64>,
+}
+
+/// Deserializes the top-level manifest object, converting each entry of its
+/// `packages` array as soon as it's parsed and skipping every other key.
+struct PackagesVisitor;
+
+impl<'de> serde::de::Visitor<'de> for PackagesVisitor {
+    type Value = Packages;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a VS manifest object")
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Packages, A::Error> {
+        let mut packages = None;
+        while let Some(key) = map.next_key::<std::borrow::Cow<str>>()? {
+            if key == "packages" {
+                packages = Some(map.next_value_seed(PackageSeqSeed)?);
+            } else {
+                map.next_value::<serde::de::IgnoredAny>()?;
+            }
+        }
+        packages.ok_or_else(|| serde::de::Error::custom("missing 'packages' array"))
+    }
+}
+
+struct PackageSeqSeed;
+
+impl<'de> serde::de::DeserializeSeed<'de> for PackageSeqSeed {
+    type Value = Packages;
+
+    fn deserialize<D: serde::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Packages, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
 
-        let payloads_offset = out_payloads.len();
-
-        if let Some(payloads_val) = pkg_obj.get("payloads")
-            && let Some(payloads_arr) = payloads_val.as_array()
-        {
-            for payload_val in payloads_arr {
-                let payload_obj = payload_val
-                    .as_object()
-                    .ok_or_else(|| anyhow::anyhow!("{}: payload is not an object", vsman_path))?;
-
-                let file_name = payload_obj
-                    .get("fileName")
-                    .and_then(|v| v.as_str())
-                    .ok_or_else(|| anyhow::anyhow!("{}: payload missing 'fileName'", vsman_path))?;
-                let sha256_str = payload_obj
-                    .get("sha256")
-                    .and_then(|v| v.as_str())
-                    .ok_or_else(|| anyhow::anyhow!("{}: payload missing 'sha256'", vsman_path))?;
-                let sha256_hex = sha256_str.to_ascii_lowercase();
-                let sha256 = Sha256::parse_hex(&sha256_hex).ok_or_else(|| {
-                    anyhow::anyhow!("{}: invalid sha256 '{}'", vsman_path, sha256_str)
-                })?;
-                let url = payload_obj
-                    .get("url")
-                    .and_then(|v| v.as_str())
-                    .ok_or_else(|| anyhow::anyhow!("{}: payload missing 'url'", vsman_path))?;
-
-                out_payloads.push(Payload {
-                    url_decoded: alloc_url_percent_decoded(url),
+impl<'de> serde::de::Visitor<'de> for PackageSeqSeed {
+    type Value = Packages;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a 'packages' array")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Packages, A::Error> {
+        let mut out = Packages {
+            packages: Vec::with_capacity(seq.size_hint().unwrap_or(0)),
+            payloads: Vec::new(),
+        };
+        while let Some(pkg) = seq.next_element::<ManifestPackage>()? {
+            let payloads_offset = out.payloads.len();
+            for payload in pkg.payloads {
+                let sha256 =
+                    Sha256::parse_hex(&payload.sha256.to_ascii_lowercase()).ok_or_else(|| {
+                        serde::de::Error::custom(format!("invalid sha256 '{}'", payload.sha256))
+                    })?;
+                out.payloads.push(Payload {
+                    url_decoded: alloc_url_percent_decoded(&payload.url),
                     sha256,
-                    file_name: file_name.to_string(),
+                    file_name: payload.file_name,
+                    size: payload.size,
+                    install_size: payload.install_size,
                 });
             }
+            out.packages.push(Package {
+                id: pkg.id,
+                version: pkg.version,
+                payloads_offset,
+                language: match pkg.language {
+                    Some(lang) => Language::from_str(&lang),
+                    None => Language::Neutral,
+                },
+            });
         }
-
-        out_packages.push(Package {
-            id: id.to_string(),
-            version: version.to_string(),
-            payloads_offset,
-            language,
-        });
+        Ok(out)
     }
+}
+
+/// Drop the packages of a VS manifest that msvcup doesn't identify: ones that
+/// [`identify_package`] doesn't know and that have no SDK payload for any arch.
+/// The kept packages and the other top-level keys are left as they are.
+/// Returns the number of packages kept and the number there were.
+pub fn prune_manifest(manifest: &mut serde_json::Value) -> Result<(usize, usize)> {
+    let Some(packages) = manifest
+        .get_mut("packages")
+        .and_then(|packages| packages.as_array_mut())
+    else {
+        anyhow::bail!("missing 'packages' array");
+    };
+    let total = packages.len();
+    packages.retain(|pkg| {
+        let id = pkg.get("id").and_then(|id| id.as_str()).unwrap_or_default();
+        if !matches!(identify_package(id), PackageId::Unknown) {
+            return true;
+        }
+        pkg.get("payloads")
+            .and_then(|payloads| payloads.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|payload| payload.get("fileName").and_then(|name| name.as_str()))
+            .any(|file_name| {
+                Arch::ALL
+                    .into_iter()
+                    .any(|arch| identify_payload(file_name, arch) == PayloadId::Sdk)
+            })
+    });
+    Ok((packages.len(), total))
+}
+
+/// Parse the VS manifest JSON into Packages
+#[cfg(test)]
+pub fn get_packages(vsman_path: &str, vsman_content: &str) -> Result<Packages> {
+    use anyhow::Context;
+    let mut deserializer = serde_json::Deserializer::from_str(vsman_content);
+    let packages = serde::Deserializer::deserialize_map(&mut deserializer, PackagesVisitor)
+        .and_then(|pkgs| deserializer.end().map(|()| pkgs))
+        .with_context(|| format!("parsing '{}'", vsman_path))?;
+    debug_assert!(packages.check_consistency().is_empty());
+    Ok(packages)
+}
 
-    Ok(Packages {
-        packages: out_packages,
-        payloads: out_payloads,
-    })
+/// Parse a VS manifest from a reader into Packages. Unlike parsing into a
+/// `serde_json::Value` first, this only holds one manifest package in memory
+/// at a time besides the result, so peak memory doesn't grow with the size of
+/// the manifest's other content.
+pub fn get_packages_streaming(reader: impl std::io::Read) -> Result<Packages> {
+    let mut deserializer = serde_json::Deserializer::from_reader(std::io::BufReader::new(reader));
+    let pkgs = serde::Deserializer::deserialize_map(&mut deserializer, PackagesVisitor)?;
+    deserializer.end()?;
+    if cfg!(debug_assertions) {
+        // Real manifests repeat an id, version and language for packages that
+        // only differ in fields msvcup doesn't read (e.g. chip), so those are
+        // only logged
+        let (duplicates, errors): (Vec<_>, Vec<_>) = pkgs
+            .check_consistency()
+            .into_iter()
+            .partition(|err| matches!(err, ConsistencyError::DuplicatePackage { .. }));
+        for err in duplicates {
+            log::debug!("manifest: {}", err);
+        }
+        debug_assert!(errors.is_empty(), "inconsistent manifest: {:?}", errors);
+    }
+    Ok(pkgs)
 }
 
 /// Identify which packages should be installed based on the install request.
@@ -578,7 +1095,7 @@ pub fn get_install_pkg(id: &str, host_arch: Arch, target_arch: Arch) -> Option<I
                     let rest2 = &after_dot[next_end..];
                     let (arch_part, arch_end) = scan_id_part(rest2, 0);
                     if let Some(arch) = Arch::from_str_ignore_case(arch_part) {
-                        if arch != target_arch {
+                        if !target_arch.accepts_payload_arch(arch) {
                             return None;
                         }
                         let final_rest = &rest2[arch_end..];
@@ -588,7 +1105,7 @@ pub fn get_install_pkg(id: &str, host_arch: Arch, target_arch: Arch) -> Option<I
                     }
                 } else if let Some(arch) = Arch::from_str_ignore_case(next_part) {
                     // CRT.<arch>.Desktop.base, CRT.<arch>.Store.base, etc.
-                    if arch != target_arch {
+                    if !target_arch.accepts_payload_arch(arch) {
                         return None;
                     }
                     let final_rest = &after_dot[next_end..];
@@ -609,7 +1126,7 @@ pub fn get_install_pkg(id: &str, host_arch: Arch, target_arch: Arch) -> Option<I
             target_arch: pkg_target,
             name,
         } => {
-            if pkg_host != host_arch || pkg_target != target_arch {
+            if pkg_host != host_arch || !target_arch.accepts_payload_arch(pkg_target) {
                 return None;
             }
             if name == "base" || name == "Res.base" {
@@ -622,6 +1139,9 @@ pub fn get_install_pkg(id: &str, host_arch: Arch, target_arch: Arch) -> Option<I
         PackageId::Diasdk => Some(InstallPkgKind::Diasdk),
         PackageId::Ninja(version) => Some(InstallPkgKind::Ninja(version.to_string())),
         PackageId::Cmake(version) => Some(InstallPkgKind::Cmake(version.to_string())),
+        PackageId::Nasm(version) => Some(InstallPkgKind::Nasm(version.to_string())),
+        PackageId::Python(version) => Some(InstallPkgKind::Python(version.to_string())),
+        PackageId::Component(_) => None,
     }
 }
 
@@ -632,6 +1152,20 @@ pub enum InstallPkgKind {
     Diasdk,
     Ninja(String),
     Cmake(String),
+    Nasm(String),
+    Python(String),
+}
+
+/// [`get_install_pkg`] results by package index, see [`Packages::build_install_index`].
+#[derive(Debug, Default)]
+pub struct InstallPkgIndex {
+    map: HashMap<usize, InstallPkgKind>,
+}
+
+impl InstallPkgIndex {
+    pub fn get(&self, pkg_index: usize) -> Option<&InstallPkgKind> {
+        self.map.get(&pkg_index)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -645,6 +1179,28 @@ pub enum ManifestUpdate {
 mod tests {
     use super::*;
 
+    #[test]
+    fn find_same_kind_in_sorted_packages() {
+        let mut pkgs = Vec::new();
+        for s in ["sdk-10.0.22621.7", "msvc-14.43.17.13", "ninja-1.12.1"] {
+            crate::util::insert_sorted(
+                &mut pkgs,
+                MsvcupPackage::from_string(s).unwrap(),
+                MsvcupPackage::order,
+            );
+        }
+        assert!(find_same_kind(&pkgs).is_none());
+
+        crate::util::insert_sorted(
+            &mut pkgs,
+            MsvcupPackage::from_string("msvc-14.30.17.6").unwrap(),
+            MsvcupPackage::order,
+        );
+        let (a, b) = find_same_kind(&pkgs).unwrap();
+        assert_eq!(a.to_string(), "msvc-14.30.17.6");
+        assert_eq!(b.to_string(), "msvc-14.43.17.13");
+    }
+
     // --- MsvcupPackageKind tests ---
 
     #[test]
@@ -655,6 +1211,8 @@ mod tests {
         assert_eq!(MsvcupPackageKind::Diasdk.as_str(), "diasdk");
         assert_eq!(MsvcupPackageKind::Ninja.as_str(), "ninja");
         assert_eq!(MsvcupPackageKind::Cmake.as_str(), "cmake");
+        assert_eq!(MsvcupPackageKind::Nasm.as_str(), "nasm");
+        assert_eq!(MsvcupPackageKind::Python.as_str(), "python");
     }
 
     #[test]
@@ -673,10 +1231,34 @@ mod tests {
         let (kind, _) = MsvcupPackageKind::from_prefix("cmake-3.31.4").unwrap();
         assert_eq!(kind, MsvcupPackageKind::Cmake);
 
+        let (kind, version) = MsvcupPackageKind::from_prefix("nasm-2.16.03").unwrap();
+        assert_eq!(kind, MsvcupPackageKind::Nasm);
+        assert_eq!(version, "2.16.03");
+
+        let (kind, version) = MsvcupPackageKind::from_prefix("python-3.13.1").unwrap();
+        assert_eq!(kind, MsvcupPackageKind::Python);
+        assert_eq!(version, "3.13.1");
+
         assert!(MsvcupPackageKind::from_prefix("unknown-1.0").is_none());
         assert!(MsvcupPackageKind::from_prefix("").is_none());
     }
 
+    #[test]
+    fn package_kind_from_str_roundtrips() {
+        for kind in MsvcupPackageKind::ALL {
+            assert_eq!(kind.as_str().parse::<MsvcupPackageKind>().unwrap(), kind);
+        }
+        assert!("".parse::<MsvcupPackageKind>().is_err());
+        let err = "msvc-14.30.17.6".parse::<MsvcupPackageKind>().unwrap_err();
+        assert_eq!(err.input, "msvc-14.30.17.6");
+        assert_eq!(err.valid.len(), MsvcupPackageKind::ALL.len());
+        assert_eq!(
+            err.to_string(),
+            "unknown package kind 'msvc-14.30.17.6', expected one of: \
+             msvc, sdk, msbuild, diasdk, ninja, cmake, nasm, python"
+        );
+    }
+
     #[test]
     fn package_kind_display() {
         assert_eq!(format!("{}", MsvcupPackageKind::Msvc), "msvc");
@@ -720,6 +1302,24 @@ mod tests {
         assert_eq!(pkg.pool_string(), "sdk-10.0.22621.7");
     }
 
+    #[test]
+    fn msvcup_package_from_pool_string_roundtrip() {
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.38.33130");
+        let parsed = MsvcupPackage::from_pool_string(&pkg.pool_string()).unwrap();
+        assert_eq!(parsed, pkg);
+        assert!(MsvcupPackage::from_pool_string("msvc-").is_err());
+        assert!(MsvcupPackage::from_pool_string("cache").is_err());
+    }
+
+    #[test]
+    fn msvcup_package_try_from_path() {
+        let pkg = MsvcupPackage::try_from_path(Path::new("/var/msvcup/msvc-14.38.33130")).unwrap();
+        assert_eq!(pkg.kind, MsvcupPackageKind::Msvc);
+        assert_eq!(pkg.version, "14.38.33130");
+        assert!(MsvcupPackage::try_from_path(Path::new("/var/msvcup/cache")).is_none());
+        assert!(MsvcupPackage::try_from_path(Path::new("/")).is_none());
+    }
+
     #[test]
     fn msvcup_package_order_by_kind_first() {
         let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.30.17.6");
@@ -813,6 +1413,49 @@ mod tests {
         }
     }
 
+    #[test]
+    fn identify_nasm() {
+        match identify_package("nasm-2.16.03") {
+            PackageId::Nasm(v) => assert_eq!(v, "2.16.03"),
+            other => panic!("expected Nasm, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn identify_python() {
+        match identify_package("python-3.13.1") {
+            PackageId::Python(v) => assert_eq!(v, "3.13.1"),
+            other => panic!("expected Python, got {:?}", other),
+        }
+        assert!(matches!(
+            identify_package("python-3.13.1-embed"),
+            PackageId::Unexpected {
+                offset: 13,
+                expected: "end"
+            }
+        ));
+    }
+
+    #[test]
+    fn identify_component() {
+        match identify_package("Microsoft.VisualStudio.Component.VC.Tools.x86.x64") {
+            PackageId::Component(name) => assert_eq!(name, "VC.Tools.x86.x64"),
+            other => panic!("expected Component, got {:?}", other),
+        }
+        assert!(matches!(
+            identify_package("Microsoft.VisualStudio.Component."),
+            PackageId::Unknown
+        ));
+        assert!(
+            get_install_pkg(
+                "Microsoft.VisualStudio.Component.VC.Tools.x86.x64",
+                Arch::X64,
+                Arch::X64
+            )
+            .is_none()
+        );
+    }
+
     #[test]
     fn identify_unknown() {
         assert!(matches!(
@@ -878,6 +1521,108 @@ mod tests {
         );
     }
 
+    #[test]
+    fn identify_payload_any_separator() {
+        for name in [
+            "Installers\\Windows SDK Desktop Headers x64-x86_en-us.msi",
+            "Installers/Windows SDK Desktop Headers x64-x86_en-us.msi",
+            "Installers/Universal CRT Headers Libraries and Sources-x86_en-us.msi",
+        ] {
+            assert_eq!(
+                identify_payload(name, Arch::X64),
+                PayloadId::Sdk,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(
+            identify_payload(
+                "Installers/Windows SDK Desktop Libs arm64-x86_en-us.msi",
+                Arch::X64
+            ),
+            PayloadId::Unknown
+        );
+        assert_eq!(
+            identify_payload("Installers/VC_redist.x64.exe", Arch::X64),
+            PayloadId::Redist
+        );
+        assert_eq!(
+            identify_payload("Installers/sub\\c2.pdb", Arch::X64),
+            PayloadId::DebugInfo
+        );
+    }
+
+    #[test]
+    fn identify_debug_payload() {
+        assert_eq!(
+            identify_payload(
+                "Installers\\Windows SDK Desktop Symbols-x86_en-us.msi",
+                Arch::X64
+            ),
+            PayloadId::DebugInfo
+        );
+        assert_eq!(
+            identify_payload("bin\\Hostx64\\x64\\c2.PDB", Arch::X64),
+            PayloadId::DebugInfo
+        );
+        // The debug CRT is linked by debug builds
+        assert!(!is_debug_payload(
+            "Microsoft.VC.14.43.17.13.CRT.x64.Desktop.debug.base.vsix"
+        ));
+        // Only the file name counts, not its directory
+        assert!(!is_debug_payload("Symbols\\tools.vsix"));
+    }
+
+    #[test]
+    fn identify_msvc_redist_and_tools_payloads() {
+        assert_eq!(
+            identify_payload(
+                "Installers\\Microsoft Visual C++ 2022 x64 Minimum Runtime.vsix",
+                Arch::X64
+            ),
+            PayloadId::Msvc
+        );
+        assert_eq!(
+            identify_payload("Installers\\VC_redist.x64.exe", Arch::X64),
+            PayloadId::Redist
+        );
+        assert_eq!(
+            identify_payload(
+                "Installers\\Windows SDK Desktop Tools x64-x86_en-us.msi",
+                Arch::X64
+            ),
+            PayloadId::Tools
+        );
+        assert!(is_redist_package(
+            "Microsoft.VC.14.43.17.13.CRT.Redist.X64.base"
+        ));
+        assert!(!is_redist_package(
+            "Microsoft.VC.14.43.17.13.CRT.x64.Desktop.base"
+        ));
+    }
+
+    #[test]
+    fn optional_payloads() {
+        for (name, optional) in [
+            ("Microsoft.VC.14.43.17.13.CRT.Redist.X64.base.vsix", true),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.x64.Desktop.debug.base.vsix",
+                true,
+            ),
+            ("VC_redist.x64.exe", true),
+            ("Windows SDK Desktop Symbols-x86_en-us.msi", true),
+            ("Microsoft.VC.14.43.17.13.CRT.x64.Desktop.base.vsix", false),
+            (
+                "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.base.vsix",
+                false,
+            ),
+            ("Windows SDK Desktop Headers x64-x86_en-us.msi", false),
+            ("ninja-win.zip", false),
+        ] {
+            assert_eq!(is_optional_payload(name), optional, "{}", name);
+        }
+    }
+
     #[test]
     fn identify_unknown_payload() {
         assert_eq!(
@@ -902,6 +1647,18 @@ mod tests {
             get_lock_file_url_kind("https://example.com/file.cab"),
             Some(LockFileUrlKind::Cab)
         );
+        assert_eq!(
+            get_lock_file_url_kind("https://example.com/llvm-19.1.0-x86_64-pc-windows-msvc.tar.xz"),
+            Some(LockFileUrlKind::TarXz)
+        );
+        assert_eq!(
+            get_lock_file_url_kind("https://example.com/tool-1.0.tgz"),
+            Some(LockFileUrlKind::TarGz)
+        );
+        assert_eq!(
+            get_lock_file_url_kind("https://example.com/notes.txt.gz"),
+            None
+        );
         assert_eq!(
             get_lock_file_url_kind("https://example.com/file.zip"),
             Some(LockFileUrlKind::Zip)
@@ -917,8 +1674,40 @@ mod tests {
         assert_eq!(Language::from_str("neutral"), Language::Neutral);
         assert_eq!(Language::from_str("en-US"), Language::EnUs);
         assert_eq!(Language::from_str("En-Us"), Language::EnUs);
-        assert_eq!(Language::from_str("fr-FR"), Language::Other);
-        assert_eq!(Language::from_str("zh-CN"), Language::Other);
+        assert_eq!(
+            Language::from_str("fr-FR"),
+            Language::Localized("fr-FR".to_string())
+        );
+        assert_eq!(Language::from_str("NEUTRAL"), Language::Neutral);
+        assert_eq!(Language::from_str("en-us"), Language::EnUs);
+        assert_eq!(
+            Language::from_str("JA-JP"),
+            Language::Localized("JA-JP".to_string())
+        );
+        assert_eq!(
+            Language::from_str("sr-Latn-RS"),
+            Language::Localized("sr-Latn-RS".to_string())
+        );
+    }
+
+    #[test]
+    fn language_matches_ignores_case() {
+        assert!(Language::from_str("ja-JP").matches(&Language::from_str("JA-jp")));
+        assert!(!Language::from_str("ja-JP").matches(&Language::from_str("zh-CN")));
+        assert!(Language::EnUs.matches(&Language::EnUs));
+        assert!(!Language::EnUs.matches(&Language::Neutral));
+    }
+
+    #[test]
+    fn language_tag_detection() {
+        assert!(is_language_tag("ja-jp"));
+        assert!(is_language_tag("sr-Latn-RS"));
+        assert!(is_language_tag("de"));
+        assert!(!is_language_tag(""));
+        assert!(!is_language_tag("x"));
+        assert!(!is_language_tag("en_US"));
+        assert!(!is_language_tag("en-"));
+        assert!(!is_language_tag("123-US"));
     }
 
     // --- get_install_pkg tests ---
@@ -957,6 +1746,22 @@ mod tests {
         assert!(result.is_none());
     }
 
+    #[test]
+    fn get_install_pkg_arm64ec_uses_arm64_tools() {
+        let result = get_install_pkg(
+            "Microsoft.VC.14.43.Tools.HostX64.TargetARM64.base",
+            Arch::X64,
+            Arch::Arm64Ec,
+        );
+        assert!(matches!(result, Some(InstallPkgKind::Msvc(_))));
+        let result = get_install_pkg(
+            "Microsoft.VC.14.43.Tools.HostX64.TargetX64.base",
+            Arch::X64,
+            Arch::Arm64Ec,
+        );
+        assert!(result.is_none());
+    }
+
     #[test]
     fn get_install_pkg_msbuild() {
         let result = get_install_pkg("Microsoft.Build", Arch::X64, Arch::X64);
@@ -969,18 +1774,515 @@ mod tests {
         assert!(matches!(result, Some(InstallPkgKind::Diasdk)));
     }
 
+    #[test]
+    fn install_index_matches_get_install_pkg() {
+        let pkgs = get_packages(
+            "test.json",
+            r#"{"packages": [
+                {"id": "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.base", "version": "14.43.34808"},
+                {"id": "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetARM64.base", "version": "14.43.34808"},
+                {"id": "some.random.package", "version": "1.0"},
+                {"id": "Microsoft.Build", "version": "17.13.0"}
+            ]}"#,
+        )
+        .unwrap();
+        let index = pkgs.build_install_index(Arch::X64, Arch::X64);
+        assert!(matches!(index.get(0), Some(InstallPkgKind::Msvc(v)) if v == "14.43.17.13"));
+        assert!(index.get(1).is_none());
+        assert!(index.get(2).is_none());
+        assert!(matches!(index.get(3), Some(InstallPkgKind::Msbuild(_))));
+        assert!(index.get(4).is_none());
+    }
+
     #[test]
     fn get_install_pkg_unknown() {
         let result = get_install_pkg("some.random.package", Arch::X64, Arch::X64);
         assert!(result.is_none());
     }
 
+    /// `identify_package` on ids from the release manifest, plus malformed ids
+    /// for each `Unexpected` branch. Expected values are the `Debug` output.
+    #[test]
+    fn identify_package_table() {
+        let cases = [
+            (
+                "Microsoft.VC.14.43.17.13.CRT.Headers.base",
+                r#"MsvcVersionSomething { build_version: "14.43.17.13", something: ".CRT.Headers.base" }"#,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.Tools.Core.Props",
+                r#"MsvcVersionToolsSomething { build_version: "14.43.17.13", something: ".Tools.Core.Props" }"#,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.Tools.HostX86.TargetArm64.Res.base",
+                r#"MsvcVersionHostTarget { build_version: "14.43.17.13", host_arch: X86, target_arch: Arm64, name: "Res.base" }"#,
+            ),
+            (
+                "Microsoft.VC.Servicing",
+                r#"Unexpected { offset: 13, expected: "version" }"#,
+            ),
+            (
+                "Microsoft.VC.14.43",
+                r#"Unexpected { offset: 18, expected: "anything" }"#,
+            ),
+            (
+                "Microsoft.VC.14.43.Tools",
+                r#"Unexpected { offset: 24, expected: "anything" }"#,
+            ),
+            (
+                "Microsoft.VC.14.43.Tools.HostMips.TargetX64.base",
+                r#"Unexpected { offset: 29, expected: "arch" }"#,
+            ),
+            (
+                "Microsoft.VC.14.43.Tools.HostX64.base",
+                r#"Unexpected { offset: 33, expected: "target_arch" }"#,
+            ),
+            (
+                "Microsoft.VC.14.43.Tools.HostX64.TargetMips.base",
+                r#"Unexpected { offset: 39, expected: "arch" }"#,
+            ),
+            (
+                "Microsoft.VisualStudio.VC.MSBuild.v170.Base",
+                r#"Msbuild("170")"#,
+            ),
+            ("Microsoft.VisualStudio.VC.MSBuild.v160.Base", "Unknown"),
+            ("Microsoft.VisualStudio.Component.", "Unknown"),
+            ("ninja-", r#"Unexpected { offset: 6, expected: "version" }"#),
+            (
+                "ninja-1.12.1-win",
+                r#"Unexpected { offset: 12, expected: "end" }"#,
+            ),
+            ("cmake-3.31.4", r#"Cmake("3.31.4")"#),
+            (
+                "cmake-3.31.4x",
+                r#"Unexpected { offset: 12, expected: "end" }"#,
+            ),
+            ("nasm-", r#"Unexpected { offset: 5, expected: "version" }"#),
+            ("python-3.13.1", r#"Python("3.13.1")"#),
+            (
+                "python-",
+                r#"Unexpected { offset: 7, expected: "version" }"#,
+            ),
+            ("Win11SDK_10.0.26100", "Unknown"),
+        ];
+        for (id, expected) in cases {
+            assert_eq!(format!("{:?}", identify_package(id)), expected, "{}", id);
+        }
+    }
+
+    /// Which CRT and host/target packages `get_install_pkg` installs, as
+    /// (id, host arch, target arch, installed).
+    #[test]
+    fn get_install_pkg_table() {
+        let cases = [
+            (
+                "Microsoft.VC.14.43.17.13.CRT.Headers.base",
+                Arch::X64,
+                Arch::Arm64,
+                true,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.Redist.X64.base",
+                Arch::X64,
+                Arch::X64,
+                true,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.Redist.ARM64.base",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.Redist.X64.Resources",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.x64.Desktop.base",
+                Arch::X64,
+                Arch::X64,
+                true,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.x64.Desktop.debug.base",
+                Arch::X64,
+                Arch::X64,
+                true,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.x64.Store.base",
+                Arch::X64,
+                Arch::X64,
+                true,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.x64.OneCore.Desktop.base",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.x86.Desktop.base",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.arm64.Desktop.base",
+                Arch::Arm64,
+                Arch::Arm64,
+                true,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.CRT.Source.base",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.ATL.Headers.base",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.Tools.Core.Props",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.Res.base",
+                Arch::X64,
+                Arch::X64,
+                true,
+            ),
+            (
+                "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetX64.Resources",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+            ("Microsoft.VC.Servicing", Arch::X64, Arch::X64, false),
+            (
+                "Microsoft.VisualStudio.Component.VC.Tools.x86.x64",
+                Arch::X64,
+                Arch::X64,
+                false,
+            ),
+        ];
+        for (id, host_arch, target_arch, installed) in cases {
+            match get_install_pkg(id, host_arch, target_arch) {
+                Some(InstallPkgKind::Msvc(version)) => {
+                    assert!(installed, "{} should not be installed", id);
+                    assert_eq!(version, "14.43.17.13", "{}", id);
+                }
+                None => assert!(!installed, "{} should be installed", id),
+                other => panic!("{}: expected an msvc package, got {:?}", id, other),
+            }
+        }
+    }
+
+    /// A manifest with packages of 0, 2, 1, 0 and 3 payloads, the last one
+    /// ending the payload list.
+    fn ranges_manifest() -> String {
+        let payload = |name: &str| {
+            format!(
+                r#"{{"fileName": "{name}", "url": "https://example.com/{name}",
+                 "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"}}"#
+            )
+        };
+        let package = |id: &str, names: &[&str]| {
+            let payloads: Vec<String> = names.iter().map(|name| payload(name)).collect();
+            format!(
+                r#"{{"id": "{id}", "version": "1.0", "payloads": [{}]}}"#,
+                payloads.join(", ")
+            )
+        };
+        format!(
+            r#"{{"packages": [{}]}}"#,
+            [
+                package("Microsoft.VisualStudio.Component.VC.Tools.x86.x64", &[]),
+                package(
+                    "Microsoft.VC.14.43.17.13.CRT.Headers.base",
+                    &["a.vsix", "b.vsix"]
+                ),
+                package("Microsoft.VisualCpp.DIA.SDK", &["c.vsix"]),
+                package("Microsoft.VisualStudio.Component.Windows11SDK.26100", &[]),
+                package("Win11SDK_10.0.26100", &["d.msi", "e.cab", "f.cab"]),
+            ]
+            .join(", ")
+        )
+    }
+
+    #[test]
+    fn payload_ranges_from_manifest() {
+        let pkgs = get_packages("test.json", &ranges_manifest()).unwrap();
+        assert_eq!(pkgs.check_consistency(), []);
+        let offsets: Vec<usize> = pkgs.packages.iter().map(|p| p.payloads_offset).collect();
+        assert_eq!(offsets, [0, 0, 2, 3, 3]);
+        let ranges: Vec<_> = (0..pkgs.packages.len())
+            .map(|i| pkgs.payload_range_from_pkg_index(i))
+            .collect();
+        assert_eq!(ranges, [0..0, 0..2, 2..3, 3..3, 3..6]);
+        let names: Vec<&str> = pkgs
+            .payloads_from_pkg_index(4)
+            .iter()
+            .map(|p| p.file_name.as_str())
+            .collect();
+        assert_eq!(names, ["d.msi", "e.cab", "f.cab"]);
+        // Empty packages never own a payload
+        let owners: Vec<usize> = (0..pkgs.payloads.len())
+            .map(|i| pkgs.pkg_index_from_payload_index(i))
+            .collect();
+        assert_eq!(owners, [1, 1, 2, 4, 4, 4]);
+    }
+
+    // --- Payload size tests ---
+
+    fn sizes_manifest() -> &'static str {
+        r#"{"packages": [{
+            "id": "pkg",
+            "version": "1.0",
+            "payloads": [
+                {"fileName": "a.vsix", "url": "https://example.com/a.vsix", "size": 100,
+                 "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"},
+                {"fileName": "b.vsix", "url": "https://example.com/b.vsix", "size": 50,
+                 "installSize": 400,
+                 "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"},
+                {"fileName": "c.vsix", "url": "https://example.com/c.vsix",
+                 "sha256": "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"}
+            ]
+        }]}"#
+    }
+
+    #[test]
+    fn get_packages_reads_sizes() {
+        let pkgs = get_packages("test.json", sizes_manifest()).unwrap();
+        assert_eq!(pkgs.payloads[0].size, 100);
+        assert_eq!(pkgs.payloads[0].install_size, None);
+        assert_eq!(pkgs.payloads[1].install_size, Some(400));
+        assert_eq!(pkgs.payloads[2].size, 0);
+    }
+
+    #[test]
+    fn get_packages_streaming_matches_get_packages() {
+        let manifest = r#"{"manifestVersion": "1.1", "info": {"id": "x", "nested": [1, {"a": null}]},
+            "packages": [
+                {"id": "a", "version": "1.0", "type": "Vsix", "dependencies": {"b": "1.0"}},
+                {"id": "b", "version": "2.0", "language": "ja-JP", "payloads": [
+                    {"fileName": "b%20c.vsix", "url": "https://example.com/b%20c.vsix", "size": 7,
+                     "sha256": "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824"}
+                ]}
+            ],
+            "signature": {}}"#;
+        let from_str = get_packages("test.json", manifest).unwrap();
+        let streamed = get_packages_streaming(manifest.as_bytes()).unwrap();
+        for pkgs in [&from_str, &streamed] {
+            let ids: Vec<&str> = pkgs.packages.iter().map(|p| p.id.as_str()).collect();
+            assert_eq!(ids, ["a", "b"]);
+            assert_eq!(pkgs.packages[1].payloads_offset, 0);
+            assert_eq!(pkgs.packages[1].language, Language::from_str("ja-JP"));
+            assert_eq!(pkgs.payloads[0].url_decoded, "https://example.com/b c.vsix");
+            assert_eq!(pkgs.payloads[0].size, 7);
+            assert_eq!(
+                pkgs.payloads[0].sha256.to_hex(),
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+            );
+        }
+    }
+
+    #[test]
+    fn check_consistency() {
+        let pkgs = get_packages("test.json", sizes_manifest()).unwrap();
+        assert_eq!(pkgs.check_consistency(), []);
+
+        let payload = |url: &str, file_name: &str| Payload {
+            url_decoded: url.to_string(),
+            sha256: pkgs.payloads[0].sha256,
+            file_name: file_name.to_string(),
+            size: 0,
+            install_size: None,
+        };
+        let package = |id: &str, payloads_offset, language| Package {
+            id: id.to_string(),
+            version: "1.0".to_string(),
+            payloads_offset,
+            language,
+        };
+        let bad = Packages {
+            packages: vec![
+                package("a", 2, Language::Neutral),
+                package("b", 1, Language::Localized("de-DE".to_string())),
+                package("b", 3, Language::Localized("DE-de".to_string())),
+                package("b", 6, Language::EnUs),
+            ],
+            payloads: vec![
+                payload("https://example.com/a.vsix", "a.vsix"),
+                payload("https://example.com/b c.vsix", "sub\\b.vsix"),
+                payload("ftp://example.com/c.vsix", "../c.vsix"),
+                payload("https://example.com/\u{FFFD}.vsix", "C:\\d.vsix"),
+                payload("https://example.com/e\0.vsix", "e\0.vsix"),
+            ],
+        };
+        assert_eq!(
+            bad.check_consistency(),
+            [
+                ConsistencyError::PayloadsOffset {
+                    pkg_index: 1,
+                    offset: 1
+                },
+                ConsistencyError::PayloadsOffset {
+                    pkg_index: 3,
+                    offset: 6
+                },
+                ConsistencyError::DuplicatePackage {
+                    id: "b".to_string(),
+                    version: "1.0".to_string(),
+                    first: 1,
+                    second: 2
+                },
+                ConsistencyError::InvalidUrl {
+                    payload_index: 2,
+                    url: "ftp://example.com/c.vsix".to_string()
+                },
+                ConsistencyError::UnsafeFileName {
+                    payload_index: 2,
+                    file_name: "../c.vsix".to_string()
+                },
+                ConsistencyError::InvalidUrl {
+                    payload_index: 3,
+                    url: "https://example.com/\u{FFFD}.vsix".to_string()
+                },
+                ConsistencyError::UnsafeFileName {
+                    payload_index: 3,
+                    file_name: "C:\\d.vsix".to_string()
+                },
+                ConsistencyError::InvalidUrl {
+                    payload_index: 4,
+                    url: "https://example.com/e\0.vsix".to_string()
+                },
+                ConsistencyError::UnsafeFileName {
+                    payload_index: 4,
+                    file_name: "e\0.vsix".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            bad.check_consistency()[2].to_string(),
+            "packages #1 and #2 are both 'b' version 1.0"
+        );
+    }
+
+    #[test]
+    fn prune_manifest_keeps_identified_packages() {
+        let mut manifest: serde_json::Value = serde_json::from_str(
+            r#"{"info": {"id": "x"}, "packages": [
+                {"id": "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetARM64.base", "version": "14.43.34808"},
+                {"id": "Microsoft.VisualStudio.Debugger", "version": "17.13.0"},
+                {"id": "Win11SDK_10.0.22621", "version": "10.0.22621.7", "payloads": [
+                    {"fileName": "Installers\\Windows SDK Desktop Libs arm64-x86_en-us.msi"}]},
+                {"id": "ninja-1.12.1", "version": "1.12.1"},
+                {"id": "Microsoft.VisualStudio.Component.VC.Tools.x86.x64", "version": "17.13.0"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(prune_manifest(&mut manifest).unwrap(), (4, 5));
+        let ids: Vec<&str> = manifest["packages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|pkg| pkg["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "Microsoft.VC.14.43.17.13.Tools.HostX64.TargetARM64.base",
+                "Win11SDK_10.0.22621",
+                "ninja-1.12.1",
+                "Microsoft.VisualStudio.Component.VC.Tools.x86.x64"
+            ]
+        );
+        assert_eq!(manifest["info"]["id"], "x");
+
+        let mut no_packages = serde_json::json!({"info": {}});
+        assert!(prune_manifest(&mut no_packages).is_err());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "inconsistent manifest")]
+    fn get_packages_streaming_asserts_consistency() {
+        let manifest = r#"{"packages": [{"id": "a", "version": "1.0", "payloads": [
+            {"fileName": "../a.vsix", "url": "https://example.com/a.vsix",
+             "sha256": "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824"}
+        ]}]}"#;
+        let _ = get_packages_streaming(manifest.as_bytes());
+    }
+
+    #[test]
+    fn get_packages_errors() {
+        let err = get_packages("test.json", r#"{"info": {}}"#).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("missing 'packages' array"),
+            "{:#}",
+            err
+        );
+        let err = get_packages("test.json", r#"{"packages": [{"version": "1.0"}]}"#).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("missing field `id`"),
+            "{:#}",
+            err
+        );
+        let err = get_packages_streaming(&br#"{"packages": []} trailing"#[..]).unwrap_err();
+        assert!(err.to_string().contains("trailing"), "{}", err);
+    }
+
+    #[test]
+    fn total_sizes() {
+        let pkgs = get_packages("test.json", sizes_manifest()).unwrap();
+        assert_eq!(pkgs.total_download_size(&[0, 1, 2]), 150);
+        assert_eq!(pkgs.total_download_size(&[1]), 50);
+        assert_eq!(pkgs.total_download_size(&[]), 0);
+        // 100 * 2 (estimated) + 400 (from installSize)
+        assert_eq!(pkgs.total_installed_size(&[0, 1]), 600);
+    }
+
+    #[test]
+    fn filter_by_language() {
+        let pkgs = get_packages(
+            "test.json",
+            r#"{"packages": [
+                {"id": "a", "version": "1.0"},
+                {"id": "b", "version": "1.0", "language": "en-US"},
+                {"id": "c", "version": "1.0", "language": "ja-JP"},
+                {"id": "d", "version": "1.0", "language": "zh-CN"}
+            ]}"#,
+        )
+        .unwrap();
+        let ids = |allowed: &[Language]| -> Vec<String> {
+            pkgs.filter_by_language(allowed)
+                .map(|(_, pkg)| pkg.id.clone())
+                .collect()
+        };
+        assert_eq!(ids(DEFAULT_LANGUAGES), ["a", "b"]);
+        let mut allowed = DEFAULT_LANGUAGES.to_vec();
+        allowed.push(Language::from_str("ja-JP"));
+        assert_eq!(ids(&allowed), ["a", "b", "c"]);
+    }
+
     // --- MsvcupPackageParseError Display ---
 
     #[test]
     fn parse_error_display() {
         let err = MsvcupPackageParseError::UnknownName;
-        assert_eq!(format!("{}", err), "unknown package name");
+        assert_eq!(
+            format!("{}", err),
+            "unknown package name, expected <kind>-<version> where kind is one of: \
+             msvc, sdk, msbuild, diasdk, ninja, cmake, nasm, python"
+        );
 
         let err = MsvcupPackageParseError::InvalidVersion("abc".to_string());
         assert_eq!(format!("{}", err), "invalid version 'abc'");
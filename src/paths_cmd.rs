@@ -0,0 +1,83 @@
+use crate::install::query_package_version;
+use crate::manifest::MsvcupDir;
+use crate::output::{OutputFormat, print_records};
+use crate::packages::MsvcupPackage;
+use anyhow::Result;
+use serde::Serialize;
+use std::path::PathBuf;
+
+#[derive(Debug, Serialize)]
+pub struct PackagePaths {
+    pub package: String,
+    /// The package's install pool directory
+    pub path: PathBuf,
+    /// Toolchain version found in the pool (msvc and sdk only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// The versioned subdirectory, e.g. `VC/Tools/MSVC/<version>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub versioned_path: Option<PathBuf>,
+}
+
+pub fn package_paths(msvcup_dir: &MsvcupDir, msvcup_pkg: &MsvcupPackage) -> Result<PackagePaths> {
+    let path = msvcup_dir.package_path(msvcup_pkg);
+    let (version, versioned_path) = match query_package_version(msvcup_pkg, &path)? {
+        Some((version, versioned_path)) => (Some(version), Some(versioned_path)),
+        None => (None, None),
+    };
+    Ok(PackagePaths {
+        package: msvcup_pkg.pool_string(),
+        path,
+        version,
+        versioned_path,
+    })
+}
+
+/// Print the install pool directory (and versioned subdirectory, if any) of each package.
+pub fn paths_command(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    format: OutputFormat,
+) -> Result<()> {
+    let mut all_paths = Vec::new();
+    for msvcup_pkg in msvcup_pkgs {
+        all_paths.push(package_paths(msvcup_dir, msvcup_pkg)?);
+    }
+
+    print_records(format, &all_paths, |paths| {
+        let mut text = format!("{} {}", paths.package, paths.path.display());
+        if let Some(versioned_path) = &paths.versioned_path {
+            text.push_str(&format!("\n{} {}", paths.package, versioned_path.display()));
+        }
+        text
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::MsvcupPackageKind;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn package_paths_msvc_and_ninja() {
+        let root = TempDir::new("package_paths");
+        let msvc_root = root.join("msvc-14.43.34808");
+        std::fs::create_dir_all(msvc_root.join("VC/Tools/MSVC/14.43.34808")).unwrap();
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.34808");
+        let paths = package_paths(&msvcup_dir, &msvc).unwrap();
+        assert_eq!(paths.path, msvc_root);
+        assert_eq!(paths.version.as_deref(), Some("14.43.34808"));
+        assert_eq!(
+            paths.versioned_path,
+            Some(msvc_root.join("VC/Tools/MSVC/14.43.34808"))
+        );
+
+        let ninja = MsvcupPackage::new(MsvcupPackageKind::Ninja, "1.12.1");
+        let paths = package_paths(&msvcup_dir, &ninja).unwrap();
+        assert_eq!(paths.path, root.join("ninja-1.12.1"));
+        assert!(paths.versioned_path.is_none());
+    }
+}
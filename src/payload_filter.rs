@@ -0,0 +1,82 @@
+use crate::error::{ErrorKind, bail_kind};
+use crate::sha::Sha256;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// An explicit allowlist of payloads for `install --payload-filter-file`.
+///
+/// The file has one payload per line, either its sha256 or its file name as it
+/// appears at the end of the payload URL (e.g. `Microsoft.VC.14.43.CRT.Headers.base.vsix`).
+/// Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct PayloadFilter {
+    sha256s: HashSet<Sha256>,
+    names: HashSet<String>,
+}
+
+impl PayloadFilter {
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("reading payload filter file '{}'", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("parsing payload filter file '{}'", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut filter = Self::default();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Sha256::parse_hex(line) {
+                Some(sha256) => {
+                    filter.sha256s.insert(sha256);
+                }
+                None => {
+                    filter.names.insert(line.to_string());
+                }
+            }
+        }
+        if filter.sha256s.is_empty() && filter.names.is_empty() {
+            bail_kind!(
+                ErrorKind::Usage,
+                "the payload filter doesn't list any payloads"
+            );
+        }
+        Ok(filter)
+    }
+
+    pub fn allows(&self, sha256: &Sha256, name: &str) -> bool {
+        self.sha256s.contains(sha256) || self.names.contains(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::HELLO_SHA256;
+
+    const OTHER_SHA: &str = "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7";
+
+    #[test]
+    fn parse_and_match() {
+        let filter = PayloadFilter::parse(&format!(
+            "# minimal set\n{}\n\n  Microsoft.VC.14.43.CRT.Headers.base.vsix  \n",
+            HELLO_SHA256.to_uppercase()
+        ))
+        .unwrap();
+        let sha = Sha256::parse_hex(HELLO_SHA256).unwrap();
+        let other = Sha256::parse_hex(OTHER_SHA).unwrap();
+        assert!(filter.allows(&sha, "anything.vsix"));
+        assert!(filter.allows(&other, "Microsoft.VC.14.43.CRT.Headers.base.vsix"));
+        assert!(!filter.allows(&other, "Microsoft.VC.14.43.CRT.Source.base.vsix"));
+    }
+
+    #[test]
+    fn empty_filter_is_usage_error() {
+        let err = PayloadFilter::parse("# nothing\n\n").unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 2);
+    }
+}
@@ -1,8 +1,10 @@
 use crate::autoenv_cmd;
+use crate::channel_kind::ChannelKind;
 use crate::config::MsvcupConfig;
+use crate::error::{ErrorKind, bail_kind};
 use crate::install;
 use crate::manifest::MsvcupDir;
-use crate::packages::{ManifestUpdate, MsvcupPackageKind, get_packages};
+use crate::packages::{DEFAULT_LANGUAGES, ManifestUpdate, MsvcupPackageKind, PayloadExclusions};
 use anyhow::Result;
 use fs_err as fs;
 use std::path::{Path, PathBuf};
@@ -10,14 +12,33 @@ use std::path::{Path, PathBuf};
 pub async fn resolve_command(
     client: &reqwest::Client,
     msvcup_dir: &MsvcupDir,
-    config_path: &str,
-    out_dir: &str,
+    config_path: &Path,
+    out_dir: Option<&Path>,
+    channel_kind: &ChannelKind,
     manifest_update: ManifestUpdate,
 ) -> Result<()> {
-    let config_path = Path::new(config_path);
     let config = MsvcupConfig::from_file(config_path)?;
+    let out_dir = match out_dir {
+        Some(dir) => dir.to_path_buf(),
+        None => match config.out_dir_path(config_path) {
+            Some(dir) => dir,
+            None => bail_kind!(
+                ErrorKind::Usage,
+                "'{}' has no out_dir, pass --out-dir",
+                config_path.display()
+            ),
+        },
+    };
+    let out_dir = out_dir.as_path();
     let msvcup_pkgs = config.msvcup_packages()?;
     let target_arch = config.target_arch();
+    if let Some(deprecation) = target_arch.deprecation() {
+        log::warn!("target_arch '{}': {}", target_arch, deprecation);
+    }
+    let mut install_dir = MsvcupDir::with_path(msvcup_dir.root_path.clone());
+    for (kind, root) in config.install_roots() {
+        install_dir.set_package_root(kind, root.into());
+    }
     let lock_file_path = config.lock_file_path(config_path);
     let lock_file_str = lock_file_path.to_str().unwrap();
 
@@ -31,6 +52,7 @@ pub async fn resolve_command(
 
     let need_manifest_update = if try_no_update {
         if let Ok(content) = fs::read_to_string(&lock_file_path) {
+            crate::lockfile_parse::check_lock_file_version(lock_file_str, &content)?;
             if crate::lockfile_parse::check_lock_file_pkgs(lock_file_str, &content, &msvcup_pkgs)
                 .is_none()
             {
@@ -47,16 +69,17 @@ pub async fn resolve_command(
     };
 
     if need_manifest_update {
-        let (vsman_path, vsman_content) = crate::manifest::read_vs_manifest(
-            client,
-            msvcup_dir,
-            crate::channel_kind::ChannelKind::Release,
-            manifest_update,
-        )
-        .await?;
-
-        let pkgs = get_packages(vsman_path.to_str().unwrap(), &vsman_content)?;
-        install::update_lock_file(&msvcup_pkgs, lock_file_str, &pkgs, target_arch)?;
+        let pkgs =
+            crate::manifest::read_vs_manifest(client, msvcup_dir, channel_kind, manifest_update)
+                .await?;
+        install::update_lock_file(
+            &msvcup_pkgs,
+            lock_file_str,
+            &pkgs,
+            DEFAULT_LANGUAGES,
+            target_arch,
+            PayloadExclusions::default(),
+        )?;
         log::info!("lock file updated: '{}'", lock_file_str);
     }
 
@@ -64,12 +87,12 @@ pub async fn resolve_command(
     fs::create_dir_all(out_dir)?;
 
     // Copy the config file to the output directory
-    let out_config_path = Path::new(out_dir).join("msvcup.toml");
+    let out_config_path = out_dir.join("msvcup.toml");
     update_file_from_file(config_path, &out_config_path)?;
 
     // Copy the lock file to the output directory
     let out_lock_name = lock_file_path.file_name().unwrap();
-    let out_lock_path = Path::new(out_dir).join(out_lock_name);
+    let out_lock_path = out_dir.join(out_lock_name);
     update_file_from_file(&lock_file_path, &out_lock_path)?;
 
     // If the lock file name in the config is not just the filename, update the config copy
@@ -91,38 +114,59 @@ pub async fn resolve_command(
     let (autoenv_exe, msvcup_exe) = find_binaries()?;
 
     // Place msvcup-autoenv.exe and msvcup.exe so `msvcup-autoenv install` can find msvcup
-    let out_autoenv = Path::new(out_dir).join("msvcup-autoenv.exe");
+    let out_autoenv = out_dir.join("msvcup-autoenv.exe");
     update_file_from_file(&autoenv_exe, &out_autoenv)?;
-    let out_msvcup = Path::new(out_dir).join("msvcup.exe");
+    let out_msvcup = out_dir.join("msvcup.exe");
     update_file_from_file(&msvcup_exe, &out_msvcup)?;
 
     let has_msvc = msvcup_pkgs
         .iter()
         .any(|p| p.kind == MsvcupPackageKind::Msvc);
     let has_sdk = msvcup_pkgs.iter().any(|p| p.kind == MsvcupPackageKind::Sdk);
+    let has_nasm = msvcup_pkgs
+        .iter()
+        .any(|p| p.kind == MsvcupPackageKind::Nasm);
+    let has_python = msvcup_pkgs
+        .iter()
+        .any(|p| p.kind == MsvcupPackageKind::Python);
 
     if has_msvc {
         for tool in autoenv_cmd::MSVC_TOOLS {
-            let dest = Path::new(out_dir).join(format!("{}.exe", tool.name));
+            let dest = out_dir.join(format!("{}.exe", tool.name));
             update_file_from_file(&autoenv_exe, &dest)?;
         }
     }
     if has_sdk {
         for tool in autoenv_cmd::SDK_TOOLS {
-            let dest = Path::new(out_dir).join(format!("{}.exe", tool.name));
+            let dest = out_dir.join(format!("{}.exe", tool.name));
+            update_file_from_file(&autoenv_exe, &dest)?;
+        }
+    }
+    if has_nasm {
+        for tool in autoenv_cmd::NASM_TOOLS {
+            let dest = out_dir.join(format!("{}.exe", tool.name));
+            update_file_from_file(&autoenv_exe, &dest)?;
+        }
+    }
+    if has_python {
+        for tool in autoenv_cmd::PYTHON_TOOLS {
+            let dest = out_dir.join(format!("{}.exe", tool.name));
             update_file_from_file(&autoenv_exe, &dest)?;
         }
     }
 
     // Step 4: Generate toolchain.cmake
-    let cmake = autoenv_cmd::generate_toolchain_cmake(target_arch, has_msvc, has_sdk);
-    let cmake_path = Path::new(out_dir).join("toolchain.cmake");
+    let cmake = autoenv_cmd::generate_toolchain_cmake(target_arch, has_msvc, has_sdk, has_nasm);
+    let cmake_path = out_dir.join("toolchain.cmake");
     crate::util::update_file(&cmake_path, cmake.as_bytes())?;
 
-    log::info!("shims placed in '{}'", out_dir);
+    // Step 5: Generate cl.rsp and link.rsp if the packages are already installed
+    autoenv_cmd::write_rsp_files(&install_dir, &msvcup_pkgs, target_arch, out_dir)?;
+
+    log::info!("shims placed in '{}'", out_dir.display());
     log::info!(
         "run 'msvcup-autoenv install' in '{}' to install packages",
-        out_dir
+        out_dir.display()
     );
 
     Ok(())
@@ -0,0 +1,587 @@
+use crate::error::{ErrorKind, bail_kind};
+use crate::lockfile_parse::{LockFileJson, parse_lock_file};
+use crate::packages::{LockFileUrlKind, MsvcupPackage, get_lock_file_url_kind};
+use crate::sha::Sha256;
+use crate::util::basename_from_url;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use serde_json::{Value, json};
+use std::path::Path;
+
+/// Characters left unescaped in purl components (the RFC 3986 unreserved set).
+const PURL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    /// CycloneDX 1.5 JSON
+    CycloneDx,
+    /// SPDX 2.3 JSON
+    SpdxJson,
+}
+
+impl SbomFormat {
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "cyclonedx" => Some(Self::CycloneDx),
+            "spdx-json" => Some(Self::SpdxJson),
+            _ => None,
+        }
+    }
+}
+
+/// A lock file package and its payloads.
+#[derive(Debug)]
+pub struct SbomPackage {
+    pub pkg: MsvcupPackage,
+    pub payloads: Vec<SbomPayload>,
+}
+
+/// A downloaded file. The cabs of an MSI are listed as its `cabs`.
+#[derive(Debug)]
+pub struct SbomPayload {
+    pub name: String,
+    pub url: String,
+    pub sha256: String,
+    pub cabs: Vec<SbomPayload>,
+}
+
+/// Group the lock file payloads by package and place each cab under the MSI
+/// that references it. `msi_cab_names` returns the cab names of an MSI payload
+/// (from its Media table), or None if the MSI can't be read. Cabs that no
+/// readable MSI references are listed directly under the first package with
+/// MSI payloads.
+pub fn collect_packages(
+    lock_file: &LockFileJson,
+    msi_cab_names: impl Fn(&Sha256, &str) -> Option<Vec<String>>,
+) -> Result<Vec<SbomPackage>> {
+    let mut placed_cabs: Vec<&str> = Vec::new();
+    let mut sbom_pkgs = Vec::new();
+    let mut first_msi_pkg = None;
+    for lock_pkg in &lock_file.packages {
+        let pkg = MsvcupPackage::from_string(&lock_pkg.name)
+            .map_err(|e| anyhow::anyhow!("invalid package name '{}': {}", lock_pkg.name, e))?;
+        let mut payloads = Vec::new();
+        for entry in &lock_pkg.payloads {
+            let name = basename_from_url(&entry.url);
+            let mut cabs = Vec::new();
+            if get_lock_file_url_kind(&entry.url) == Some(LockFileUrlKind::Msi) {
+                first_msi_pkg.get_or_insert(sbom_pkgs.len());
+                let sha256 = Sha256::parse_hex(&entry.sha256).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "invalid sha256 for payload '{}': '{}'",
+                        entry.url,
+                        entry.sha256
+                    )
+                })?;
+                for cab_name in msi_cab_names(&sha256, name).unwrap_or_default() {
+                    if let Some((cab_name, cab)) = lock_file.cabs.get_key_value(&cab_name) {
+                        placed_cabs.push(cab_name);
+                        cabs.push(SbomPayload {
+                            name: cab_name.clone(),
+                            url: cab.url.clone(),
+                            sha256: cab.sha256.clone(),
+                            cabs: Vec::new(),
+                        });
+                    }
+                }
+            }
+            payloads.push(SbomPayload {
+                name: name.to_string(),
+                url: entry.url.clone(),
+                sha256: entry.sha256.clone(),
+                cabs,
+            });
+        }
+        sbom_pkgs.push(SbomPackage { pkg, payloads });
+    }
+
+    let mut unplaced: Vec<(&String, _)> = lock_file
+        .cabs
+        .iter()
+        .filter(|(name, _)| !placed_cabs.contains(&name.as_str()))
+        .collect();
+    unplaced.sort_by(|a, b| a.0.cmp(b.0));
+    if !unplaced.is_empty() {
+        log::warn!(
+            "{} cab(s) aren't referenced by any cached MSI, install the packages first \
+             to list them under their MSI",
+            unplaced.len()
+        );
+        let Some(index) = first_msi_pkg else {
+            bail_kind!(
+                ErrorKind::LockFileMismatch,
+                "the lock file lists cabs but no MSI payloads"
+            );
+        };
+        for (name, cab) in unplaced {
+            sbom_pkgs[index].payloads.push(SbomPayload {
+                name: name.clone(),
+                url: cab.url.clone(),
+                sha256: cab.sha256.clone(),
+                cabs: Vec::new(),
+            });
+        }
+    }
+    Ok(sbom_pkgs)
+}
+
+fn purl(name: &str, version: &str, url: &str, sha256: &str) -> String {
+    format!(
+        "pkg:generic/{}@{}?checksum=sha256:{}&download_url={}",
+        utf8_percent_encode(name, PURL_ENCODE_SET),
+        utf8_percent_encode(version, PURL_ENCODE_SET),
+        sha256,
+        utf8_percent_encode(url, PURL_ENCODE_SET)
+    )
+}
+
+fn cyclonedx_component(pkg: &MsvcupPackage, payload: &SbomPayload) -> Value {
+    let mut component = json!({
+        "type": "file",
+        "bom-ref": format!("{}/{}", pkg.pool_string(), payload.sha256),
+        "name": payload.name,
+        "version": pkg.version,
+        "hashes": [{"alg": "SHA-256", "content": payload.sha256}],
+        "purl": purl(&payload.name, &pkg.version, &payload.url, &payload.sha256),
+        "externalReferences": [{"type": "distribution", "url": payload.url}],
+    });
+    if !payload.cabs.is_empty() {
+        component["components"] = payload
+            .cabs
+            .iter()
+            .map(|cab| cyclonedx_component(pkg, cab))
+            .collect();
+    }
+    component
+}
+
+/// A CycloneDX 1.5 document. `serial` seeds the document's serial number.
+pub fn cyclonedx(sbom_pkgs: &[SbomPackage], serial: &Sha256, created: &str) -> Value {
+    let hex = serial.to_hex();
+    // A name-based (version 5 style) UUID so the same lock file gives the same serial
+    let uuid = format!(
+        "{}-{}-5{}-8{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[13..16],
+        &hex[17..20],
+        &hex[20..32]
+    );
+    let components: Vec<Value> = sbom_pkgs
+        .iter()
+        .map(|sbom_pkg| {
+            let pkg = &sbom_pkg.pkg;
+            json!({
+                "type": "application",
+                "bom-ref": pkg.pool_string(),
+                "name": pkg.kind.to_string(),
+                "version": pkg.version,
+                "purl": format!("pkg:generic/{}@{}", pkg.kind, pkg.version),
+                "components": sbom_pkg
+                    .payloads
+                    .iter()
+                    .map(|payload| cyclonedx_component(pkg, payload))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    json!({
+        "$schema": "http://cyclonedx.org/schema/bom-1.5.schema.json",
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "serialNumber": format!("urn:uuid:{}", uuid),
+        "version": 1,
+        "metadata": {
+            "timestamp": created,
+            "tools": {
+                "components": [{
+                    "type": "application",
+                    "name": "msvcup",
+                    "version": env!("CARGO_PKG_VERSION"),
+                }],
+            },
+        },
+        "components": components,
+    })
+}
+
+fn spdx_package(
+    pkg: &MsvcupPackage,
+    parent_id: &str,
+    payload: &SbomPayload,
+    packages: &mut Vec<Value>,
+    relationships: &mut Vec<Value>,
+) {
+    let id = format!("SPDXRef-{}-{}", pkg.pool_string(), &payload.sha256[..16]);
+    packages.push(json!({
+        "SPDXID": id,
+        "name": payload.name,
+        "versionInfo": pkg.version,
+        "downloadLocation": payload.url,
+        "filesAnalyzed": false,
+        "checksums": [{"algorithm": "SHA256", "checksumValue": payload.sha256}],
+        "externalRefs": [{
+            "referenceCategory": "PACKAGE-MANAGER",
+            "referenceType": "purl",
+            "referenceLocator": purl(&payload.name, &pkg.version, &payload.url, &payload.sha256),
+        }],
+    }));
+    relationships.push(json!({
+        "spdxElementId": parent_id,
+        "relationshipType": "CONTAINS",
+        "relatedSpdxElement": id,
+    }));
+    for cab in &payload.cabs {
+        spdx_package(pkg, &id, cab, packages, relationships);
+    }
+}
+
+/// An SPDX 2.3 JSON document. `serial` makes the document namespace unique.
+pub fn spdx(sbom_pkgs: &[SbomPackage], name: &str, serial: &Sha256, created: &str) -> Value {
+    let mut packages = Vec::new();
+    let mut relationships = Vec::new();
+    for sbom_pkg in sbom_pkgs {
+        let pkg = &sbom_pkg.pkg;
+        let id = format!("SPDXRef-{}", pkg.pool_string());
+        packages.push(json!({
+            "SPDXID": id,
+            "name": pkg.kind.to_string(),
+            "versionInfo": pkg.version,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+        }));
+        relationships.push(json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": id,
+        }));
+        for payload in &sbom_pkg.payloads {
+            spdx_package(pkg, &id, payload, &mut packages, &mut relationships);
+        }
+    }
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": format!(
+            "https://github.com/wolfv/msvcup/spdx/{}-{}",
+            name,
+            serial.to_hex()
+        ),
+        "creationInfo": {
+            "created": created,
+            "creators": [format!("Tool: msvcup-{}", env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
+
+/// Format seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+fn rfc3339_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// The document creation time: `SOURCE_DATE_EPOCH` if set, for reproducible
+/// documents, otherwise now.
+fn creation_time() -> Result<String> {
+    let secs = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch
+            .parse()
+            .with_context(|| format!("invalid SOURCE_DATE_EPOCH '{}'", epoch))?,
+        Err(_) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    Ok(rfc3339_utc(secs))
+}
+
+/// Write an SBOM of the payloads in a lock file. MSIs found in `cache_dir` are
+/// read to place their cabs under them.
+pub fn sbom_command(
+    lock_file_path: &str,
+    cache_dir: &Path,
+    format: SbomFormat,
+    out_path: &Path,
+) -> Result<()> {
+    let content = fs::read_to_string(lock_file_path)?;
+    let lock_file = parse_lock_file(lock_file_path, &content)?;
+    let cache_dir = crate::util::path_str(cache_dir)?;
+    let sbom_pkgs = collect_packages(&lock_file, |sha256, name| {
+        let msi_path = crate::install::cache_entry_path(cache_dir, sha256, name);
+        match crate::msi_extract::read_msi_cab_names(&msi_path) {
+            Ok(cab_names) => Some(cab_names),
+            Err(e) => {
+                log::debug!("not reading cabs of '{}': {:#}", msi_path.display(), e);
+                None
+            }
+        }
+    })?;
+
+    let serial = Sha256::of_bytes(content.as_bytes());
+    let created = creation_time()?;
+    let document = match format {
+        SbomFormat::CycloneDx => cyclonedx(&sbom_pkgs, &serial, &created),
+        SbomFormat::SpdxJson => {
+            let name = Path::new(lock_file_path)
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "msvcup".to_string());
+            spdx(&sbom_pkgs, &name, &serial, &created)
+        }
+    };
+    let mut json = serde_json::to_string_pretty(&document)?;
+    json.push('\n');
+    fs::write(out_path, json)?;
+    log::info!("wrote '{}'", out_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VSIX_SHA: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+    const MSI_SHA: &str = "2222222222222222222222222222222222222222222222222222222222222222";
+    const CAB_SHA: &str = "3333333333333333333333333333333333333333333333333333333333333333";
+
+    fn fixture_lock_file() -> LockFileJson {
+        let content = r#"{
+            "version": 1,
+            "cabs": {"a.cab": {"url": "https://example.com/a.cab", "sha256": "CAB_SHA"}},
+            "packages": [
+                {"name": "msvc-14.43.17.13", "payloads": [
+                    {"url": "https://example.com/Microsoft.VC.Tools.vsix", "sha256": "VSIX_SHA"}
+                ]},
+                {"name": "sdk-10.0.22621.7", "payloads": [
+                    {"url": "https://example.com/Universal CRT Headers.msi", "sha256": "MSI_SHA"}
+                ]}
+            ]
+        }"#
+        .replace("VSIX_SHA", VSIX_SHA)
+        .replace("MSI_SHA", MSI_SHA)
+        .replace("CAB_SHA", CAB_SHA);
+        parse_lock_file("fixture.json", &content).unwrap()
+    }
+
+    fn fixture_packages() -> Vec<SbomPackage> {
+        collect_packages(&fixture_lock_file(), |sha256, name| {
+            assert_eq!(sha256.to_hex(), MSI_SHA);
+            assert_eq!(name, "Universal CRT Headers.msi");
+            Some(vec!["a.cab".to_string()])
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn cabs_without_cached_msi_go_to_the_msi_package() {
+        let sbom_pkgs = collect_packages(&fixture_lock_file(), |_, _| None).unwrap();
+        let sdk_payloads: Vec<&str> = sbom_pkgs[1]
+            .payloads
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(sdk_payloads, ["Universal CRT Headers.msi", "a.cab"]);
+        assert!(sbom_pkgs[1].payloads[0].cabs.is_empty());
+    }
+
+    #[test]
+    fn rfc3339() {
+        assert_eq!(rfc3339_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339_utc(1709251199), "2024-02-29T23:59:59Z");
+    }
+
+    #[test]
+    fn cyclonedx_snapshot() {
+        let serial = Sha256::parse_hex(VSIX_SHA).unwrap();
+        let document = cyclonedx(&fixture_packages(), &serial, "2024-01-01T00:00:00Z");
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            document,
+            json!({
+                "$schema": "http://cyclonedx.org/schema/bom-1.5.schema.json",
+                "bomFormat": "CycloneDX",
+                "specVersion": "1.5",
+                "serialNumber": "urn:uuid:11111111-1111-5111-8111-111111111111",
+                "version": 1,
+                "metadata": {
+                    "timestamp": "2024-01-01T00:00:00Z",
+                    "tools": {"components": [
+                        {"type": "application", "name": "msvcup", "version": version}
+                    ]}
+                },
+                "components": [
+                    {
+                        "type": "application",
+                        "bom-ref": "msvc-14.43.17.13",
+                        "name": "msvc",
+                        "version": "14.43.17.13",
+                        "purl": "pkg:generic/msvc@14.43.17.13",
+                        "components": [{
+                            "type": "file",
+                            "bom-ref": format!("msvc-14.43.17.13/{}", VSIX_SHA),
+                            "name": "Microsoft.VC.Tools.vsix",
+                            "version": "14.43.17.13",
+                            "hashes": [{"alg": "SHA-256", "content": VSIX_SHA}],
+                            "purl": format!(
+                                "pkg:generic/Microsoft.VC.Tools.vsix@14.43.17.13?checksum=sha256:{}\
+                                 &download_url=https%3A%2F%2Fexample.com%2FMicrosoft.VC.Tools.vsix",
+                                VSIX_SHA
+                            ),
+                            "externalReferences": [
+                                {"type": "distribution", "url": "https://example.com/Microsoft.VC.Tools.vsix"}
+                            ]
+                        }]
+                    },
+                    {
+                        "type": "application",
+                        "bom-ref": "sdk-10.0.22621.7",
+                        "name": "sdk",
+                        "version": "10.0.22621.7",
+                        "purl": "pkg:generic/sdk@10.0.22621.7",
+                        "components": [{
+                            "type": "file",
+                            "bom-ref": format!("sdk-10.0.22621.7/{}", MSI_SHA),
+                            "name": "Universal CRT Headers.msi",
+                            "version": "10.0.22621.7",
+                            "hashes": [{"alg": "SHA-256", "content": MSI_SHA}],
+                            "purl": format!(
+                                "pkg:generic/Universal%20CRT%20Headers.msi@10.0.22621.7?checksum=sha256:{}\
+                                 &download_url=https%3A%2F%2Fexample.com%2FUniversal%20CRT%20Headers.msi",
+                                MSI_SHA
+                            ),
+                            "externalReferences": [
+                                {"type": "distribution", "url": "https://example.com/Universal CRT Headers.msi"}
+                            ],
+                            "components": [{
+                                "type": "file",
+                                "bom-ref": format!("sdk-10.0.22621.7/{}", CAB_SHA),
+                                "name": "a.cab",
+                                "version": "10.0.22621.7",
+                                "hashes": [{"alg": "SHA-256", "content": CAB_SHA}],
+                                "purl": format!(
+                                    "pkg:generic/a.cab@10.0.22621.7?checksum=sha256:{}\
+                                     &download_url=https%3A%2F%2Fexample.com%2Fa.cab",
+                                    CAB_SHA
+                                ),
+                                "externalReferences": [
+                                    {"type": "distribution", "url": "https://example.com/a.cab"}
+                                ]
+                            }]
+                        }]
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn spdx_snapshot() {
+        let serial = Sha256::parse_hex(VSIX_SHA).unwrap();
+        let document = spdx(
+            &fixture_packages(),
+            "msvcup-lock",
+            &serial,
+            "2024-01-01T00:00:00Z",
+        );
+        let vsix_id = "SPDXRef-msvc-14.43.17.13-1111111111111111";
+        let msi_id = "SPDXRef-sdk-10.0.22621.7-2222222222222222";
+        let cab_id = "SPDXRef-sdk-10.0.22621.7-3333333333333333";
+        assert_eq!(
+            document["documentNamespace"],
+            format!(
+                "https://github.com/wolfv/msvcup/spdx/msvcup-lock-{}",
+                VSIX_SHA
+            )
+        );
+        assert_eq!(
+            document["creationInfo"]["creators"],
+            json!([format!("Tool: msvcup-{}", env!("CARGO_PKG_VERSION"))])
+        );
+        assert_eq!(
+            document["packages"][3],
+            json!({
+                "SPDXID": msi_id,
+                "name": "Universal CRT Headers.msi",
+                "versionInfo": "10.0.22621.7",
+                "downloadLocation": "https://example.com/Universal CRT Headers.msi",
+                "filesAnalyzed": false,
+                "checksums": [{"algorithm": "SHA256", "checksumValue": MSI_SHA}],
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": format!(
+                        "pkg:generic/Universal%20CRT%20Headers.msi@10.0.22621.7?checksum=sha256:{}\
+                         &download_url=https%3A%2F%2Fexample.com%2FUniversal%20CRT%20Headers.msi",
+                        MSI_SHA
+                    )
+                }]
+            })
+        );
+        let ids: Vec<&str> = document["packages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["SPDXID"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            ids,
+            [
+                "SPDXRef-msvc-14.43.17.13",
+                vsix_id,
+                "SPDXRef-sdk-10.0.22621.7",
+                msi_id,
+                cab_id
+            ]
+        );
+        let relationships: Vec<(&str, &str, &str)> = document["relationships"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                (
+                    r["spdxElementId"].as_str().unwrap(),
+                    r["relationshipType"].as_str().unwrap(),
+                    r["relatedSpdxElement"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            relationships,
+            [
+                ("SPDXRef-DOCUMENT", "DESCRIBES", "SPDXRef-msvc-14.43.17.13"),
+                ("SPDXRef-msvc-14.43.17.13", "CONTAINS", vsix_id),
+                ("SPDXRef-DOCUMENT", "DESCRIBES", "SPDXRef-sdk-10.0.22621.7"),
+                ("SPDXRef-sdk-10.0.22621.7", "CONTAINS", msi_id),
+                (msi_id, "CONTAINS", cab_id),
+            ]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::{ErrorKind, bail_kind};
+use crate::output::{OutputFormat, print_records};
+use crate::util::order_dotted_numeric;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// Default version feed: the latest GitHub release. Any JSON document with
+/// `version` and `url` fields works as a feed for private distributions.
+pub const DEFAULT_FEED_URL: &str = "https://api.github.com/repos/wolfv/msvcup/releases/latest";
+
+#[derive(Debug, Deserialize)]
+pub struct VersionFeed {
+    #[serde(alias = "tag_name")]
+    pub version: String,
+    #[serde(alias = "html_url")]
+    pub url: String,
+}
+
+impl VersionFeed {
+    /// The feed version without a leading `v` (release tags are `v0.1.1`).
+    pub fn version_number(&self) -> &str {
+        self.version.strip_prefix('v').unwrap_or(&self.version)
+    }
+
+    /// Whether the feed advertises a newer version than `current`.
+    pub fn is_newer_than(&self, current: &str) -> bool {
+        order_dotted_numeric(self.version_number(), current) == Ordering::Greater
+    }
+}
+
+/// The `self-check` record.
+#[derive(Debug, Serialize)]
+pub struct SelfCheckReport {
+    pub current_version: String,
+    pub latest_version: String,
+    pub url: String,
+    pub update_available: bool,
+}
+
+impl SelfCheckReport {
+    pub fn new(current: &str, feed: &VersionFeed) -> Self {
+        Self {
+            current_version: current.to_string(),
+            latest_version: feed.version_number().to_string(),
+            url: feed.url.clone(),
+            update_available: feed.is_newer_than(current),
+        }
+    }
+
+    fn text(&self) -> String {
+        if self.update_available {
+            format!(
+                "update available: msvcup {} -> {} ({})",
+                self.current_version, self.latest_version, self.url
+            )
+        } else {
+            format!("msvcup {} is up to date", self.current_version)
+        }
+    }
+}
+
+/// Fetch the version feed and report whether a newer msvcup is available.
+pub async fn self_check_command(
+    client: &reqwest::Client,
+    feed_url: &str,
+    format: OutputFormat,
+) -> Result<()> {
+    let current = env!("CARGO_PKG_VERSION");
+    let response = client
+        .get(feed_url)
+        .header(reqwest::header::USER_AGENT, format!("msvcup/{}", current))
+        .send()
+        .await
+        .with_context(|| format!("fetching version feed '{}'", feed_url))?;
+    if !response.status().is_success() {
+        bail_kind!(
+            ErrorKind::Network,
+            "fetch '{}': HTTP status {}",
+            feed_url,
+            response.status()
+        );
+    }
+    let body = response
+        .text()
+        .await
+        .with_context(|| format!("reading version feed '{}'", feed_url))?;
+    let feed: VersionFeed = serde_json::from_str(&body)
+        .with_context(|| format!("parsing version feed '{}'", feed_url))?;
+
+    let report = SelfCheckReport::new(current, &feed);
+    print_records(format, &[report], SelfCheckReport::text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_plain_feed() {
+        let feed: VersionFeed =
+            serde_json::from_str(r#"{"version": "0.2.0", "url": "https://example.com/msvcup"}"#)
+                .unwrap();
+        assert_eq!(feed.version_number(), "0.2.0");
+        assert_eq!(feed.url, "https://example.com/msvcup");
+    }
+
+    #[test]
+    fn parse_github_release_feed() {
+        let feed: VersionFeed = serde_json::from_str(
+            r#"{"tag_name": "v0.3.1", "html_url": "https://github.com/wolfv/msvcup/releases/tag/v0.3.1", "draft": false}"#,
+        )
+        .unwrap();
+        assert_eq!(feed.version_number(), "0.3.1");
+    }
+
+    #[test]
+    fn version_comparison() {
+        let feed = VersionFeed {
+            version: "v0.1.10".to_string(),
+            url: String::new(),
+        };
+        assert!(feed.is_newer_than("0.1.9"));
+        assert!(!feed.is_newer_than("0.1.10"));
+        assert!(!feed.is_newer_than("0.2.0"));
+    }
+
+    #[test]
+    fn report_json_shape() {
+        let feed = VersionFeed {
+            version: "v0.2.0".to_string(),
+            url: "https://example.com/msvcup".to_string(),
+        };
+        let report = SelfCheckReport::new("0.1.1", &feed);
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"current_version":"0.1.1","latest_version":"0.2.0","url":"https://example.com/msvcup","update_available":true}"#
+        );
+        assert_eq!(
+            report.text(),
+            "update available: msvcup 0.1.1 -> 0.2.0 (https://example.com/msvcup)"
+        );
+    }
+}
@@ -1,7 +1,14 @@
 use sha2::{Digest, Sha256 as Sha256Hasher};
 use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// How much [`Sha256::from_reader`] and
+/// [`Sha256Streaming::update_from_async_reader`] read at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Sha256 {
     pub bytes: [u8; 32],
 }
@@ -17,6 +24,36 @@ impl Sha256 {
     pub fn to_hex(&self) -> String {
         hex::encode(self.bytes)
     }
+
+    /// Hash `data`.
+    pub fn of_bytes(data: &[u8]) -> Sha256 {
+        let mut hasher = Sha256Streaming::new();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// Hash everything `r` yields, reading in 64 KiB chunks.
+    pub fn from_reader(r: &mut impl Read) -> io::Result<Sha256> {
+        let mut hasher = Sha256Streaming::new();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize())
+    }
+
+    /// Hash the contents of the file at `path`.
+    #[allow(dead_code)]
+    pub fn hash_file(path: &Path) -> io::Result<Sha256> {
+        let mut file = fs_err::File::open(path)?;
+        Self::from_reader(&mut file)
+    }
 }
 
 impl fmt::Display for Sha256 {
@@ -46,6 +83,42 @@ impl Sha256Streaming {
         self.hasher.update(data);
     }
 
+    /// Hash everything `reader` yields, reading in 64 KiB chunks. Hashing is
+    /// CPU-bound, so each chunk is hashed on the blocking thread pool while the
+    /// next one is read. On error the hasher is left with an unspecified prefix
+    /// of the input.
+    #[allow(dead_code)]
+    pub async fn update_from_async_reader<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> io::Result<()> {
+        let mut hasher = Some(std::mem::take(&mut self.hasher));
+        let mut hashing: Option<tokio::task::JoinHandle<(Sha256Hasher, Vec<u8>)>> = None;
+        let mut buf = Vec::with_capacity(CHUNK_SIZE);
+        let mut spare = Vec::with_capacity(CHUNK_SIZE);
+        let result = loop {
+            let read = read_chunk(reader, &mut buf).await;
+            if let Some(task) = hashing.take() {
+                let (done, chunk) = task.await.map_err(io::Error::other)?;
+                hasher = Some(done);
+                spare = chunk;
+            }
+            match read {
+                Ok(()) if buf.is_empty() => break Ok(()),
+                Ok(()) => {}
+                Err(e) => break Err(e),
+            }
+            let mut next = hasher.take().expect("the previous chunk was hashed");
+            let chunk = std::mem::replace(&mut buf, std::mem::take(&mut spare));
+            hashing = Some(tokio::task::spawn_blocking(move || {
+                next.update(&chunk);
+                (next, chunk)
+            }));
+        };
+        self.hasher = hasher.expect("the last chunk was hashed");
+        result
+    }
+
     pub fn finalize(self) -> Sha256 {
         let result = self.hasher.finalize();
         let mut bytes = [0u8; 32];
@@ -54,12 +127,24 @@ impl Sha256Streaming {
     }
 }
 
+/// Fill `buf` with up to [`CHUNK_SIZE`] bytes, leaving it empty at the end.
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.clear();
+    buf.reserve(CHUNK_SIZE);
+    while buf.len() < CHUNK_SIZE {
+        if reader.read_buf(buf).await? == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{HELLO_SHA256, TempDir};
 
     const ZEROS_HEX: &str = "0000000000000000000000000000000000000000000000000000000000000000";
-    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
 
     #[test]
     fn parse_hex_valid() {
@@ -129,6 +214,109 @@ mod tests {
         assert_eq!(result.to_hex(), HELLO_SHA256);
     }
 
+    #[test]
+    fn of_bytes_hash_of_hello() {
+        assert_eq!(Sha256::of_bytes(b"hello").to_hex(), HELLO_SHA256);
+        assert_eq!(
+            Sha256::of_bytes(b"").to_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn from_reader_hash_of_hello() {
+        let sha = Sha256::from_reader(&mut &b"hello"[..]).unwrap();
+        assert_eq!(sha.to_hex(), HELLO_SHA256);
+    }
+
+    #[test]
+    fn from_reader_spans_chunks() {
+        let data = vec![b'a'; 64 * 1024 * 2 + 7];
+        assert_eq!(
+            Sha256::from_reader(&mut data.as_slice()).unwrap(),
+            Sha256::of_bytes(&data)
+        );
+    }
+
+    #[tokio::test]
+    async fn async_reader_matches_sync() {
+        let mut hasher = Sha256Streaming::new();
+        hasher.update(b"hel");
+        hasher
+            .update_from_async_reader(&mut &b"lo"[..])
+            .await
+            .unwrap();
+        assert_eq!(hasher.finalize().to_hex(), HELLO_SHA256);
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 3 + 7).map(|i| i as u8).collect();
+        let mut hasher = Sha256Streaming::new();
+        hasher
+            .update_from_async_reader(&mut data.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(
+            hasher.finalize(),
+            Sha256::from_reader(&mut data.as_slice()).unwrap()
+        );
+
+        // A reader that errors midway returns the error
+        let mut failing = AsyncReadExt::chain(&data[..CHUNK_SIZE + 1], error_reader());
+        let mut hasher = Sha256Streaming::new();
+        assert!(hasher.update_from_async_reader(&mut failing).await.is_err());
+    }
+
+    fn error_reader() -> impl AsyncRead + Unpin {
+        struct ErrorReader;
+        impl AsyncRead for ErrorReader {
+            fn poll_read(
+                self: std::pin::Pin<&mut Self>,
+                _cx: &mut std::task::Context<'_>,
+                _buf: &mut tokio::io::ReadBuf<'_>,
+            ) -> std::task::Poll<io::Result<()>> {
+                std::task::Poll::Ready(Err(io::Error::other("broken pipe")))
+            }
+        }
+        ErrorReader
+    }
+
+    /// Throughput of the sync and async hashing, run with
+    /// `cargo test --release sha::tests::hash_throughput -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn hash_throughput() {
+        let data = vec![0x5au8; 256 * 1024 * 1024];
+        let mib = data.len() as f64 / (1024.0 * 1024.0);
+
+        let start = std::time::Instant::now();
+        let sync = Sha256::from_reader(&mut data.as_slice()).unwrap();
+        let sync_secs = start.elapsed().as_secs_f64();
+
+        let start = std::time::Instant::now();
+        let mut hasher = Sha256Streaming::new();
+        hasher
+            .update_from_async_reader(&mut data.as_slice())
+            .await
+            .unwrap();
+        let async_secs = start.elapsed().as_secs_f64();
+
+        assert_eq!(hasher.finalize(), sync);
+        println!(
+            "sync: {:.0} MiB/s, async: {:.0} MiB/s",
+            mib / sync_secs,
+            mib / async_secs
+        );
+    }
+
+    #[test]
+    fn hash_file_known_content() {
+        let dir = TempDir::new("hash_file");
+        let path = dir.join("hello.txt");
+        std::fs::write(&path, "hello").unwrap();
+
+        assert_eq!(Sha256::hash_file(&path).unwrap().to_hex(), HELLO_SHA256);
+        assert!(Sha256::hash_file(&dir.join("missing")).is_err());
+    }
+
     #[test]
     fn streaming_hash_empty() {
         let hasher = Sha256Streaming::new();
@@ -0,0 +1,416 @@
+use crate::arch::Arch;
+use crate::casefix_cmd::CasefixMode;
+use crate::error::{ErrorKind, bail_kind};
+use crate::install::{FinishKind, query_package_version};
+use crate::manifest::MsvcupDir;
+use crate::packages::MsvcupPackage;
+use crate::util::{create_symlink, remove_files_and_prune_dirs};
+use anyhow::{Context, Result, bail};
+use fs_err as fs;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// File in the output directory listing what the last splat produced.
+pub const SPLAT_MANIFEST_NAME: &str = ".msvcup-splat";
+
+/// The llvm spelling of an arch, used for the arch directories of the layout.
+pub fn llvm_arch_name(arch: Arch) -> &'static str {
+    match arch {
+        Arch::X64 => "x86_64",
+        Arch::X86 => "x86",
+        Arch::Arm => "aarch",
+        Arch::Arm64 => "aarch64",
+        Arch::Arm64Ec => "arm64ec",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplatMode {
+    Copy,
+    Link,
+}
+
+impl SplatMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            SplatMode::Copy => "copy",
+            SplatMode::Link => "link",
+        }
+    }
+}
+
+/// Debug symbols and redistributables aren't needed to compile and link.
+fn is_skipped(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    lower.ends_with(".pdb") || lower == "debug" || lower == "redist"
+}
+
+/// Map the include and lib directories of the installed msvc and sdk packages to
+/// the xwin layout, returning the source of every output file keyed by its
+/// `/`-separated path relative to the output directory. With `single_arch` the
+/// libraries go directly into `crt/lib` and `sdk/lib/{ucrt,um}`.
+pub fn splat_files(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_archs: &[Arch],
+    single_arch: bool,
+) -> Result<BTreeMap<String, PathBuf>> {
+    if target_archs.is_empty() {
+        bail_kind!(ErrorKind::Usage, "no target architecture was given");
+    }
+    if single_arch && target_archs.len() > 1 {
+        bail_kind!(
+            ErrorKind::Usage,
+            "--copy-into-single-arch needs exactly one --target-cpu, got {}",
+            target_archs.len()
+        );
+    }
+    let lib_dir = |prefix: &str, arch: Arch| {
+        if single_arch {
+            prefix.to_string()
+        } else {
+            format!("{}/{}", prefix, llvm_arch_name(arch))
+        }
+    };
+
+    let mut dirs: Vec<(PathBuf, String)> = Vec::new();
+    for (i, msvcup_pkg) in msvcup_pkgs.iter().enumerate() {
+        let Some(finish_kind) = FinishKind::from_package_kind(msvcup_pkg.kind) else {
+            bail_kind!(
+                ErrorKind::Usage,
+                "splat only handles msvc and sdk packages, not {}",
+                msvcup_pkg
+            );
+        };
+        if let Some(other) = msvcup_pkgs[..i].iter().find(|p| p.kind == msvcup_pkg.kind) {
+            bail_kind!(
+                ErrorKind::Usage,
+                "{} and {} would splat into the same files, give only one",
+                other,
+                msvcup_pkg
+            );
+        }
+        let install_path = msvcup_dir.package_path(msvcup_pkg);
+        if !install_path.is_dir() {
+            bail!(
+                "'{}' does not exist, install {} first",
+                install_path.display(),
+                msvcup_pkg
+            );
+        }
+        let (version, versioned_path) = query_package_version(msvcup_pkg, &install_path)?
+            .expect("msvc and sdk packages have a versioned layout");
+        log::debug!("{} install version '{}'", msvcup_pkg, version);
+
+        let mut pkg_dirs: Vec<(PathBuf, String)> = Vec::new();
+        match finish_kind {
+            FinishKind::Msvc => {
+                pkg_dirs.push((versioned_path.join("include"), "crt/include".to_string()));
+                for &arch in target_archs {
+                    pkg_dirs.push((
+                        versioned_path.join("lib").join(arch.as_str()),
+                        lib_dir("crt/lib", arch),
+                    ));
+                }
+            }
+            FinishKind::Sdk => {
+                for sub in ["ucrt", "um", "shared"] {
+                    pkg_dirs.push((versioned_path.join(sub), format!("sdk/include/{}", sub)));
+                }
+                let lib_path = install_path
+                    .join("Windows Kits")
+                    .join("10")
+                    .join("Lib")
+                    .join(&version);
+                for &arch in target_archs {
+                    for sub in ["ucrt", "um"] {
+                        pkg_dirs.push((
+                            lib_path.join(sub).join(arch.payload_arch().as_str()),
+                            lib_dir(&format!("sdk/lib/{}", sub), arch),
+                        ));
+                    }
+                }
+            }
+        }
+        for (src, _) in &pkg_dirs {
+            if !src.is_dir() {
+                bail!(
+                    "{}: '{}' does not exist, is it installed for {}?",
+                    msvcup_pkg,
+                    src.display(),
+                    target_archs
+                        .iter()
+                        .map(Arch::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
+        dirs.extend(pkg_dirs);
+    }
+
+    let mut files = BTreeMap::new();
+    for (src_dir, dest_dir) in dirs {
+        collect_files(&src_dir, &dest_dir, &mut files)?;
+    }
+    Ok(files)
+}
+
+fn collect_files(
+    src_dir: &Path,
+    dest_dir: &str,
+    files: &mut BTreeMap<String, PathBuf>,
+) -> Result<()> {
+    for entry in fs::read_dir(src_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_skipped(&name) {
+            continue;
+        }
+        let dest = format!("{}/{}", dest_dir, name);
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), &dest, files)?;
+        } else {
+            files.insert(dest, entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// What [`splat`] did to the output directory.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SplatStats {
+    pub written: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// Copy or link `files` into `out_dir`. Files whose source, size and mode match
+/// the previous run's manifest and still exist are left alone, and files the
+/// previous run produced that aren't in `files` anymore are removed.
+pub fn splat(
+    out_dir: &Path,
+    files: &BTreeMap<String, PathBuf>,
+    mode: SplatMode,
+) -> Result<SplatStats> {
+    let manifest_path = out_dir.join(SPLAT_MANIFEST_NAME);
+    let previous: HashMap<String, String> = match fs::read_to_string(&manifest_path) {
+        Ok(content) => content
+            .lines()
+            .filter_map(|line| {
+                let (_, rest) = line.split_once('\t')?;
+                let (dest, _) = rest.split_once('\t')?;
+                Some((dest.to_string(), line.to_string()))
+            })
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut stats = SplatStats::default();
+    let mut manifest = String::new();
+    for (dest, src) in files {
+        let src =
+            std::path::absolute(src).with_context(|| format!("resolving '{}'", src.display()))?;
+        let len = fs::metadata(&src)?.len();
+        let line = format!("{}\t{}\t{}\t{}", mode.as_str(), dest, src.display(), len);
+        let dest_path = out_dir.join(dest);
+        let exists = std::fs::symlink_metadata(&dest_path).is_ok();
+        if exists && previous.get(dest) == Some(&line) {
+            stats.unchanged += 1;
+        } else {
+            if exists {
+                fs::remove_file(&dest_path)?;
+            }
+            fs::create_dir_all(dest_path.parent().unwrap())?;
+            match mode {
+                SplatMode::Copy => {
+                    fs::copy(&src, &dest_path)?;
+                }
+                SplatMode::Link => create_symlink(&src, &dest_path, false)?,
+            }
+            stats.written += 1;
+        }
+        manifest.push_str(&line);
+        manifest.push('\n');
+    }
+
+    let stale: Vec<PathBuf> = previous
+        .keys()
+        .filter(|dest| !files.contains_key(*dest))
+        .map(|dest| out_dir.join(dest))
+        .collect();
+    remove_files_and_prune_dirs(&stale, out_dir)?;
+    stats.removed = stale.len();
+
+    crate::util::update_file(&manifest_path, manifest.as_bytes())?;
+    Ok(stats)
+}
+
+/// Lay the headers and libraries of installed msvc and sdk packages out the way
+/// cargo-xwin and clang cross setups expect them.
+pub fn splat_command(
+    msvcup_dir: &MsvcupDir,
+    msvcup_pkgs: &[MsvcupPackage],
+    target_archs: &[Arch],
+    out_dir: &Path,
+    mode: SplatMode,
+    single_arch: bool,
+    casefix: bool,
+) -> Result<()> {
+    let files = splat_files(msvcup_dir, msvcup_pkgs, target_archs, single_arch)?;
+    fs::create_dir_all(out_dir)?;
+    let stats = splat(out_dir, &files, mode)?;
+    log::info!(
+        "'{}': {} file(s) written, {} unchanged, {} removed",
+        out_dir.display(),
+        stats.written,
+        stats.unchanged,
+        stats.removed
+    );
+    if casefix {
+        crate::casefix_cmd::casefix_command(out_dir, CasefixMode::Symlinks)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::MsvcupPackageKind;
+    use crate::test_support::TempDir;
+
+    fn make_tree(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, file).unwrap();
+        }
+    }
+
+    /// An msvc and an sdk package installed with x64 and arm64 libraries.
+    fn fixture(root: &Path) -> (MsvcupDir, Vec<MsvcupPackage>) {
+        let msvc = MsvcupPackage::new(MsvcupPackageKind::Msvc, "14.43.17.13");
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        let msvc_ver = "VC/Tools/MSVC/14.43.34808";
+        make_tree(
+            &root.join(msvc.pool_string()),
+            &[
+                &format!("{}/include/vcruntime.h", msvc_ver),
+                &format!("{}/lib/x64/msvcrt.lib", msvc_ver),
+                &format!("{}/lib/x64/msvcrt.pdb", msvc_ver),
+                &format!("{}/lib/x64/store/msvcrt.lib", msvc_ver),
+                &format!("{}/lib/arm64/msvcrt.lib", msvc_ver),
+                "VC/Redist/MSVC/14.43.34808/vc_redist.x64.exe",
+            ],
+        );
+        let kits = "Windows Kits/10";
+        make_tree(
+            &root.join(sdk.pool_string()),
+            &[
+                &format!("{}/Include/10.0.22621.0/ucrt/stdio.h", kits),
+                &format!("{}/Include/10.0.22621.0/um/Windows.h", kits),
+                &format!("{}/Include/10.0.22621.0/shared/sal.h", kits),
+                &format!("{}/Include/10.0.22621.0/winrt/roapi.h", kits),
+                &format!("{}/Lib/10.0.22621.0/ucrt/x64/ucrt.lib", kits),
+                &format!("{}/Lib/10.0.22621.0/um/x64/kernel32.lib", kits),
+                &format!("{}/Lib/10.0.22621.0/ucrt/arm64/ucrt.lib", kits),
+                &format!("{}/Lib/10.0.22621.0/um/arm64/kernel32.lib", kits),
+            ],
+        );
+        (MsvcupDir::with_path(root.to_path_buf()), vec![msvc, sdk])
+    }
+
+    #[test]
+    fn splat_files_layout() {
+        let root = TempDir::new("splat_layout");
+        let (msvcup_dir, pkgs) = fixture(&root);
+
+        let files = splat_files(&msvcup_dir, &pkgs, &[Arch::X64, Arch::Arm64], false).unwrap();
+        let dests: Vec<&str> = files.keys().map(String::as_str).collect();
+        assert_eq!(
+            dests,
+            [
+                "crt/include/vcruntime.h",
+                "crt/lib/aarch64/msvcrt.lib",
+                "crt/lib/x86_64/msvcrt.lib",
+                "crt/lib/x86_64/store/msvcrt.lib",
+                "sdk/include/shared/sal.h",
+                "sdk/include/ucrt/stdio.h",
+                "sdk/include/um/Windows.h",
+                "sdk/lib/ucrt/aarch64/ucrt.lib",
+                "sdk/lib/ucrt/x86_64/ucrt.lib",
+                "sdk/lib/um/aarch64/kernel32.lib",
+                "sdk/lib/um/x86_64/kernel32.lib",
+            ]
+        );
+
+        let single = splat_files(&msvcup_dir, &pkgs, &[Arch::Arm64], true).unwrap();
+        assert!(single.contains_key("crt/lib/msvcrt.lib"));
+        assert!(single.contains_key("sdk/lib/um/kernel32.lib"));
+
+        let err = splat_files(&msvcup_dir, &pkgs, &[Arch::X64, Arch::Arm64], true).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 2);
+        let err = splat_files(&msvcup_dir, &pkgs, &[Arch::X86], false).unwrap_err();
+        assert!(
+            err.to_string().contains("is it installed for x86?"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn splat_is_incremental() {
+        let root = TempDir::new("splat_incremental");
+        let (msvcup_dir, pkgs) = fixture(&root);
+        let out = root.join("out");
+
+        let both = splat_files(&msvcup_dir, &pkgs, &[Arch::X64, Arch::Arm64], false).unwrap();
+        let stats = splat(&out, &both, SplatMode::Copy).unwrap();
+        assert_eq!(stats.written, 11);
+        assert_eq!(
+            std::fs::read_to_string(out.join("sdk/include/um/Windows.h")).unwrap(),
+            "Windows Kits/10/Include/10.0.22621.0/um/Windows.h"
+        );
+
+        let stats = splat(&out, &both, SplatMode::Copy).unwrap();
+        assert_eq!(
+            stats,
+            SplatStats {
+                written: 0,
+                unchanged: 11,
+                removed: 0
+            }
+        );
+
+        // Dropping arm64 removes its files and the directories they were in
+        let x64 = splat_files(&msvcup_dir, &pkgs, &[Arch::X64], false).unwrap();
+        let stats = splat(&out, &x64, SplatMode::Copy).unwrap();
+        assert_eq!(stats.removed, 3);
+        assert!(!out.join("crt/lib/aarch64").exists());
+        assert!(out.join("crt/lib/x86_64/msvcrt.lib").is_file());
+
+        // A deleted output file is restored
+        std::fs::remove_file(out.join("crt/include/vcruntime.h")).unwrap();
+        assert_eq!(splat(&out, &x64, SplatMode::Copy).unwrap().written, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn splat_links() {
+        let root = TempDir::new("splat_link");
+        let (msvcup_dir, pkgs) = fixture(&root);
+        let out = root.join("out");
+
+        let files = splat_files(&msvcup_dir, &pkgs, &[Arch::X64], false).unwrap();
+        splat(&out, &files, SplatMode::Copy).unwrap();
+        // Switching modes replaces every file
+        assert_eq!(splat(&out, &files, SplatMode::Link).unwrap().written, 8);
+        let link = out.join("crt/lib/x86_64/msvcrt.lib");
+        assert!(std::fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(
+            std::fs::read_link(&link).unwrap(),
+            files["crt/lib/x86_64/msvcrt.lib"]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use crate::file_exclusions::{FileExclusions, SkippedFiles};
+use crate::util::sanitize_filename;
+use crate::zip_extract::{create_install_file, skip_install_file, strip_root_component};
+use anyhow::{Context, Result};
+use fs_err as fs;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy)]
+pub enum TarKind {
+    Gz,
+    Xz,
+}
+
+/// Extract a `.tar.gz`/`.tar.xz` file to an install directory, writing an install
+/// manifest the same way [`crate::zip_extract::extract_zip_to_dir`] does.
+pub fn extract_tar_to_dir(
+    cache_path: &Path,
+    install_dir_path: &Path,
+    kind: TarKind,
+    strip_root_dir: bool,
+    exclusions: &FileExclusions,
+    installing_manifest: &mut fs::File,
+) -> Result<SkippedFiles> {
+    let file = BufReader::new(
+        fs::File::open(cache_path)
+            .with_context(|| format!("opening '{}'", cache_path.display()))?,
+    );
+    let decoder: Box<dyn Read> = match kind {
+        TarKind::Gz => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        TarKind::Xz => Box::new(lzma_rust2::XzReader::new(file, true)),
+    };
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut last_root_dir: Option<String> = None;
+    let mut skipped = SkippedFiles::default();
+
+    for entry in archive
+        .entries()
+        .with_context(|| format!("reading tar '{}'", cache_path.display()))?
+    {
+        let mut entry = entry.with_context(|| format!("reading tar '{}'", cache_path.display()))?;
+        let raw_name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        // Archives made with `tar -C dir .` prefix every entry with `./`
+        let name = raw_name.trim_start_matches("./");
+        if name.is_empty() {
+            continue;
+        }
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_dir() {
+            continue;
+        }
+        if !entry_type.is_file() {
+            log::debug!(
+                "{}: skipping '{}', not a regular file ({:?})",
+                cache_path.display(),
+                name,
+                entry_type
+            );
+            continue;
+        }
+        let filename = sanitize_filename(name)
+            .with_context(|| format!("invalid tar filename '{}'", raw_name))?
+            .replace('\\', "/");
+
+        let sub_path = if strip_root_dir {
+            strip_root_component(&filename, &mut last_root_dir)?
+        } else {
+            &filename
+        };
+
+        if exclusions.excludes(sub_path) {
+            skip_install_file(install_dir_path, sub_path, installing_manifest)?;
+            skipped.add(entry.size());
+            continue;
+        }
+        let mut outfile = create_install_file(install_dir_path, sub_path, installing_manifest)?;
+        io::copy(&mut entry, &mut outfile)?;
+    }
+
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::io::Write;
+
+    fn write_test_tar(out: impl Write, entries: &[(&str, &[u8])]) {
+        let mut builder = tar::Builder::new(out);
+        let mut dir_header = tar::Header::new_gnu();
+        dir_header.set_entry_type(tar::EntryType::Directory);
+        dir_header.set_size(0);
+        builder
+            .append_data(&mut dir_header, "./tool-1.0/", io::empty())
+            .unwrap();
+        for (name, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            builder.append_data(&mut header, name, *content).unwrap();
+        }
+        builder.into_inner().unwrap().flush().unwrap();
+    }
+
+    fn extract(archive: &Path, kind: TarKind, install_dir: &Path, strip_root_dir: bool) -> String {
+        let manifest_path = install_dir.with_extension("files");
+        let mut manifest = fs::File::create(&manifest_path).unwrap();
+        extract_tar_to_dir(
+            archive,
+            install_dir,
+            kind,
+            strip_root_dir,
+            &FileExclusions::default(),
+            &mut manifest,
+        )
+        .unwrap();
+        drop(manifest);
+        std::fs::read_to_string(&manifest_path).unwrap()
+    }
+
+    #[test]
+    fn extract_tar_gz_and_xz() {
+        let dir = TempDir::new("extract_tar");
+        let entries: &[(&str, &[u8])] = &[
+            ("./tool-1.0/bin/tool.exe", b"tool"),
+            ("./tool-1.0/share/doc.txt", b"doc"),
+        ];
+
+        let gz_path = dir.join("tool.tar.gz");
+        let gz = flate2::write::GzEncoder::new(
+            std::fs::File::create(&gz_path).unwrap(),
+            flate2::Compression::fast(),
+        );
+        write_test_tar(gz, entries);
+
+        let xz_path = dir.join("tool.tar.xz");
+        let mut xz = lzma_rust2::XzWriter::new(
+            std::fs::File::create(&xz_path).unwrap(),
+            lzma_rust2::XzOptions::default(),
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        write_test_tar(&mut buf, entries);
+        xz.write_all(&buf).unwrap();
+        xz.finish().unwrap();
+
+        let gz_install = dir.join("gz");
+        let manifest = extract(&gz_path, TarKind::Gz, &gz_install, true);
+        assert_eq!(
+            std::fs::read(gz_install.join("bin").join("tool.exe")).unwrap(),
+            b"tool"
+        );
+        let sep = std::path::MAIN_SEPARATOR;
+        assert_eq!(
+            manifest,
+            format!("new bin{sep}tool.exe\nnew share{sep}doc.txt\n")
+        );
+
+        let xz_install = dir.join("xz");
+        extract(&xz_path, TarKind::Xz, &xz_install, false);
+        assert_eq!(
+            std::fs::read(xz_install.join("tool-1.0").join("share").join("doc.txt")).unwrap(),
+            b"doc"
+        );
+        // A second payload with the same files records them as added
+        let manifest = extract(&xz_path, TarKind::Xz, &xz_install, false);
+        assert!(manifest.starts_with("add tool-1.0"), "{}", manifest);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::disk_space::format_size;
+use crate::file_exclusions::SkippedFiles;
+use crate::install::installed_files;
+use crate::manifest::MsvcupDir;
+use crate::packages::MsvcupPackage;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters collected while installing, shared between payload tasks.
+#[derive(Debug, Default)]
+pub struct InstallStats {
+    cache_hits: AtomicU64,
+    cache_hit_bytes: AtomicU64,
+    downloads: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    payloads_extracted: AtomicU64,
+    payloads_already_installed: AtomicU64,
+    files_new: AtomicU64,
+    files_added: AtomicU64,
+    files_skipped: AtomicU64,
+    bytes_skipped: AtomicU64,
+    /// (package, install version) of each finished package
+    install_versions: Mutex<Vec<(String, String)>>,
+    /// Payloads `install --keep-going` couldn't install, with their error
+    failures: Mutex<Vec<(FailedPayload, anyhow::Error)>>,
+    elapsed: Duration,
+}
+
+impl InstallStats {
+    pub fn record_cache_hit(&self, bytes: u64) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+        self.cache_hit_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_download(&self, bytes: u64) {
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A payload was extracted, writing `new` files that didn't exist and adding
+    /// itself to `added` files another payload had written already.
+    pub fn record_extracted(&self, new: u64, added: u64) {
+        self.payloads_extracted.fetch_add(1, Ordering::Relaxed);
+        self.files_new.fetch_add(new, Ordering::Relaxed);
+        self.files_added.fetch_add(added, Ordering::Relaxed);
+    }
+
+    /// Files `install --exclude` left out of an extracted payload.
+    pub fn record_skipped(&self, skipped: SkippedFiles) {
+        self.files_skipped
+            .fetch_add(skipped.files, Ordering::Relaxed);
+        self.bytes_skipped
+            .fetch_add(skipped.bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_already_installed(&self) {
+        self.payloads_already_installed
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_install_version(&self, msvcup_pkg: &MsvcupPackage, install_version: &str) {
+        self.install_versions
+            .lock()
+            .unwrap()
+            .push((msvcup_pkg.pool_string(), install_version.to_string()));
+    }
+
+    pub fn record_failure(
+        &self,
+        msvcup_pkg: &MsvcupPackage,
+        payload: &str,
+        optional: bool,
+        error: anyhow::Error,
+    ) {
+        let failed = FailedPayload {
+            package: msvcup_pkg.pool_string(),
+            payload: payload.to_string(),
+            optional,
+            error: format!("{:#}", error),
+        };
+        self.failures.lock().unwrap().push((failed, error));
+    }
+
+    /// Add the counters and install versions `other` collected, e.g. those of
+    /// the payload tasks once they're all done. Its failures aren't added.
+    pub fn merge(&self, other: &InstallStats) {
+        for (total, count) in [
+            (&self.cache_hits, &other.cache_hits),
+            (&self.cache_hit_bytes, &other.cache_hit_bytes),
+            (&self.downloads, &other.downloads),
+            (&self.bytes_downloaded, &other.bytes_downloaded),
+            (&self.payloads_extracted, &other.payloads_extracted),
+            (
+                &self.payloads_already_installed,
+                &other.payloads_already_installed,
+            ),
+            (&self.files_new, &other.files_new),
+            (&self.files_added, &other.files_added),
+            (&self.files_skipped, &other.files_skipped),
+            (&self.bytes_skipped, &other.bytes_skipped),
+        ] {
+            total.fetch_add(count.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        self.install_versions
+            .lock()
+            .unwrap()
+            .extend(other.install_versions.lock().unwrap().iter().cloned());
+    }
+
+    pub fn set_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed = elapsed;
+    }
+
+    /// The summary block `install` prints when it's done.
+    pub fn summary(&self) -> String {
+        let count = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut out = format!(
+            "downloaded:        {} payload(s), {}\n\
+             already fetched:   {} payload(s), {}\n\
+             extracted:         {} payload(s)\n\
+             already installed: {} payload(s)\n",
+            count(&self.downloads),
+            format_size(count(&self.bytes_downloaded)),
+            count(&self.cache_hits),
+            format_size(count(&self.cache_hit_bytes)),
+            count(&self.payloads_extracted),
+            count(&self.payloads_already_installed),
+        );
+        let failures = self.failures.lock().unwrap();
+        if !failures.is_empty() {
+            out.push_str(&format!(
+                "failed:            {} payload(s), {} essential\n",
+                failures.len(),
+                failures.iter().filter(|(f, _)| !f.optional).count()
+            ));
+        }
+        out.push_str(&format!(
+            "files written:     {} new, {} added to existing\n",
+            count(&self.files_new),
+            count(&self.files_added),
+        ));
+        if count(&self.files_skipped) > 0 {
+            out.push_str(&format!(
+                "files excluded:    {} file(s), {}\n",
+                count(&self.files_skipped),
+                format_size(count(&self.bytes_skipped))
+            ));
+        }
+        out.push_str(&format!("elapsed:           {:.1?}\n", self.elapsed));
+        for (pkg, install_version) in self.install_versions.lock().unwrap().iter() {
+            out.push_str(&format!("{}: install version {}\n", pkg, install_version));
+        }
+        out
+    }
+
+    /// A table of the payloads that failed to install, if any did.
+    pub fn failure_table(&self) -> Option<String> {
+        let failures = self.failures.lock().unwrap();
+        if failures.is_empty() {
+            return None;
+        }
+        let rows: Vec<[&str; 4]> = failures
+            .iter()
+            .map(|(f, _)| {
+                let kind = if f.optional { "optional" } else { "essential" };
+                [
+                    f.package.as_str(),
+                    f.payload.as_str(),
+                    kind,
+                    f.error.as_str(),
+                ]
+            })
+            .collect();
+        let width = |col: usize| rows.iter().map(|row| row[col].len()).max().unwrap_or(0);
+        let (package_width, payload_width) = (width(0), width(1));
+        let mut out = String::from("failed payloads:\n");
+        for [package, payload, kind, error] in rows {
+            out.push_str(&format!(
+                "  {:package_width$}  {:payload_width$}  {:9}  {}\n",
+                package, payload, kind, error
+            ));
+        }
+        Some(out)
+    }
+
+    /// Fail with the first essential payload's error, or the first optional
+    /// one's if only optional payloads failed, so the exit code is that of the
+    /// failure.
+    pub fn into_result(self) -> Result<()> {
+        let mut failures = self.failures.into_inner().unwrap();
+        let count = failures.len();
+        let essential = failures.iter().filter(|(f, _)| !f.optional).count();
+        let Some(first) = failures
+            .iter()
+            .position(|(f, _)| !f.optional)
+            .or((count > 0).then_some(0))
+        else {
+            return Ok(());
+        };
+        let (_, error) = failures.swap_remove(first);
+        Err(error.context(format!(
+            "{} payload(s) failed to install, {} of them essential",
+            count, essential
+        )))
+    }
+}
+
+/// A payload `install --keep-going` couldn't install.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedPayload {
+    pub package: String,
+    pub payload: String,
+    /// See [`is_optional_payload`](crate::packages::is_optional_payload)
+    pub optional: bool,
+    pub error: String,
+}
+
+/// The `--telemetry-output` file, describing what an install produced so build
+/// systems (Bazel, Buck, ...) can declare the installed files as outputs.
+#[derive(Debug, Serialize)]
+pub struct Telemetry {
+    pub installed_packages: Vec<String>,
+    pub installed_files: Vec<PathBuf>,
+    pub cache_hits: u64,
+    pub cache_hit_bytes: u64,
+    pub downloads: u64,
+    pub bytes_downloaded: u64,
+    pub payloads_extracted: u64,
+    pub payloads_already_installed: u64,
+    pub files_new: u64,
+    pub files_added: u64,
+    /// Files `--exclude` left out and their uncompressed size
+    pub files_skipped: u64,
+    pub bytes_skipped: u64,
+    /// Package name to the version found in its install directory
+    pub install_versions: BTreeMap<String, String>,
+    pub failed_payloads: Vec<FailedPayload>,
+    pub elapsed_secs: f64,
+}
+
+impl Telemetry {
+    /// Collect the installed files from the `.files` manifests of each package.
+    pub fn collect(
+        msvcup_dir: &MsvcupDir,
+        msvcup_pkgs: &[MsvcupPackage],
+        stats: &InstallStats,
+    ) -> Result<Self> {
+        let mut files = Vec::new();
+        for msvcup_pkg in msvcup_pkgs {
+            let install_path = msvcup_dir.package_path(msvcup_pkg);
+            files.extend(installed_files(&install_path)?);
+        }
+        files.sort();
+        files.dedup();
+        Ok(Self {
+            installed_packages: msvcup_pkgs.iter().map(|p| p.pool_string()).collect(),
+            installed_files: files,
+            cache_hits: stats.cache_hits.load(Ordering::Relaxed),
+            cache_hit_bytes: stats.cache_hit_bytes.load(Ordering::Relaxed),
+            downloads: stats.downloads.load(Ordering::Relaxed),
+            bytes_downloaded: stats.bytes_downloaded.load(Ordering::Relaxed),
+            payloads_extracted: stats.payloads_extracted.load(Ordering::Relaxed),
+            payloads_already_installed: stats.payloads_already_installed.load(Ordering::Relaxed),
+            files_new: stats.files_new.load(Ordering::Relaxed),
+            files_added: stats.files_added.load(Ordering::Relaxed),
+            files_skipped: stats.files_skipped.load(Ordering::Relaxed),
+            bytes_skipped: stats.bytes_skipped.load(Ordering::Relaxed),
+            install_versions: stats
+                .install_versions
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+            failed_payloads: stats
+                .failures
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(f, _)| f.clone())
+                .collect(),
+            elapsed_secs: stats.elapsed.as_secs_f64(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path, content)
+            .with_context(|| format!("writing telemetry output '{}'", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::MsvcupPackageKind;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn collect_reads_files_manifests() {
+        let root = TempDir::new("telemetry");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Ninja, "1.12.1");
+        let install_meta_dir = root.join(pkg.pool_string()).join("install");
+        std::fs::create_dir_all(&install_meta_dir).unwrap();
+        let ninja = root.join(pkg.pool_string()).join("ninja.exe");
+        let license = root.join(pkg.pool_string()).join("LICENSE");
+        std::fs::write(
+            install_meta_dir.join("abc-ninja-win.zip.files"),
+            format!("{}\n{}\n", ninja.display(), license.display()),
+        )
+        .unwrap();
+        // Pending manifests from interrupted installs aren't installed files
+        std::fs::write(
+            install_meta_dir.join("def-other.zip.files.pending"),
+            "def-other.zip\nnew other\n",
+        )
+        .unwrap();
+
+        let mut stats = InstallStats::default();
+        stats.record_already_installed();
+        stats.record_install_version(&pkg, "1.12.1");
+        let payload_stats = InstallStats::default();
+        payload_stats.record_cache_hit(2048);
+        payload_stats.record_download(100);
+        payload_stats.record_download(50);
+        payload_stats.record_extracted(3, 1);
+        stats.merge(&payload_stats);
+        stats.set_elapsed(std::time::Duration::from_millis(1500));
+        let summary = stats.summary();
+        assert!(
+            summary.contains("downloaded:        2 payload(s), 150 B\n"),
+            "{}",
+            summary
+        );
+        assert!(summary.contains("already fetched:   1 payload(s), 2.0 KiB\n"));
+        assert!(summary.contains("files written:     3 new, 1 added to existing\n"));
+        assert!(summary.contains("elapsed:           1.5s\n"));
+        assert!(summary.ends_with("ninja-1.12.1: install version 1.12.1\n"));
+        assert!(!summary.contains("failed:"));
+        assert!(stats.failure_table().is_none());
+
+        let telemetry = Telemetry::collect(&msvcup_dir, &[pkg], &stats).unwrap();
+        assert_eq!(telemetry.installed_packages, ["ninja-1.12.1"]);
+        assert_eq!(telemetry.installed_files, [license, ninja]);
+        assert_eq!(telemetry.cache_hits, 1);
+        assert_eq!(telemetry.downloads, 2);
+        assert_eq!(telemetry.bytes_downloaded, 150);
+        assert_eq!(telemetry.payloads_extracted, 1);
+        assert_eq!(telemetry.payloads_already_installed, 1);
+        assert_eq!(telemetry.files_new, 3);
+        assert_eq!(telemetry.install_versions["ninja-1.12.1"], "1.12.1");
+
+        let out = root.join("telemetry.json");
+        telemetry.write(&out).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+        assert_eq!(json["downloads"], 2);
+        assert_eq!(json["installed_files"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn failures_pick_the_essential_error() {
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Ninja, "1.12.1");
+        let stats = InstallStats::default();
+        stats.record_failure(
+            &pkg,
+            "VC_redist.x64.exe",
+            true,
+            anyhow::anyhow!("connection reset"),
+        );
+        stats.record_failure(
+            &pkg,
+            "ninja-win.zip",
+            false,
+            crate::error::Error::new(crate::error::ErrorKind::HashMismatch, "SHA256 mismatch")
+                .into(),
+        );
+        assert!(
+            stats
+                .summary()
+                .contains("failed:            2 payload(s), 1 essential\n")
+        );
+        assert_eq!(
+            stats.failure_table().unwrap(),
+            "failed payloads:\n\
+             \x20 ninja-1.12.1  VC_redist.x64.exe  optional   connection reset\n\
+             \x20 ninja-1.12.1  ninja-win.zip      essential  SHA256 mismatch\n"
+        );
+        let err = stats.into_result().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 payload(s) failed to install, 1 of them essential"
+        );
+        assert_eq!(crate::error::exit_code(&err), 5);
+        assert!(InstallStats::default().into_result().is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+//! Fixtures shared by the unit tests.
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// The sha256 of `hello`, used wherever a fixture needs a well-formed hash.
+pub const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+/// An empty directory under the system temp dir that is removed again when
+/// dropped, including when the test panics.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates `msvcup_test_<name>`, clearing out anything a previous run
+    /// left behind. `name` must be unique across the tests.
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("msvcup_test_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Builds a VS manifest with just the fields `get_packages` reads. Every
+/// payload gets [`HELLO_SHA256`] as its hash.
+#[derive(Default)]
+pub struct FixtureManifest {
+    packages: Vec<serde_json::Value>,
+}
+
+impl FixtureManifest {
+    pub fn new() -> FixtureManifest {
+        FixtureManifest::default()
+    }
+
+    /// Adds a package with `(file name, url, size)` payloads.
+    pub fn package(self, id: &str, version: &str, payloads: &[(&str, &str, u64)]) -> Self {
+        self.push(id, version, None, payloads)
+    }
+
+    /// Adds a package that is only installed for `language`.
+    pub fn localized(
+        self,
+        id: &str,
+        version: &str,
+        language: &str,
+        payloads: &[(&str, &str, u64)],
+    ) -> Self {
+        self.push(id, version, Some(language), payloads)
+    }
+
+    fn push(
+        mut self,
+        id: &str,
+        version: &str,
+        language: Option<&str>,
+        payloads: &[(&str, &str, u64)],
+    ) -> Self {
+        let mut package = serde_json::json!({"id": id, "version": version});
+        if let Some(language) = language {
+            package["language"] = language.into();
+        }
+        if !payloads.is_empty() {
+            package["payloads"] = payloads
+                .iter()
+                .map(|(file_name, url, size)| {
+                    serde_json::json!({
+                        "fileName": file_name,
+                        "url": url,
+                        "size": size,
+                        "sha256": HELLO_SHA256,
+                    })
+                })
+                .collect();
+        }
+        self.packages.push(package);
+        self
+    }
+
+    pub fn build(&self) -> String {
+        serde_json::json!({"packages": self.packages}).to_string()
+    }
+}
@@ -0,0 +1,222 @@
+use crate::arch::Arch;
+use crate::error::{ErrorKind, bail_kind};
+use crate::install::query_package_version;
+use crate::manifest::MsvcupDir;
+use crate::packages::{MsvcupPackage, MsvcupPackageKind};
+use crate::zip_extract::{ZipKind, extract_zip_file};
+use anyhow::{Context, Result, bail};
+use fs_err as fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Signature of the `VS_FIXEDFILEINFO` struct in a PE version resource.
+const FIXED_FILE_INFO_SIGNATURE: [u8; 4] = 0xFEEF04BDu32.to_le_bytes();
+
+/// Print the version of the main tool of an installed package by running it
+/// (`cl.exe /Bv`, `cmake --version`, `ninja --version`, `nasm -v`,
+/// `python --version`).
+///
+/// Off Windows the tool is run under Wine if it's in PATH, otherwise the file
+/// version is read from the executable's version resource.
+pub fn tool_version_command(msvcup_dir: &MsvcupDir, msvcup_pkg: &MsvcupPackage) -> Result<()> {
+    let install_path = msvcup_dir.package_path(msvcup_pkg);
+    if !install_path.is_dir() {
+        bail!(
+            "{} is not installed, '{}' does not exist",
+            msvcup_pkg,
+            install_path.display()
+        );
+    }
+    let (exe, args): (PathBuf, &[&str]) = match msvcup_pkg.kind {
+        MsvcupPackageKind::Msvc => (find_cl_exe(msvcup_pkg, &install_path)?, &["/Bv"]),
+        MsvcupPackageKind::Cmake => (install_path.join("bin").join("cmake.exe"), &["--version"]),
+        MsvcupPackageKind::Ninja => (install_path.join("ninja.exe"), &["--version"]),
+        MsvcupPackageKind::Nasm => (install_path.join("nasm.exe"), &["-v"]),
+        MsvcupPackageKind::Python => (install_path.join("python.exe"), &["--version"]),
+        MsvcupPackageKind::Sdk | MsvcupPackageKind::Msbuild | MsvcupPackageKind::Diasdk => {
+            bail_kind!(
+                ErrorKind::Usage,
+                "tool-version supports msvc, cmake, ninja, nasm and python packages, not {}",
+                msvcup_pkg.kind
+            )
+        }
+    };
+    if !exe.is_file() {
+        bail!("{}: '{}' does not exist", msvcup_pkg, exe.display());
+    }
+
+    let mut command = if cfg!(windows) {
+        Command::new(&exe)
+    } else if let Some(wine) = find_in_path("wine") {
+        let mut command = Command::new(wine);
+        command.arg(&exe);
+        command
+    } else {
+        log::debug!(
+            "wine not found, reading the version resource of '{}'",
+            exe.display()
+        );
+        let content = fs::read(&exe)?;
+        let version = pe_file_version(&content)
+            .ok_or_else(|| anyhow::anyhow!("'{}' has no version resource", exe.display()))?;
+        println!("{}", version);
+        return Ok(());
+    };
+
+    // cl.exe exits with an error without a source file, so the status is ignored
+    let output = command
+        .args(args)
+        .output()
+        .with_context(|| format!("running '{}'", exe.display()))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let version = version_line(&stdout, &stderr).ok_or_else(|| {
+        anyhow::anyhow!(
+            "'{}' didn't print a version (exit status {})",
+            exe.display(),
+            output.status
+        )
+    })?;
+    println!("{}", version);
+    Ok(())
+}
+
+/// cl.exe for the native host targeting itself, trying x64 then x86 hosted tools
+/// if the native ones aren't installed.
+fn find_cl_exe(msvcup_pkg: &MsvcupPackage, install_path: &Path) -> Result<PathBuf> {
+    let (_, versioned_path) = query_package_version(msvcup_pkg, install_path)?
+        .expect("msvc packages have a versioned layout");
+    let native_arch = Arch::native().unwrap_or(Arch::X64);
+    let candidates: Vec<PathBuf> = [native_arch, Arch::X64, Arch::X86]
+        .into_iter()
+        .map(|host| {
+            versioned_path
+                .join("bin")
+                .join(format!("Host{}", host))
+                .join(host.as_str())
+                .join("cl.exe")
+        })
+        .collect();
+    Ok(candidates
+        .iter()
+        .find(|p| p.is_file())
+        .unwrap_or(&candidates[0])
+        .clone())
+}
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+}
+
+/// The line of a tool's output that carries its version. cl.exe prints its
+/// banner on stderr; cmake, ninja, nasm and python print the version first on
+/// stdout.
+fn version_line<'a>(stdout: &'a str, stderr: &'a str) -> Option<&'a str> {
+    stdout
+        .lines()
+        .chain(stderr.lines())
+        .map(str::trim)
+        .find(|l| l.contains("Version") || l.contains("version"))
+        .or_else(|| stdout.lines().map(str::trim).find(|l| !l.is_empty()))
+}
+
+/// Print the file version of an executable inside a ZIP/VSIX, read from its
+/// version resource, without extracting the rest of the archive.
+pub fn archive_tool_version_command(archive: &Path, file_path: &str) -> Result<()> {
+    let kind = match archive.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("vsix") => ZipKind::Vsix,
+        _ => ZipKind::Zip,
+    };
+    let content = extract_zip_file(archive, kind, file_path)?;
+    let version = pe_file_version(&content).ok_or_else(|| {
+        anyhow::anyhow!(
+            "'{}' in '{}' has no version resource",
+            file_path,
+            archive.display()
+        )
+    })?;
+    println!("{}", version);
+    Ok(())
+}
+
+/// Find the `VS_FIXEDFILEINFO` in a PE image and format its file version as
+/// `major.minor.build.revision`.
+pub fn pe_file_version(content: &[u8]) -> Option<String> {
+    let offset = content
+        .windows(FIXED_FILE_INFO_SIGNATURE.len())
+        .position(|w| w == FIXED_FILE_INFO_SIGNATURE)?;
+    // signature, struct version, then the file version as two DWORDs
+    let read_u32 = |at: usize| {
+        let bytes = content.get(offset + at..offset + at + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+    let ms = read_u32(8)?;
+    let ls = read_u32(12)?;
+    Some(format!(
+        "{}.{}.{}.{}",
+        ms >> 16,
+        ms & 0xffff,
+        ls >> 16,
+        ls & 0xffff
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn fixed_file_info(ms: u32, ls: u32) -> Vec<u8> {
+        let mut bytes = b"MZ padding VS_VERSION_INFO\0".to_vec();
+        bytes.extend_from_slice(&FIXED_FILE_INFO_SIGNATURE);
+        bytes.extend_from_slice(&0x0001_0000u32.to_le_bytes());
+        bytes.extend_from_slice(&ms.to_le_bytes());
+        bytes.extend_from_slice(&ls.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn pe_file_version_reads_fixed_file_info() {
+        let content = fixed_file_info((19 << 16) | 43, (34808 << 16) | 1);
+        assert_eq!(pe_file_version(&content).unwrap(), "19.43.34808.1");
+    }
+
+    #[test]
+    fn version_line_per_tool() {
+        let cl_banner = "Microsoft (R) C/C++ Optimizing Compiler Version 19.43.34808 for x64\n\
+                         Copyright (C) Microsoft Corporation.  All rights reserved.\n";
+        assert_eq!(
+            version_line("", cl_banner),
+            Some("Microsoft (R) C/C++ Optimizing Compiler Version 19.43.34808 for x64")
+        );
+        assert_eq!(
+            version_line("cmake version 3.31.4\n\nCMake suite maintained", ""),
+            Some("cmake version 3.31.4")
+        );
+        assert_eq!(version_line("1.12.1\n", ""), Some("1.12.1"));
+        assert_eq!(version_line("", ""), None);
+    }
+
+    #[test]
+    fn tool_version_requires_installed_package() {
+        let root = TempDir::new("tool_version");
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+        let ninja = MsvcupPackage::new(MsvcupPackageKind::Ninja, "1.12.1");
+        assert!(tool_version_command(&msvcup_dir, &ninja).is_err());
+
+        let sdk = MsvcupPackage::new(MsvcupPackageKind::Sdk, "10.0.22621.7");
+        std::fs::create_dir_all(root.join(sdk.pool_string())).unwrap();
+        let err = tool_version_command(&msvcup_dir, &sdk).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 2);
+    }
+
+    #[test]
+    fn pe_file_version_missing_or_truncated() {
+        assert!(pe_file_version(b"MZ no version here").is_none());
+        let content = fixed_file_info(1, 2);
+        assert!(pe_file_version(&content[..content.len() - 1]).is_none());
+    }
+}
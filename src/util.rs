@@ -1,6 +1,7 @@
 use anyhow::Result;
 use std::cmp::Ordering;
-use std::path::Path;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 pub fn order_dotted_numeric(lhs: &str, rhs: &str) -> Ordering {
     let mut lhs_it = lhs.split('.');
@@ -78,6 +79,21 @@ pub fn basename_from_url(url: &str) -> &str {
     }
 }
 
+/// The basename of `s` with everything from its last '.' removed.
+pub fn basename_without_extension(s: &str) -> &str {
+    let basename = basename_from_url(s);
+    match basename.rfind('.') {
+        Some(i) => &basename[..i],
+        None => basename,
+    }
+}
+
+/// The extension (without the '.') of the basename of `url`, if it has one.
+pub fn extension_from_url(url: &str) -> Option<&str> {
+    let basename = basename_from_url(url);
+    basename.rfind('.').map(|i| &basename[i + 1..])
+}
+
 /// Insert into a sorted Vec, deduplicating
 pub fn insert_sorted<T, F>(list: &mut Vec<T>, item: T, cmp: F)
 where
@@ -89,6 +105,32 @@ where
     }
 }
 
+/// Collect `items` into a sorted Vec, deduplicating like [`insert_sorted`]: of
+/// equal items the first one is kept. Sorting once is faster than inserting one
+/// at a time for anything but a few dozen items, see `benches/insert_sorted.rs`.
+pub fn build_sorted<T, F>(items: impl IntoIterator<Item = T>, cmp: F) -> Vec<T>
+where
+    F: Fn(&T, &T) -> Ordering,
+{
+    let mut list: Vec<T> = items.into_iter().collect();
+    list.sort_by(&cmp);
+    list.dedup_by(|later, earlier| cmp(earlier, later) == Ordering::Equal);
+    list
+}
+
+/// `path` as a `&str` for the APIs that take one. A path that isn't valid UTF-8
+/// (e.g. an `--install-dir` in another encoding on Linux) is an I/O error, so it
+/// exits as a filesystem failure.
+pub fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("path '{}' is not valid UTF-8", path.display()),
+        )
+        .into()
+    })
+}
+
 /// Write `content` to `path` only if it differs from the existing file.
 pub fn update_file(path: &Path, content: &[u8]) -> Result<()> {
     let needs_update = match fs_err::read(path) {
@@ -104,6 +146,146 @@ pub fn update_file(path: &Path, content: &[u8]) -> Result<()> {
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilenameError {
+    NullByte,
+    AbsolutePath,
+    DotComponent,
+}
+
+impl fmt::Display for FilenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NullByte => write!(f, "filename contains a null byte"),
+            Self::AbsolutePath => write!(f, "filename is an absolute path"),
+            Self::DotComponent => write!(f, "filename contains a '.' or '..' component"),
+        }
+    }
+}
+
+impl std::error::Error for FilenameError {}
+
+/// Validate an archive entry name before joining it onto an install directory.
+/// Rejects null bytes, absolute paths (`/`, `\` or a drive letter) and `.`/`..`
+/// components, treating both `/` and `\` as separators.
+pub fn sanitize_filename(raw: &str) -> Result<&str, FilenameError> {
+    if raw.contains('\0') {
+        return Err(FilenameError::NullByte);
+    }
+    if has_root_prefix(raw) {
+        return Err(FilenameError::AbsolutePath);
+    }
+    if raw
+        .split(['/', '\\'])
+        .any(|part| part == "." || part == "..")
+    {
+        return Err(FilenameError::DotComponent);
+    }
+    Ok(raw)
+}
+
+/// Whether `path` starts with `/`, `\` or a drive letter, regardless of the host OS.
+fn has_root_prefix(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    path.starts_with('/')
+        || path.starts_with('\\')
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+/// The line recorded for an installed file in a `.files` install manifest. Paths
+/// are stored relative to the install directory so the msvcup root can be moved.
+pub fn install_manifest_entry(install_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(install_dir)
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+/// Resolve a `.files` install manifest line against the current install directory.
+/// Manifests written by older versions contain absolute paths, which are kept as is.
+pub fn resolve_install_manifest_entry(install_dir: &Path, entry: &str) -> PathBuf {
+    if has_root_prefix(entry) {
+        PathBuf::from(entry)
+    } else {
+        install_dir.join(entry)
+    }
+}
+
+/// Whether `path` is below `root` without any `.`, `..` or root components in
+/// between, so it can't escape `root` once resolved.
+pub fn is_inside(path: &Path, root: &Path) -> bool {
+    path.strip_prefix(root).is_ok_and(|rel| {
+        rel.components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+    })
+}
+
+/// Remove the given files, then remove every directory between them and `root`
+/// that became empty, deepest first. `root` itself is never removed, files that
+/// are already gone are ignored, and nothing is removed if any path is outside
+/// `root`.
+pub fn remove_files_and_prune_dirs(paths: &[PathBuf], root: &Path) -> Result<()> {
+    for path in paths {
+        if !is_inside(path, root) {
+            anyhow::bail!(
+                "refusing to remove '{}', it is outside of '{}'",
+                path.display(),
+                root.display()
+            );
+        }
+    }
+
+    let mut dirs: Vec<&Path> = Vec::new();
+    for path in paths {
+        match fs_err::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let mut dir = path.parent();
+        while let Some(d) = dir {
+            if d == root || dirs.contains(&d) {
+                break;
+            }
+            dirs.push(d);
+            dir = d.parent();
+        }
+    }
+
+    // Children have more components than their parents, so this removes bottom-up
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    for dir in dirs {
+        // Fails for directories that still have other content, which is what we want
+        if std::fs::remove_dir(dir).is_ok() {
+            log::debug!("removed empty directory '{}'", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// Create a symlink at `link` pointing to `target`. Windows needs to know whether
+/// the target is a directory.
+pub fn create_symlink(target: &Path, link: &Path, is_dir: bool) -> Result<()> {
+    #[cfg(unix)]
+    let result = {
+        let _ = is_dir;
+        std::os::unix::fs::symlink(target, link)
+    };
+    #[cfg(windows)]
+    let result = if is_dir {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    };
+    result.map_err(|e| {
+        anyhow::anyhow!(e).context(format!(
+            "linking '{}' to '{}'",
+            link.display(),
+            target.display()
+        ))
+    })
+}
+
 pub fn alloc_url_percent_decoded(url: &str) -> String {
     percent_encoding::percent_decode_str(url)
         .decode_utf8_lossy()
@@ -114,6 +296,15 @@ pub fn alloc_url_percent_decoded(url: &str) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    #[cfg(unix)]
+    fn path_str_rejects_invalid_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        assert_eq!(path_str(Path::new("a/b")).unwrap(), "a/b");
+        let err = path_str(Path::new(std::ffi::OsStr::from_bytes(b"a\xff"))).unwrap_err();
+        assert_eq!(crate::error::exit_code(&err), 6);
+    }
+
     #[test]
     fn test_order_dotted_numeric() {
         assert_eq!(order_dotted_numeric("0.1", "0.1"), Ordering::Equal);
@@ -201,6 +392,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_basename_without_extension() {
+        assert_eq!(
+            basename_without_extension("https://example.com/path/to/file.vsix"),
+            "file"
+        );
+        assert_eq!(basename_without_extension("archive.tar.gz"), "archive.tar");
+        assert_eq!(
+            basename_without_extension("https://example.com/noext"),
+            "noext"
+        );
+        assert_eq!(basename_without_extension(""), "");
+    }
+
+    #[test]
+    fn test_extension_from_url() {
+        assert_eq!(
+            extension_from_url("https://example.com/path/to/file.vsix"),
+            Some("vsix")
+        );
+        assert_eq!(extension_from_url("file.msi"), Some("msi"));
+        assert_eq!(extension_from_url("https://example.com/noext"), None);
+        assert_eq!(extension_from_url("https://example.com.au/noext"), None);
+        assert_eq!(extension_from_url("trailing."), Some(""));
+    }
+
     #[test]
     fn test_insert_sorted_ascending() {
         let mut list: Vec<i32> = Vec::new();
@@ -219,6 +436,19 @@ mod tests {
         assert_eq!(list, vec![1, 2]);
     }
 
+    #[test]
+    fn test_build_sorted_matches_insert_sorted() {
+        let items = [(3, 'a'), (1, 'b'), (3, 'c'), (2, 'd'), (1, 'e')];
+        let by_key = |a: &(i32, char), b: &(i32, char)| a.0.cmp(&b.0);
+        let mut inserted = Vec::new();
+        for item in items {
+            insert_sorted(&mut inserted, item, by_key);
+        }
+        assert_eq!(build_sorted(items, by_key), inserted);
+        assert_eq!(inserted, [(1, 'b'), (2, 'd'), (3, 'a')]);
+        assert!(build_sorted(Vec::<i32>::new(), |a, b| a.cmp(b)).is_empty());
+    }
+
     #[test]
     fn test_insert_sorted_empty() {
         let mut list: Vec<i32> = Vec::new();
@@ -226,6 +456,36 @@ mod tests {
         assert_eq!(list, vec![42]);
     }
 
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(
+            sanitize_filename("Contents/VC/bin/cl.exe"),
+            Ok("Contents/VC/bin/cl.exe")
+        );
+        assert_eq!(sanitize_filename("a..b/c"), Ok("a..b/c"));
+        assert_eq!(sanitize_filename("a\0b"), Err(FilenameError::NullByte));
+        assert_eq!(
+            sanitize_filename("/etc/passwd"),
+            Err(FilenameError::AbsolutePath)
+        );
+        assert_eq!(
+            sanitize_filename("\\server\\x"),
+            Err(FilenameError::AbsolutePath)
+        );
+        assert_eq!(sanitize_filename("C:/x"), Err(FilenameError::AbsolutePath));
+        assert_eq!(sanitize_filename("c:x"), Err(FilenameError::AbsolutePath));
+        assert_eq!(
+            sanitize_filename("a/../b"),
+            Err(FilenameError::DotComponent)
+        );
+        assert_eq!(
+            sanitize_filename("a\\..\\b"),
+            Err(FilenameError::DotComponent)
+        );
+        assert_eq!(sanitize_filename("./a"), Err(FilenameError::DotComponent));
+        assert_eq!(sanitize_filename(".."), Err(FilenameError::DotComponent));
+    }
+
     #[test]
     fn test_alloc_url_percent_decoded() {
         assert_eq!(alloc_url_percent_decoded("hello%20world"), "hello world");
@@ -236,9 +496,7 @@ mod tests {
 
     #[test]
     fn test_update_file() {
-        let dir = std::env::temp_dir().join("msvcup_test_update_file");
-        let _ = std::fs::remove_dir_all(&dir);
-        std::fs::create_dir_all(&dir).unwrap();
+        let dir = crate::test_support::TempDir::new("update_file");
 
         let path = dir.join("test.txt");
 
@@ -253,7 +511,88 @@ mod tests {
         // Update when content differs
         update_file(&path, b"world").unwrap();
         assert_eq!(std::fs::read_to_string(&path).unwrap(), "world");
+    }
+
+    #[test]
+    fn install_manifest_entries_are_relative() {
+        let install_dir = Path::new("/msvcup/msvc-14.43.17.13");
+        let entry = install_manifest_entry(install_dir, &install_dir.join("VC").join("cl.exe"));
+        assert_eq!(Path::new(&entry), Path::new("VC").join("cl.exe"));
+        assert_eq!(
+            resolve_install_manifest_entry(Path::new("/moved/msvc-14.43.17.13"), &entry),
+            Path::new("/moved/msvc-14.43.17.13")
+                .join("VC")
+                .join("cl.exe")
+        );
+    }
+
+    #[test]
+    fn resolve_install_manifest_entry_keeps_absolute_paths() {
+        let install_dir = Path::new("/msvcup/msvc-14.43.17.13");
+        for old in [
+            "C:\\msvcup\\msvc-14.43.17.13\\VC\\cl.exe",
+            "/msvcup/msvc-14.43.17.13/VC/cl.exe",
+        ] {
+            assert_eq!(
+                resolve_install_manifest_entry(install_dir, old),
+                PathBuf::from(old)
+            );
+        }
+    }
+
+    fn make_tree(root: &Path, files: &[&str]) {
+        for file in files {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+    }
 
-        let _ = std::fs::remove_dir_all(&dir);
+    #[test]
+    fn prune_dirs_shared_parents() {
+        let root = crate::test_support::TempDir::new("prune_shared");
+        make_tree(
+            &root,
+            &[
+                "VC/bin/x64/cl.exe",
+                "VC/bin/x64/link.exe",
+                "VC/lib/x64/a.lib",
+            ],
+        );
+        remove_files_and_prune_dirs(
+            &[
+                root.join("VC/bin/x64/cl.exe"),
+                root.join("VC/bin/x64/link.exe"),
+                root.join("VC/lib/x64/a.lib"),
+            ],
+            &root,
+        )
+        .unwrap();
+        assert!(root.is_dir());
+        assert!(!root.join("VC").exists());
+    }
+
+    #[test]
+    fn prune_dirs_keeps_non_empty_siblings() {
+        let root = crate::test_support::TempDir::new("prune_siblings");
+        make_tree(&root, &["VC/bin/x64/cl.exe", "VC/bin/x86/cl.exe"]);
+        remove_files_and_prune_dirs(&[root.join("VC/bin/x64/cl.exe")], &root).unwrap();
+        assert!(!root.join("VC/bin/x64").exists());
+        assert!(root.join("VC/bin/x86/cl.exe").exists());
+        // Files that are already gone are fine
+        remove_files_and_prune_dirs(&[root.join("VC/bin/x64/cl.exe")], &root).unwrap();
+    }
+
+    #[test]
+    fn prune_dirs_refuses_paths_outside_root() {
+        let root = crate::test_support::TempDir::new("prune_outside");
+        make_tree(&root, &["pool/a.txt", "other/b.txt"]);
+        let pool = root.join("pool");
+        for outside in [root.join("other/b.txt"), pool.join("../other/b.txt")] {
+            assert!(remove_files_and_prune_dirs(&[pool.join("a.txt"), outside], &pool).is_err());
+        }
+        // Nothing was removed
+        assert!(pool.join("a.txt").exists());
+        assert!(root.join("other/b.txt").exists());
     }
 }
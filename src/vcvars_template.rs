@@ -0,0 +1,328 @@
+use crate::arch::Arch;
+use crate::error::{ErrorKind, bail_kind};
+use crate::install::{BIN_VAR, FinishKind};
+use anyhow::{Context, Result};
+use fs_err as fs;
+use std::path::Path;
+
+/// A user-provided variant of the generated `vcvars-{arch}.bat` scripts, for
+/// `install --vcvars-template`. It's rendered to `vcvars-{arch}-{name}.bat`,
+/// where the name is the template's file name up to the first `.`.
+///
+/// The template is copied as-is except for these placeholders:
+///
+/// - `{install_dir}`: the absolute package directory
+/// - `{version}`: the installed version of the package
+/// - `{msvc_version}`, `{sdk_version}`: the installed version of the install's
+///   msvc or sdk package, for packages of either kind
+/// - `{host}`: the architecture of the tools, e.g. `x64`
+/// - `{target}`: the target architecture as it appears in the tool and library
+///   directories, e.g. `arm64` (also for arm64ec)
+/// - `{path_var}`: `PATH`, or `MSVCUP_BIN` with `--no-vcvars-path`
+///
+/// `{{` and `}}` stand for literal braces.
+#[derive(Debug)]
+pub struct VcvarsTemplate {
+    name: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    InstallDir,
+    Version,
+    MsvcVersion,
+    SdkVersion,
+    Host,
+    Target,
+    PathVar,
+}
+
+impl Placeholder {
+    const ALL: [Placeholder; 7] = [
+        Placeholder::InstallDir,
+        Placeholder::Version,
+        Placeholder::MsvcVersion,
+        Placeholder::SdkVersion,
+        Placeholder::Host,
+        Placeholder::Target,
+        Placeholder::PathVar,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::InstallDir => "install_dir",
+            Self::Version => "version",
+            Self::MsvcVersion => "msvc_version",
+            Self::SdkVersion => "sdk_version",
+            Self::Host => "host",
+            Self::Target => "target",
+            Self::PathVar => "path_var",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// The values a template is filled in with for one package and target arch.
+pub struct VcvarsValues<'a> {
+    pub install_path: &'a Path,
+    pub install_version: &'a str,
+    /// The install version of the msvc package, unless there's none or several
+    pub msvc_version: Option<&'a str>,
+    /// The install version of the sdk package, unless there's none or several
+    pub sdk_version: Option<&'a str>,
+    pub host_arch: Arch,
+    pub target_arch: Arch,
+    pub vcvars_path: bool,
+}
+
+impl VcvarsTemplate {
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("reading vcvars template '{}'", path.display()))?;
+        let mut template = Self::parse(&content)
+            .with_context(|| format!("parsing vcvars template '{}'", path.display()))?;
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        match file_name.split('.').next() {
+            Some(name) if !name.is_empty() => template.name = name.to_string(),
+            _ => {}
+        }
+        Ok(template)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut rest = content;
+        while let Some(pos) = rest.find(['{', '}']) {
+            text.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+            let line = content[..content.len() - rest.len() + pos]
+                .matches('\n')
+                .count()
+                + 1;
+            if rest[pos..].starts_with("{{") || rest[pos..].starts_with("}}") {
+                text.push_str(&rest[pos..pos + 1]);
+                rest = &after[1..];
+                continue;
+            }
+            if rest[pos..].starts_with('}') {
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "line {}: unmatched '}}', use '}}}}' for a literal brace",
+                    line
+                );
+            }
+            let Some(end) = after.find('}') else {
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "line {}: unterminated placeholder, use '{{{{' for a literal brace",
+                    line
+                );
+            };
+            let name = &after[..end];
+            let Some(placeholder) = Placeholder::ALL.into_iter().find(|p| p.name() == name) else {
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "line {}: unknown placeholder '{{{}}}', expected one of: {}",
+                    line,
+                    name,
+                    Placeholder::ALL
+                        .iter()
+                        .map(|p| format!("{{{}}}", p.name()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            };
+            if !text.is_empty() {
+                segments.push(Segment::Text(std::mem::take(&mut text)));
+            }
+            segments.push(Segment::Placeholder(placeholder));
+            rest = &after[end + 1..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        Ok(Self {
+            name: "template".to_string(),
+            segments,
+        })
+    }
+
+    /// The name in the file names the template is rendered to.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Check that every placeholder resolves for an install of packages of
+    /// `finish_kinds`: `{msvc_version}` needs an msvc package, and a single one
+    /// if the template is also rendered for an sdk package. The same goes for
+    /// `{sdk_version}`.
+    pub fn check(&self, finish_kinds: &[FinishKind]) -> Result<()> {
+        for (placeholder, kind, kind_name) in [
+            (Placeholder::MsvcVersion, FinishKind::Msvc, "msvc"),
+            (Placeholder::SdkVersion, FinishKind::Sdk, "sdk"),
+        ] {
+            if !self.segments.contains(&Segment::Placeholder(placeholder)) {
+                continue;
+            }
+            let count = finish_kinds.iter().filter(|k| **k == kind).count();
+            if count == 0 {
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "the vcvars template uses {{{}}}, but no {} package is installed",
+                    placeholder.name(),
+                    kind_name
+                );
+            }
+            if count > 1 && finish_kinds.iter().any(|k| *k != kind) {
+                bail_kind!(
+                    ErrorKind::Usage,
+                    "the vcvars template uses {{{}}}, but {} {} packages are installed, \
+                     use {{version}} for each package's own version",
+                    placeholder.name(),
+                    count,
+                    kind_name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in the placeholders, see [`VcvarsTemplate::check`].
+    pub fn render(&self, values: &VcvarsValues) -> Result<String> {
+        let mut out = String::new();
+        for segment in &self.segments {
+            let placeholder = match segment {
+                Segment::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+                Segment::Placeholder(placeholder) => *placeholder,
+            };
+            match placeholder {
+                Placeholder::InstallDir => out.push_str(&values.install_path.to_string_lossy()),
+                Placeholder::Version => out.push_str(values.install_version),
+                Placeholder::MsvcVersion | Placeholder::SdkVersion => {
+                    let version = match placeholder {
+                        Placeholder::MsvcVersion => values.msvc_version,
+                        _ => values.sdk_version,
+                    };
+                    let Some(version) = version else {
+                        bail_kind!(
+                            ErrorKind::Usage,
+                            "the vcvars template uses {{{}}}, but its package isn't installed",
+                            placeholder.name()
+                        );
+                    };
+                    out.push_str(version)
+                }
+                Placeholder::Host => out.push_str(values.host_arch.as_str()),
+                Placeholder::Target => out.push_str(values.target_arch.payload_arch().as_str()),
+                Placeholder::PathVar => {
+                    out.push_str(if values.vcvars_path { "PATH" } else { BIN_VAR })
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> VcvarsValues<'static> {
+        VcvarsValues {
+            install_path: Path::new("C:\\msvcup\\msvc-14.43.17.13"),
+            install_version: "14.43.34808",
+            msvc_version: Some("14.43.34808"),
+            sdk_version: Some("10.0.22621.0"),
+            host_arch: Arch::X64,
+            target_arch: Arch::Arm64Ec,
+            vcvars_path: true,
+        }
+    }
+
+    #[test]
+    fn render_placeholders() {
+        let template = VcvarsTemplate::parse(
+            "@echo off\n\
+             set \"{path_var}={install_dir}\\VC\\Tools\\MSVC\\{msvc_version}\\bin\\Host{host}\\{target};%{path_var}%\"\n\
+             set \"SDK_VERSION={sdk_version}\"\n\
+             rem {{literal}} {version}\n",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render(&values()).unwrap(),
+            "@echo off\n\
+             set \"PATH=C:\\msvcup\\msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\arm64;%PATH%\"\n\
+             set \"SDK_VERSION=10.0.22621.0\"\n\
+             rem {literal} 14.43.34808\n"
+        );
+
+        let mut no_path = values();
+        no_path.vcvars_path = false;
+        assert_eq!(
+            VcvarsTemplate::parse("{path_var}")
+                .unwrap()
+                .render(&no_path)
+                .unwrap(),
+            "MSVCUP_BIN"
+        );
+    }
+
+    #[test]
+    fn reject_unresolvable_placeholders() {
+        let err = VcvarsTemplate::parse("set X=1\nset Y={sdk_dir}\n").unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "line 2: unknown placeholder '{sdk_dir}', expected one of: {install_dir}"
+            ),
+            "{}",
+            err
+        );
+        let err = VcvarsTemplate::parse("set X={version").unwrap_err();
+        assert!(
+            err.to_string().contains("unterminated placeholder"),
+            "{}",
+            err
+        );
+        assert!(VcvarsTemplate::parse("}").is_err());
+
+        // Both versions resolve for the usual msvc + sdk install
+        let template = VcvarsTemplate::parse("{msvc_version} {sdk_version}").unwrap();
+        template
+            .check(&[FinishKind::Msvc, FinishKind::Sdk])
+            .unwrap();
+        let err = template.check(&[FinishKind::Msvc]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the vcvars template uses {sdk_version}, but no sdk package is installed"
+        );
+        assert_eq!(crate::error::exit_code(&err), 2);
+
+        // Side by side msvc versions only have a version of their own
+        let template = VcvarsTemplate::parse("{msvc_version}").unwrap();
+        template
+            .check(&[FinishKind::Msvc, FinishKind::Msvc])
+            .unwrap();
+        let err = template
+            .check(&[FinishKind::Msvc, FinishKind::Msvc, FinishKind::Sdk])
+            .unwrap_err();
+        assert!(err.to_string().contains("2 msvc packages"), "{}", err);
+
+        let mut no_msvc = values();
+        no_msvc.msvc_version = None;
+        assert!(template.render(&no_msvc).is_err());
+    }
+}
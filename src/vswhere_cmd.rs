@@ -0,0 +1,567 @@
+use crate::manifest::MsvcupDir;
+use crate::packages::{MsvcupPackage, MsvcupPackageKind};
+use crate::paths_cmd::package_paths;
+use crate::sha::Sha256;
+use crate::util::order_dotted_numeric;
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+
+/// Product id reported for every instance. vswhere only lists Build Tools with
+/// `-products *`, but msvcup instances are listed by default.
+const PRODUCT_ID: &str = "Microsoft.VisualStudio.Product.BuildTools";
+const VC_TOOLS_COMPONENT: &str = "Microsoft.VisualStudio.Component.VC.Tools.x86.x64";
+const VC_TOOLS_ARM64_COMPONENT: &str = "Microsoft.VisualStudio.Component.VC.Tools.ARM64";
+const VC_TOOLS_WORKLOAD: &str = "Microsoft.VisualStudio.Workload.VCTools";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VswhereFormat {
+    Text,
+    Json,
+    Value,
+}
+
+/// The subset of vswhere's command line that build tools commonly use. Anything
+/// else is ignored so scripts written for the real vswhere keep working.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VswhereArgs {
+    pub latest: bool,
+    /// `-products`; empty or `*` matches every instance
+    pub products: Vec<String>,
+    pub requires: Vec<String>,
+    pub requires_any: bool,
+    pub property: Option<String>,
+    pub format: Option<VswhereFormat>,
+}
+
+impl VswhereArgs {
+    /// Parse vswhere-style arguments. Both `-flag` and `--flag` are accepted and
+    /// flags are case-insensitive, like vswhere.
+    pub fn parse(args: &[String]) -> Self {
+        let mut parsed = Self::default();
+        let mut i = 0;
+        // Values following a flag, up to the next flag
+        let take_values = |i: &mut usize| -> Vec<String> {
+            let mut values = Vec::new();
+            while let Some(arg) = args.get(*i + 1) {
+                if arg.starts_with('-') {
+                    break;
+                }
+                values.push(arg.clone());
+                *i += 1;
+            }
+            values
+        };
+        while i < args.len() {
+            let flag = args[i].trim_start_matches('-').to_ascii_lowercase();
+            match flag.as_str() {
+                "latest" => parsed.latest = true,
+                "products" => parsed.products.extend(take_values(&mut i)),
+                "requires" => parsed.requires.extend(take_values(&mut i)),
+                "requiresany" => parsed.requires_any = true,
+                "property" => parsed.property = take_values(&mut i).into_iter().next(),
+                "format" => {
+                    parsed.format = match take_values(&mut i).first().map(String::as_str) {
+                        Some("json") => Some(VswhereFormat::Json),
+                        Some("value") => Some(VswhereFormat::Value),
+                        Some("text") => Some(VswhereFormat::Text),
+                        other => {
+                            log::debug!("vswhere: ignoring unsupported format {:?}", other);
+                            None
+                        }
+                    }
+                }
+                _ => {
+                    let ignored = take_values(&mut i);
+                    log::debug!("vswhere: ignoring '{}' {:?}", args[i], ignored);
+                }
+            }
+            i += 1;
+        }
+        parsed
+    }
+}
+
+/// A vswhere instance record, using vswhere's field names.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VsInstance {
+    pub instance_id: String,
+    pub installation_name: String,
+    pub installation_path: String,
+    pub installation_version: String,
+    pub product_id: String,
+    pub state: u32,
+    pub is_complete: bool,
+    pub is_launchable: bool,
+    pub is_prerelease: bool,
+    pub is_reboot_required: bool,
+    pub display_name: String,
+    pub description: String,
+    pub channel_id: String,
+    pub catalog: VsCatalog,
+    #[serde(skip)]
+    pub components: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VsCatalog {
+    pub product_display_version: String,
+    pub product_line: String,
+    pub product_line_version: String,
+    pub product_name: String,
+    pub product_semantic_version: String,
+}
+
+impl VsInstance {
+    /// Build an instance for an installed msvc package. The package version
+    /// carries the VS version (`14.43.17.13` is toolset 14.43 from VS 17.13) and
+    /// `toolset_version` is its versioned directory (e.g. `14.43.34808`).
+    pub fn new(
+        msvcup_pkg: &MsvcupPackage,
+        installation_path: String,
+        toolset_version: &str,
+        mut components: Vec<String>,
+    ) -> Self {
+        let parts: Vec<&str> = msvcup_pkg.version.split('.').collect();
+        let vs_major = parts.get(2).copied().unwrap_or("17");
+        let vs_minor = parts.get(3).copied().unwrap_or("0");
+        let toolset_build = toolset_version.split('.').nth(2).unwrap_or("0");
+        let display_version = format!("{}.{}", vs_major, vs_minor);
+        let product_line_version = match vs_major {
+            "15" => "2017",
+            "16" => "2019",
+            "17" => "2022",
+            "18" => "2026",
+            _ => "",
+        };
+        components.extend([
+            VC_TOOLS_COMPONENT.to_string(),
+            VC_TOOLS_WORKLOAD.to_string(),
+        ]);
+        components.sort();
+        components.dedup();
+
+        let sha256 = Sha256::of_bytes(installation_path.as_bytes());
+        Self {
+            instance_id: sha256.to_hex()[..8].to_string(),
+            installation_name: format!("msvcup/{}", msvcup_pkg),
+            installation_version: format!("{}.{}.0", display_version, toolset_build),
+            installation_path,
+            product_id: PRODUCT_ID.to_string(),
+            state: 4294967295,
+            is_complete: true,
+            is_launchable: false,
+            is_prerelease: false,
+            is_reboot_required: false,
+            display_name: format!(
+                "Visual Studio Build Tools {} (msvcup)",
+                product_line_version
+            ),
+            description: format!("MSVC toolset {} installed by msvcup", toolset_version),
+            channel_id: format!("VisualStudio.{}.Release", vs_major),
+            catalog: VsCatalog {
+                product_display_version: display_version.clone(),
+                product_line: format!("Dev{}", vs_major),
+                product_line_version: product_line_version.to_string(),
+                product_name: "Visual Studio".to_string(),
+                product_semantic_version: format!("{}.0+{}.0", display_version, toolset_build),
+            },
+            components,
+        }
+    }
+
+    fn matches(&self, args: &VswhereArgs) -> bool {
+        let product_matches = args.products.is_empty()
+            || args
+                .products
+                .iter()
+                .any(|p| p == "*" || p.eq_ignore_ascii_case(&self.product_id));
+        let has = |id: &String| self.components.iter().any(|c| c.eq_ignore_ascii_case(id));
+        let requires_matches = args.requires.is_empty()
+            || if args.requires_any {
+                args.requires.iter().any(has)
+            } else {
+                args.requires.iter().all(has)
+            };
+        product_matches && requires_matches
+    }
+
+    /// Look up a property by vswhere name, e.g. `installationPath` or
+    /// `catalog_productLineVersion` (`catalog.` also works).
+    fn property(&self, name: &str) -> Option<String> {
+        let json = serde_json::to_value(self).ok()?;
+        let value = match name
+            .split_once('_')
+            .or_else(|| name.split_once('.'))
+            .filter(|(prefix, _)| prefix.eq_ignore_ascii_case("catalog"))
+        {
+            Some((_, key)) => find_key(json.get("catalog")?, key)?,
+            None => find_key(&json, name)?,
+        };
+        match value {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Object(_) => None,
+            other => Some(other.to_string()),
+        }
+    }
+}
+
+fn find_key<'a>(object: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    object
+        .as_object()?
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v)
+}
+
+/// Synthesize vswhere instances from the msvc packages installed in `msvcup_dir`.
+/// Installed sdk packages add their `Windows1xSDK` component to every instance.
+pub fn installed_instances(msvcup_dir: &MsvcupDir) -> Result<Vec<VsInstance>> {
+    let installed = msvcup_dir.list_installed_packages()?;
+    let mut sdk_components = Vec::new();
+    for sdk in installed
+        .iter()
+        .filter(|p| p.kind == MsvcupPackageKind::Sdk)
+    {
+        if let Some(component) = sdk_component(&sdk.version) {
+            sdk_components.push(component);
+        }
+    }
+
+    let mut instances = Vec::new();
+    for msvc in installed
+        .iter()
+        .filter(|p| p.kind == MsvcupPackageKind::Msvc)
+    {
+        let paths = match package_paths(msvcup_dir, msvc) {
+            Ok(paths) => paths,
+            Err(e) => {
+                log::debug!("vswhere: skipping {}: {:#}", msvc, e);
+                continue;
+            }
+        };
+        let (Some(version), Some(versioned_path)) = (paths.version, paths.versioned_path) else {
+            continue;
+        };
+        let mut components = sdk_components.clone();
+        if versioned_path.join("lib").join("arm64").is_dir() {
+            components.push(VC_TOOLS_ARM64_COMPONENT.to_string());
+        }
+        instances.push(VsInstance::new(
+            msvc,
+            paths.path.display().to_string(),
+            &version,
+            components,
+        ));
+    }
+    Ok(instances)
+}
+
+/// `Microsoft.VisualStudio.Component.Windows11SDK.22621` for sdk `10.0.22621.7`.
+fn sdk_component(sdk_version: &str) -> Option<String> {
+    let build: u32 = sdk_version.split('.').nth(2)?.parse().ok()?;
+    let generation = if build >= 22000 { 11 } else { 10 };
+    Some(format!(
+        "Microsoft.VisualStudio.Component.Windows{}SDK.{}",
+        generation, build
+    ))
+}
+
+/// Filter the instances like vswhere does and render them.
+pub fn render(mut instances: Vec<VsInstance>, args: &VswhereArgs) -> Result<String> {
+    instances.retain(|i| i.matches(args));
+    instances
+        .sort_by(|a, b| order_dotted_numeric(&b.installation_version, &a.installation_version));
+    if args.latest {
+        instances.truncate(1);
+    }
+
+    let format = args.format.unwrap_or(VswhereFormat::Text);
+    if format == VswhereFormat::Json {
+        return match &args.property {
+            Some(name) => {
+                let values: Vec<_> = instances
+                    .iter()
+                    .filter_map(|i| i.property(name))
+                    .map(|value| serde_json::json!({ name.as_str(): value }))
+                    .collect();
+                Ok(serde_json::to_string_pretty(&values)?)
+            }
+            None => Ok(serde_json::to_string_pretty(&instances)?),
+        };
+    }
+
+    let mut out = String::new();
+    for instance in &instances {
+        match &args.property {
+            Some(name) => {
+                if let Some(value) = instance.property(name) {
+                    out.push_str(&value);
+                    out.push('\n');
+                }
+            }
+            None => {
+                let json = serde_json::to_value(instance)?;
+                for (key, value) in json.as_object().into_iter().flatten() {
+                    let lines: Vec<(String, &serde_json::Value)> = match value {
+                        serde_json::Value::Object(catalog) => catalog
+                            .iter()
+                            .map(|(k, v)| (format!("{}_{}", key, k), v))
+                            .collect(),
+                        _ => vec![(key.clone(), value)],
+                    };
+                    for (key, value) in lines {
+                        let value = match value {
+                            serde_json::Value::String(s) => s.clone(),
+                            other => other.to_string(),
+                        };
+                        if format == VswhereFormat::Value {
+                            out.push_str(&format!("{}\n", value));
+                        } else {
+                            out.push_str(&format!("{}: {}\n", key, value));
+                        }
+                    }
+                }
+                if format == VswhereFormat::Text {
+                    out.push('\n');
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Print vswhere-compatible output for the msvc toolsets installed by msvcup.
+pub fn vswhere_command(msvcup_dir: &MsvcupDir, args: &VswhereArgs) -> Result<()> {
+    let mut out = render(installed_instances(msvcup_dir)?, args)?;
+    if args.format == Some(VswhereFormat::Json) {
+        out.push('\n');
+    }
+    std::io::stdout().lock().write_all(out.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    /// Output of `vswhere -products * -format json` for a Build Tools 2022 install,
+    /// trimmed to the fields msvcup reports.
+    const REAL_VSWHERE_JSON: &str = r#"[
+      {
+        "instanceId": "3ed9d5bb",
+        "installDate": "2024-11-20T09:12:41Z",
+        "installationName": "VisualStudio/17.13.0+35806.99",
+        "installationPath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\2022\\BuildTools",
+        "installationVersion": "17.13.35806.99",
+        "productId": "Microsoft.VisualStudio.Product.BuildTools",
+        "productPath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\2022\\BuildTools\\Common7\\Tools\\LaunchDevCmd.bat",
+        "state": 4294967295,
+        "isComplete": true,
+        "isLaunchable": true,
+        "isPrerelease": false,
+        "isRebootRequired": false,
+        "displayName": "Visual Studio Build Tools 2022",
+        "description": "The Visual Studio Build Tools allows you to build native and managed MSBuild-based applications without requiring the Visual Studio IDE.",
+        "channelId": "VisualStudio.17.Release",
+        "channelUri": "https://aka.ms/vs/17/release/channel",
+        "enginePath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\Installer\\resources\\app\\ServiceHub\\Services\\Microsoft.VisualStudio.Setup.Service",
+        "updateDate": "2025-02-11T18:03:12.8431095Z",
+        "catalog": {
+          "buildBranch": "d17.13",
+          "buildVersion": "17.13.35806.99",
+          "id": "VisualStudio/17.13.0+35806.99",
+          "localBuild": "build-lab",
+          "manifestName": "VisualStudio",
+          "manifestType": "installer",
+          "productDisplayVersion": "17.13.0",
+          "productLine": "Dev17",
+          "productLineVersion": "2022",
+          "productMilestone": "RTW",
+          "productMilestoneIsPreRelease": "False",
+          "productName": "Visual Studio",
+          "productPatchVersion": "0",
+          "productPreReleaseMilestoneSuffix": "1.0",
+          "productSemanticVersion": "17.13.0+35806.99",
+          "requiredEngineVersion": "3.13.2069.61826"
+        },
+        "properties": {
+          "campaignId": "",
+          "channelManifestId": "VisualStudio.17.Release/17.13.0+35806.99",
+          "nickname": "",
+          "setupEngineFilePath": "C:\\Program Files (x86)\\Microsoft Visual Studio\\Installer\\setup.exe"
+        }
+      }
+    ]"#;
+
+    fn args(s: &str) -> VswhereArgs {
+        let args: Vec<String> = s.split_whitespace().map(str::to_string).collect();
+        VswhereArgs::parse(&args)
+    }
+
+    fn instance(version: &str, toolset_version: &str) -> VsInstance {
+        let pkg = MsvcupPackage::new(MsvcupPackageKind::Msvc, version);
+        VsInstance::new(
+            &pkg,
+            format!("C:\\msvcup\\{}", pkg),
+            toolset_version,
+            vec!["Microsoft.VisualStudio.Component.Windows11SDK.22621".to_string()],
+        )
+    }
+
+    #[test]
+    fn field_names_match_real_vswhere() {
+        let real: serde_json::Value = serde_json::from_str(REAL_VSWHERE_JSON).unwrap();
+        let real = &real[0];
+        let ours = serde_json::to_value(instance("14.43.17.13", "14.43.34808")).unwrap();
+        for (key, value) in ours.as_object().unwrap() {
+            let real_value = real
+                .get(key)
+                .unwrap_or_else(|| panic!("vswhere has no '{}' field", key));
+            assert_eq!(
+                std::mem::discriminant(value),
+                std::mem::discriminant(real_value),
+                "{}",
+                key
+            );
+        }
+        for key in ours["catalog"].as_object().unwrap().keys() {
+            assert!(real["catalog"].get(key).is_some(), "catalog.{}", key);
+        }
+    }
+
+    #[test]
+    fn instance_versions() {
+        let instance = instance("14.43.17.13", "14.43.34808");
+        assert_eq!(instance.installation_version, "17.13.34808.0");
+        assert_eq!(instance.catalog.product_line_version, "2022");
+        assert_eq!(instance.catalog.product_line, "Dev17");
+        assert_eq!(instance.channel_id, "VisualStudio.17.Release");
+        assert_eq!(instance.instance_id.len(), 8);
+    }
+
+    #[test]
+    fn parse_args() {
+        let parsed = args(
+            "-latest -products * -requires Microsoft.VisualStudio.Component.VC.Tools.x86.x64 \
+             -property installationPath -nologo -version [17.0,18.0) -format value",
+        );
+        assert_eq!(
+            parsed,
+            VswhereArgs {
+                latest: true,
+                products: vec!["*".to_string()],
+                requires: vec![VC_TOOLS_COMPONENT.to_string()],
+                requires_any: false,
+                property: Some("installationPath".to_string()),
+                format: Some(VswhereFormat::Value),
+            }
+        );
+        assert_eq!(args("--format json").format, Some(VswhereFormat::Json));
+        assert!(args("-Latest").latest);
+    }
+
+    #[test]
+    fn filter_and_render() {
+        let instances = || {
+            vec![
+                instance("14.42.17.12", "14.42.34433"),
+                instance("14.43.17.13", "14.43.34808"),
+            ]
+        };
+        assert_eq!(
+            render(instances(), &args("-latest -property installationPath")).unwrap(),
+            "C:\\msvcup\\msvc-14.43.17.13\n"
+        );
+        assert_eq!(
+            render(
+                instances(),
+                &args("-products * -requires Microsoft.VisualStudio.Component.VC.Tools.x86.x64 -property catalog_productLineVersion")
+            )
+            .unwrap(),
+            "2022\n2022\n"
+        );
+        assert_eq!(
+            render(
+                instances(),
+                &args("-requires Microsoft.VisualStudio.Component.VC.Tools.ARM64")
+            )
+            .unwrap(),
+            ""
+        );
+        assert_eq!(
+            render(
+                instances(),
+                &args("-requiresAny -requires Microsoft.VisualStudio.Component.VC.Tools.ARM64 Microsoft.VisualStudio.Workload.VCTools -property installationVersion")
+            )
+            .unwrap(),
+            "17.13.34808.0\n17.12.34433.0\n"
+        );
+        assert_eq!(
+            render(
+                instances(),
+                &args("-products Microsoft.VisualStudio.Product.Community")
+            )
+            .unwrap(),
+            ""
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(instances(), &args("-latest -format json")).unwrap())
+                .unwrap();
+        assert_eq!(json[0]["installationPath"], "C:\\msvcup\\msvc-14.43.17.13");
+        assert_eq!(json[0]["catalog"]["productDisplayVersion"], "17.13");
+
+        let text = render(instances(), &args("-latest")).unwrap();
+        assert!(
+            text.contains("installationVersion: 17.13.34808.0\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("catalog_productLine: Dev17\n"), "{}", text);
+    }
+
+    #[test]
+    fn installed_instances_from_pools() {
+        let root = TempDir::new("vswhere");
+        std::fs::create_dir_all(root.join("msvc-14.43.17.13/VC/Tools/MSVC/14.43.34808/lib/arm64"))
+            .unwrap();
+        std::fs::create_dir_all(root.join("sdk-10.0.22621.7")).unwrap();
+        std::fs::create_dir_all(root.join("ninja-1.12.1")).unwrap();
+        std::fs::create_dir_all(root.join("cache")).unwrap();
+        let msvcup_dir = MsvcupDir::with_path(root.to_path_buf());
+
+        let instances = installed_instances(&msvcup_dir).unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(
+            instances[0].installation_path,
+            root.join("msvc-14.43.17.13").display().to_string()
+        );
+        assert!(
+            instances[0]
+                .components
+                .contains(&"Microsoft.VisualStudio.Component.Windows11SDK.22621".to_string())
+        );
+        assert!(
+            instances[0]
+                .components
+                .contains(&VC_TOOLS_ARM64_COMPONENT.to_string())
+        );
+    }
+
+    #[test]
+    fn sdk_components() {
+        assert_eq!(
+            sdk_component("10.0.22621.7").unwrap(),
+            "Microsoft.VisualStudio.Component.Windows11SDK.22621"
+        );
+        assert_eq!(
+            sdk_component("10.0.19041.5").unwrap(),
+            "Microsoft.VisualStudio.Component.Windows10SDK.19041"
+        );
+        assert!(sdk_component("bogus").is_none());
+    }
+}
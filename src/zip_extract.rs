@@ -1,113 +1,325 @@
+use crate::file_exclusions::{FileExclusions, SKIP_PREFIX, SkippedFiles};
+use crate::util::{install_manifest_entry, sanitize_filename};
 use anyhow::{Context, Result};
 use fs_err as fs;
 use std::io::{self, Write};
 use std::path::Path;
 
-/// Extract a ZIP/VSIX file to an install directory, writing an install manifest
+/// Extract a ZIP/VSIX file to an install directory, writing an install manifest.
+/// Returns the files `exclusions` left out.
 pub fn extract_zip_to_dir(
     cache_path: &Path,
     install_dir_path: &Path,
     kind: ZipKind,
     strip_root_dir: bool,
+    exclusions: &FileExclusions,
     installing_manifest: &mut fs::File,
-) -> Result<()> {
+) -> Result<SkippedFiles> {
     let file = fs::File::open(cache_path)
         .with_context(|| format!("opening '{}'", cache_path.display()))?;
     let mut archive = zip::ZipArchive::new(file)
         .with_context(|| format!("reading ZIP '{}'", cache_path.display()))?;
 
-    let prefix = match kind {
-        ZipKind::Vsix => "Contents/",
-        ZipKind::Zip => "",
-    };
-
     let mut last_root_dir: Option<String> = None;
+    let mut skipped = SkippedFiles::default();
 
     for i in 0..archive.len() {
         let mut entry = archive.by_index(i)?;
-        let raw_name = entry.name().to_string();
-
-        // Normalize separators
-        let filename = raw_name.replace('\\', "/");
-
-        if filename.is_empty() || filename.starts_with('/') {
+        let Some(sub_path) =
+            entry_sub_path(entry.name(), kind, strip_root_dir, &mut last_root_dir)?
+        else {
+            continue;
+        };
+        if exclusions.excludes(&sub_path) {
+            skip_install_file(install_dir_path, &sub_path, installing_manifest)?;
+            skipped.add(entry.size());
             continue;
         }
+        let mut outfile = create_install_file(install_dir_path, &sub_path, installing_manifest)?;
+        io::copy(&mut entry, &mut outfile)?;
+    }
 
-        // Check for . and .. components
-        for part in filename.split('/') {
-            if part == "." || part == ".." {
-                anyhow::bail!(
-                    "ZIP filename contains '.' or '..' component: '{}'",
-                    filename
-                );
-            }
-        }
+    Ok(skipped)
+}
 
-        // Skip entries not in the expected prefix
-        if !filename.starts_with(prefix) {
-            continue;
-        }
+/// The names of all entries of a ZIP/VSIX. Only the central directory is read,
+/// no entry is decompressed.
+pub fn extract_zip_entry_names(archive_path: &Path) -> Result<Vec<String>> {
+    let file = fs::File::open(archive_path)
+        .with_context(|| format!("opening '{}'", archive_path.display()))?;
+    let archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("reading ZIP '{}'", archive_path.display()))?;
+    Ok(archive.file_names().map(str::to_string).collect())
+}
 
-        // Skip directories
-        if filename.ends_with('/') {
-            continue;
+/// Check the entry names of a ZIP/VSIX before [`extract_zip_to_dir`] writes
+/// anything, so an archive it would fail on halfway isn't partially installed.
+/// Returns the number of files that would be extracted.
+pub fn check_zip_entries(
+    archive_path: &Path,
+    kind: ZipKind,
+    strip_root_dir: bool,
+) -> Result<usize> {
+    let mut last_root_dir: Option<String> = None;
+    let mut file_count = 0;
+    for raw_name in extract_zip_entry_names(archive_path)? {
+        if entry_sub_path(&raw_name, kind, strip_root_dir, &mut last_root_dir)
+            .with_context(|| format!("checking '{}'", archive_path.display()))?
+            .is_some()
+        {
+            file_count += 1;
         }
+    }
+    if file_count == 0 {
+        log::debug!(
+            "'{}' has no files under '{}'",
+            archive_path.display(),
+            kind.prefix()
+        );
+    }
+    Ok(file_count)
+}
+
+/// The `/`-separated install path of the entry `raw_name`, or None for
+/// directories and entries outside the content prefix.
+fn entry_sub_path(
+    raw_name: &str,
+    kind: ZipKind,
+    strip_root_dir: bool,
+    last_root_dir: &mut Option<String>,
+) -> Result<Option<String>> {
+    if raw_name.is_empty() {
+        return Ok(None);
+    }
+    sanitize_filename(raw_name).with_context(|| format!("invalid ZIP filename '{}'", raw_name))?;
 
-        // Remove prefix, then URL percent-decode
-        let sub_path_encoded = &filename[prefix.len()..];
-        let sub_path_decoded =
-            percent_encoding::percent_decode_str(sub_path_encoded).decode_utf8_lossy();
-        let sub_path_decoded = sub_path_decoded.as_ref();
+    // Normalize separators
+    let filename = raw_name.replace('\\', "/");
 
-        // Strip root directory if requested
-        let sub_path = if strip_root_dir {
-            let sep_pos = sub_path_decoded.find('/').ok_or_else(|| {
-                anyhow::anyhow!("no root dir to strip from '{}'", sub_path_decoded)
-            })?;
-            let root_dir = &sub_path_decoded[..sep_pos];
-            if let Some(ref last) = last_root_dir
-                && last != root_dir
-            {
-                anyhow::bail!(
-                    "root dir changed from '{}' to '{}', cannot strip",
-                    last,
-                    root_dir
-                );
-            }
-            last_root_dir = Some(root_dir.to_string());
-            &sub_path_decoded[sep_pos..]
-        } else {
-            sub_path_decoded
-        };
+    // Skip entries not in the expected prefix and directories
+    let Some(sub_path_encoded) = filename.strip_prefix(kind.prefix()) else {
+        return Ok(None);
+    };
+    if filename.ends_with('/') {
+        return Ok(None);
+    }
 
-        let install_path = install_dir_path.join(
-            sub_path
-                .strip_prefix('/')
-                .unwrap_or(sub_path)
-                .replace('/', std::path::MAIN_SEPARATOR_STR),
+    // URL percent-decode
+    let sub_path_decoded =
+        percent_encoding::percent_decode_str(sub_path_encoded).decode_utf8_lossy();
+    let sub_path_decoded = sanitize_filename(sub_path_decoded.as_ref())
+        .with_context(|| format!("invalid decoded ZIP filename '{}'", sub_path_decoded))?;
+
+    let sub_path = if strip_root_dir {
+        strip_root_component(sub_path_decoded, last_root_dir)?
+    } else {
+        sub_path_decoded
+    };
+    Ok(Some(sub_path.to_string()))
+}
+
+/// Strip the root directory all of an archive's entries are in. Fails if an
+/// entry has no root directory or a different one than the previous entry.
+pub fn strip_root_component<'a>(
+    sub_path: &'a str,
+    last_root_dir: &mut Option<String>,
+) -> Result<&'a str> {
+    let sep_pos = sub_path
+        .find('/')
+        .ok_or_else(|| anyhow::anyhow!("no root dir to strip from '{}'", sub_path))?;
+    let root_dir = &sub_path[..sep_pos];
+    if let Some(last) = last_root_dir
+        && last != root_dir
+    {
+        anyhow::bail!(
+            "root dir changed from '{}' to '{}', cannot strip",
+            last,
+            root_dir
         );
+    }
+    *last_root_dir = Some(root_dir.to_string());
+    Ok(&sub_path[sep_pos..])
+}
 
-        // Check if file already exists
-        if install_path.exists() {
-            writeln!(installing_manifest, "add {}", install_path.display())?;
-        } else {
-            writeln!(installing_manifest, "new {}", install_path.display())?;
-            if let Some(parent) = install_path.parent() {
-                fs::create_dir_all(parent)?;
-            }
-        }
+/// Create the file for an archive entry at the `/`-separated `sub_path` of the
+/// install directory, recording it in the install manifest as `new` or, if an
+/// earlier payload already installed it, `add`.
+pub fn create_install_file(
+    install_dir_path: &Path,
+    sub_path: &str,
+    installing_manifest: &mut fs::File,
+) -> Result<fs::File> {
+    let install_path = install_dir_path.join(
+        sub_path
+            .strip_prefix('/')
+            .unwrap_or(sub_path)
+            .replace('/', std::path::MAIN_SEPARATOR_STR),
+    );
 
-        let mut outfile = fs::File::create(&install_path)
-            .with_context(|| format!("creating '{}'", install_path.display()))?;
-        io::copy(&mut entry, &mut outfile)?;
+    // Check if file already exists
+    let manifest_entry = install_manifest_entry(install_dir_path, &install_path);
+    if install_path.exists() {
+        writeln!(installing_manifest, "add {}", manifest_entry)?;
+    } else {
+        writeln!(installing_manifest, "new {}", manifest_entry)?;
+        if let Some(parent) = install_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
     }
 
+    fs::File::create(&install_path)
+        .with_context(|| format!("creating '{}'", install_path.display()))
+}
+
+/// Record the file at the `/`-separated `sub_path` of the install directory as
+/// `skip` in the install manifest instead of creating it.
+pub fn skip_install_file(
+    install_dir_path: &Path,
+    sub_path: &str,
+    installing_manifest: &mut fs::File,
+) -> Result<()> {
+    let install_path = install_dir_path.join(
+        sub_path
+            .strip_prefix('/')
+            .unwrap_or(sub_path)
+            .replace('/', std::path::MAIN_SEPARATOR_STR),
+    );
+    writeln!(
+        installing_manifest,
+        "{}{}",
+        SKIP_PREFIX,
+        install_manifest_entry(install_dir_path, &install_path)
+    )?;
     Ok(())
 }
 
+/// Read a single file out of a ZIP/VSIX without extracting the rest.
+///
+/// `file_path` is relative to the archive's content root (i.e. without the VSIX
+/// `Contents/` prefix) and is matched case-insensitively with either separator.
+pub fn extract_zip_file(archive_path: &Path, kind: ZipKind, file_path: &str) -> Result<Vec<u8>> {
+    let file = fs::File::open(archive_path)
+        .with_context(|| format!("opening '{}'", archive_path.display()))?;
+    let mut archive = zip::ZipArchive::new(file)
+        .with_context(|| format!("reading ZIP '{}'", archive_path.display()))?;
+
+    let wanted = file_path.replace('\\', "/");
+    let wanted = wanted.trim_start_matches('/');
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let filename = entry.name().replace('\\', "/");
+        let Some(sub_path_encoded) = filename.strip_prefix(kind.prefix()) else {
+            continue;
+        };
+        let sub_path = percent_encoding::percent_decode_str(sub_path_encoded).decode_utf8_lossy();
+        if sub_path.eq_ignore_ascii_case(wanted) {
+            let mut content = Vec::with_capacity(entry.size() as usize);
+            io::Read::read_to_end(&mut entry, &mut content).with_context(|| {
+                format!("reading '{}' from '{}'", filename, archive_path.display())
+            })?;
+            return Ok(content);
+        }
+    }
+    anyhow::bail!(
+        "'{}' does not contain '{}'",
+        archive_path.display(),
+        file_path
+    );
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum ZipKind {
     Vsix,
     Zip,
 }
+
+impl ZipKind {
+    /// Directory inside the archive that holds the installable files
+    fn prefix(&self) -> &'static str {
+        match self {
+            ZipKind::Vsix => "Contents/",
+            ZipKind::Zip => "",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn check_entries_before_extracting() {
+        let dir = TempDir::new("check_zip_entries");
+        let vsix = dir.join("test.vsix");
+        write_test_zip(
+            &vsix,
+            &[
+                ("manifest.json", b"{}"),
+                ("Contents/VC/", b""),
+                ("Contents/VC/bin/cl.exe", b"cl"),
+            ],
+        );
+        assert_eq!(
+            extract_zip_entry_names(&vsix).unwrap(),
+            ["manifest.json", "Contents/VC/", "Contents/VC/bin/cl.exe"]
+        );
+        assert_eq!(check_zip_entries(&vsix, ZipKind::Vsix, false).unwrap(), 1);
+        assert_eq!(check_zip_entries(&vsix, ZipKind::Zip, false).unwrap(), 2);
+
+        // Extracting would only fail at the second root dir, after writing the first
+        let zip = dir.join("two-roots.zip");
+        write_test_zip(&zip, &[("a/x.exe", b"x"), ("b/y.exe", b"y")]);
+        assert_eq!(check_zip_entries(&zip, ZipKind::Zip, false).unwrap(), 2);
+        let err = check_zip_entries(&zip, ZipKind::Zip, true).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("root dir changed"),
+            "{:#}",
+            err
+        );
+    }
+
+    fn write_test_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let file = std::fs::File::create(path).unwrap();
+        let mut zip = zip::ZipWriter::new(file);
+        for (name, content) in entries {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn extract_zip_file_vsix() {
+        let dir = TempDir::new("extract_zip_file");
+        let vsix = dir.join("test.vsix");
+        write_test_zip(
+            &vsix,
+            &[
+                ("manifest.json", b"{}"),
+                ("Contents/VC/bin/cl.exe", b"cl"),
+                ("Contents/VC/bin/My%20Tool.exe", b"tool"),
+            ],
+        );
+
+        assert_eq!(
+            extract_zip_file(&vsix, ZipKind::Vsix, "VC/bin/cl.exe").unwrap(),
+            b"cl"
+        );
+        assert_eq!(
+            extract_zip_file(&vsix, ZipKind::Vsix, "vc\\BIN\\CL.EXE").unwrap(),
+            b"cl"
+        );
+        assert_eq!(
+            extract_zip_file(&vsix, ZipKind::Vsix, "VC/bin/My Tool.exe").unwrap(),
+            b"tool"
+        );
+        // Entries outside Contents/ aren't part of a VSIX's files
+        assert!(extract_zip_file(&vsix, ZipKind::Vsix, "manifest.json").is_err());
+        assert_eq!(
+            extract_zip_file(&vsix, ZipKind::Zip, "manifest.json").unwrap(),
+            b"{}"
+        );
+    }
+}
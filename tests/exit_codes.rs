@@ -0,0 +1,457 @@
+//! Checks the documented exit codes of the `msvcup` binary.
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("msvcup_test_exit_codes_{}", name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Serve `body` for every request on a local port, returning the base URL.
+fn serve_fixture(body: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let mut buf = [0u8; 4096];
+            let _ = stream.read(&mut buf);
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(header.as_bytes());
+            let _ = stream.write_all(body);
+        }
+    });
+    format!("http://{}", addr)
+}
+
+#[test]
+fn invalid_package_name_is_usage_error() {
+    let dir = temp_dir("usage");
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--lock-file"])
+        .arg(dir.join("msvcup.lock"))
+        .args(["--manifest-update", "off", "bogus-1.0"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("msvc, sdk, msbuild, diasdk, ninja, cmake"),
+        "{}",
+        stderr
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn kind_without_version_is_usage_error_without_a_terminal() {
+    let dir = temp_dir("bare_kind");
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--lock-file"])
+        .arg(dir.join("msvcup.lock"))
+        .args(["--manifest-update", "off", "msvc"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid package 'msvc'"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn two_msvc_versions_in_env_is_usage_error() {
+    let dir = temp_dir("env_versions");
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["env", "msvc-14.30.17.6", "msvc-14.43.17.13"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("got msvc-14.30.17.6 and msvc-14.43.17.13"),
+        "{}",
+        stderr
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn locked_install_from_discovered_config_is_lock_file_mismatch() {
+    let dir = temp_dir("locked");
+    std::fs::write(
+        dir.join("msvcup.toml"),
+        "[msvcup]\nlock_file = \"msvcup.lock\"\ntarget_arch = \"x64\"\n\n\
+         [packages]\nmsvc = \"14.43.17.13\"\n",
+    )
+    .unwrap();
+    let sub_dir = dir.join("src");
+    std::fs::create_dir_all(&sub_dir).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .current_dir(&sub_dir)
+        .args(["install", "--locked"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("msvcup.lock' doesn't exist"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn broken_parent_config_only_warns_for_informational_commands() {
+    let dir = temp_dir("broken_config");
+    std::fs::write(
+        dir.join("msvcup.toml"),
+        "[msvcup]\nlock_file = \"msvcup.lock\"\ntarget_arch = \"riscv64\"\n\n\
+         [packages]\nmsvc = \"14.43.17.13\"\n",
+    )
+    .unwrap();
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_msvcup"))
+            .env("MSVCUP_INSTALL_DIR", dir.join("install"))
+            .current_dir(&dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&["vswhere"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("ignoring the project config"), "{}", stderr);
+
+    let output = run(&["cargo-config", "msvc-14.43.17.13", "sdk-10.0.22621.7"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("msvcup.toml'"), "{}", stderr);
+    assert!(stderr.contains("invalid target_arch"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn only_kind_not_requested_is_usage_error() {
+    let dir = temp_dir("only");
+    let status = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--lock-file"])
+        .arg(dir.join("msvcup.lock"))
+        .args([
+            "--manifest-update",
+            "off",
+            "--only",
+            "sdk",
+            "msvc-14.43.34808",
+        ])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn missing_lock_file_for_unknown_version_is_lock_file_mismatch() {
+    let dir = temp_dir("mismatch");
+    let manifest_dir = dir.join("manifest").join("vs-release");
+    std::fs::create_dir_all(&manifest_dir).unwrap();
+    std::fs::write(manifest_dir.join("latest"), r#"{"packages": []}"#).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--lock-file"])
+        .arg(dir.join("msvcup.lock"))
+        .args(["--manifest-update", "off", "msvc-14.43.34808"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn sha_mismatch_is_hash_error() {
+    let dir = temp_dir("sha");
+    let base_url = serve_fixture(b"not the expected content");
+    let lock_file = dir.join("msvcup.lock");
+    std::fs::write(
+        &lock_file,
+        format!(
+            r#"{{"packages": [{{"name": "ninja-1.12.1", "payloads": [
+                {{"url": "{}/ninja-win.zip",
+                  "sha256": "0000000000000000000000000000000000000000000000000000000000000000"}}
+            ]}}]}}"#,
+            base_url
+        ),
+    )
+    .unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--lock-file"])
+        .arg(&lock_file)
+        .args(["--manifest-update", "off", "--cache-dir"])
+        .arg(dir.join("cache"))
+        .arg("ninja-1.12.1")
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(5));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn packages_file_is_expanded() {
+    let dir = temp_dir("packages_file");
+    let list = dir.join("packages.txt");
+    std::fs::write(&list, "# toolchain\nbogus-1.0 # not a package\n\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--lock-file"])
+        .arg(dir.join("msvcup.lock"))
+        .args(["--manifest-update", "off"])
+        .arg(format!("@{}", list.display()))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid package 'bogus-1.0'"), "{}", stderr);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--lock-file"])
+        .arg(dir.join("msvcup.lock"))
+        .args(["--manifest-update", "off"])
+        .arg(format!("@{}", dir.join("missing.txt").display()))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(6));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn channel_with_channel_url_is_usage_error() {
+    let dir = temp_dir("channel_conflict");
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["list", "--channel", "all"])
+        .args(["--channel-url", "http://127.0.0.1:9/channel"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("can't be combined"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn global_cache_is_the_default_cache_dir() {
+    let dir = temp_dir("global_cache");
+    let lock_file = dir.join("msvcup.lock");
+    std::fs::write(&lock_file, r#"{"version": 1, "packages": []}"#).unwrap();
+    let cache_key_paths = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+            .env("MSVCUP_INSTALL_DIR", &dir)
+            .env("MSVCUP_GLOBAL_CACHE", dir.join("env-cache"))
+            .args(["cache", "key", "--json", "--lock-file"])
+            .arg(&lock_file)
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        json[0]["paths"][0].as_str().unwrap().to_string()
+    };
+    assert_eq!(
+        cache_key_paths(&[]),
+        dir.join("env-cache").to_str().unwrap()
+    );
+    let flag_cache = dir.join("flag-cache");
+    assert_eq!(
+        cache_key_paths(&["--global-cache", flag_cache.to_str().unwrap()]),
+        flag_cache.to_str().unwrap()
+    );
+    let own_cache = dir.join("own-cache");
+    assert_eq!(
+        cache_key_paths(&[
+            "--global-cache",
+            flag_cache.to_str().unwrap(),
+            "--cache-dir",
+            own_cache.to_str().unwrap()
+        ]),
+        own_cache.to_str().unwrap()
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn transactional_install_rolls_back_a_failed_package() {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    zip.start_file("ninja.exe", zip::write::SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"not really ninja").unwrap();
+    let body: &'static [u8] = zip.finish().unwrap().into_inner().leak();
+    let sha256: String = {
+        use sha2::Digest;
+        sha2::Sha256::digest(body)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    };
+    let base_url = serve_fixture(body);
+
+    // The second payload fails its hash check after the first one is installed
+    for transactional in [false, true] {
+        let dir = temp_dir(&format!("transactional_{}", transactional));
+        let lock_file = dir.join("msvcup.lock");
+        std::fs::write(
+            &lock_file,
+            format!(
+                r#"{{"packages": [{{"name": "ninja-1.12.1", "payloads": [
+                    {{"url": "{base_url}/ninja-win.zip", "sha256": "{sha256}"}},
+                    {{"url": "{base_url}/extra-win.zip",
+                      "sha256": "0000000000000000000000000000000000000000000000000000000000000000"}}
+                ]}}]}}"#
+            ),
+        )
+        .unwrap();
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_msvcup"));
+        cmd.env("MSVCUP_INSTALL_DIR", &dir)
+            .args(["install", "--lock-file"])
+            .arg(&lock_file)
+            .args(["--manifest-update", "off", "--cache-dir"])
+            .arg(dir.join("cache"))
+            .arg("ninja-1.12.1");
+        if transactional {
+            cmd.arg("--transactional");
+        }
+        let output = cmd.output().unwrap();
+        assert_eq!(output.status.code(), Some(5));
+
+        let pool = dir.join("ninja-1.12.1");
+        let manifests = std::fs::read_dir(pool.join("install"))
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        if transactional {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains("rolled back"), "{}", stderr);
+            assert!(!pool.join("ninja.exe").exists());
+            assert_eq!(manifests, 0);
+        } else {
+            assert!(pool.join("ninja.exe").exists());
+            assert_eq!(manifests, 1);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
+
+#[test]
+fn keep_going_installs_past_a_failed_payload() {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    zip.start_file("ninja.exe", zip::write::SimpleFileOptions::default())
+        .unwrap();
+    zip.write_all(b"not really ninja").unwrap();
+    let body: &'static [u8] = zip.finish().unwrap().into_inner().leak();
+    let sha256: String = {
+        use sha2::Digest;
+        sha2::Sha256::digest(body)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    };
+    let base_url = serve_fixture(body);
+
+    // The optional redist payload fails its hash check before the essential one
+    let dir = temp_dir("keep_going");
+    let lock_file = dir.join("msvcup.lock");
+    std::fs::write(
+        &lock_file,
+        format!(
+            r#"{{"packages": [{{"name": "ninja-1.12.1", "payloads": [
+                {{"url": "{base_url}/VC_redist.x64.zip",
+                  "sha256": "0000000000000000000000000000000000000000000000000000000000000000"}},
+                {{"url": "{base_url}/ninja-win.zip", "sha256": "{sha256}"}}
+            ]}}]}}"#
+        ),
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .args(["install", "--keep-going", "--lock-file"])
+        .arg(&lock_file)
+        .args(["--manifest-update", "off", "--cache-dir"])
+        .arg(dir.join("cache"))
+        .arg("ninja-1.12.1")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(5));
+    assert!(dir.join("ninja-1.12.1").join("ninja.exe").exists());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("failed:            1 payload(s), 0 essential"),
+        "{}",
+        stdout
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("failed payloads:"), "{}", stderr);
+    assert!(stderr.contains("VC_redist.x64.zip  optional"), "{}", stderr);
+    assert!(
+        stderr.contains("1 payload(s) failed to install, 0 of them essential"),
+        "{}",
+        stderr
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn install_arguments_from_the_environment() {
+    let dir = temp_dir("install_env");
+    let output = Command::new(env!("CARGO_BIN_EXE_msvcup"))
+        .env("MSVCUP_INSTALL_DIR", &dir)
+        .env("MSVCUP_PACKAGES", "ninja-1.12.1:bogus-1.0")
+        .args(["install", "--lock-file"])
+        .arg(dir.join("msvcup.lock"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("invalid package 'bogus-1.0'"), "{}", stderr);
+
+    // The lock file and manifest update policy, a bad policy is a usage error
+    let base_url = serve_fixture(b"not the expected content");
+    let lock_file = dir.join("env.lock");
+    std::fs::write(
+        &lock_file,
+        format!(
+            r#"{{"packages": [{{"name": "ninja-1.12.1", "payloads": [
+                {{"url": "{}/ninja-win.zip",
+                  "sha256": "0000000000000000000000000000000000000000000000000000000000000000"}}
+            ]}}]}}"#,
+            base_url
+        ),
+    )
+    .unwrap();
+    let install = |manifest_update: &str| {
+        Command::new(env!("CARGO_BIN_EXE_msvcup"))
+            .env("MSVCUP_INSTALL_DIR", &dir)
+            .env("MSVCUP_PACKAGES", "ninja-1.12.1")
+            .env("MSVCUP_LOCK_FILE", &lock_file)
+            .env("MSVCUP_MANIFEST_UPDATE", manifest_update)
+            .args(["install", "--cache-dir"])
+            .arg(dir.join("cache"))
+            .status()
+            .unwrap()
+    };
+    // The payload is fetched from the lock file and fails its hash check
+    assert_eq!(install("off").code(), Some(5));
+    assert_eq!(install("sometimes").code(), Some(2));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,31 @@
+# Generated by msvcup bazel-toolchain, do not edit.
+
+MSVCUP_X64_WINDOWS = {
+    "cpu": "x64_windows",
+    "host_cpu": "x86_64",
+    "target_cpu": "x86_64",
+    "msvc_cl_path": "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\x64\\cl.exe",
+    "msvc_link_path": "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\x64\\link.exe",
+    "msvc_lib_path": "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\x64\\lib.exe",
+    "msvc_ml_path": "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\x64\\ml64.exe",
+    "msvc_env_path": "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\bin\\Hostx64\\x64;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\bin\\10.0.22621.0\\x64",
+    "msvc_env_include": "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\include;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\ucrt;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\shared;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\um;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\winrt;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\cppwinrt",
+    "msvc_env_lib": "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\lib\\x64;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Lib\\10.0.22621.0\\ucrt\\x64;<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Lib\\10.0.22621.0\\um\\x64",
+    "cxx_builtin_include_directories": [
+        "<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\include",
+        "<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\ucrt",
+        "<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\shared",
+        "<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\um",
+        "<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\winrt",
+        "<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Include\\10.0.22621.0\\cppwinrt",
+    ],
+    "archiver_flags": [
+        "/MACHINE:X64",
+    ],
+    "default_link_flags": [
+        "/MACHINE:X64",
+        "/LIBPATH:<root>/msvc-14.43.17.13\\VC\\Tools\\MSVC\\14.43.34808\\lib\\x64",
+        "/LIBPATH:<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Lib\\10.0.22621.0\\ucrt\\x64",
+        "/LIBPATH:<root>/sdk-10.0.22621.7\\Windows Kits\\10\\Lib\\10.0.22621.0\\um\\x64",
+    ],
+}